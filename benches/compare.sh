@@ -0,0 +1,36 @@
+#!/bin/sh
+# Runs the headless_fps benchmark on an older commit, then on the working tree, with the same
+# arguments. The README table comes from
+#   benches/compare.sh ':/Report KIL jams'
+#   benches/compare.sh ':/Make CPU generic'
+# The first one, the last commit before the CPU went generic over its bus, is the default.
+# ':/<text>' is the newest commit with <text> in its message: it still works after a rebase.
+#
+# compare.sh [<commit> [<rom> <frames>]]: a relative ROM path is taken in each tree
+set -e
+
+commit=${1:-:/Report KIL jams}
+[ $# -gt 0 ] && shift
+root=$(git rev-parse --show-toplevel)
+dir=$(mktemp -d)
+trap 'git -C "$root" worktree remove --force "$dir/tree"; rm -rf "$dir"' EXIT
+
+git -C "$root" worktree add --detach "$dir/tree" "$commit"
+mkdir -p "$dir/tree/benches"
+cp "$root/benches/headless_fps.rs" "$dir/tree/benches/"
+if ! grep -q headless_fps "$dir/tree/Cargo.toml"; then
+    printf '\n[[bench]]\nname = "headless_fps"\nharness = false\n' >> "$dir/tree/Cargo.toml"
+fi
+# the CPU used to take a boxed bus
+if grep -q 'Box<dyn CpuBus' "$dir/tree/src/cpu/cpu.rs"; then
+    sed -i.bak 's/CPU::new(bus)/CPU::new(Box::new(bus))/' "$dir/tree/benches/headless_fps.rs"
+fi
+# same dependency versions on both sides
+if [ -f "$root/Cargo.lock" ]; then
+    cp "$root/Cargo.lock" "$dir/tree/"
+fi
+
+echo "== $(git -C "$root" rev-parse --short "$commit")"
+(cd "$dir/tree" && CARGO_TARGET_DIR="$dir/target" cargo bench -q --bench headless_fps -- "$@")
+echo "== working tree, on $(git -C "$root" rev-parse --short HEAD)"
+(cd "$root" && cargo bench -q --bench headless_fps -- "$@")
@@ -0,0 +1,96 @@
+// Headless emulation speed: runs a ROM without a window and reports emulated frames per second.
+//
+// - "frame render": the frame callback renders the whole frame, same as the native frontend does
+//   (only presenting it is skipped)
+// - "no frame render": the frame callback does nothing, this is how batch jobs run
+// - "cpu only": nestest's automation mode ($C000) on a MockBus, in frames worth of CPU cycles.
+//   There is no PPU to pay for, so this is the cost of the CPU core alone.
+//
+// cargo bench --bench headless_fps [-- <rom> <frames>]
+use rustness::bus::Bus;
+use rustness::bus::MockBus;
+use rustness::cpu::cpu::CPU;
+use rustness::input;
+use rustness::ppu::ppu::NesPPU;
+use rustness::rom::Rom;
+use rustness::screen::frame::Frame;
+use rustness::screen::render;
+use std::cell::Cell;
+use std::time::Instant;
+
+const DEFAULT_ROM: &str = "test_rom/nestest.nes";
+const DEFAULT_FRAMES: usize = 3_000;
+const RUNS: usize = 5;
+const CPU_CYCLES_PER_FRAME: usize = 29_781;
+
+// last instruction of the nestest automation run
+const NESTEST_END: u16 = 0xc66e;
+
+fn run_nes(data: &[u8], frames: usize, with_render: bool) -> f64 {
+    let rom = Rom::load(data).unwrap();
+    let rendered = Cell::new(0usize);
+    let mut frame = Frame::new();
+    let bus = Bus::<NesPPU>::new(rom, |ppu: &NesPPU, _: &mut input::Joypad| {
+        if with_render {
+            render::render(ppu, &mut frame);
+        }
+        rendered.set(rendered.get() + 1);
+    });
+    let mut cpu = CPU::new(bus);
+    cpu.power_on();
+
+    let start = Instant::now();
+    cpu.run_until(|_| rendered.get() >= frames);
+    frames as f64 / start.elapsed().as_secs_f64()
+}
+
+fn run_cpu_only(data: &[u8], frames: usize) -> f64 {
+    let rom = Rom::load(data).unwrap();
+    let mut bus = MockBus::new();
+    let prg = &rom.prg_rom[..0x4000];
+    bus.space[0x8000..0xc000].copy_from_slice(prg);
+    bus.space[0xc000..].copy_from_slice(prg);
+    let mut cpu = CPU::new(bus);
+
+    let start = Instant::now();
+    while cpu.cycles() < frames * CPU_CYCLES_PER_FRAME {
+        cpu.program_counter = 0xc000;
+        cpu.run_until(|cpu| cpu.program_counter == NESTEST_END);
+    }
+    frames as f64 / start.elapsed().as_secs_f64()
+}
+
+fn report(name: &str, frames: usize, run: impl Fn() -> f64) {
+    let mut results: Vec<f64> = (0..RUNS).map(|_| run()).collect();
+    results.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    println!(
+        "{:>16}: {} frames, {:.0} fps (median of {} runs, min {:.0}, max {:.0})",
+        name,
+        frames,
+        results[RUNS / 2],
+        RUNS,
+        results[0],
+        results[RUNS - 1]
+    );
+}
+
+fn main() {
+    // cargo passes "--bench" to harness-less benchmarks
+    let args: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    let path = args.first().map(String::as_str).unwrap_or(DEFAULT_ROM);
+    let frames = args
+        .get(1)
+        .map(|frames| frames.parse().expect("frames must be a number"))
+        .unwrap_or(DEFAULT_FRAMES);
+    let data = std::fs::read(path).unwrap();
+
+    println!("{}", path);
+    report("frame render", frames, || run_nes(&data, frames, true));
+    report("no frame render", frames, || run_nes(&data, frames, false));
+    if path == DEFAULT_ROM {
+        report("cpu only", frames, || run_cpu_only(&data, frames));
+    }
+}
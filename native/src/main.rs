@@ -1,6 +1,8 @@
 use rustness::bus::Bus;
+use rustness::cdl::CodeDataLog;
 use rustness::cpu::cpu::CPU;
-use rustness::cpu::mem::Mem;
+use rustness::debugger::history::{self, History};
+use rustness::debugger::{DebugBus, Debugger};
 use rustness::input;
 use rustness::ppu::ppu::NesPPU;
 use rustness::rom::Rom;
@@ -13,6 +15,7 @@ use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Rect;
 use std::fs::File;
 use std::io::Read;
+use std::path::Path;
 use std::time::Duration;
 use std::time::SystemTime;
 
@@ -32,12 +35,29 @@ fn main() {
     key_map.insert(Keycode::A, input::JoypadButton::BUTTON_A);
     key_map.insert(Keycode::S, input::JoypadButton::BUTTON_B);
 
-    let mut file = File::open(dbg!(env::args().collect::<Vec<String>>()).get(1).unwrap()).unwrap();
+    let args: Vec<String> = env::args().collect();
+    let mut file = File::open(dbg!(&args).get(1).unwrap()).unwrap();
     let mut data = Vec::new();
     file.read_to_end(&mut data).unwrap();
 
     let rom = Rom::load(&data).unwrap();
 
+    // a .cdl path among the extra arguments turns on the code/data logger, continuing that log
+    let cdl_path = args.iter().skip(2).find(|path| path.ends_with(".cdl")).map(|path| Path::new(path).to_path_buf());
+    let cdl = cdl_path.as_ref().map(|path| Rc::new(RefCell::new(CodeDataLog::open(&rom, path).unwrap())));
+    let save_cdl = {
+        let cdl = cdl.clone();
+        move || {
+            if let (Some(cdl), Some(path)) = (&cdl, &cdl_path) {
+                let cdl = cdl.borrow();
+                cdl.save(path).unwrap();
+                let (code, data, unused) = cdl.prg_summary();
+                println!("{}: {} code, {} data, {} unused PRG bytes", path.display(), code, data, unused);
+            }
+        }
+    };
+    let save_cdl_rc = save_cdl.clone();
+
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
@@ -73,6 +93,12 @@ fn main() {
 
     let trace_rc = trace.clone();
 
+    let reset = Rc::from(RefCell::from(false));
+    let reset_rc = reset.clone();
+
+    let pause = Rc::from(RefCell::from(false));
+    let pause_rc = pause.clone();
+
     let frame = Frame::new();
     let func = move |z: &NesPPU, joypad: &mut input::Joypad| {
         for event in event_pump.poll_iter() {
@@ -81,7 +107,10 @@ fn main() {
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => std::process::exit(0),
+                } => {
+                    save_cdl_rc();
+                    std::process::exit(0)
+                }
                 Event::KeyDown {
                     keycode: Some(Keycode::D),
                     ..
@@ -89,6 +118,19 @@ fn main() {
                     let upd = !*trace_rc.borrow();
                     trace_rc.replace(upd);
                 }
+                Event::KeyDown {
+                    keycode: Some(Keycode::R),
+                    ..
+                } => {
+                    reset_rc.replace(true);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::B),
+                    ..
+                } => {
+                    // break into the debugger console on stdin
+                    pause_rc.replace(true);
+                }
 
                 Event::KeyDown { keycode, .. } => {
                     if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
@@ -183,17 +225,47 @@ fn main() {
     };
 
     let mut bus = Bus::<'_, NesPPU>::new(rom, func);
+    if let Some(cdl) = cdl {
+        bus.log_code_data(cdl);
+    }
 
-    let pc = Mem::read_u16(&mut bus, 0xfffc);
-    println!("ROM Start address: {}", pc);
-    let mut cpu = CPU::new(Box::from(bus));
-    cpu.program_counter = pc;
+    let mut cpu = CPU::new(DebugBus::new(bus));
+    cpu.power_on();
+    println!("ROM Start address: {}", cpu.program_counter);
+
+    // symbol files can follow the ROM path
+    let mut debugger = Debugger::new();
+    for path in args.iter().skip(2).filter(|path| !path.ends_with(".cdl")) {
+        debugger.symbols.load(Path::new(path)).unwrap();
+    }
+    debugger.resume();
+    let stdin = std::io::stdin();
+    let mut input = stdin.lock();
+    let mut output = std::io::stdout();
 
     let trace_rc2 = trace.clone();
-    cpu.interpret_fn(0xffff, |cpu| {
+    let reset_rc2 = reset.clone();
+    // backtrace and last instructions go to stderr if the emulation aborts
+    let symbols = debugger.symbols.clone();
+    let mut history = History::new(history::DEFAULT_LENGTH);
+    let halt = history::run(&mut cpu, &mut history, &symbols, |cpu| {
+        if reset_rc2.replace(false) {
+            cpu.reset();
+        }
+        if pause.replace(false) {
+            debugger.pause();
+        }
+        if let Some(stop) = debugger.check(cpu) {
+            if !debugger.prompt(cpu, stop, &mut input, &mut output) {
+                return true;
+            }
+        }
         if *trace_rc2.borrow() {
             // ::std::thread::sleep(Duration::new(0, 10000));
-            println!("{}", rustness::cpu::trace(cpu));
+            println!("{}", rustness::cpu::trace_with_symbols(cpu, &debugger.symbols));
         }
+        false
     });
+    println!("CPU halted: {:?}", halt);
+    save_cdl();
 }
@@ -0,0 +1,994 @@
+// Two pass 6502 assembler. Accepts the syntax produced by `disasm`, plus labels,
+// `NAME = expr` constants and the `.org`, `.base`, `.byte`, `.word` and `.incbin` directives.
+//
+// Encodings come from CPU_OPS_CODES: when several opcodes share a mnemonic and
+// addressing mode (e.g. the undocumented NOPs), the first one in the table is used.
+use crate::cpu::mem::AddressingMode;
+use crate::cpu::opscode::{self, OpsCode};
+use std::collections::HashMap;
+
+/// Operand syntax of an instruction, independent of the page-cross timing variants
+/// of AddressingMode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    /// 65C02 only: (zp)
+    ZeroPageIndirect,
+    Relative,
+}
+
+impl Operand {
+    pub fn of(ops: &OpsCode) -> Operand {
+        match ops.mode {
+            AddressingMode::Immediate => Operand::Immediate,
+            AddressingMode::Accumulator => Operand::Accumulator,
+            AddressingMode::ZeroPage => Operand::ZeroPage,
+            AddressingMode::ZeroPage_X => Operand::ZeroPageX,
+            AddressingMode::ZeroPage_Y => Operand::ZeroPageY,
+            AddressingMode::Absolute => Operand::Absolute,
+            AddressingMode::Absolute_X | AddressingMode::Absolute_X_PageCross => Operand::AbsoluteX,
+            AddressingMode::Absolute_Y | AddressingMode::Absolute_Y_PageCross => Operand::AbsoluteY,
+            AddressingMode::Indirect_X => Operand::IndirectX,
+            AddressingMode::Indirect_Y | AddressingMode::Indirect_Y_PageCross => Operand::IndirectY,
+            AddressingMode::ZeroPage_Indirect => Operand::ZeroPageIndirect,
+            AddressingMode::NoneAddressing => match ops.len {
+                1 => Operand::Implied,
+                2 => Operand::Relative,
+                _ if ops.code == 0x6c => Operand::Indirect,
+                _ => Operand::Absolute,
+            },
+        }
+    }
+
+    /// Instruction length in bytes
+    pub fn instruction_len(self) -> u16 {
+        match self {
+            Operand::Implied | Operand::Accumulator => 1,
+            Operand::Absolute | Operand::AbsoluteX | Operand::AbsoluteY | Operand::Indirect => 3,
+            _ => 2,
+        }
+    }
+}
+
+lazy_static! {
+    static ref ENCODINGS: HashMap<(String, Operand), u8> = {
+        let mut map = HashMap::new();
+        for ops in opscode::CPU_OPS_CODES.iter() {
+            map.entry((ops.mnemonic.to_string(), Operand::of(ops)))
+                .or_insert(ops.code);
+        }
+        map
+    };
+}
+
+/// Opcode used to encode `mnemonic` with the given operand.
+/// Mnemonics are case-insensitive and undocumented ones may be written without the `*` prefix.
+pub fn opcode(mnemonic: &str, operand: Operand) -> Option<u8> {
+    let mnemonic = mnemonic.to_ascii_uppercase();
+    ENCODINGS
+        .get(&(mnemonic.clone(), operand))
+        .or_else(|| ENCODINGS.get(&(format!("*{}", mnemonic), operand)))
+        .copied()
+}
+
+fn is_mnemonic(mnemonic: &str) -> bool {
+    let mnemonic = mnemonic.to_ascii_uppercase();
+    let undocumented = format!("*{}", mnemonic);
+    ENCODINGS
+        .keys()
+        .any(|(m, _)| *m == mnemonic || *m == undocumented)
+}
+
+#[derive(Debug)]
+pub struct Assembly {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    /// Labels and constants
+    pub symbols: HashMap<String, u16>,
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, String> {
+    assemble_with(source, &|path| {
+        std::fs::read(path).map_err(|e| format!("can't include {}: {}", path, e))
+    })
+}
+
+/// Assembles `source`, loading `.incbin` files with `include`
+pub fn assemble_with(
+    source: &str,
+    include: &dyn Fn(&str) -> Result<Vec<u8>, String>,
+) -> Result<Assembly, String> {
+    let mut statements = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        parse_line(i + 1, line, include, &mut statements)
+            .map_err(|e| format!("line {}: {}", i + 1, e))?;
+    }
+
+    let mut asm = Assembler {
+        symbols: HashMap::new(),
+        operands: vec![None; statements.len()],
+        origin: None,
+        base: None,
+        bytes: Vec::new(),
+        final_pass: false,
+    };
+    asm.pass(&statements)?;
+    asm.final_pass = true;
+    asm.pass(&statements)?;
+
+    Ok(Assembly {
+        origin: asm.origin.unwrap_or(0),
+        bytes: asm.bytes,
+        symbols: asm
+            .symbols
+            .into_iter()
+            .map(|(name, value)| (name, value as u16))
+            .collect(),
+    })
+}
+
+#[derive(Debug)]
+enum Index {
+    None,
+    X,
+    Y,
+}
+
+#[derive(Debug)]
+enum Syntax {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    Direct(Expr, Index),
+    /// `(expr)`: JMP indirect, or a parenthesized address for everything else
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+}
+
+#[derive(Debug)]
+enum Item {
+    Expr(Expr),
+    Str(Vec<u8>),
+}
+
+#[derive(Debug)]
+enum Statement {
+    Label(String),
+    Constant(String, Expr),
+    Org(Expr),
+    /// Sets the program counter without padding: the code that follows is placed at the current
+    /// position in the output but assembled for the new address (e.g. a PRG bank)
+    Base(Expr),
+    Byte(Vec<Item>),
+    Word(Vec<Expr>),
+    Incbin(Vec<u8>),
+    Instruction(String, Syntax),
+}
+
+fn parse_line(
+    line_no: usize,
+    line: &str,
+    include: &dyn Fn(&str) -> Result<Vec<u8>, String>,
+    out: &mut Vec<(usize, Statement)>,
+) -> Result<(), String> {
+    let mut rest = strip_comment(line).trim();
+    if rest.is_empty() {
+        return Ok(());
+    }
+
+    let ident_len = identifier_len(rest);
+    if ident_len > 0 {
+        let after = rest[ident_len..].trim_start();
+        if let Some(after) = after.strip_prefix(':') {
+            out.push((line_no, Statement::Label(rest[..ident_len].to_string())));
+            rest = after.trim();
+        } else if let Some(after) = after.strip_prefix('=') {
+            let expr = parse_expr(after)?;
+            out.push((
+                line_no,
+                Statement::Constant(rest[..ident_len].to_string(), expr),
+            ));
+            return Ok(());
+        }
+    }
+    if rest.is_empty() {
+        return Ok(());
+    }
+
+    let split = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let (word, args) = (&rest[..split], rest[split..].trim());
+
+    let statement = match word.to_ascii_lowercase().as_str() {
+        ".org" => Statement::Org(parse_expr(args)?),
+        ".base" => Statement::Base(parse_expr(args)?),
+        ".incbin" => Statement::Incbin(include(&String::from_utf8_lossy(&parse_string(args)?))?),
+        ".byte" | ".db" => Statement::Byte(
+            split_args(args)
+                .into_iter()
+                .map(|arg| {
+                    let arg = arg.trim();
+                    if arg.starts_with('"') {
+                        parse_string(arg).map(Item::Str)
+                    } else {
+                        parse_expr(arg).map(Item::Expr)
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        ".word" | ".dw" => Statement::Word(
+            split_args(args)
+                .into_iter()
+                .map(parse_expr)
+                .collect::<Result<_, _>>()?,
+        ),
+        _ if word.starts_with('.') => return Err(format!("unknown directive {}", word)),
+        _ if is_mnemonic(word) => Statement::Instruction(word.to_string(), parse_operand(args)?),
+        _ => return Err(format!("unknown instruction {}", word)),
+    };
+    out.push((line_no, statement));
+    Ok(())
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, ';') => return &line[..i],
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Splits on commas that are not part of a string or character literal.
+fn split_args(args: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in args.char_indices() {
+        match (quote, c) {
+            (None, ',') => {
+                result.push(&args[start..i]);
+                start = i + 1;
+            }
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    result.push(&args[start..]);
+    result
+}
+
+fn parse_string(arg: &str) -> Result<Vec<u8>, String> {
+    if arg.len() < 2 || !arg.ends_with('"') {
+        return Err(format!("unterminated string {}", arg));
+    }
+    Ok(arg[1..arg.len() - 1].bytes().collect())
+}
+
+fn identifier_len(s: &str) -> usize {
+    let mut len = 0;
+    for (i, c) in s.char_indices() {
+        let valid = c == '_' || c == '@' || c == '.' && i > 0 || c.is_ascii_alphabetic();
+        if !(valid || i > 0 && c.is_ascii_digit()) {
+            break;
+        }
+        len = i + c.len_utf8();
+    }
+    len
+}
+
+/// True if the opening parenthesis at the start of `s` is closed by its last character
+fn enclosed(s: &str) -> bool {
+    if !s.starts_with('(') || !s.ends_with(')') {
+        return false;
+    }
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '(') => depth += 1,
+            (None, ')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return i == s.len() - 1;
+                }
+            }
+            (None, '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn parse_operand(args: &str) -> Result<Syntax, String> {
+    if args.is_empty() {
+        return Ok(Syntax::None);
+    }
+    if args.eq_ignore_ascii_case("a") {
+        return Ok(Syntax::Accumulator);
+    }
+    if let Some(stripped) = args.strip_prefix('#') {
+        return Ok(Syntax::Immediate(parse_expr(stripped)?));
+    }
+
+    let parts = split_args(args);
+    match parts.as_slice() {
+        [single] if enclosed(single.trim()) => {
+            let single = single.trim();
+            Ok(Syntax::Indirect(parse_expr(&single[1..single.len() - 1])?))
+        }
+        [single] => Ok(Syntax::Direct(parse_expr(single)?, Index::None)),
+        [head, index] => {
+            let head = head.trim();
+            let index = index.trim().to_ascii_uppercase();
+            let index = index.split_whitespace().collect::<String>();
+            match index.as_str() {
+                "X)" if head.starts_with('(') => Ok(Syntax::IndirectX(parse_expr(&head[1..])?)),
+                "Y" if enclosed(head) => {
+                    Ok(Syntax::IndirectY(parse_expr(&head[1..head.len() - 1])?))
+                }
+                "X" => Ok(Syntax::Direct(parse_expr(head)?, Index::X)),
+                "Y" => Ok(Syntax::Direct(parse_expr(head)?, Index::Y)),
+                _ => Err(format!("invalid operand {}", args)),
+            }
+        }
+        _ => Err(format!("invalid operand {}", args)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Or,
+    Xor,
+    And,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinaryOp {
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 0,
+            BinaryOp::Xor => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Shl | BinaryOp::Shr => 3,
+            BinaryOp::Add | BinaryOp::Sub => 4,
+            BinaryOp::Mul | BinaryOp::Div => 5,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Expr {
+    /// value, and whether the literal was written with more than 8 bits (`$00ff`)
+    Number(i64, bool),
+    Symbol(String),
+    ProgramCounter,
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    LowByte(Box<Expr>),
+    HighByte(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Wide literals force absolute addressing even if the value fits into the zero page
+    fn is_wide(&self) -> bool {
+        match self {
+            Expr::Number(_, wide) => *wide,
+            Expr::Negate(e) | Expr::Not(e) => e.is_wide(),
+            Expr::Binary(_, a, b) => a.is_wide() || b.is_wide(),
+            _ => false,
+        }
+    }
+
+    fn eval(&self, symbols: &HashMap<String, i64>, pc: u16) -> Result<i64, String> {
+        Ok(match self {
+            Expr::Number(value, _) => *value,
+            Expr::Symbol(name) => *symbols
+                .get(name)
+                .ok_or_else(|| format!("undefined symbol {}", name))?,
+            Expr::ProgramCounter => pc as i64,
+            Expr::Negate(e) => e.eval(symbols, pc)?.wrapping_neg(),
+            Expr::Not(e) => !e.eval(symbols, pc)?,
+            Expr::LowByte(e) => e.eval(symbols, pc)? & 0xff,
+            Expr::HighByte(e) => (e.eval(symbols, pc)? >> 8) & 0xff,
+            Expr::Binary(op, a, b) => {
+                let a = a.eval(symbols, pc)?;
+                let b = b.eval(symbols, pc)?;
+                match op {
+                    BinaryOp::Or => a | b,
+                    BinaryOp::Xor => a ^ b,
+                    BinaryOp::And => a & b,
+                    BinaryOp::Shl => a.checked_shl(b as u32).unwrap_or(0),
+                    BinaryOp::Shr => a.checked_shr(b as u32).unwrap_or(0),
+                    BinaryOp::Add => a.wrapping_add(b),
+                    BinaryOp::Sub => a.wrapping_sub(b),
+                    BinaryOp::Mul => a.wrapping_mul(b),
+                    BinaryOp::Div => a
+                        .checked_div(b)
+                        .ok_or_else(|| "division by zero".to_string())?,
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Number(i64, bool),
+    Symbol(String),
+    Star,
+    Op(char),
+    Shl,
+    Shr,
+    LParen,
+    RParen,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let rest: String = chars[i..].iter().collect();
+        match c {
+            _ if c.is_whitespace() => i += 1,
+            '$' | '%' => {
+                let (radix, digits_per_byte) = if c == '$' { (16, 2) } else { (2, 8) };
+                let digits: String = chars[i + 1..]
+                    .iter()
+                    .take_while(|d| d.is_digit(radix))
+                    .collect();
+                if digits.is_empty() {
+                    return Err(format!("invalid number {}", rest));
+                }
+                let value = i64::from_str_radix(&digits, radix)
+                    .map_err(|_| format!("number out of range {}{}", c, digits))?;
+                tokens.push(Token::Number(value, digits.len() > digits_per_byte));
+                i += 1 + digits.len();
+            }
+            '0'..='9' => {
+                let digits: String = chars[i..]
+                    .iter()
+                    .take_while(|d| d.is_ascii_digit())
+                    .collect();
+                let value = digits
+                    .parse::<i64>()
+                    .map_err(|_| format!("number out of range {}", digits))?;
+                tokens.push(Token::Number(value, value > 0xff));
+                i += digits.len();
+            }
+            '\'' => {
+                if chars.get(i + 2) != Some(&'\'') {
+                    return Err(format!("invalid character literal {}", rest));
+                }
+                tokens.push(Token::Number(chars[i + 1] as i64, false));
+                i += 3;
+            }
+            '*' => {
+                tokens.push(Token::Star);
+                i += 1;
+            }
+            '(' | ')' => {
+                tokens.push(if c == '(' {
+                    Token::LParen
+                } else {
+                    Token::RParen
+                });
+                i += 1;
+            }
+            '<' | '>' if chars.get(i + 1) == Some(&c) => {
+                tokens.push(if c == '<' { Token::Shl } else { Token::Shr });
+                i += 2;
+            }
+            '+' | '-' | '/' | '&' | '|' | '^' | '~' | '<' | '>' => {
+                tokens.push(Token::Op(c));
+                i += 1;
+            }
+            _ => {
+                let len = identifier_len(&rest);
+                if len == 0 {
+                    return Err(format!("unexpected character '{}'", c));
+                }
+                tokens.push(Token::Symbol(rest[..len].to_string()));
+                i += rest[..len].chars().count();
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_expr(s: &str) -> Result<Expr, String> {
+    let tokens = tokenize(s)?;
+    if tokens.is_empty() {
+        return Err("missing expression".to_string());
+    }
+    let mut pos = 0;
+    let expr = parse_binary(&tokens, &mut pos, 0)?;
+    if pos != tokens.len() {
+        return Err(format!(
+            "unexpected {:?} in expression {}",
+            tokens[pos],
+            s.trim()
+        ));
+    }
+    Ok(expr)
+}
+
+fn binary_op(token: Option<&Token>) -> Option<BinaryOp> {
+    Some(match token? {
+        Token::Star => BinaryOp::Mul,
+        Token::Shl => BinaryOp::Shl,
+        Token::Shr => BinaryOp::Shr,
+        Token::Op('|') => BinaryOp::Or,
+        Token::Op('^') => BinaryOp::Xor,
+        Token::Op('&') => BinaryOp::And,
+        Token::Op('+') => BinaryOp::Add,
+        Token::Op('-') => BinaryOp::Sub,
+        Token::Op('/') => BinaryOp::Div,
+        _ => return None,
+    })
+}
+
+fn parse_binary(tokens: &[Token], pos: &mut usize, min_precedence: u8) -> Result<Expr, String> {
+    let mut lhs = parse_unary(tokens, pos)?;
+    while let Some(op) = binary_op(tokens.get(*pos)) {
+        if op.precedence() < min_precedence {
+            break;
+        }
+        *pos += 1;
+        let rhs = parse_binary(tokens, pos, op.precedence() + 1)?;
+        lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+    Ok(lhs)
+}
+
+fn parse_unary(tokens: &[Token], pos: &mut usize) -> Result<Expr, String> {
+    let token = tokens
+        .get(*pos)
+        .ok_or_else(|| "unexpected end of expression".to_string())?;
+    *pos += 1;
+    Ok(match token {
+        Token::Number(value, wide) => Expr::Number(*value, *wide),
+        Token::Symbol(name) => Expr::Symbol(name.clone()),
+        Token::Star => Expr::ProgramCounter,
+        Token::Op('-') => Expr::Negate(Box::new(parse_unary(tokens, pos)?)),
+        Token::Op('~') => Expr::Not(Box::new(parse_unary(tokens, pos)?)),
+        Token::Op('<') => Expr::LowByte(Box::new(parse_unary(tokens, pos)?)),
+        Token::Op('>') => Expr::HighByte(Box::new(parse_unary(tokens, pos)?)),
+        Token::LParen => {
+            let expr = parse_binary(tokens, pos, 0)?;
+            if tokens.get(*pos) != Some(&Token::RParen) {
+                return Err("missing )".to_string());
+            }
+            *pos += 1;
+            expr
+        }
+        other => return Err(format!("unexpected {:?} in expression", other)),
+    })
+}
+
+struct Assembler {
+    symbols: HashMap<String, i64>,
+    /// Operand chosen for every instruction in the first pass
+    operands: Vec<Option<Operand>>,
+    origin: Option<u16>,
+    /// Program counter set by `.base`, and the output position it was set at
+    base: Option<(u16, usize)>,
+    bytes: Vec<u8>,
+    final_pass: bool,
+}
+
+impl Assembler {
+    fn pc(&self) -> u16 {
+        match self.base {
+            Some((pc, position)) => pc.wrapping_add((self.bytes.len() - position) as u16),
+            None => self
+                .origin
+                .unwrap_or(0)
+                .wrapping_add(self.bytes.len() as u16),
+        }
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<Option<i64>, String> {
+        match expr.eval(&self.symbols, self.pc()) {
+            Ok(value) => Ok(Some(value)),
+            Err(_) if !self.final_pass => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn byte(&self, expr: &Expr) -> Result<u8, String> {
+        match self.eval(expr)? {
+            Some(value) if !(-0x80..=0xff).contains(&value) => {
+                Err(format!("value ${:x} does not fit into a byte", value))
+            }
+            value => Ok(value.unwrap_or(0) as u8),
+        }
+    }
+
+    fn word(&self, expr: &Expr) -> Result<u16, String> {
+        match self.eval(expr)? {
+            Some(value) if !(-0x8000..=0xffff).contains(&value) => {
+                Err(format!("value ${:x} does not fit into a word", value))
+            }
+            value => Ok(value.unwrap_or(0) as u16),
+        }
+    }
+
+    fn pass(&mut self, statements: &[(usize, Statement)]) -> Result<(), String> {
+        self.origin = None;
+        self.base = None;
+        self.bytes.clear();
+        for (i, (line, statement)) in statements.iter().enumerate() {
+            self.statement(i, statement)
+                .map_err(|e| format!("line {}: {}", line, e))?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, index: usize, statement: &Statement) -> Result<(), String> {
+        match statement {
+            Statement::Label(name) => {
+                if !self.final_pass && self.symbols.contains_key(name) {
+                    return Err(format!("duplicate label {}", name));
+                }
+                self.symbols.insert(name.clone(), self.pc() as i64);
+            }
+            Statement::Constant(name, expr) => {
+                if let Some(value) = self.eval(expr)? {
+                    self.symbols.insert(name.clone(), value);
+                }
+            }
+            Statement::Org(expr) => {
+                let target = expr
+                    .eval(&self.symbols, self.pc())
+                    .map_err(|e| format!(".org: {}", e))?;
+                if !(0..=0xffff).contains(&target) {
+                    return Err(format!(".org ${:x} is out of range", target));
+                }
+                let target = target as u16;
+                match self.origin {
+                    None if self.bytes.is_empty() && self.base.is_none() => {
+                        self.origin = Some(target)
+                    }
+                    _ if target < self.pc() => {
+                        return Err(format!(".org ${:04x} is behind ${:04x}", target, self.pc()))
+                    }
+                    _ => {
+                        let pad = target - self.pc();
+                        self.bytes.extend(std::iter::repeat_n(0, pad as usize));
+                    }
+                }
+            }
+            Statement::Base(expr) => {
+                let pc = expr
+                    .eval(&self.symbols, self.pc())
+                    .map_err(|e| format!(".base: {}", e))?;
+                if !(0..=0xffff).contains(&pc) {
+                    return Err(format!(".base ${:x} is out of range", pc));
+                }
+                self.base = Some((pc as u16, self.bytes.len()));
+            }
+            Statement::Incbin(data) => self.bytes.extend(data),
+            Statement::Byte(items) => {
+                for item in items {
+                    match item {
+                        Item::Expr(expr) => {
+                            let value = self.byte(expr)?;
+                            self.bytes.push(value);
+                        }
+                        Item::Str(s) => self.bytes.extend(s),
+                    }
+                }
+            }
+            Statement::Word(exprs) => {
+                for expr in exprs {
+                    let value = self.word(expr)?;
+                    self.bytes.extend(&value.to_le_bytes());
+                }
+            }
+            Statement::Instruction(mnemonic, syntax) => {
+                let operand = match self.operands[index] {
+                    Some(operand) => operand,
+                    None => {
+                        let operand = self.select(mnemonic, syntax)?;
+                        self.operands[index] = Some(operand);
+                        operand
+                    }
+                };
+                self.instruction(mnemonic, syntax, operand)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn select(&self, mnemonic: &str, syntax: &Syntax) -> Result<Operand, String> {
+        let has = |operand| opcode(mnemonic, operand).is_some();
+        let (expr, index) = match syntax {
+            Syntax::None if has(Operand::Implied) => return Ok(Operand::Implied),
+            Syntax::None if has(Operand::Accumulator) => return Ok(Operand::Accumulator),
+            Syntax::None => return Err(format!("{} requires an operand", mnemonic)),
+            Syntax::Accumulator => return Ok(Operand::Accumulator),
+            Syntax::Immediate(_) => return Ok(Operand::Immediate),
+            Syntax::IndirectX(_) => return Ok(Operand::IndirectX),
+            Syntax::IndirectY(_) => return Ok(Operand::IndirectY),
+            Syntax::Indirect(_) if has(Operand::Indirect) => return Ok(Operand::Indirect),
+            Syntax::Indirect(_) if has(Operand::ZeroPageIndirect) => {
+                return Ok(Operand::ZeroPageIndirect)
+            }
+            Syntax::Indirect(expr) => (expr, &Index::None),
+            Syntax::Direct(expr, index) => (expr, index),
+        };
+        let (zero_page, absolute) = match index {
+            Index::None if has(Operand::Relative) => return Ok(Operand::Relative),
+            Index::None => (Operand::ZeroPage, Operand::Absolute),
+            Index::X => (Operand::ZeroPageX, Operand::AbsoluteX),
+            Index::Y => (Operand::ZeroPageY, Operand::AbsoluteY),
+        };
+        let fits =
+            !expr.is_wide() && matches!(self.eval(expr)?, Some(v) if (0..=0xff).contains(&v));
+        if has(zero_page) && (fits || !has(absolute)) {
+            Ok(zero_page)
+        } else {
+            Ok(absolute)
+        }
+    }
+
+    fn instruction(
+        &mut self,
+        mnemonic: &str,
+        syntax: &Syntax,
+        operand: Operand,
+    ) -> Result<(), String> {
+        let code = opcode(mnemonic, operand)
+            .ok_or_else(|| format!("{} does not support {:?} addressing", mnemonic, operand))?;
+        let pc = self.pc();
+        let expr = match syntax {
+            Syntax::None | Syntax::Accumulator => None,
+            Syntax::Immediate(e)
+            | Syntax::Direct(e, _)
+            | Syntax::Indirect(e)
+            | Syntax::IndirectX(e)
+            | Syntax::IndirectY(e) => Some(e),
+        };
+
+        // the operand is evaluated before emitting the opcode, so that `*` is the instruction address
+        let arg = match (operand.instruction_len(), expr) {
+            (1, _) => vec![],
+            (_, None) => return Err(format!("{} requires an operand", mnemonic)),
+            (2, Some(expr)) if operand == Operand::Relative => {
+                let target = self.word(expr)?;
+                let offset = target.wrapping_sub(pc.wrapping_add(2)) as i16;
+                if self.final_pass && !(-128..=127).contains(&offset) {
+                    return Err(format!("branch target ${:04x} is out of range", target));
+                }
+                vec![offset as u8]
+            }
+            (2, Some(expr)) => {
+                let value = self.byte(expr)?;
+                if operand != Operand::Immediate && self.eval(expr)?.is_some_and(|v| v < 0) {
+                    return Err(format!("zero page address ${:x} is negative", value));
+                }
+                vec![value]
+            }
+            (_, Some(expr)) => self.word(expr)?.to_le_bytes().to_vec(),
+        };
+        self.bytes.push(code);
+        self.bytes.extend(arg);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::cpu::transform;
+    use crate::disasm::Disasm;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn bytes(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().bytes
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        assert_eq!(bytes("NOP"), transform("ea"));
+        assert_eq!(bytes("asl"), transform("0a"));
+        assert_eq!(bytes("ROL A"), transform("2a"));
+        assert_eq!(bytes("LDA #$10"), transform("a9 10"));
+        assert_eq!(bytes("LDA $10"), transform("a5 10"));
+        assert_eq!(bytes("LDA $10,X"), transform("b5 10"));
+        assert_eq!(bytes("LDX $10,Y"), transform("b6 10"));
+        assert_eq!(bytes("LDA $1234"), transform("ad 34 12"));
+        assert_eq!(bytes("LDA $0010"), transform("ad 10 00"));
+        assert_eq!(bytes("LDA $1234,X"), transform("bd 34 12"));
+        assert_eq!(bytes("LDA $1234, y"), transform("b9 34 12"));
+        assert_eq!(bytes("LDA ($10,X)"), transform("a1 10"));
+        assert_eq!(bytes("LDA ($10),Y"), transform("b1 10"));
+        assert_eq!(bytes("JMP ($1234)"), transform("6c 34 12"));
+        assert_eq!(bytes("JMP $1234"), transform("4c 34 12"));
+        assert_eq!(bytes("JSR $1234"), transform("20 34 12"));
+        assert_eq!(bytes("BNE $0000"), transform("d0 fe"));
+        // no absolute,Y form for STX
+        assert_eq!(bytes("STX $10,Y"), transform("96 10"));
+        // no zero page,Y form for LDA
+        assert_eq!(bytes("LDA $10,Y"), transform("b9 10 00"));
+    }
+
+    #[test]
+    fn test_undocumented_mnemonics() {
+        assert_eq!(bytes("*LAX $10"), transform("a7 10"));
+        assert_eq!(bytes("lax $10"), transform("a7 10"));
+        assert_eq!(bytes("*NOP #$10"), transform("80 10"));
+        assert_eq!(opcode("*NOP", Operand::Implied), Some(0x1a));
+        assert_eq!(opcode("NOP", Operand::Implied), Some(0xea));
+    }
+
+    #[test]
+    fn test_labels_and_branches() {
+        let asm = assemble(
+            "
+            .org $8000
+            reset:  LDX #$08   ; count down
+            loop:   DEX
+                    BNE loop
+                    BEQ done
+                    JMP reset
+            done:   RTS
+            ",
+        )
+        .unwrap();
+        assert_eq!(asm.origin, 0x8000);
+        assert_eq!(asm.bytes, transform("a2 08 ca d0 fd f0 03 4c 00 80 60"));
+        assert_eq!(asm.symbols["loop"], 0x8002);
+        assert_eq!(asm.symbols["done"], 0x800a);
+    }
+
+    #[test]
+    fn test_forward_reference_uses_absolute_addressing() {
+        let asm = assemble("LDA var\nRTS\nvar = $10\nLDA var").unwrap();
+        assert_eq!(asm.bytes, transform("ad 10 00 60 a5 10"));
+    }
+
+    #[test]
+    fn test_directives() {
+        let asm = assemble(
+            r#"
+            .org $c000
+            .byte 1, $02, %11, 'A', "hi;"
+            .word $1234, end
+            .org $c00e
+            end: .db <end, >end
+            "#,
+        )
+        .unwrap();
+        assert_eq!(asm.origin, 0xc000);
+        assert_eq!(
+            asm.bytes,
+            transform("01 02 03 41 68 69 3b 34 12 0e c0 00 00 00 0e c0")
+        );
+    }
+
+    #[test]
+    fn test_base_and_incbin() {
+        let include = |path: &str| match path {
+            "chr.bin" => Ok(vec![0xaa, 0xbb]),
+            _ => Err(format!("no such file {}", path)),
+        };
+        let asm = assemble_with(
+            r#"
+            .byte "NES"
+            .base $8000
+            start: JMP start
+            .base $c000
+            JMP start
+            .incbin "chr.bin"
+            "#,
+            &include,
+        )
+        .unwrap();
+        assert_eq!(asm.origin, 0);
+        assert_eq!(asm.bytes, transform("4e 45 53 4c 00 80 4c 00 80 aa bb"));
+        assert_eq!(
+            assemble_with(".incbin \"missing\"", &include).unwrap_err(),
+            "line 1: no such file missing"
+        );
+    }
+
+    #[test]
+    fn test_expressions() {
+        let asm = assemble(
+            "
+            base = $0200
+            size = 2 * (3 + 4) - 1
+            LDA #size
+            LDA #-1
+            LDA #~$0f & $ff
+            LDA base + size * 2
+            LDA #1 << 4 | 1
+            LDA #$80 >> 7 ^ 3
+            LDA #base / $100
+            JMP *
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            asm.bytes,
+            transform("a9 0d a9 ff a9 f0 ad 1a 02 a9 11 a9 02 a9 02 4c 0f 00")
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        fn error(source: &str) -> String {
+            assemble(source).unwrap_err()
+        }
+        assert_eq!(error("NOP\nFOO"), "line 2: unknown instruction FOO");
+        assert_eq!(error("LDA undefined"), "line 1: undefined symbol undefined");
+        assert_eq!(error("a: NOP\na: NOP"), "line 2: duplicate label a");
+        assert_eq!(
+            error("LDA #$100"),
+            "line 1: value $100 does not fit into a byte"
+        );
+        assert_eq!(
+            error("STA #$10"),
+            "line 1: STA does not support Immediate addressing"
+        );
+        assert_eq!(error("LDA"), "line 1: LDA requires an operand");
+        assert_eq!(
+            error(".org $10\nNOP\n.org $0"),
+            "line 3: .org $0000 is behind $0011"
+        );
+        assert_eq!(error(".bank 0"), "line 1: unknown directive .bank");
+        assert_eq!(error("LDA #(1"), "line 1: missing )");
+        assert_eq!(error("LDA #1/0"), "line 1: division by zero");
+        assert_eq!(
+            error(".org $1000\nBNE $1100"),
+            "line 2: branch target $1100 is out of range"
+        );
+    }
+
+    #[test]
+    fn test_disasm_round_trip() {
+        let mut rng = StdRng::seed_from_u64(6502);
+        for _ in 0..200 {
+            let len = rng.gen_range(1, 512);
+            let program: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+
+            let disasm = Disasm::new(&program, 0);
+            let source: Vec<&str> = disasm
+                .program
+                .iter()
+                .map(|line| &line["0000: ".len()..])
+                .collect();
+            let source = source.join("\n");
+            let asm = assemble(&source).unwrap_or_else(|e| panic!("{}\n{}", e, source));
+            assert_eq!(asm.bytes, program, "\n{}", source);
+        }
+    }
+}
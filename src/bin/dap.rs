@@ -0,0 +1,41 @@
+// Debug Adapter Protocol server, over stdio or a TCP port
+//
+// cargo run --bin dap                 (the client starts the adapter and talks on stdin/stdout)
+// cargo run --bin dap -- --port 4711  (the client connects to the port)
+use rustness::debugger::dap;
+use std::io::{self, BufReader};
+use std::net::TcpListener;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.as_slice() {
+        [] => dap::serve(BufReader::new(io::stdin()), io::stdout()),
+        [flag, port] if flag == "--port" => {
+            let port: u16 = port.parse().unwrap_or_else(|_| {
+                eprintln!("invalid port: {}", port);
+                process::exit(2);
+            });
+            serve_tcp(port)
+        }
+        _ => {
+            eprintln!("usage: dap [--port <port>]");
+            process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Serves one client after the other
+fn serve_tcp(port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("listening on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        dap::serve(BufReader::new(stream.try_clone()?), stream)?;
+    }
+    Ok(())
+}
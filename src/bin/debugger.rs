@@ -0,0 +1,123 @@
+// Runs a .nes file headless under the debugger console (type 'help' at the prompt)
+//
+// cargo run --bin debugger -- test_rom/nestest.nes [symbols.nl ...]
+//
+// With --gdb, a GDB remote stub waits on the local port instead of the console:
+// cargo run --bin debugger -- --gdb 2345 test_rom/nestest.nes
+//
+// With --profile, it runs the given number of frames instead and prints where the cycles went.
+// The folded stacks for flamegraph.pl are written next to the ROM (nestest.folded):
+// cargo run --bin debugger -- --profile 600 test_rom/nestest.nes [symbols.nl ...]
+use rustness::bus::Bus;
+use rustness::cpu::cpu::{HaltReason, CPU};
+use rustness::debugger::history::{self, History};
+use rustness::debugger::profiler::Profiler;
+use rustness::debugger::{gdb, DebugBus, Debugger};
+use rustness::input;
+use rustness::ppu::ppu::NesPPU;
+use rustness::rom::Rom;
+use std::io;
+use std::path::Path;
+use std::process;
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut gdb_port = None;
+    let mut profile_frames = None;
+    while args.len() > 1 && args[0].starts_with("--") {
+        match args[0].as_str() {
+            "--gdb" => {
+                gdb_port = Some(
+                    args[1]
+                        .parse::<u16>()
+                        .unwrap_or_else(|_| fail(format!("invalid port: {}", args[1]))),
+                )
+            }
+            "--profile" => {
+                profile_frames = Some(
+                    args[1]
+                        .parse::<usize>()
+                        .unwrap_or_else(|_| fail(format!("invalid number of frames: {}", args[1]))),
+                )
+            }
+            option => fail(format!("unknown option: {}", option)),
+        }
+        args.drain(..2);
+    }
+    let (path, symbol_files) = match args.split_first() {
+        Some((path, symbol_files)) => (path, symbol_files),
+        None => {
+            eprintln!(
+                "usage: debugger [--gdb <port> | --profile <frames>] <rom.nes> [symbol files...]"
+            );
+            process::exit(2);
+        }
+    };
+    let data = std::fs::read(path).unwrap_or_else(|e| fail(format!("can't read {}: {}", path, e)));
+    let rom = Rom::load(&data).unwrap_or_else(|e| fail(format!("can't load {}: {}", path, e)));
+
+    if let Some(port) = gdb_port {
+        let bus = Bus::<NesPPU>::new(rom, |_: &NesPPU, _: &mut input::Joypad| {});
+        let mut cpu = CPU::new(bus);
+        cpu.power_on();
+        gdb::listen(&mut cpu, port).unwrap_or_else(|e| fail(format!("gdb: {}", e)));
+        return;
+    }
+
+    let mut debugger = Debugger::new();
+    for file in symbol_files {
+        debugger
+            .symbols
+            .load(Path::new(file))
+            .unwrap_or_else(|e| fail(format!("can't load {}: {}", file, e)));
+    }
+
+    if let Some(frames) = profile_frames {
+        let bus = Bus::<NesPPU>::new(rom, |_: &NesPPU, _: &mut input::Joypad| {});
+        let mut cpu = CPU::new(bus);
+        cpu.power_on();
+        let mut profiler = Profiler::new();
+        let halt = cpu.run_until(|cpu| {
+            profiler.update(cpu);
+            profiler.frames() == frames
+        });
+        if halt != HaltReason::Breakpoint {
+            println!("CPU halted: {:?}", halt);
+        }
+        println!("{}", profiler.report(&cpu, &debugger.symbols, 20));
+
+        let folded = Path::new(path).with_extension("folded");
+        let mut output = Vec::new();
+        profiler
+            .write_folded(&cpu, &debugger.symbols, &mut output)
+            .and_then(|_| std::fs::write(&folded, output))
+            .unwrap_or_else(|e| fail(format!("can't write {}: {}", folded.display(), e)));
+        println!("\nfolded stacks written to {}", folded.display());
+        return;
+    }
+
+    let bus = Bus::<NesPPU>::new(rom, |_: &NesPPU, _: &mut input::Joypad| {});
+    let mut cpu = CPU::new(DebugBus::new(bus));
+    cpu.power_on();
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut output = io::stdout();
+    let symbols = debugger.symbols.clone();
+    let mut history = History::new(history::DEFAULT_LENGTH);
+    let halt = history::run(&mut cpu, &mut history, &symbols, |cpu| {
+        match debugger.check(cpu) {
+            Some(stop) => !debugger.prompt(cpu, stop, &mut input, &mut output),
+            None => false,
+        }
+    });
+    // the predicate only holds when the user quits
+    if halt != HaltReason::Breakpoint {
+        println!("CPU halted: {:?}", halt);
+    }
+}
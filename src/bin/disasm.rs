@@ -0,0 +1,61 @@
+// Prints the code reachable from the interrupt vectors of a .nes file,
+// or exports the whole file as reassemblable ca65/asm6 source
+//
+// cargo run --bin disasm -- test_rom/nestest.nes
+// cargo run --bin disasm -- --ca65 nestest.s test_rom/nestest.nes
+use rustness::disasm::export::{self, Dialect};
+use rustness::disasm::flow;
+use rustness::rom::Rom;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "usage: disasm [--ca65 <out.s> | --asm6 <out.asm>] <rom.nes>";
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (output, path) = match args.as_slice() {
+        [path] => (None, path),
+        [flag, out, path] if flag == "--ca65" => (Some((Dialect::Ca65, out)), path),
+        [flag, out, path] if flag == "--asm6" => (Some((Dialect::Asm6, out)), path),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let data = std::fs::read(path).unwrap_or_else(|e| fail(format!("can't read {}: {}", path, e)));
+
+    match output {
+        None => {
+            let rom =
+                Rom::load(&data).unwrap_or_else(|e| fail(format!("can't load {}: {}", path, e)));
+            for line in flow::disassemble_rom(&rom).lines() {
+                println!("{}", line);
+            }
+        }
+        Some((dialect, out)) => {
+            // `.incbin` refers to the CHR file by name only: assemble from the output directory
+            let out = Path::new(out);
+            let chr_path = out.with_extension("chr");
+            let chr_file = chr_path.file_name().unwrap().to_string_lossy();
+            let export = export::export(&data, dialect, &chr_file)
+                .unwrap_or_else(|e| fail(format!("can't export {}: {}", path, e)));
+
+            let write = |path: &Path, data: &[u8]| {
+                std::fs::write(path, data)
+                    .unwrap_or_else(|e| fail(format!("can't write {}: {}", path.display(), e)))
+            };
+            write(out, export.source.as_bytes());
+            if !export.chr.is_empty() {
+                write(&chr_path, &export.chr);
+            }
+            if let Some(config) = export.linker_config {
+                write(&out.with_extension("cfg"), config.as_bytes());
+            }
+        }
+    }
+}
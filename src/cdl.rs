@@ -0,0 +1,218 @@
+// Code/Data Logger: records how every byte of PRG and CHR ROM is used while the game runs.
+// The .cdl file is the FCEUX format, also read by Mesen: one byte of flags per PRG ROM byte,
+// followed by one per CHR ROM byte. http://fceux.com/web/help/CodeDataLogger.html
+//
+// PRG flags:
+//   0x01 code: opcode or operand of an executed instruction
+//   0x02 data: read by an instruction
+//   0x0c CPU address window the byte was accessed through: (addr >> 13) & 3, shifted left by 2
+//   0x10 code reached through an indirect jump, JMP ($xxxx)
+//   0x20 data read through a pointer, LDA ($xx),Y
+// CHR flags:
+//   0x01 fetched for rendering
+//   0x02 read by the program through $2007
+use crate::rom::Rom;
+use std::path::Path;
+
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+pub const INDIRECT_CODE: u8 = 0x10;
+pub const INDIRECT_DATA: u8 = 0x20;
+
+pub const CHR_RENDERED: u8 = 0x01;
+pub const CHR_READ: u8 = 0x02;
+
+/// What the CPU reads memory for. It tells the bus before reads that are not plain data reads.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ReadKind {
+    /// Opcode or operand fetch
+    Code,
+    /// Opcode fetch at the target of an indirect jump
+    IndirectCode,
+    Data,
+    /// Data read through a pointer
+    IndirectData,
+    /// Read the CPU throws away, it tells nothing about the byte
+    Dummy,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CodeDataLog {
+    pub prg: Vec<u8>,
+    pub chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(rom: &Rom) -> Self {
+        CodeDataLog {
+            prg: vec![0; rom.prg_rom.len()],
+            chr: vec![0; rom.chr_rom.len()],
+        }
+    }
+
+    /// Continues a log from a previous session. Its size must match the ROM.
+    pub fn from_bytes(rom: &Rom, data: &[u8]) -> Result<Self, String> {
+        let prg_len = rom.prg_rom.len();
+        if data.len() != prg_len + rom.chr_rom.len() {
+            return Err(format!(
+                "expected {} bytes for this ROM, got {}",
+                prg_len + rom.chr_rom.len(),
+                data.len()
+            ));
+        }
+        Ok(CodeDataLog {
+            prg: data[..prg_len].to_vec(),
+            chr: data[prg_len..].to_vec(),
+        })
+    }
+
+    /// Loads the log at `path` if there is one, a new one otherwise
+    pub fn open(rom: &Rom, path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(CodeDataLog::new(rom));
+        }
+        let data =
+            std::fs::read(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        CodeDataLog::from_bytes(rom, &data).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.prg.clone();
+        data.extend(&self.chr);
+        data
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        std::fs::write(path, self.to_bytes())
+            .map_err(|e| format!("can't write {}: {}", path.display(), e))
+    }
+
+    /// Read of PRG ROM `offset`, mapped at CPU address `addr`
+    pub fn log_prg(&mut self, offset: usize, addr: u16, kind: ReadKind) {
+        let flags = match kind {
+            ReadKind::Code => CODE,
+            ReadKind::IndirectCode => CODE | INDIRECT_CODE,
+            ReadKind::Data => DATA,
+            ReadKind::IndirectData => DATA | INDIRECT_DATA,
+            ReadKind::Dummy => return,
+        };
+        let window = ((addr >> 13) & 3) as u8;
+        if let Some(byte) = self.prg.get_mut(offset) {
+            *byte |= flags | window << 2;
+        }
+    }
+
+    pub fn log_chr(&mut self, offset: usize, len: usize, flags: u8) {
+        let end = (offset + len).min(self.chr.len());
+        for byte in self.chr[offset.min(end)..end].iter_mut() {
+            *byte |= flags;
+        }
+    }
+
+    /// Number of PRG bytes seen as code, as data, and not accessed at all
+    pub fn prg_summary(&self) -> (usize, usize, usize) {
+        let count = |flag: u8| self.prg.iter().filter(|b| *b & flag != 0).count();
+        let unused = self.prg.iter().filter(|b| **b & (CODE | DATA) == 0).count();
+        (count(CODE), count(DATA), unused)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::bus::Bus;
+    use crate::cpu::cpu::CPU;
+    use crate::input;
+    use crate::ppu::ppu::NesPPU;
+    use crate::rom::test_ines_rom;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_flags() {
+        let rom = test_ines_rom::test_rom();
+        let mut cdl = CodeDataLog::new(&rom);
+        cdl.log_prg(0x0000, 0x8000, ReadKind::Code);
+        cdl.log_prg(0x0001, 0x8001, ReadKind::Data);
+        cdl.log_prg(0x4000, 0xc000, ReadKind::IndirectCode);
+        cdl.log_prg(0x7ffa, 0xfffa, ReadKind::Data);
+        cdl.log_prg(0x7ffb, 0xfffb, ReadKind::IndirectData);
+        cdl.log_prg(0x0002, 0x8002, ReadKind::Dummy);
+        cdl.log_prg(0x0000, 0x8000, ReadKind::Data);
+        cdl.log_chr(0x1ff8, 16, CHR_RENDERED);
+        cdl.log_chr(0x0000, 1, CHR_READ);
+
+        assert_eq!(&cdl.prg[..3], &[0x03, 0x02, 0x00]);
+        assert_eq!(cdl.prg[0x4000], 0x19);
+        assert_eq!(&cdl.prg[0x7ffa..0x7ffc], &[0x0e, 0x2e]);
+        assert_eq!(cdl.chr[0], CHR_READ);
+        assert_eq!(&cdl.chr[0x1ff7..], &[0, 1, 1, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(cdl.prg_summary(), (2, 4, 0x8000 - 5));
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let rom = test_ines_rom::test_rom();
+        let mut cdl = CodeDataLog::new(&rom);
+        cdl.log_prg(0x10, 0x8010, ReadKind::Code);
+        cdl.log_chr(0x20, 1, CHR_RENDERED);
+        let data = cdl.to_bytes();
+        assert_eq!(data.len(), 0x8000 + 0x2000);
+        assert_eq!(data[0x10], CODE);
+        assert_eq!(data[0x8020], CHR_RENDERED);
+
+        assert_eq!(CodeDataLog::from_bytes(&rom, &data), Ok(cdl));
+        assert!(CodeDataLog::from_bytes(&rom, &data[1..]).is_err());
+    }
+
+    #[test]
+    fn test_logging_while_running() {
+        let asm = assemble(
+            "
+            .org $c000
+    reset:  LDA #$20
+            STA $00
+            LDA #$c0
+            STA $01
+            LDY #$01
+            LDA ($00),Y
+            LDA table
+            JMP (pointer)
+            .byte $ff
+            .org $c018
+    target: NOP
+    loop:   JMP loop
+            .org $c020
+    table:  .byte $11, $22
+    pointer: .word target
+            .org $fffa
+            .word loop, reset, loop
+            ",
+        )
+        .unwrap();
+        let mut data = vec![
+            0x4e, 0x45, 0x53, 0x1a, 0x01, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        data.extend(&asm.bytes);
+        data.extend(vec![0; 0x2000]);
+        let rom = Rom::load(&data).unwrap();
+        let cdl = Rc::new(RefCell::new(CodeDataLog::new(&rom)));
+        let mut bus = Bus::<NesPPU>::new(rom, |_: &NesPPU, _: &mut input::Joypad| {});
+        bus.log_code_data(cdl.clone());
+        let mut cpu = CPU::new(bus);
+        cpu.power_on();
+        cpu.run_until(|cpu| cpu.bus.ppu().line == 241);
+
+        let cdl = cdl.borrow();
+        // $C000-$DFFF is CPU window 2, $E000-$FFFF is window 3
+        assert_eq!(&cdl.prg[0x00..0x03], &[0x09, 0x09, 0x09]);
+        assert_eq!(&cdl.prg[0x0f..0x13], &[0x09, 0x09, 0x09, 0x00]);
+        assert_eq!(&cdl.prg[0x18..0x1c], &[0x19, 0x09, 0x09, 0x09]);
+        assert_eq!(&cdl.prg[0x20..0x24], &[0x0a, 0x2a, 0x0a, 0x0a]);
+        assert_eq!(&cdl.prg[0x3ffa..], &[0x00, 0x00, 0x0e, 0x0e, 0x00, 0x00]);
+        // blank nametables and OAM: tile 0 everywhere
+        assert_eq!(&cdl.chr[0x00..0x10], &[CHR_RENDERED; 16]);
+        assert_eq!(cdl.chr[0x10], 0);
+    }
+}
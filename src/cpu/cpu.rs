@@ -1,11 +1,11 @@
 // https://skilldrick.github.io/easy6502/
 // http://nesdev.com/6502_cpu.txt
 use crate::bus::CpuBus;
+use crate::cdl::ReadKind;
 use crate::cpu::mem::AddressingMode;
 use crate::cpu::opscode;
 use hex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 
 bitflags! {
 /// # Status Register (P) http://wiki.nesdev.com/w/index.php/Status_flags
@@ -41,11 +41,13 @@ const STACK_SIZE: u8 = 0xff;
 
 const STACK_RESET: u8 = 0xfd;
 
+const RESET_VECTOR: u16 = 0xfffc;
+
 mod interrupt {
-    #[derive(PartialEq, Eq)]
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
     pub enum InterruptType {
         BRK,
-        IRQ, //todo: will be needed for APU
+        IRQ,
         NMI,
     }
 
@@ -54,51 +56,130 @@ mod interrupt {
         pub(super) itype: InterruptType,
         pub(super) vector_addr: u16,
         pub(super) b_flag_mask: u8,
-        pub(super) cpu_cycles: u8,
+        pub(super) dummy_reads: u8,
     }
 
     pub(super) const BRK: Interrupt = Interrupt {
         itype: InterruptType::BRK,
         vector_addr: 0xfffe,
         b_flag_mask: 0b00110000,
-        cpu_cycles: 1,
+        dummy_reads: 0, // BRK fetches its padding byte by itself
     };
 
-    #[allow(dead_code)]
-    //todo: will be needed for APU
     pub(super) const IRQ: Interrupt = Interrupt {
         itype: InterruptType::IRQ,
         vector_addr: 0xfffe,
         b_flag_mask: 0b00100000,
-        cpu_cycles: 2,
+        dummy_reads: 2,
     };
 
     pub(super) const NMI: Interrupt = Interrupt {
         itype: InterruptType::NMI,
         vector_addr: 0xfffA,
         b_flag_mask: 0b00100000,
-        cpu_cycles: 2,
+        dummy_reads: 2,
     };
 }
 
-pub struct CPU<'a> {
+pub use interrupt::InterruptType;
+
+/// Reason why `run_*` returned control to the caller
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HaltReason {
+    /// Caller supplied condition holds
+    Breakpoint,
+    /// KIL opcode locked up the CPU, only reset brings it back
+    Jam { pc: u16, opcode: u8 },
+    /// Requested amount of cycles has been executed
+    BudgetExhausted,
+}
+
+/// CPUs sharing this core. They differ in their opcode tables and in a handful of behaviours.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum CpuVariant {
+    /// NES CPU: NMOS 6502 with decimal mode cut out
+    #[default]
+    Ricoh2A03,
+    /// NMOS 6502 with BCD arithmetic
+    Nmos6502,
+    /// WDC 65C02: new instructions, no JMP indirect bug, D flag cleared on interrupts,
+    /// valid N and Z flags in decimal mode
+    Wdc65C02,
+}
+
+impl CpuVariant {
+    pub fn opscodes(&self) -> &'static [&'static opscode::OpsCode; 256] {
+        match self {
+            CpuVariant::Ricoh2A03 => &opscode::OPSCODES_2A03,
+            CpuVariant::Nmos6502 => &opscode::OPSCODES_6502,
+            CpuVariant::Wdc65C02 => &opscode::OPSCODES_65C02,
+        }
+    }
+
+    fn has_decimal_mode(&self) -> bool {
+        *self != CpuVariant::Ricoh2A03
+    }
+}
+
+/// Programmer visible registers, for debuggers
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Registers {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub pc: u16,
+    pub p: CpuFlags,
+}
+
+/// Decodes a hex dump like "a9 8d" into bytes
+pub fn transform(s: &str) -> Vec<u8> {
+    hex::decode(s.replace(' ', "")).expect("Decoding failed")
+}
+
+pub struct CPU<B: CpuBus> {
     pub(super) register_a: u8,
     pub(super) register_x: u8,
     pub(super) register_y: u8,
     pub(super) stack_pointer: u8,
     pub program_counter: u16,
     pub(super) flags: CpuFlags,
-    pub bus: Box<dyn CpuBus + 'a>,
+    pub bus: B,
+    variant: CpuVariant,
+    opscodes: &'static [&'static opscode::OpsCode; 256],
+    nmi_pending: bool,
+    irq_pending: bool,
+    nmi_poll: bool,
+    irq_poll: bool,
+    cycles: usize,
+    jam: Option<HaltReason>,
+    waiting: bool,
+    serviced: Option<(InterruptType, u16)>,
 }
 
-impl<'a> CPU<'a> {
-    pub fn transform(s: &str) -> Vec<u8> {
-        hex::decode(s.replace(' ', "")).expect("Decoding failed")
+impl<B: CpuBus> CPU<B> {
+    fn decimal_mode(&self) -> bool {
+        self.variant.has_decimal_mode() && self.flags.contains(CpuFlags::DECIMAL_MODE)
     }
 
-    /// note: ignoring decimal mode
-    /// http://www.righto.com/2012/12/the-6502-overflow-flag-explained.html
     fn add_to_register_a(&mut self, data: u8) {
+        if self.decimal_mode() {
+            self.add_decimal(data);
+        } else {
+            self.add_binary(data);
+        }
+    }
+
+    fn sub_from_register_a(&mut self, data: u8) {
+        if self.decimal_mode() {
+            self.sub_decimal(data);
+        } else {
+            self.add_binary(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+        }
+    }
+
+    /// http://www.righto.com/2012/12/the-6502-overflow-flag-explained.html
+    fn add_binary(&mut self, data: u8) {
         let sum = self.register_a as u16
             + data as u16
             + (if self.flags.contains(CpuFlags::CARRY) {
@@ -126,9 +207,63 @@ impl<'a> CPU<'a> {
         self.set_register_a(result);
     }
 
-    /// note: ignoring decimal mode
-    fn sub_from_register_a(&mut self, data: u8) {
-        self.add_to_register_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+    /// http://www.6502.org/tutorials/decimal_mode.html#A
+    /// NMOS sets N and V from the intermediate result and Z from the binary sum,
+    /// 65C02 spends an extra cycle to set N and Z from the final result
+    fn add_decimal(&mut self, data: u8) {
+        let a = self.register_a;
+        let carry = self.flags.contains(CpuFlags::CARRY) as u8;
+
+        let mut lo = (a & 0x0f) + (data & 0x0f) + carry;
+        if lo >= 0x0a {
+            lo = ((lo + 0x06) & 0x0f) + 0x10;
+        }
+        let mut sum = (a & 0xf0) as u16 + (data & 0xf0) as u16 + lo as u16;
+        let signed_sum = (a & 0xf0) as i8 as i16 + (data & 0xf0) as i8 as i16 + lo as i16;
+        self.flags.set(CpuFlags::OVERFLOW, !(-128..=127).contains(&signed_sum));
+        self.flags.set(CpuFlags::NEGATIV, sum & 0x80 != 0);
+        if sum >= 0xa0 {
+            sum += 0x60;
+        }
+        self.flags.set(CpuFlags::CARRY, sum > 0xff);
+        self.register_a = sum as u8;
+
+        if self.variant == CpuVariant::Wdc65C02 {
+            self.dummy_read();
+            self.udpate_cpu_flags(self.register_a);
+        } else {
+            self.flags.set(CpuFlags::ZERO, a.wrapping_add(data).wrapping_add(carry) == 0);
+        }
+    }
+
+    /// http://www.6502.org/tutorials/decimal_mode.html#A
+    /// NMOS sets all the flags as in binary mode, 65C02 spends an extra cycle to set N and Z from the result
+    fn sub_decimal(&mut self, data: u8) {
+        let a = self.register_a;
+        let borrow = 1 - self.flags.contains(CpuFlags::CARRY) as i16;
+
+        // C and V are the same as in binary mode
+        self.add_binary(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+
+        let lo = (a & 0x0f) as i16 - (data & 0x0f) as i16 - borrow;
+        if self.variant == CpuVariant::Wdc65C02 {
+            let mut result = a as i16 - data as i16 - borrow;
+            if result < 0 {
+                result -= 0x60;
+            }
+            if lo < 0 {
+                result -= 0x06;
+            }
+            self.dummy_read();
+            self.set_register_a(result as u8);
+        } else {
+            let lo = if lo < 0 { ((lo - 0x06) & 0x0f) - 0x10 } else { lo };
+            let mut result = (a & 0xf0) as i16 - (data & 0xf0) as i16 + lo;
+            if result < 0 {
+                result -= 0x60;
+            }
+            self.register_a = result as u8;
+        }
     }
 
     fn and_with_register_a(&mut self, data: u8) {
@@ -162,16 +297,50 @@ impl<'a> CPU<'a> {
     }
 
     fn interrupt(&mut self, interrupt: interrupt::Interrupt) {
+        for _ in 0..interrupt.dummy_reads {
+            self.dummy_read();
+        }
         self.stack_push_u16(self.program_counter);
+
+        // an NMI detected before the flags are pushed hijacks BRK and IRQ
+        // http://wiki.nesdev.com/w/index.php/CPU_interrupts#Interrupt_hijacking
+        let (itype, vector_addr) = if interrupt.itype == interrupt::InterruptType::NMI || self.nmi_pending {
+            self.nmi_pending = false;
+            (interrupt::InterruptType::NMI, interrupt::NMI.vector_addr)
+        } else {
+            (interrupt.itype, interrupt.vector_addr)
+        };
+
         let mut flag = self.flags.clone();
-        flag.set(CpuFlags::BREAK, interrupt.b_flag_mask & 0b010000 == 1);
-        flag.set(CpuFlags::BREAK2, interrupt.b_flag_mask & 0b100000 == 1);
+        flag.set(CpuFlags::BREAK, interrupt.b_flag_mask & 0b010000 != 0);
+        flag.set(CpuFlags::BREAK2, interrupt.b_flag_mask & 0b100000 != 0);
 
         self.stack_push(flag.bits);
         self.flags.insert(CpuFlags::INTERRUPT_DISABLE);
+        if self.variant == CpuVariant::Wdc65C02 {
+            self.flags.remove(CpuFlags::DECIMAL_MODE);
+        }
+
+        self.program_counter = self.mem_read_u16(vector_addr);
+        self.serviced = Some((itype, self.program_counter));
+
+        // the first instruction of a handler is always executed
+        self.nmi_poll = false;
+    }
+
+    /// Interrupt lines are sampled at the end of every cycle, but the CPU only looks at
+    /// the value sampled during the second-to-last cycle of an instruction.
+    /// That's why CLI, SEI and PLP (which change the I flag in the last cycle) delay an IRQ by one instruction.
+    /// http://wiki.nesdev.com/w/index.php/CPU_interrupts#Detailed_interrupt_behavior
+    #[inline]
+    fn poll_interrupts(&mut self) {
+        self.nmi_poll = self.nmi_pending;
+        self.irq_poll = self.irq_pending;
 
-        self.bus.tick(interrupt.cpu_cycles);
-        self.program_counter = self.mem_read_u16(interrupt.vector_addr);
+        if self.bus.poll_nmi_status().is_some() {
+            self.nmi_pending = true;
+        }
+        self.irq_pending = self.bus.poll_irq_status() && !self.flags.contains(CpuFlags::INTERRUPT_DISABLE);
     }
 
     fn udpate_cpu_flags(&mut self, last_operation: u8) {
@@ -209,6 +378,10 @@ impl<'a> CPU<'a> {
         self.stack_pointer = self.stack_pointer.wrapping_sub(1)
     }
 
+    fn stack_dummy_read(&mut self) {
+        self.mem_read((STACK as u16) + self.stack_pointer as u16);
+    }
+
     fn stack_push_u16(&mut self, data: u16) {
         let hi = (data >> 8) as u8;
         let lo = (data & 0xff) as u8;
@@ -227,16 +400,70 @@ impl<'a> CPU<'a> {
         // self.mem_read_u16((STACK as u16) + self.stack_pointer as u16)
     }
 
+    /// Every memory access takes exactly one CPU cycle
+    #[inline]
     pub(super) fn mem_read(&mut self, pos: u16) -> u8 {
-        self.bus.read(pos)
+        let data = self.bus.read(pos);
+        self.bus.tick(1);
+        self.cycles += 1;
+        self.poll_interrupts();
+        data
     }
 
     pub(super) fn mem_read_u16(&mut self, pos: u16) -> u16 {
-        self.bus.read_u16(pos)
+        let lo = self.mem_read(pos) as u16;
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
+        (hi << 8) | lo
     }
 
+    /// A write to $4014 halts the CPU for the OAM DMA
+    #[inline]
     pub(super) fn mem_write(&mut self, pos: u16, data: u8) {
         self.bus.write(pos, data);
+        self.bus.tick(1);
+        self.cycles += 1 + self.bus.take_stall_cycles();
+        self.poll_interrupts();
+    }
+
+    pub(super) fn fetch_u8(&mut self) -> u8 {
+        self.bus.next_read(ReadKind::Code);
+        let data = self.mem_read(self.program_counter);
+        self.program_counter = self.program_counter.wrapping_add(1);
+        data
+    }
+
+    pub(super) fn fetch_u16(&mut self) -> u16 {
+        let lo = self.fetch_u8() as u16;
+        let hi = self.fetch_u8() as u16;
+        (hi << 8) | lo
+    }
+
+    /// Single byte instructions still read the next byte, and throw it away
+    pub(super) fn dummy_read(&mut self) {
+        self.bus.next_read(ReadKind::Dummy);
+        self.mem_read(self.program_counter);
+    }
+
+    /// Read-modify-write instructions write the unmodified value back
+    /// before writing the result (http://wiki.nesdev.com/w/index.php/CPU_pin_out_and_signal_description)
+    fn read_modify_write(&mut self, mode: &AddressingMode, op: fn(&mut Self, u8) -> u8) -> u8 {
+        if let AddressingMode::Accumulator = mode {
+            self.dummy_read();
+            let result = op(self, self.register_a);
+            self.register_a = result;
+            return result;
+        }
+
+        let addr = mode.operand_addr(self);
+        let data = self.mem_read(addr);
+        if self.variant == CpuVariant::Wdc65C02 {
+            self.mem_read(addr); // dummy read
+        } else {
+            self.mem_write(addr, data); // dummy write
+        }
+        let result = op(self, data);
+        self.mem_write(addr, result);
+        result
     }
 
     fn compare(&mut self, mode: &AddressingMode, compare_with: u8) {
@@ -251,89 +478,99 @@ impl<'a> CPU<'a> {
     }
 
     fn branch(&mut self, condition: bool) {
+        let jump: i8 = self.fetch_u8() as i8;
         if condition {
-            self.bus.tick(1);
-            let jump: i8 = self.mem_read(self.program_counter) as i8;
-            let jump_addr = self
-                .program_counter
-                .wrapping_add(1)
-                .wrapping_add(jump as u16);
+            self.dummy_read();
+            let jump_addr = self.program_counter.wrapping_add(jump as u16);
 
-            // todo: figure this out
-            if self.program_counter.wrapping_add(1) & 0xFF00 != jump_addr & 0xFF00 {
-                self.bus.tick(1);
+            if self.program_counter & 0xFF00 != jump_addr & 0xFF00 {
+                // dummy read from the address with un-fixed high byte
+                self.bus.next_read(ReadKind::Dummy);
+                self.mem_read((self.program_counter & 0xFF00) | (jump_addr & 0x00FF));
             }
             self.program_counter = jump_addr;
         }
     }
 
-    fn rol(&mut self, mode: &AddressingMode) -> u8 {
-        let mut data = mode.read_u8(self);
+    fn rol_value(&mut self, data: u8) -> u8 {
         let old_carry = self.flags.contains(CpuFlags::CARRY);
-
-        if data >> 7 == 1 {
-            self.set_carry_flag();
-        } else {
-            self.clear_carry_flag();
-        }
-        data = data << 1;
-        if old_carry {
-            data = data | 1;
-        }
-        mode.write_u8(self, data);
-        self.update_negative_flag(data);
-        data
+        self.flags.set(CpuFlags::CARRY, data >> 7 == 1);
+        let result = (data << 1) | old_carry as u8;
+        self.udpate_cpu_flags(result);
+        result
     }
 
-    fn ror(&mut self, mode: &AddressingMode) -> u8 {
-        let mut data = mode.read_u8(self);
+    fn ror_value(&mut self, data: u8) -> u8 {
         let old_carry = self.flags.contains(CpuFlags::CARRY);
+        self.flags.set(CpuFlags::CARRY, data & 1 == 1);
+        let result = (data >> 1) | ((old_carry as u8) << 7);
+        self.udpate_cpu_flags(result);
+        result
+    }
 
-        if data & 1 == 1 {
-            self.set_carry_flag();
-        } else {
-            self.clear_carry_flag();
-        }
-        data = data >> 1;
-        if old_carry {
-            data = data | 0b10000000;
-        }
-        mode.write_u8(self, data);
-        data
+    fn asl_value(&mut self, data: u8) -> u8 {
+        self.flags.set(CpuFlags::CARRY, data >> 7 == 1);
+        let result = data << 1;
+        self.udpate_cpu_flags(result);
+        result
+    }
+
+    fn lsr_value(&mut self, data: u8) -> u8 {
+        self.flags.set(CpuFlags::CARRY, data & 1 == 1);
+        let result = data >> 1;
+        self.udpate_cpu_flags(result);
+        result
+    }
+
+    fn inc_value(&mut self, data: u8) -> u8 {
+        let result = data.wrapping_add(1);
+        self.udpate_cpu_flags(result);
+        result
+    }
+
+    fn dec_value(&mut self, data: u8) -> u8 {
+        let result = data.wrapping_sub(1);
+        self.udpate_cpu_flags(result);
+        result
+    }
+
+    fn rol(&mut self, mode: &AddressingMode) -> u8 {
+        self.read_modify_write(mode, Self::rol_value)
+    }
+
+    fn ror(&mut self, mode: &AddressingMode) -> u8 {
+        self.read_modify_write(mode, Self::ror_value)
     }
 
     fn asl(&mut self, mode: &AddressingMode) -> u8 {
-        let mut data = mode.read_u8(self);
-        if data >> 7 == 1 {
-            self.set_carry_flag();
-        } else {
-            self.clear_carry_flag();
-        }
-        data = data << 1;
-        mode.write_u8(self, data);
-        self.udpate_cpu_flags(data);
-        data
+        self.read_modify_write(mode, Self::asl_value)
     }
 
     fn lsr(&mut self, mode: &AddressingMode) -> u8 {
-        let mut data = mode.read_u8(self);
-        if data & 1 == 1 {
-            self.set_carry_flag();
-        } else {
-            self.clear_carry_flag();
-        }
-        data = data >> 1;
-        mode.write_u8(self, data);
-        self.udpate_cpu_flags(data);
-        data
+        self.read_modify_write(mode, Self::lsr_value)
     }
 
     fn inc(&mut self, mode: &AddressingMode) -> u8 {
-        let mut data = mode.read_u8(self);
-        data = data.wrapping_add(1);
-        mode.write_u8(self, data);
-        self.udpate_cpu_flags(data);
-        data
+        self.read_modify_write(mode, Self::inc_value)
+    }
+
+    fn dec(&mut self, mode: &AddressingMode) -> u8 {
+        self.read_modify_write(mode, Self::dec_value)
+    }
+
+    /// Unstable stores (SHX, SHY, AHX, TAS) write `data & (H + 1)`, where H is the high byte of the base address.
+    /// When the page is crossed the high byte of the target address gets corrupted the same way.
+    /// http://visual6502.org/wiki/index.php?title=6502_Unsupported_Opcodes
+    fn store_and_high_byte(&mut self, mode: &AddressingMode, index: u8, data: u8) {
+        let addr = mode.operand_addr(self);
+        let base = addr.wrapping_sub(index as u16);
+        let data = data & ((base >> 8) as u8).wrapping_add(1);
+        let addr = if base & 0xFF00 != addr & 0xFF00 {
+            (data as u16) << 8 | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        self.mem_write(addr, data);
     }
 
     fn lda(&mut self, mode: &AddressingMode) -> u8 {
@@ -347,91 +584,222 @@ impl<'a> CPU<'a> {
         self.udpate_cpu_flags(self.register_x);
     }
 
-    pub fn interpret(&mut self, program: &[u8], mem_start: u16) {
-        self.test_interpret_fn(program, mem_start, |_| {});
+    /// https://wiki.nesdev.com/w/index.php/CPU_power_up_state
+    pub fn power_on(&mut self) {
+        self.bus.power_on();
+        self.register_a = 0;
+        self.register_x = 0;
+        self.register_y = 0;
+        self.stack_pointer = 0;
+        self.flags = CpuFlags::from_bits_truncate(0b100100);
+        self.cycles = 0;
+        self.reset_sequence();
+    }
+
+    /// Reset button: registers are left as is, except for SP and the I flag
+    pub fn reset(&mut self) {
+        self.bus.reset();
+        self.reset_sequence();
+    }
+
+    /// Reset goes through the interrupt sequence with writes turned into reads:
+    /// the stack pointer is decremented by 3, but nothing gets pushed
+    fn reset_sequence(&mut self) {
+        self.jam = None;
+        self.serviced = None;
+        self.waiting = false;
+        self.nmi_pending = false;
+        self.irq_pending = false;
+        self.nmi_poll = false;
+        self.irq_poll = false;
+
+        self.dummy_read();
+        self.dummy_read();
+        for _ in 0..3 {
+            self.stack_dummy_read();
+            self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+        }
+        self.flags.insert(CpuFlags::INTERRUPT_DISABLE);
+        if self.variant == CpuVariant::Wdc65C02 {
+            self.flags.remove(CpuFlags::DECIMAL_MODE);
+        }
+        self.program_counter = self.mem_read_u16(RESET_VECTOR);
     }
 
-    pub fn test_interpret_fn<F>(&mut self, program: &[u8], mem_start: u16, callback_opt: F)
-    where
-        F: FnMut(&mut CPU),
-    {
-        self.program_counter = mem_start;
-        let mut pos = self.program_counter;
+    /// Writes the program into memory starting at `mem_start`
+    pub fn load(&mut self, program: &[u8], mem_start: u16) {
+        let mut pos = mem_start;
         for x in program {
-            self.mem_write(pos, *x);
-            pos += 1;
+            self.bus.write(pos, *x);
+            pos = pos.wrapping_add(1);
+        }
+    }
+
+    /// Loads the program and runs it until execution leaves the loaded code
+    pub fn interpret(&mut self, program: &[u8], mem_start: u16) -> HaltReason {
+        self.load(program, mem_start);
+        self.program_counter = mem_start;
+        let program = mem_start as usize..mem_start as usize + program.len();
+        self.run_until(|cpu| !program.contains(&(cpu.program_counter as usize)))
+    }
+
+    /// Executes a single instruction, preceded by an interrupt sequence if one is pending.
+    /// Returns the number of cycles consumed, a jammed CPU doesn't consume any.
+    pub fn step(&mut self) -> usize {
+        if self.jam.is_some() {
+            return 0;
+        }
+        let start = self.cycles;
+        self.execute_next_op();
+        self.cycles - start
+    }
+
+    /// Executes instructions until at least `cycles` cycles are consumed
+    pub fn run_cycles(&mut self, cycles: usize) -> HaltReason {
+        let end = self.cycles + cycles;
+        while self.cycles < end {
+            if let Some(jam) = self.jam {
+                return jam;
+            }
+            self.step();
         }
-        self.interpret_fn(mem_start as usize + program.len(), callback_opt);
+        HaltReason::BudgetExhausted
     }
 
-    pub fn interpret_fn<F>(&mut self, program_end: usize, mut callback_opt: F)
-    //todo: program end is not needed
+    /// Executes instructions until `predicate` holds. The predicate is checked before every instruction.
+    pub fn run_until<F>(&mut self, mut predicate: F) -> HaltReason
     where
-        F: FnMut(&mut CPU),
+        F: FnMut(&mut CPU<B>) -> bool,
     {
-        let ref opscodes: HashMap<u8, &'static opscode::OpsCode> = *opscode::OPSCODES_MAP;
-        while (self.program_counter as usize) < program_end {
-            callback_opt(self);
-            self.execute_next_op(program_end, &opscodes);
+        loop {
+            if let Some(jam) = self.jam {
+                return jam;
+            }
+            if predicate(self) {
+                return HaltReason::Breakpoint;
+            }
+            self.step();
         }
     }
 
-    fn execute_next_op(
-        &mut self,
-        program_end: usize,
-        opscodes: &HashMap<u8, &'static opscode::OpsCode>,
-    ) {
-        if let Some(_nmi) = self.bus.poll_nmi_status() {
+    /// Total number of cycles executed
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    /// Interrupt sequence the last step went through and the handler address it jumped to.
+    /// NMI and IRQ are serviced before the instruction of the step, which is then the handler's first;
+    /// BRK is the instruction itself. An NMI hijacking BRK or IRQ is reported as NMI.
+    pub fn serviced_interrupt(&self) -> Option<(InterruptType, u16)> {
+        self.serviced
+    }
+
+    pub fn variant(&self) -> CpuVariant {
+        self.variant
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.register_a,
+            x: self.register_x,
+            y: self.register_y,
+            sp: self.stack_pointer,
+            pc: self.program_counter,
+            p: self.flags,
+        }
+    }
+
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.register_a = registers.a;
+        self.register_x = registers.x;
+        self.register_y = registers.y;
+        self.stack_pointer = registers.sp;
+        self.program_counter = registers.pc;
+        self.flags = registers.p;
+    }
+
+    fn execute_next_op(&mut self) {
+        self.serviced = None;
+        if self.waiting {
+            // WAI idles until an interrupt line is asserted, even a masked IRQ wakes it up
+            if !self.nmi_pending && !self.bus.poll_irq_status() {
+                self.dummy_read();
+                return;
+            }
+            self.waiting = false;
+        }
+
+        if self.nmi_poll {
             self.interrupt(interrupt::NMI);
+        } else if self.irq_poll {
+            self.interrupt(interrupt::IRQ);
         }
 
-        let code = self.mem_read(self.program_counter);
-        let ops = opscodes.get(&code).unwrap();
+        let code = self.fetch_u8();
+        let ops = self.opscodes[code as usize];
 
-        self.program_counter += 1;
-        let program_counter_state = self.program_counter;
+        if self.variant == CpuVariant::Wdc65C02 && self.execute_65c02_op(code, ops) {
+            return;
+        }
 
         match code {
             /* BRK */
             0x00 => {
-                self.program_counter += 1;
-
-                // testing mode:
-                // - BRK vector address is empty
-                if self.mem_read(interrupt::BRK.vector_addr) == 0x00 {
-                    self.program_counter = program_end as u16;
-                    return;
-                }
-
-                if !self.flags.contains(CpuFlags::INTERRUPT_DISABLE) {
-                    self.interrupt(interrupt::BRK);
-                }
+                self.fetch_u8();
+                self.interrupt(interrupt::BRK);
             }
 
-            /* CLD */ 0xd8 => self.flags.remove(CpuFlags::DECIMAL_MODE),
+            /* CLD */ 0xd8 => {
+                self.dummy_read();
+                self.flags.remove(CpuFlags::DECIMAL_MODE)
+            }
 
-            /* CLI */ 0x58 => self.flags.remove(CpuFlags::INTERRUPT_DISABLE),
+            /* CLI */ 0x58 => {
+                self.dummy_read();
+                self.flags.remove(CpuFlags::INTERRUPT_DISABLE)
+            }
 
-            /* CLV */ 0xb8 => self.flags.remove(CpuFlags::OVERFLOW),
+            /* CLV */ 0xb8 => {
+                self.dummy_read();
+                self.flags.remove(CpuFlags::OVERFLOW)
+            }
 
-            /* CLC */ 0x18 => self.clear_carry_flag(),
+            /* CLC */ 0x18 => {
+                self.dummy_read();
+                self.clear_carry_flag()
+            }
 
-            /* SEC */ 0x38 => self.set_carry_flag(),
+            /* SEC */ 0x38 => {
+                self.dummy_read();
+                self.set_carry_flag()
+            }
 
-            /* SEI */ 0x78 => self.flags.insert(CpuFlags::INTERRUPT_DISABLE),
+            /* SEI */ 0x78 => {
+                self.dummy_read();
+                self.flags.insert(CpuFlags::INTERRUPT_DISABLE)
+            }
 
-            /* SED */ 0xf8 => self.flags.insert(CpuFlags::DECIMAL_MODE),
+            /* SED */ 0xf8 => {
+                self.dummy_read();
+                self.flags.insert(CpuFlags::DECIMAL_MODE)
+            }
 
-            /* PHA */ 0x48 => self.stack_push(self.register_a),
+            /* PHA */ 0x48 => {
+                self.dummy_read();
+                self.stack_push(self.register_a)
+            }
 
             /* PLA */
             0x68 => {
+                self.dummy_read();
+                self.stack_dummy_read();
                 let data = self.stack_pop();
                 self.set_register_a(data);
             }
 
             /* PHP */
             0x08 => {
+                self.dummy_read();
                 //http://wiki.nesdev.com/w/index.php/CPU_status_flag_behavior
                 let mut flags = self.flags.clone();
                 flags.insert(CpuFlags::BREAK);
@@ -441,6 +809,8 @@ impl<'a> CPU<'a> {
 
             /* PLP */
             0x28 => {
+                self.dummy_read();
+                self.stack_dummy_read();
                 self.flags.bits = self.stack_pop();
                 self.flags.remove(CpuFlags::BREAK);
                 self.flags.insert(CpuFlags::BREAK2);
@@ -504,12 +874,14 @@ impl<'a> CPU<'a> {
 
             /* INX */
             0xe8 => {
+                self.dummy_read();
                 self.register_x = self.register_x.wrapping_add(1);
                 self.udpate_cpu_flags(self.register_x);
             }
 
             /* INY */
             0xc8 => {
+                self.dummy_read();
                 self.register_y = self.register_y.wrapping_add(1);
                 self.udpate_cpu_flags(self.register_y);
             }
@@ -517,15 +889,13 @@ impl<'a> CPU<'a> {
             /* DEC */
             0xc6 | 0xd6 | 0xce | 0xde => {
                 //todo tests
-                let mut data = ops.mode.read_u8(self);
-                data = data.wrapping_sub(1);
-                ops.mode.write_u8(self, data);
-                self.udpate_cpu_flags(data);
+                self.dec(&ops.mode);
             }
 
             /* DEX */
             0xca => {
                 //todo tests
+                self.dummy_read();
                 self.register_x = self.register_x.wrapping_sub(1);
                 self.udpate_cpu_flags(self.register_x);
             }
@@ -533,6 +903,7 @@ impl<'a> CPU<'a> {
             /* DEY */
             0x88 => {
                 //todo tests
+                self.dummy_read();
                 self.register_y = self.register_y.wrapping_sub(1);
                 self.udpate_cpu_flags(self.register_y);
             }
@@ -552,44 +923,49 @@ impl<'a> CPU<'a> {
 
             /* JMP Absolute */
             0x4c => {
-                let mem_address = self.mem_read_u16(self.program_counter);
+                let mem_address = self.fetch_u16();
                 self.program_counter = mem_address;
             }
 
             /* JMP Indirect */
             0x6c => {
-                let mem_address = self.mem_read_u16(self.program_counter);
+                let mem_address = self.fetch_u16();
                 // let indirect_ref = self.mem_read_u16(mem_address);
                 //6502 bug mode with with page boundary:
                 //  if address $3000 contains $40, $30FF contains $80, and $3100 contains $50,
                 // the result of JMP ($30FF) will be a transfer of control to $4080 rather than $5080 as you intended
                 // i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
 
-                let indirect_ref = if mem_address & 0x00FF == 0x00FF {
-                    let lo = self.mem_read(mem_address);
-                    let hi = self.mem_read(mem_address & 0xFF00);
-                    (hi as u16) << 8 | (lo as u16)
-                } else {
-                    self.mem_read_u16(mem_address)
-                };
+                let lo = self.mem_read(mem_address);
+                let hi = self.mem_read((mem_address & 0xFF00) | (mem_address.wrapping_add(1) & 0x00FF));
+                let indirect_ref = (hi as u16) << 8 | (lo as u16);
 
                 self.program_counter = indirect_ref;
+                self.bus.next_read(ReadKind::IndirectCode);
             }
 
             /* JSR */
             0x20 => {
-                self.stack_push_u16(self.program_counter + 2 - 1);
-                let target_address = self.mem_read_u16(self.program_counter);
-                self.program_counter = target_address
+                let lo = self.fetch_u8() as u16;
+                self.stack_dummy_read();
+                self.stack_push_u16(self.program_counter);
+                self.bus.next_read(ReadKind::Code);
+                let hi = self.mem_read(self.program_counter) as u16;
+                self.program_counter = hi << 8 | lo;
             }
 
             /* RTS */
             0x60 => {
-                self.program_counter = self.stack_pop_u16() + 1;
+                self.dummy_read();
+                self.stack_dummy_read();
+                self.program_counter = self.stack_pop_u16();
+                self.fetch_u8();
             }
 
             /* RTI */
             0x40 => {
+                self.dummy_read();
+                self.stack_dummy_read();
                 self.flags.bits = self.stack_pop();
                 self.flags.remove(CpuFlags::BREAK);
                 self.flags.insert(CpuFlags::BREAK2);
@@ -687,39 +1063,45 @@ impl<'a> CPU<'a> {
 
             /* NOP */
             0xea => {
-                //do nothing
+                self.dummy_read();
             }
 
             /* TAX */
             0xaa => {
+                self.dummy_read();
                 self.tax();
             }
 
             /* TAY */
             0xa8 => {
+                self.dummy_read();
                 self.register_y = self.register_a;
                 self.udpate_cpu_flags(self.register_y);
             }
 
             /* TSX */
             0xba => {
+                self.dummy_read();
                 self.register_x = self.stack_pointer;
                 self.udpate_cpu_flags(self.register_x);
             }
 
             /* TXA */
             0x8a => {
+                self.dummy_read();
                 self.register_a = self.register_x;
                 self.udpate_cpu_flags(self.register_a);
             }
 
             /* TXS */
             0x9a => {
+                self.dummy_read();
                 self.stack_pointer = self.register_x;
             }
 
             /* TYA */
             0x98 => {
+                self.dummy_read();
                 self.register_a = self.register_y;
                 self.udpate_cpu_flags(self.register_a);
             }
@@ -728,13 +1110,8 @@ impl<'a> CPU<'a> {
 
             /* DCP */
             0xc7 | 0xd7 | 0xCF | 0xdF | 0xdb | 0xd3 | 0xc3 => {
-                let mut data = ops.mode.read_u8(self);
-                data = data.wrapping_sub(1);
-                ops.mode.write_u8(self, data);
-                // self._udpate_cpu_flags(data);
-                if data <= self.register_a {
-                    self.flags.insert(CpuFlags::CARRY);
-                }
+                let data = self.dec(&ops.mode);
+                self.flags.set(CpuFlags::CARRY, data <= self.register_a);
 
                 self.udpate_cpu_flags(self.register_a.wrapping_sub(data));
             }
@@ -760,7 +1137,7 @@ impl<'a> CPU<'a> {
             /* SKB */
             0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {
                 /* 2 byte NOP (immidiate ) */
-                // todo: might be worth doing the read
+                ops.mode.read_u8(self);
             }
 
             /* AXS */
@@ -769,9 +1146,7 @@ impl<'a> CPU<'a> {
                 let x_and_a = self.register_x & self.register_a;
                 let result = x_and_a.wrapping_sub(data);
 
-                if data <= x_and_a {
-                    self.flags.insert(CpuFlags::CARRY);
-                }
+                self.flags.set(CpuFlags::CARRY, data <= x_and_a);
                 self.udpate_cpu_flags(result);
 
                 self.register_x = result;
@@ -781,9 +1156,8 @@ impl<'a> CPU<'a> {
             0x6B => {
                 let data = ops.mode.read_u8(self);
                 self.and_with_register_a(data);
-                self.ror(&AddressingMode::Accumulator);
-                //todo: registers
-                let result = self.register_a;
+                let result = self.ror_value(self.register_a);
+                self.register_a = result;
                 let bit_5 = (result >> 5) & 1;
                 let bit_6 = (result >> 6) & 1;
 
@@ -823,7 +1197,7 @@ impl<'a> CPU<'a> {
             0x4b => {
                 let data = ops.mode.read_u8(self);
                 self.and_with_register_a(data);
-                self.lsr(&AddressingMode::Accumulator);
+                self.register_a = self.lsr_value(self.register_a);
             }
 
             //todo: test for everything bellow
@@ -847,11 +1221,20 @@ impl<'a> CPU<'a> {
                 self.sub_from_register_a(data);
             }
 
-            /* NOPs */
-            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2 => { /* do nothing */
+            /* KIL */
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2 => {
+                self.dummy_read();
+                self.program_counter = self.program_counter.wrapping_sub(1);
+                self.jam = Some(HaltReason::Jam {
+                    pc: self.program_counter,
+                    opcode: code,
+                });
             }
 
-            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => { /* do nothing */ }
+            /* NOPs */
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => {
+                self.dummy_read();
+            }
 
             /* LAX */
             0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
@@ -866,18 +1249,17 @@ impl<'a> CPU<'a> {
                 ops.mode.write_u8(self, data);
             }
 
-            /* LXA */
+            /* LXA: unstable, $EE is the usual "magic" constant */
             0xab => {
-                self.lda(&ops.mode);
+                let data = ops.mode.read_u8(self);
+                self.set_register_a((self.register_a | 0xee) & data);
                 self.tax();
             }
 
             /* XAA */
             0x8b => {
-                self.register_a = self.register_x;
-                self.udpate_cpu_flags(self.register_a);
                 let data = ops.mode.read_u8(self);
-                self.and_with_register_a(data);
+                self.set_register_a((self.register_a | 0xee) & self.register_x & data);
             }
 
             /* LAS */
@@ -891,62 +1273,225 @@ impl<'a> CPU<'a> {
 
             /* TAS */  //todo this and below really needs testing!!!
             0x9b => {
+                self.stack_pointer = self.register_a & self.register_x;
+                self.store_and_high_byte(&ops.mode, self.register_y, self.stack_pointer);
+            }
+
+            /* AHX */
+            0x93 | 0x9f => {
                 let data = self.register_a & self.register_x;
-                self.stack_pointer = data;
-                let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
+                self.store_and_high_byte(&ops.mode, self.register_y, data);
+            }
 
-                let data = ((mem_address >> 8) as u8 + 1) & self.stack_pointer;
-                ops.mode.write_u8(self, data)
+            /* SHX */
+            0x9e => {
+                self.store_and_high_byte(&ops.mode, self.register_y, self.register_x);
             }
 
-            /* AHX  Indirect Y */
-            0x93 => {
-                let pos: u8 = self.mem_read(self.program_counter);
-                let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
-                let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                ops.mode.write_u8(self, data);
+            /* SHY */
+            0x9c => {
+                self.store_and_high_byte(&ops.mode, self.register_x, self.register_y);
             }
+        }
+    }
 
-            /* AHX Absolute Y*/
-            0x9f => {
-                let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
+    /// Opcodes that are new or behave differently on 65C02. Returns false for the ones shared with NMOS.
+    /// http://www.6502.org/tutorials/65c02opcodes.html
+    #[cold]
+    fn execute_65c02_op(&mut self, code: u8, ops: &opscode::OpsCode) -> bool {
+        match code {
+            /* BRA */
+            0x80 => self.branch(true),
 
-                let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                ops.mode.write_u8(self, data);
+            /* PHX */
+            0xda => {
+                self.dummy_read();
+                self.stack_push(self.register_x);
             }
 
-            /* SHX */
-            0x9e => {
-                let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
+            /* PHY */
+            0x5a => {
+                self.dummy_read();
+                self.stack_push(self.register_y);
+            }
 
-                // todo if cross page boundry {
-                //     mem_address &= (self.x as u16) << 8;
-                // }
-                let data = self.register_x & ((mem_address >> 8) as u8 + 1);
-                ops.mode.write_u8(self, data);
+            /* PLX */
+            0xfa => {
+                self.dummy_read();
+                self.stack_dummy_read();
+                let data = self.stack_pop();
+                self.set_register_x(data);
             }
 
-            /* SHY */
-            0x9c => {
-                let mem_address = self.mem_read_u16(self.program_counter) + self.register_x as u16;
-                // todo if cross oage boundry {
-                //     mem_address &= (self.y as u16) << 8;
-                // }
-                let data = self.register_y & ((mem_address >> 8) as u8 + 1);
-                ops.mode.write_u8(self, data);
+            /* PLY */
+            0x7a => {
+                self.dummy_read();
+                self.stack_dummy_read();
+                let data = self.stack_pop();
+                self.set_register_y(data);
+            }
+
+            /* STZ */
+            0x64 | 0x74 | 0x9c | 0x9e => ops.mode.write_u8(self, 0),
+
+            /* TRB */
+            0x14 | 0x1c => {
+                self.read_modify_write(&ops.mode, Self::trb_value);
+            }
+
+            /* TSB */
+            0x04 | 0x0c => {
+                self.read_modify_write(&ops.mode, Self::tsb_value);
+            }
+
+            /* INC A */
+            0x1a => {
+                self.inc(&ops.mode);
+            }
+
+            /* DEC A */
+            0x3a => {
+                self.dec(&ops.mode);
+            }
+
+            /* BIT #imm only affects Z */
+            0x89 => {
+                let data = ops.mode.read_u8(self);
+                self.flags.set(CpuFlags::ZERO, self.register_a & data == 0);
+            }
+
+            /* BIT zp,X abs,X */
+            0x34 | 0x3c => {
+                let data = ops.mode.read_u8(self);
+                self.flags.set(CpuFlags::ZERO, self.register_a & data == 0);
+                self.flags.set(CpuFlags::NEGATIV, data & 0b10000000 > 0);
+                self.flags.set(CpuFlags::OVERFLOW, data & 0b01000000 > 0);
+            }
+
+            /* (zp) */
+            0x12 => {
+                let data = ops.mode.read_u8(self);
+                self.or_with_register_a(data);
+            }
+            0x32 => {
+                let data = ops.mode.read_u8(self);
+                self.and_with_register_a(data);
+            }
+            0x52 => {
+                let data = ops.mode.read_u8(self);
+                self.xor_with_register_a(data);
+            }
+            0x72 => {
+                let data = ops.mode.read_u8(self);
+                self.add_to_register_a(data);
+            }
+            0x92 => ops.mode.write_u8(self, self.register_a),
+            0xb2 => {
+                self.lda(&ops.mode);
+            }
+            0xd2 => self.compare(&ops.mode, self.register_a),
+            0xf2 => {
+                let data = ops.mode.read_u8(self);
+                self.sub_from_register_a(data);
+            }
+
+            /* JMP Indirect without the page wrap bug */
+            0x6c => {
+                let mem_address = self.fetch_u16();
+                self.dummy_read();
+                self.program_counter = self.mem_read_u16(mem_address);
+                self.bus.next_read(ReadKind::IndirectCode);
+            }
+
+            /* JMP (abs,X) */
+            0x7c => {
+                let base = self.fetch_u16();
+                self.dummy_read();
+                let mem_address = base.wrapping_add(self.register_x as u16);
+                self.program_counter = self.mem_read_u16(mem_address);
+                self.bus.next_read(ReadKind::IndirectCode);
+            }
+
+            /* RMB, SMB */
+            0x07 | 0x17 | 0x27 | 0x37 | 0x47 | 0x57 | 0x67 | 0x77 | 0x87 | 0x97 | 0xa7 | 0xb7
+            | 0xc7 | 0xd7 | 0xe7 | 0xf7 => {
+                let bit = 1 << ((code >> 4) & 0b111);
+                let addr = self.fetch_u8() as u16;
+                let data = self.mem_read(addr);
+                self.mem_read(addr); // dummy read
+                let result = if code & 0x80 == 0 { data & !bit } else { data | bit };
+                self.mem_write(addr, result);
+            }
+
+            /* BBR, BBS */
+            0x0f | 0x1f | 0x2f | 0x3f | 0x4f | 0x5f | 0x6f | 0x7f | 0x8f | 0x9f | 0xaf | 0xbf
+            | 0xcf | 0xdf | 0xef | 0xff => {
+                let bit = 1 << ((code >> 4) & 0b111);
+                let addr = self.fetch_u8() as u16;
+                let data = self.mem_read(addr);
+                self.mem_read(addr); // dummy read
+                let is_set = data & bit != 0;
+                self.branch(if code & 0x80 == 0 { !is_set } else { is_set });
+            }
+
+            /* WAI */
+            0xcb => {
+                self.dummy_read();
+                self.dummy_read();
+                self.waiting = true;
+            }
+
+            /* STP: only reset brings the CPU back */
+            0xdb => {
+                self.dummy_read();
+                self.dummy_read();
+                self.program_counter = self.program_counter.wrapping_sub(1);
+                self.jam = Some(HaltReason::Jam {
+                    pc: self.program_counter,
+                    opcode: code,
+                });
+            }
+
+            /* 1 byte NOPs take a single cycle */
+            0x03 | 0x13 | 0x23 | 0x33 | 0x43 | 0x53 | 0x63 | 0x73 | 0x83 | 0x93 | 0xa3 | 0xb3
+            | 0xc3 | 0xd3 | 0xe3 | 0xf3 | 0x0b | 0x1b | 0x2b | 0x3b | 0x4b | 0x5b | 0x6b | 0x7b
+            | 0x8b | 0x9b | 0xab | 0xbb | 0xeb | 0xfb => {}
+
+            /* NOP read */
+            0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xc2 | 0xe2 | 0x44 | 0x54 | 0xd4 | 0xf4 | 0xdc
+            | 0xfc => {
+                ops.mode.read_u8(self);
             }
-        }
 
-        self.bus.tick(ops.cycles);
+            /* 8 cycles NOP */
+            0x5c => {
+                self.fetch_u16();
+                for _ in 0..5 {
+                    self.dummy_read();
+                }
+            }
 
-        // if there were no jumps, advance program counter
-        // todo: find more elegant way
-        if program_counter_state == self.program_counter {
-            self.program_counter += (ops.len - 1) as u16;
+            _ => return false,
         }
+        true
     }
 
-    pub fn new<'b>(bus: Box<dyn CpuBus + 'b>) -> CPU<'b> {
+    fn tsb_value(&mut self, data: u8) -> u8 {
+        self.flags.set(CpuFlags::ZERO, self.register_a & data == 0);
+        data | self.register_a
+    }
+
+    fn trb_value(&mut self, data: u8) -> u8 {
+        self.flags.set(CpuFlags::ZERO, self.register_a & data == 0);
+        data & !self.register_a
+    }
+
+    /// NES 2A03 CPU
+    pub fn new(bus: B) -> CPU<B> {
+        CPU::with_variant(bus, CpuVariant::default())
+    }
+
+    pub fn with_variant(bus: B, variant: CpuVariant) -> CPU<B> {
         return CPU {
             register_a: 0,
             register_x: 0,
@@ -955,6 +1500,16 @@ impl<'a> CPU<'a> {
             program_counter: 0,
             flags: CpuFlags::from_bits_truncate(0b100100),
             bus: bus,
+            variant,
+            opscodes: variant.opscodes(),
+            nmi_pending: false,
+            irq_pending: false,
+            nmi_poll: false,
+            irq_poll: false,
+            cycles: 0,
+            jam: None,
+            waiting: false,
+            serviced: None,
         };
     }
 }
@@ -962,21 +1517,20 @@ impl<'a> CPU<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::bus::DynamicBusWrapper;
+    use crate::bus::BusTrace;
     use crate::bus::MockBus;
-    use std::cell::RefCell;
-    use std::rc::Rc;
+    use crate::cpu::mem::Mem;
 
     #[test]
     fn test_transform() {
-        assert_eq!(CPU::transform("a9 8d"), [169, 141]);
+        assert_eq!(transform("a9 8d"), [169, 141]);
     }
 
     #[test]
     fn test_0xa9_load_into_register_a() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
-        cpu.interpret(&CPU::transform("a9 8d"), 100);
+        let mut cpu = CPU::new(mem);
+        cpu.interpret(&transform("a9 8d"), 100);
         assert_eq!(cpu.register_a, 0x8d);
         assert_eq!(cpu.program_counter, 102);
     }
@@ -984,9 +1538,9 @@ mod test {
     #[test]
     fn test_larger_program() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.interpret(
-            &CPU::transform("a9 01 8d 00 02 a9 05 8d 01 02 a9 08 8d 02 02"),
+            &transform("a9 01 8d 00 02 a9 05 8d 01 02 a9 08 8d 02 02"),
             100,
         );
         assert_eq!(cpu.mem_read(0x0200), 01);
@@ -998,9 +1552,9 @@ mod test {
     #[test]
     fn test_0x48_pha() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 100;
-        cpu.interpret(&CPU::transform("48"), 100);
+        cpu.interpret(&transform("48"), 100);
         assert_eq!(cpu.stack_pointer, STACK_RESET - 1);
         assert_eq!(cpu.mem_read(STACK + STACK_RESET as u16), 100);
         assert_eq!(cpu.program_counter, 101);
@@ -1009,8 +1563,8 @@ mod test {
     #[test]
     fn test_0x68_pla() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
-        cpu.interpret(&CPU::transform("a9 ff 48 a9 00 68"), 100);
+        let mut cpu = CPU::new(mem);
+        cpu.interpret(&transform("a9 ff 48 a9 00 68"), 100);
         assert_eq!(cpu.stack_pointer, STACK_RESET);
         assert_eq!(cpu.register_a, 0xff);
         assert_eq!(cpu.program_counter, 106);
@@ -1019,25 +1573,25 @@ mod test {
     #[test]
     fn test_0x48_pla_flags() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
-        cpu.interpret(&CPU::transform("a9 00 48 a9 01 68"), 100);
+        let mut cpu = CPU::new(mem);
+        cpu.interpret(&transform("a9 00 48 a9 01 68"), 100);
         assert!(cpu.flags.contains(CpuFlags::ZERO));
     }
 
     #[test]
     fn test_stack_overflowing() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
-        cpu.interpret(&CPU::transform("68"), 100);
+        let mut cpu = CPU::new(mem);
+        cpu.interpret(&transform("68"), 100);
     }
 
     #[test]
     fn test_0x18_clc() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.flags.insert(CpuFlags::CARRY);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
-        cpu.interpret(&CPU::transform("18"), 100);
+        cpu.interpret(&transform("18"), 100);
         assert!(!cpu.flags.contains(CpuFlags::CARRY));
         assert_eq!(cpu.program_counter, 101);
     }
@@ -1045,9 +1599,9 @@ mod test {
     #[test]
     fn test_0x38_sec() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         assert!(!cpu.flags.contains(CpuFlags::CARRY));
-        cpu.interpret(&CPU::transform("38"), 100);
+        cpu.interpret(&transform("38"), 100);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
         assert_eq!(cpu.program_counter, 101);
     }
@@ -1055,9 +1609,9 @@ mod test {
     #[test]
     fn test_0x85_sta() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 101;
-        cpu.interpret(&CPU::transform("85 10"), 100);
+        cpu.interpret(&transform("85 10"), 100);
         assert_eq!(cpu.mem_read(0x10), 101);
         assert_eq!(cpu.program_counter, 102);
     }
@@ -1065,10 +1619,10 @@ mod test {
     #[test]
     fn test_0x95_sta() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 101;
         cpu.register_x = 0x50;
-        cpu.interpret(&CPU::transform("95 10"), 100);
+        cpu.interpret(&transform("95 10"), 100);
         assert_eq!(cpu.mem_read(0x60), 101);
         assert_eq!(cpu.program_counter, 102);
     }
@@ -1076,9 +1630,9 @@ mod test {
     #[test]
     fn test_0x8d_sta() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 100;
-        cpu.interpret(&CPU::transform("8d 00 02"), 100);
+        cpu.interpret(&transform("8d 00 02"), 100);
         assert_eq!(cpu.mem_read(0x0200), 100);
         assert_eq!(cpu.program_counter, 103);
     }
@@ -1086,10 +1640,10 @@ mod test {
     #[test]
     fn test_0x9d_sta_absolute_x() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 101;
         cpu.register_x = 0x50;
-        cpu.interpret(&CPU::transform("9d 00 11"), 100);
+        cpu.interpret(&transform("9d 00 11"), 100);
         assert_eq!(cpu.mem_read(0x1150), 101);
         assert_eq!(cpu.program_counter, 103);
     }
@@ -1097,10 +1651,10 @@ mod test {
     #[test]
     fn test_0x99_sta_absolute_y() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 101;
         cpu.register_y = 0x66;
-        cpu.interpret(&CPU::transform("99 00 11"), 100);
+        cpu.interpret(&transform("99 00 11"), 100);
         assert_eq!(cpu.mem_read(0x1166), 101);
         assert_eq!(cpu.program_counter, 103);
     }
@@ -1108,14 +1662,14 @@ mod test {
     #[test]
     fn test_0x81_sta() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_x = 2;
         cpu.mem_write(0x2, 0x05);
         cpu.mem_write(0x3, 0x07);
 
         cpu.register_a = 0x66;
 
-        cpu.interpret(&CPU::transform("81 00"), 100);
+        cpu.interpret(&transform("81 00"), 100);
         assert_eq!(cpu.mem_read(0x0705), 0x66);
         assert_eq!(cpu.program_counter, 102);
     }
@@ -1123,14 +1677,14 @@ mod test {
     #[test]
     fn test_091_sta() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_y = 0x10;
         cpu.mem_write(0x2, 0x05);
         cpu.mem_write(0x3, 0x07);
 
         cpu.register_a = 0x66;
 
-        cpu.interpret(&CPU::transform("91 02"), 100);
+        cpu.interpret(&transform("91 02"), 100);
         assert_eq!(cpu.mem_read(0x0705 + 0x10), 0x66);
         assert_eq!(cpu.program_counter, 102);
     }
@@ -1138,9 +1692,9 @@ mod test {
     #[test]
     fn test_0x69_adc() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0x10;
-        cpu.interpret(&CPU::transform("69 02"), 100);
+        cpu.interpret(&transform("69 02"), 100);
         assert_eq!(cpu.register_a, 0x12);
         assert_eq!(cpu.program_counter, 102);
     }
@@ -1148,9 +1702,9 @@ mod test {
     #[test]
     fn test_0x69_adc_carry_zero_flag() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0x81;
-        cpu.interpret(&CPU::transform("69 7f"), 100);
+        cpu.interpret(&transform("69 7f"), 100);
         assert_eq!(cpu.register_a, 0x0);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
         assert!(cpu.flags.contains(CpuFlags::ZERO));
@@ -1160,9 +1714,9 @@ mod test {
     #[test]
     fn test_0x69_adc_overflow_cary_flag() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0x8a;
-        cpu.interpret(&CPU::transform("69 8a"), 100);
+        cpu.interpret(&transform("69 8a"), 100);
         assert_eq!(cpu.register_a, 0x14);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
         assert!(cpu.flags.contains(CpuFlags::OVERFLOW));
@@ -1171,9 +1725,9 @@ mod test {
     #[test]
     fn test_0xe9_sbc() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0x10;
-        cpu.interpret(&CPU::transform("e9 02"), 100);
+        cpu.interpret(&transform("e9 02"), 100);
         assert_eq!(cpu.register_a, 0x0d);
         assert_eq!(cpu.program_counter, 102);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
@@ -1184,9 +1738,9 @@ mod test {
     #[test]
     fn test_0xe9_sbc_negative() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0x02;
-        cpu.interpret(&CPU::transform("e9 03"), 100);
+        cpu.interpret(&transform("e9 03"), 100);
         assert_eq!(cpu.register_a, 0xfe);
         assert!(!cpu.flags.contains(CpuFlags::CARRY));
         assert!(cpu.flags.contains(CpuFlags::NEGATIV));
@@ -1195,9 +1749,9 @@ mod test {
     #[test]
     fn test_0xe9_sbc_overflow() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0x50;
-        cpu.interpret(&CPU::transform("e9 b0"), 100);
+        cpu.interpret(&transform("e9 b0"), 100);
         assert_eq!(cpu.register_a, 0x9f);
         assert!(!cpu.flags.contains(CpuFlags::CARRY));
         assert!(cpu.flags.contains(CpuFlags::NEGATIV));
@@ -1207,9 +1761,9 @@ mod test {
     #[test]
     fn test_0x29_and_flags() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0b11010010;
-        cpu.interpret(&CPU::transform("29 90"), 100); //0b10010000
+        cpu.interpret(&transform("29 90"), 100); //0b10010000
         assert_eq!(cpu.register_a, 0b10010000);
         assert!(cpu.flags.contains(CpuFlags::NEGATIV));
         assert!(!cpu.flags.contains(CpuFlags::ZERO));
@@ -1218,9 +1772,9 @@ mod test {
     #[test]
     fn test_0x49_eor_flags() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0b11010010;
-        cpu.interpret(&CPU::transform("49 07"), 100); //0b00000111
+        cpu.interpret(&transform("49 07"), 100); //0b00000111
         assert_eq!(cpu.register_a, 0b11010101);
         assert!(cpu.flags.contains(CpuFlags::NEGATIV));
         assert!(!cpu.flags.contains(CpuFlags::ZERO));
@@ -1229,9 +1783,9 @@ mod test {
     #[test]
     fn test_0x09_ora_flags() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0b11010010;
-        cpu.interpret(&CPU::transform("09 07"), 100); //0b00000111
+        cpu.interpret(&transform("09 07"), 100); //0b00000111
         assert_eq!(cpu.register_a, 0b11010111);
         assert!(cpu.flags.contains(CpuFlags::NEGATIV));
         assert!(!cpu.flags.contains(CpuFlags::ZERO));
@@ -1240,9 +1794,9 @@ mod test {
     #[test]
     fn test_0x0a_asl_accumulator() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0b11010010;
-        cpu.interpret(&CPU::transform("0a"), 100);
+        cpu.interpret(&transform("0a"), 100);
         assert_eq!(cpu.program_counter, 101);
         assert_eq!(cpu.register_a, 0b10100100);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
@@ -1251,9 +1805,9 @@ mod test {
     #[test]
     fn test_0x06_asl_memory() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.mem_write(0x10, 0b01000001);
-        cpu.interpret(&CPU::transform("06 10"), 100);
+        cpu.interpret(&transform("06 10"), 100);
         assert_eq!(cpu.mem_read(0x10), 0b10000010);
         assert!(cpu.flags.contains(CpuFlags::NEGATIV));
     }
@@ -1261,9 +1815,9 @@ mod test {
     #[test]
     fn test_0x06_asl_memory_flags() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.mem_write(0x10, 0b10000000);
-        cpu.interpret(&CPU::transform("06 10"), 100);
+        cpu.interpret(&transform("06 10"), 100);
         assert_eq!(cpu.mem_read(0x10), 0b0);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
         assert!(cpu.flags.contains(CpuFlags::ZERO));
@@ -1273,10 +1827,10 @@ mod test {
     #[test]
     fn test_0xf6_inc_memory_zero_page_x() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_x = 1;
         cpu.mem_write(0x10, 127);
-        cpu.interpret(&CPU::transform("f6 0f"), 100);
+        cpu.interpret(&transform("f6 0f"), 100);
         assert_eq!(cpu.mem_read(0x10), 128);
         assert!(cpu.flags.contains(CpuFlags::NEGATIV));
     }
@@ -1284,9 +1838,9 @@ mod test {
     #[test]
     fn test_0x46_lsr_memory_flags() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.mem_write(0x10, 0b00000001);
-        cpu.interpret(&CPU::transform("46 10"), 100);
+        cpu.interpret(&transform("46 10"), 100);
         assert_eq!(cpu.mem_read(0x10), 0b0);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
         assert!(cpu.flags.contains(CpuFlags::ZERO));
@@ -1296,9 +1850,9 @@ mod test {
     #[test]
     fn test_0x2e_rol_memory_flags() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.mem_write(0x1510, 0b10000001);
-        cpu.interpret(&CPU::transform("2e 10 15"), 100);
+        cpu.interpret(&transform("2e 10 15"), 100);
         assert_eq!(cpu.mem_read(0x1510), 0b00000010);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
     }
@@ -1306,10 +1860,10 @@ mod test {
     #[test]
     fn test_0x2e_rol_memory_flags_carry() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.flags.insert(CpuFlags::CARRY);
         cpu.mem_write(0x1510, 0b00000001);
-        cpu.interpret(&CPU::transform("2e 10 15"), 100);
+        cpu.interpret(&transform("2e 10 15"), 100);
         assert_eq!(cpu.mem_read(0x1510), 0b00000011);
         assert!(!cpu.flags.contains(CpuFlags::CARRY));
     }
@@ -1317,10 +1871,10 @@ mod test {
     #[test]
     fn test_0x6e_ror_memory_flags_carry() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.flags.insert(CpuFlags::CARRY);
         cpu.mem_write(0x1510, 0b01000010);
-        cpu.interpret(&CPU::transform("6e 10 15"), 100);
+        cpu.interpret(&transform("6e 10 15"), 100);
         assert_eq!(cpu.mem_read(0x1510), 0b10100001);
         assert!(!cpu.flags.contains(CpuFlags::CARRY));
     }
@@ -1328,19 +1882,19 @@ mod test {
     #[test]
     fn test_0x6e_zero_flag() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.flags.insert(CpuFlags::CARRY);
         cpu.mem_write(0x1510, 0b00000001);
-        cpu.interpret(&CPU::transform("6e 10 15"), 100);
+        cpu.interpret(&transform("6e 10 15"), 100);
         assert!(!cpu.flags.contains(CpuFlags::ZERO));
     }
 
     #[test]
     fn test_0x6a_ror_accumulator_zero_falg() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 1;
-        cpu.interpret(&CPU::transform("6a"), 100);
+        cpu.interpret(&transform("6a"), 100);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
         assert!(cpu.flags.contains(CpuFlags::ZERO));
         assert_eq!(cpu.register_a, 0);
@@ -1349,29 +1903,29 @@ mod test {
     #[test]
     fn test_0xbe_ldx_absolute_y() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.mem_write(0x1166, 55);
         cpu.register_y = 0x66;
-        cpu.interpret(&CPU::transform("be 00 11"), 100);
+        cpu.interpret(&transform("be 00 11"), 100);
         assert_eq!(cpu.register_x, 55);
     }
 
     #[test]
     fn test_0xb4_ldy_zero_page_x() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.mem_write(0x66, 55);
         cpu.register_x = 0x06;
-        cpu.interpret(&CPU::transform("b4 60"), 100);
+        cpu.interpret(&transform("b4 60"), 100);
         assert_eq!(cpu.register_y, 55);
     }
 
     #[test]
     fn test_0xc8_iny() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_y = 127;
-        cpu.interpret(&CPU::transform("c8"), 100);
+        cpu.interpret(&transform("c8"), 100);
         assert_eq!(cpu.register_y, 128);
         assert!(cpu.flags.contains(CpuFlags::NEGATIV));
     }
@@ -1379,9 +1933,9 @@ mod test {
     #[test]
     fn test_0xe8_inx() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_x = 0xff;
-        cpu.interpret(&CPU::transform("e8"), 100);
+        cpu.interpret(&transform("e8"), 100);
         assert_eq!(cpu.register_x, 0);
         assert!(cpu.flags.contains(CpuFlags::ZERO));
     }
@@ -1389,25 +1943,25 @@ mod test {
     #[test]
     fn test_0x6c_jmp_indirect() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.mem_write(0x0120, 0xfc);
         cpu.mem_write(0x0121, 0xba);
-        cpu.interpret(&CPU::transform("6c 20 01"), 100);
+        cpu.interpret(&transform("6c 20 01"), 100);
         assert_eq!(cpu.program_counter, 0xbafc);
     }
 
     #[test]
     fn test_0x4c_jmp_absolute() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
-        cpu.interpret(&CPU::transform("4c 34 12"), 100);
+        let mut cpu = CPU::new(mem);
+        cpu.interpret(&transform("4c 34 12"), 100);
         assert_eq!(cpu.program_counter, 0x1234);
     }
 
     #[test]
     fn test_0xea_nop() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.flags.insert(CpuFlags::CARRY);
         cpu.flags.insert(CpuFlags::NEGATIV);
         let flags = cpu.flags.clone();
@@ -1415,7 +1969,7 @@ mod test {
         cpu.register_x = 2;
         cpu.register_a = 3;
 
-        cpu.interpret(&CPU::transform("ea"), 100);
+        cpu.interpret(&transform("ea"), 100);
         assert_eq!(cpu.program_counter, 101);
         assert_eq!(cpu.register_y, 1);
         assert_eq!(cpu.register_x, 2);
@@ -1427,26 +1981,26 @@ mod test {
     #[test]
     fn test_0xaa_tax() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 66;
-        cpu.interpret(&CPU::transform("aa"), 100);
+        cpu.interpret(&transform("aa"), 100);
         assert_eq!(cpu.register_x, 66);
     }
 
     #[test]
     fn test_0xa8_tay() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 66;
-        cpu.interpret(&CPU::transform("a8"), 100);
+        cpu.interpret(&transform("a8"), 100);
         assert_eq!(cpu.register_y, 66);
     }
 
     #[test]
     fn test_0xba_tsx() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
-        cpu.interpret(&CPU::transform("ba"), 100);
+        let mut cpu = CPU::new(mem);
+        cpu.interpret(&transform("ba"), 100);
         assert_eq!(cpu.register_x, STACK_RESET);
         assert!(cpu.flags.contains(CpuFlags::NEGATIV));
     }
@@ -1454,18 +2008,18 @@ mod test {
     #[test]
     fn test_0x8a_txa() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_x = 66;
-        cpu.interpret(&CPU::transform("8a"), 100);
+        cpu.interpret(&transform("8a"), 100);
         assert_eq!(cpu.register_a, 66);
     }
 
     #[test]
     fn test_0x9a_txs() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_x = 0;
-        cpu.interpret(&CPU::transform("9a"), 100);
+        cpu.interpret(&transform("9a"), 100);
         assert_eq!(cpu.stack_pointer, 0);
         assert!(!cpu.flags.contains(CpuFlags::ZERO)); // should not affect flags
     }
@@ -1473,18 +2027,18 @@ mod test {
     #[test]
     fn test_0x98_tya() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_y = 66;
-        cpu.interpret(&CPU::transform("98"), 100);
+        cpu.interpret(&transform("98"), 100);
         assert_eq!(cpu.register_a, 66);
     }
 
     #[test]
     fn test_0x20_jsr() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         let pc = 100; //cpu.program_counter;
-        cpu.interpret(&CPU::transform("20 04 06"), 100);
+        cpu.interpret(&transform("20 04 06"), 100);
         assert_eq!(cpu.program_counter, 0x604);
         assert_eq!(cpu.stack_pointer, STACK_RESET - 0x2);
         let return_pos = cpu.stack_pop_u16();
@@ -1494,17 +2048,18 @@ mod test {
     #[test]
     fn test_0x60_rts() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         /*
             JSR init
-            BRK
+            JMP end
 
             init:
             LDX #$05
             RTS
+            end:
         */
-        cpu.interpret(&CPU::transform("20 69 00 00 00 a2 05 60"), 100);
-        assert_eq!(cpu.program_counter, 108);
+        cpu.interpret(&transform("20 6a 00 4c 6d 00 a2 05 60"), 100);
+        assert_eq!(cpu.program_counter, 109);
         assert_eq!(cpu.stack_pointer, STACK_RESET);
         assert_eq!(cpu.register_x, 0x5);
     }
@@ -1512,7 +2067,7 @@ mod test {
     #[test]
     fn test_0x40_rti() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.flags.bits = 0b11000001;
         cpu.program_counter = 0x100;
         cpu.stack_push_u16(cpu.program_counter);
@@ -1520,7 +2075,7 @@ mod test {
 
         cpu.flags.bits = 0;
         cpu.program_counter = 0;
-        cpu.interpret(&CPU::transform("40"), 100);
+        cpu.interpret(&transform("40"), 100);
 
         assert_eq!(cpu.flags.bits, 0b11100001);
         assert_eq!(cpu.program_counter, 0x100);
@@ -1529,27 +2084,27 @@ mod test {
     #[test]
     fn test_0xc9_cmp_immidiate() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0x6;
-        cpu.interpret(&CPU::transform("c9 05"), 100);
+        cpu.interpret(&transform("c9 05"), 100);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
 
         cpu.program_counter = 0;
         cpu.flags.bits = 0;
-        cpu.interpret(&CPU::transform("c9 06"), 100);
+        cpu.interpret(&transform("c9 06"), 100);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
         assert!(cpu.flags.contains(CpuFlags::ZERO));
 
         cpu.program_counter = 0;
         cpu.flags.bits = 0;
-        cpu.interpret(&CPU::transform("c9 07"), 100);
+        cpu.interpret(&transform("c9 07"), 100);
         assert!(!cpu.flags.contains(CpuFlags::CARRY));
         assert!(!cpu.flags.contains(CpuFlags::ZERO));
         assert!(cpu.flags.contains(CpuFlags::NEGATIV));
 
         cpu.program_counter = 0;
         cpu.flags.bits = 0;
-        cpu.interpret(&CPU::transform("c9 90"), 100);
+        cpu.interpret(&transform("c9 90"), 100);
         assert!(!cpu.flags.contains(CpuFlags::CARRY));
         assert!(!cpu.flags.contains(CpuFlags::ZERO));
         assert!(!cpu.flags.contains(CpuFlags::NEGATIV));
@@ -1558,22 +2113,22 @@ mod test {
     #[test]
     fn test_0xd0_bne() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         // jump
         cpu.flags.remove(CpuFlags::ZERO);
-        cpu.interpret(&CPU::transform("d0 04"), 100);
+        cpu.interpret(&transform("d0 04"), 100);
         assert_eq!(cpu.program_counter, 100 + 0x6);
 
         // no jump
         cpu.flags.insert(CpuFlags::ZERO);
-        cpu.interpret(&CPU::transform("d0 04"), 100);
+        cpu.interpret(&transform("d0 04"), 100);
         assert_eq!(cpu.program_counter, 100 + 0x02);
     }
 
     #[test]
     fn test_0xd0_bne_snippet() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         /*
             LDX #$08
         decrement:
@@ -1583,17 +2138,17 @@ mod test {
             BNE decrement
             BRK
         */
-        cpu.interpret(&CPU::transform("a2 08 ca c8 e0 03 d0 fa 00"), 100);
+        cpu.interpret(&transform("a2 08 ca c8 e0 03 d0 fa 00"), 100);
         assert_eq!(cpu.register_y, 5);
     }
 
     #[test]
     fn test_0x24_bit() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0b00000010;
         cpu.mem_write(0x10, 0b10111101);
-        cpu.interpret(&CPU::transform("24 10"), 100);
+        cpu.interpret(&transform("24 10"), 100);
 
         assert!(cpu.flags.contains(CpuFlags::ZERO));
         assert!(cpu.flags.contains(CpuFlags::NEGATIV));
@@ -1603,11 +2158,11 @@ mod test {
     #[test]
     fn test_unofficial_0xc7_dcp() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 2;
         cpu.mem_write(0x10, 3);
 
-        cpu.interpret(&CPU::transform("c7 10"), 100);
+        cpu.interpret(&transform("c7 10"), 100);
 
         assert_eq!(cpu.mem_read(0x10), 2);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
@@ -1618,10 +2173,10 @@ mod test {
     #[test]
     fn test_unofficial_0x2f_rla() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0b10000011;
         cpu.mem_write(0x1510, 0b10000001);
-        cpu.interpret(&CPU::transform("2f 10 15"), 100);
+        cpu.interpret(&transform("2f 10 15"), 100);
         assert_eq!(cpu.mem_read(0x1510), 0b00000010);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
         assert_eq!(cpu.register_a, 0b00000010);
@@ -1630,11 +2185,11 @@ mod test {
     #[test]
     fn test_unofficial_0xcb_axs() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0b10000011;
         cpu.register_x = 0b10000001;
 
-        cpu.interpret(&CPU::transform("cb 10"), 100); //0b0010000
+        cpu.interpret(&transform("cb 10"), 100); //0b0010000
 
         assert_eq!(cpu.register_x, 0b1110001);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
@@ -1645,10 +2200,10 @@ mod test {
     #[test]
     fn test_unofficial_0x6b_arr() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0b11010000;
 
-        cpu.interpret(&CPU::transform("6b 90"), 100); //0b10010000
+        cpu.interpret(&transform("6b 90"), 100); //0b10010000
 
         assert_eq!(cpu.register_a, 0b01001000);
         assert!(cpu.flags.contains(CpuFlags::CARRY));
@@ -1660,9 +2215,9 @@ mod test {
     #[test]
     fn test_unoffical_0x0b_anc() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0b11010010;
-        cpu.interpret(&CPU::transform("0b 90"), 100); //0b10010000
+        cpu.interpret(&transform("0b 90"), 100); //0b10010000
         assert_eq!(cpu.register_a, 0b10010000);
         assert!(cpu.flags.contains(CpuFlags::NEGATIV));
         assert!(!cpu.flags.contains(CpuFlags::ZERO));
@@ -1674,7 +2229,7 @@ mod test {
         let mut mem = MockBus::new();
         mem.space[0xfffe] = 110;
         mem.space[0xffff] = 0;
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.flags.remove(CpuFlags::INTERRUPT_DISABLE);
         /*
             BRK
@@ -1688,7 +2243,7 @@ mod test {
             RTI
         */
         cpu.interpret(
-            &CPU::transform("00 00 ca a9 00 8d FE FF 00 00 a2 05 40"),
+            &transform("00 00 ca a9 00 8d FE FF 00 00 a2 05 40"),
             100,
         ); //0b10010000
         assert_eq!(cpu.register_x, 4);
@@ -1696,39 +2251,621 @@ mod test {
 
     #[test]
     fn test_0x00_nmi() {
-        let bus = Rc::from(RefCell::from(MockBus::new()));
+        let mut bus = MockBus::new();
 
-        bus.borrow_mut().nmi_interrupt = Some(1u8);
-        bus.borrow_mut().space[0xfffA] = 104;
-        bus.borrow_mut().space[0xfffB] = 0;
-        let bus_wrap = DynamicBusWrapper::new(bus.clone());
+        bus.nmi_interrupt = Some(1u8);
+        bus.space[0xfffa] = 105;
+        bus.space[0xfffb] = 0;
 
-        let mut cpu = CPU::new(Box::from(bus_wrap));
+        let mut cpu = CPU::new(bus);
 
         /*
+            NOP  ; NMI is serviced once the current instruction is done
             DEX
-            JMP 106
+            JMP 107
 
             nmi:
             LDX #$05
             RTI
         */
 
-        cpu.interpret(&CPU::transform("ca 4c 6A 00 a2 05 40"), 100); //0b10010000
+        cpu.interpret(&transform("ea ca 4c 6B 00 a2 05 40"), 100); //0b10010000
         assert_eq!(cpu.register_x, 4);
-        assert_eq!(bus.borrow().cycles, 21);
+        // NOP(2) + NMI(7) + LDX(2) + RTI(6) + DEX(2) + JMP(3) + RTI(6)
+        assert_eq!(cpu.bus.cycles, 28);
+    }
+
+    struct InterruptBus {
+        mem: MockBus,
+        nmi_at_cycle: usize,
+    }
+
+    impl Mem for InterruptBus {
+        fn write(&mut self, pos: u16, data: u8) {
+            self.mem.write(pos, data)
+        }
+
+        fn read(&mut self, pos: u16) -> u8 {
+            self.mem.read(pos)
+        }
+    }
+
+    impl CpuBus for InterruptBus {
+        fn poll_nmi_status(&mut self) -> Option<u8> {
+            if self.mem.cycles == self.nmi_at_cycle {
+                Some(1)
+            } else {
+                None
+            }
+        }
+
+        fn poll_irq_status(&self) -> bool {
+            self.mem.poll_irq_status()
+        }
+
+        fn tick(&mut self, cycles: u8) {
+            self.mem.tick(cycles)
+        }
+
+        fn trace(&self) -> BusTrace {
+            self.mem.trace()
+        }
+
+        fn peek(&self, addr: u16) -> u8 {
+            self.mem.peek(addr)
+        }
+
+        fn poke(&mut self, addr: u16, data: u8) {
+            self.mem.poke(addr, data)
+        }
+    }
+
+    /// IRQ/BRK handler at 0x0510 sets X to 2, NMI handler at 0x0520 sets X to 1,
+    /// both leave the program by jumping to 0x0700
+    fn interrupt_bus(irq: bool, nmi_at_cycle: usize) -> InterruptBus {
+        let mut mem = MockBus::new();
+        mem.irq_interrupt = irq;
+        mem.space[0xfffe] = 0x10;
+        mem.space[0xffff] = 0x05;
+        mem.space[0xfffa] = 0x20;
+        mem.space[0xfffb] = 0x05;
+        let handler = transform("a2 02 4c 00 07");
+        mem.space[0x0510..0x0515].copy_from_slice(&handler);
+        let handler = transform("a2 01 4c 00 07");
+        mem.space[0x0520..0x0525].copy_from_slice(&handler);
+        InterruptBus { mem, nmi_at_cycle }
+    }
+
+    fn run_interrupt_test(cpu: &mut CPU<InterruptBus>, program: &str) {
+        cpu.load(&transform(program), 0x0600);
+        cpu.program_counter = 0x0600;
+        cpu.run_until(|cpu| cpu.program_counter == 0x0700);
+    }
+
+    #[test]
+    fn test_irq_is_masked_by_interrupt_disable() {
+        let mut cpu = CPU::new(interrupt_bus(true, 0));
+        run_interrupt_test(&mut cpu, "e8 e8 4c 00 07");
+        assert_eq!(cpu.register_x, 2);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+    }
+
+    #[test]
+    fn test_irq_after_cli_is_delayed_by_one_instruction() {
+        let mut cpu = CPU::new(interrupt_bus(true, 0));
+        cpu.register_y = 0;
+        run_interrupt_test(&mut cpu, "58 c8 c8 c8");
+        assert_eq!(cpu.register_y, 1);
+        assert_eq!(cpu.register_x, 2);
+        // pushed return address and flags without the B flag
+        assert_eq!(cpu.mem_read_u16(0x01fc), 0x0602);
+        assert_eq!(cpu.bus.read(0x01fb) & 0b0011_0000, 0b0010_0000);
+    }
+
+    #[test]
+    fn test_irq_after_sei_is_still_serviced() {
+        let mut cpu = CPU::new(interrupt_bus(true, 0));
+        cpu.flags.remove(CpuFlags::INTERRUPT_DISABLE);
+        run_interrupt_test(&mut cpu, "78 c8");
+        assert_eq!(cpu.register_y, 0);
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    fn test_irq_after_plp_is_delayed_by_one_instruction() {
+        let mut cpu = CPU::new(interrupt_bus(true, 0));
+        // PLP of zero clears the I flag
+        run_interrupt_test(&mut cpu, "a9 00 48 28 c8 c8");
+        assert_eq!(cpu.register_y, 1);
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    fn test_brk_ignores_interrupt_disable() {
+        let mut cpu = CPU::new(interrupt_bus(false, 0));
+        run_interrupt_test(&mut cpu, "00 ff");
+        assert_eq!(cpu.register_x, 2);
+        assert_eq!(cpu.mem_read_u16(0x01fc), 0x0602);
+        assert_eq!(cpu.bus.read(0x01fb) & 0b0011_0000, 0b0011_0000);
+    }
+
+    #[test]
+    fn test_nmi_hijacks_brk() {
+        // NMI edge during the 3rd cycle of BRK, before the flags are pushed
+        let mut cpu = CPU::new(interrupt_bus(false, 3));
+        run_interrupt_test(&mut cpu, "00 ff");
+        assert_eq!(cpu.register_x, 1);
+        // B flag is still pushed
+        assert_eq!(cpu.bus.read(0x01fb) & 0b0011_0000, 0b0011_0000);
+    }
+
+    #[test]
+    fn test_nmi_hijacks_irq() {
+        let mut cpu = CPU::new(interrupt_bus(true, 6));
+        cpu.flags.remove(CpuFlags::INTERRUPT_DISABLE);
+        // IRQ starts after NOP (2 cycles), NMI arrives during its 4th cycle
+        run_interrupt_test(&mut cpu, "ea ea");
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.bus.read(0x01fb) & 0b0011_0000, 0b0010_0000);
+    }
+
+    #[test]
+    fn test_serviced_interrupt_is_reported_for_one_step() {
+        let mut cpu = CPU::new(interrupt_bus(true, 0));
+        cpu.load(&transform("58 ea ea"), 0x0600);
+        cpu.program_counter = 0x0600;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.serviced_interrupt(), None);
+        cpu.step();
+        // IRQ before the second NOP: the step executed LDX #2 of the handler
+        assert_eq!(cpu.serviced_interrupt(), Some((InterruptType::IRQ, 0x0510)));
+        assert_eq!(cpu.program_counter, 0x0512);
+        cpu.step();
+        assert_eq!(cpu.serviced_interrupt(), None);
+
+        let mut cpu = CPU::new(interrupt_bus(false, 0));
+        cpu.load(&transform("00 ff"), 0x0600);
+        cpu.program_counter = 0x0600;
+        cpu.step();
+        assert_eq!(cpu.serviced_interrupt(), Some((InterruptType::BRK, 0x0510)));
+        assert_eq!(cpu.program_counter, 0x0510);
+    }
+
+    fn cycles_of(program: &str, setup: fn(&mut CPU<MockBus>)) -> usize {
+        let mut cpu = CPU::new(MockBus::new());
+        setup(&mut cpu);
+        let program = transform(program);
+        cpu.interpret(&program, 0x0600);
+        cpu.bus.cycles
+    }
+
+    #[test]
+    fn test_cycles_indexed_read_page_cross() {
+        assert_eq!(cycles_of("bd 00 10", |cpu| cpu.register_x = 0xff), 4);
+        assert_eq!(cycles_of("bd 01 10", |cpu| cpu.register_x = 0xff), 5);
+        assert_eq!(cycles_of("b1 10", |cpu| cpu.register_y = 0x10), 5);
+    }
+
+    #[test]
+    fn test_cycles_indexed_write_always_dummy_read() {
+        assert_eq!(cycles_of("9d 00 10", |cpu| cpu.register_x = 1), 5);
+        assert_eq!(cycles_of("91 10", |cpu| cpu.register_y = 1), 6);
+        assert_eq!(cycles_of("fe 00 10", |cpu| cpu.register_x = 1), 7);
+    }
+
+    #[test]
+    fn test_cycles_implied_and_stack() {
+        assert_eq!(cycles_of("ca", |_| {}), 2);
+        assert_eq!(cycles_of("48 68", |_| {}), 3 + 4);
+        // JSR, RTS, JMP out of the program
+        assert_eq!(cycles_of("20 06 06 4c 00 07 60", |_| {}), 6 + 6 + 3);
+    }
+
+    #[test]
+    fn test_cycles_branch() {
+        assert_eq!(cycles_of("d0 00", |cpu| cpu.flags.insert(CpuFlags::ZERO)), 2);
+        assert_eq!(cycles_of("d0 00", |_| {}), 3);
+        // BNE to the previous page
+        assert_eq!(cycles_of("d0 80", |_| {}), 4);
+    }
+
+    #[test]
+    fn test_step_returns_cycles() {
+        let mut cpu = CPU::new(MockBus::new());
+        cpu.load(&transform("a9 01 8d 00 02"), 0x0600);
+        cpu.program_counter = 0x0600;
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.cycles(), 6);
+    }
+
+    #[test]
+    fn test_run_cycles() {
+        let mut cpu = CPU::new(MockBus::new());
+        cpu.load(&transform("e8 4c 00 06"), 0x0600);
+        cpu.program_counter = 0x0600;
+        assert_eq!(cpu.run_cycles(10), HaltReason::BudgetExhausted);
+        assert_eq!(cpu.cycles(), 10);
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    fn test_run_until() {
+        let mut cpu = CPU::new(MockBus::new());
+        cpu.load(&transform("e8 4c 00 06"), 0x0600);
+        cpu.program_counter = 0x0600;
+        let halt = cpu.run_until(|cpu| cpu.register_x == 3 && cpu.program_counter == 0x0600);
+        assert_eq!(halt, HaltReason::Breakpoint);
+        assert_eq!(cpu.register_x, 3);
+    }
+
+    #[test]
+    fn test_kil_jams_cpu() {
+        let mut cpu = CPU::new(MockBus::new());
+        let halt = cpu.interpret(&transform("e8 02 e8"), 0x0600);
+        assert_eq!(
+            halt,
+            HaltReason::Jam {
+                pc: 0x0601,
+                opcode: 0x02
+            }
+        );
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.step(), 0);
+        assert_eq!(cpu.program_counter, 0x0601);
+        assert_eq!(cpu.run_cycles(100), halt);
+    }
+
+    #[test]
+    fn test_power_on() {
+        let mut mem = MockBus::new();
+        mem.space[0xfffc] = 0x00;
+        mem.space[0xfffd] = 0xc0;
+        let mut cpu = CPU::new(mem);
+        cpu.register_a = 1;
+        cpu.flags = CpuFlags::from_bits_truncate(0);
+        cpu.power_on();
+
+        assert_eq!(cpu.program_counter, 0xc000);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+        assert_eq!(cpu.register_a, 0);
+        assert_eq!(cpu.flags.bits(), 0x24);
+        assert_eq!(cpu.cycles(), 7);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut mem = MockBus::new();
+        mem.space[0xfffc] = 0x00;
+        mem.space[0xfffd] = 0x06;
+        let mut cpu = CPU::new(mem);
+        cpu.power_on();
+        // KIL
+        cpu.interpret(&transform("a9 05 48 02"), 0x0600);
+        assert_eq!(cpu.stack_pointer, STACK_RESET - 1);
+
+        cpu.reset();
+        assert_eq!(cpu.program_counter, 0x0600);
+        assert_eq!(cpu.stack_pointer, STACK_RESET - 4);
+        assert_eq!(cpu.register_a, 5);
+        assert!(cpu.flags.contains(CpuFlags::INTERRUPT_DISABLE));
+        // nothing is pushed
+        assert_eq!(cpu.bus.read(0x01fc), 0x00);
+        assert_eq!(cpu.step(), 2);
     }
 
     #[test]
     fn test_ololo() {
         let mem = MockBus::new();
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.register_a = 0b00000010;
         cpu.mem_write(0x10, 0b10111101);
-        cpu.interpret(&CPU::transform("24 10"), 100);
+        cpu.interpret(&transform("24 10"), 100);
 
         assert!(cpu.flags.contains(CpuFlags::ZERO));
         assert!(cpu.flags.contains(CpuFlags::NEGATIV));
         assert!(!cpu.flags.contains(CpuFlags::OVERFLOW));
     }
+
+    fn decimal(variant: CpuVariant, program: &str) -> CPU<MockBus> {
+        let mut cpu = CPU::with_variant(MockBus::new(), variant);
+        cpu.interpret(&transform(program), 0x0600);
+        cpu
+    }
+
+    #[test]
+    fn test_2a03_ignores_decimal_mode() {
+        // SED CLC LDA #$09 ADC #$01
+        let cpu = decimal(CpuVariant::Ricoh2A03, "f8 18 a9 09 69 01");
+        assert_eq!(cpu.register_a, 0x0a);
+    }
+
+    #[test]
+    fn test_decimal_adc() {
+        for &variant in &[CpuVariant::Nmos6502, CpuVariant::Wdc65C02] {
+            // SED CLC LDA #$12 ADC #$34
+            let cpu = decimal(variant, "f8 18 a9 12 69 34");
+            assert_eq!(cpu.register_a, 0x46);
+            assert!(!cpu.flags.contains(CpuFlags::CARRY));
+
+            // SED CLC LDA #$15 ADC #$26
+            let cpu = decimal(variant, "f8 18 a9 15 69 26");
+            assert_eq!(cpu.register_a, 0x41);
+
+            // SED SEC LDA #$81 ADC #$92
+            let cpu = decimal(variant, "f8 38 a9 81 69 92");
+            assert_eq!(cpu.register_a, 0x74);
+            assert!(cpu.flags.contains(CpuFlags::CARRY));
+        }
+    }
+
+    #[test]
+    fn test_decimal_sbc() {
+        for &variant in &[CpuVariant::Nmos6502, CpuVariant::Wdc65C02] {
+            // SED SEC LDA #$46 SBC #$12
+            let cpu = decimal(variant, "f8 38 a9 46 e9 12");
+            assert_eq!(cpu.register_a, 0x34);
+            assert!(cpu.flags.contains(CpuFlags::CARRY));
+
+            // SED SEC LDA #$40 SBC #$13
+            let cpu = decimal(variant, "f8 38 a9 40 e9 13");
+            assert_eq!(cpu.register_a, 0x27);
+
+            // SED CLC LDA #$32 SBC #$02
+            let cpu = decimal(variant, "f8 18 a9 32 e9 02");
+            assert_eq!(cpu.register_a, 0x29);
+
+            // SED SEC LDA #$12 SBC #$21
+            let cpu = decimal(variant, "f8 38 a9 12 e9 21");
+            assert_eq!(cpu.register_a, 0x91);
+            assert!(!cpu.flags.contains(CpuFlags::CARRY));
+        }
+    }
+
+    #[test]
+    fn test_decimal_flags() {
+        // SED CLC LDA #$99 ADC #$01: NMOS takes Z from the binary sum and N from the intermediate result
+        let cpu = decimal(CpuVariant::Nmos6502, "f8 18 a9 99 69 01");
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.flags.contains(CpuFlags::CARRY));
+        assert!(!cpu.flags.contains(CpuFlags::ZERO));
+        assert!(cpu.flags.contains(CpuFlags::NEGATIV));
+
+        let cpu = decimal(CpuVariant::Wdc65C02, "f8 18 a9 99 69 01");
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.flags.contains(CpuFlags::CARRY));
+        assert!(cpu.flags.contains(CpuFlags::ZERO));
+        assert!(!cpu.flags.contains(CpuFlags::NEGATIV));
+    }
+
+    #[test]
+    fn test_decimal_cycles() {
+        let cycles = |variant| {
+            let mut cpu = CPU::with_variant(MockBus::new(), variant);
+            cpu.load(&transform("f8 69 01"), 0x0600);
+            cpu.program_counter = 0x0600;
+            cpu.step();
+            cpu.step()
+        };
+        assert_eq!(cycles(CpuVariant::Nmos6502), 2);
+        assert_eq!(cycles(CpuVariant::Wdc65C02), 3);
+    }
+
+    #[test]
+    fn test_opscode_tables() {
+        for &variant in &[
+            CpuVariant::Ricoh2A03,
+            CpuVariant::Nmos6502,
+            CpuVariant::Wdc65C02,
+        ] {
+            for (code, ops) in variant.opscodes().iter().enumerate() {
+                assert_eq!(ops.code as usize, code);
+            }
+        }
+        assert_eq!(CpuVariant::Wdc65C02.opscodes()[0x80].mnemonic, "BRA");
+        assert_eq!(CpuVariant::Nmos6502.opscodes()[0x80].mnemonic, "*NOP");
+    }
+
+    fn cmos(program: &str) -> CPU<MockBus> {
+        decimal(CpuVariant::Wdc65C02, program)
+    }
+
+    #[test]
+    fn test_65c02_jmp_indirect_page_wrap_is_fixed() {
+        let mut mem = MockBus::new();
+        mem.space[0x30ff] = 0x80;
+        mem.space[0x3000] = 0x40;
+        mem.space[0x3100] = 0x50;
+        let mut cpu = CPU::with_variant(mem, CpuVariant::Wdc65C02);
+        cpu.load(&transform("6c ff 30"), 0x0600);
+        cpu.program_counter = 0x0600;
+        assert_eq!(cpu.step(), 6);
+        assert_eq!(cpu.program_counter, 0x5080);
+    }
+
+    #[test]
+    fn test_65c02_jmp_absolute_x() {
+        let mut mem = MockBus::new();
+        mem.space[0x3002] = 0x34;
+        mem.space[0x3003] = 0x12;
+        let mut cpu = CPU::with_variant(mem, CpuVariant::Wdc65C02);
+        cpu.register_x = 2;
+        cpu.load(&transform("7c 00 30"), 0x0600);
+        cpu.program_counter = 0x0600;
+        assert_eq!(cpu.step(), 6);
+        assert_eq!(cpu.program_counter, 0x1234);
+    }
+
+    #[test]
+    fn test_65c02_bra() {
+        // BRA +2, LDX #$01, LDY #$01
+        let cpu = cmos("80 02 a2 01 a0 01");
+        assert_eq!(cpu.register_x, 0);
+        assert_eq!(cpu.register_y, 1);
+    }
+
+    #[test]
+    fn test_65c02_phx_ply() {
+        // LDX #$85 PHX PLY
+        let cpu = cmos("a2 85 da 7a");
+        assert_eq!(cpu.register_y, 0x85);
+        assert!(cpu.flags.contains(CpuFlags::NEGATIV));
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+    }
+
+    #[test]
+    fn test_65c02_stz() {
+        let mut mem = MockBus::new();
+        mem.space[0x10] = 0xff;
+        mem.space[0x0211] = 0xff;
+        let mut cpu = CPU::with_variant(mem, CpuVariant::Wdc65C02);
+        cpu.register_x = 1;
+        cpu.interpret(&transform("64 10 9e 10 02"), 0x0600);
+        assert_eq!(cpu.bus.space[0x10], 0);
+        assert_eq!(cpu.bus.space[0x0211], 0);
+    }
+
+    #[test]
+    fn test_65c02_tsb_trb() {
+        // LDA #$0f TSB $10
+        let mut mem = MockBus::new();
+        mem.space[0x10] = 0xf0;
+        let mut cpu = CPU::with_variant(mem, CpuVariant::Wdc65C02);
+        cpu.interpret(&transform("a9 0f 04 10"), 0x0600);
+        assert_eq!(cpu.bus.space[0x10], 0xff);
+        assert!(cpu.flags.contains(CpuFlags::ZERO));
+
+        // LDA #$0f TRB $10
+        let mut mem = MockBus::new();
+        mem.space[0x10] = 0xff;
+        let mut cpu = CPU::with_variant(mem, CpuVariant::Wdc65C02);
+        cpu.interpret(&transform("a9 0f 14 10"), 0x0600);
+        assert_eq!(cpu.bus.space[0x10], 0xf0);
+        assert!(!cpu.flags.contains(CpuFlags::ZERO));
+    }
+
+    #[test]
+    fn test_65c02_inc_dec_accumulator() {
+        let cpu = cmos("a9 ff 1a");
+        assert_eq!(cpu.register_a, 0);
+        assert!(cpu.flags.contains(CpuFlags::ZERO));
+
+        let cpu = cmos("a9 00 3a");
+        assert_eq!(cpu.register_a, 0xff);
+        assert!(cpu.flags.contains(CpuFlags::NEGATIV));
+    }
+
+    #[test]
+    fn test_65c02_bit_immediate_only_sets_zero() {
+        // LDA #$01 BIT #$c0
+        let cpu = cmos("a9 01 89 c0");
+        assert!(cpu.flags.contains(CpuFlags::ZERO));
+        assert!(!cpu.flags.contains(CpuFlags::NEGATIV));
+        assert!(!cpu.flags.contains(CpuFlags::OVERFLOW));
+    }
+
+    #[test]
+    fn test_65c02_zero_page_indirect() {
+        let mut mem = MockBus::new();
+        mem.space[0x10] = 0x00;
+        mem.space[0x11] = 0x02;
+        mem.space[0x0200] = 0x55;
+        let mut cpu = CPU::with_variant(mem, CpuVariant::Wdc65C02);
+        cpu.load(&transform("b2 10 92 20"), 0x0600);
+        cpu.program_counter = 0x0600;
+        assert_eq!(cpu.step(), 5);
+        assert_eq!(cpu.register_a, 0x55);
+    }
+
+    #[test]
+    fn test_65c02_rmb_smb() {
+        let mut mem = MockBus::new();
+        mem.space[0x10] = 0xff;
+        mem.space[0x11] = 0x00;
+        let mut cpu = CPU::with_variant(mem, CpuVariant::Wdc65C02);
+        // RMB3 $10, SMB7 $11
+        cpu.interpret(&transform("37 10 f7 11"), 0x0600);
+        assert_eq!(cpu.bus.space[0x10], 0b1111_0111);
+        assert_eq!(cpu.bus.space[0x11], 0b1000_0000);
+    }
+
+    #[test]
+    fn test_65c02_bbr_bbs() {
+        let mut mem = MockBus::new();
+        mem.space[0x10] = 0b0000_0100;
+        let mut cpu = CPU::with_variant(mem, CpuVariant::Wdc65C02);
+        // BBS2 $10,+2; LDX #$01; BBR2 $10,+2; LDY #$01
+        cpu.interpret(&transform("af 10 02 a2 01 2f 10 02 a0 01"), 0x0600);
+        assert_eq!(cpu.register_x, 0);
+        assert_eq!(cpu.register_y, 1);
+    }
+
+    #[test]
+    fn test_65c02_undefined_opcodes_are_nops() {
+        let mut cpu = CPU::with_variant(MockBus::new(), CpuVariant::Wdc65C02);
+        cpu.load(&transform("03 02 ff 5c 00 00"), 0x0600);
+        cpu.program_counter = 0x0600;
+        assert_eq!(cpu.step(), 1);
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.program_counter, 0x0606);
+    }
+
+    #[test]
+    fn test_65c02_shift_absolute_x_cycles() {
+        let cycles = |x: u8| {
+            let mut cpu = CPU::with_variant(MockBus::new(), CpuVariant::Wdc65C02);
+            cpu.register_x = x;
+            cpu.load(&transform("1e f0 02"), 0x0600);
+            cpu.program_counter = 0x0600;
+            cpu.step()
+        };
+        assert_eq!(cycles(0x01), 6);
+        assert_eq!(cycles(0x10), 7);
+    }
+
+    #[test]
+    fn test_65c02_brk_clears_decimal_flag() {
+        let mut mem = MockBus::new();
+        mem.space[0xfffe] = 0x00;
+        mem.space[0xffff] = 0x07;
+        let mut cpu = CPU::with_variant(mem, CpuVariant::Wdc65C02);
+        cpu.load(&transform("f8 00 00"), 0x0600);
+        cpu.program_counter = 0x0600;
+        cpu.run_until(|cpu| cpu.program_counter == 0x0700);
+        assert!(!cpu.flags.contains(CpuFlags::DECIMAL_MODE));
+        // pushed flags still have it
+        assert_eq!(cpu.bus.space[0x01fb] & 0b1000, 0b1000);
+    }
+
+    #[test]
+    fn test_65c02_stp() {
+        let mut cpu = CPU::with_variant(MockBus::new(), CpuVariant::Wdc65C02);
+        let halt = cpu.interpret(&transform("e8 db e8"), 0x0600);
+        assert_eq!(
+            halt,
+            HaltReason::Jam {
+                pc: 0x0601,
+                opcode: 0xdb
+            }
+        );
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_65c02_wai_waits_for_interrupt() {
+        let mut cpu = CPU::with_variant(MockBus::new(), CpuVariant::Wdc65C02);
+        // SEI WAI INX
+        cpu.load(&transform("78 cb e8"), 0x0600);
+        cpu.program_counter = 0x0600;
+        cpu.run_cycles(100);
+        assert_eq!(cpu.program_counter, 0x0602);
+        assert_eq!(cpu.register_x, 0);
+
+        // masked IRQ resumes execution without calling the handler
+        cpu.bus.irq_interrupt = true;
+        cpu.run_cycles(10);
+        assert_eq!(cpu.register_x, 1);
+    }
 }
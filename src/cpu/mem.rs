@@ -1,3 +1,5 @@
+use crate::bus::CpuBus;
+use crate::cdl::ReadKind;
 use crate::cpu::cpu::CPU;
 
 const ZERO_PAGE: u16 = 0x0;
@@ -20,7 +22,7 @@ pub trait Mem {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
     Immediate,
@@ -36,12 +38,17 @@ pub enum AddressingMode {
     Indirect_X,
     Indirect_Y,
     Indirect_Y_PageCross,
+    /// 65C02 only: (zp)
+    ZeroPage_Indirect,
     NoneAddressing,
 }
 
 impl AddressingMode {
-    pub fn get_absolute_addr(&self, cpu: &mut CPU, base: u16) -> (bool, u16) {
-        match self {
+    /// Resolves the operand address for tracing purposes only:
+    /// doesn't advance the clock and doesn't perform dummy reads.
+    /// Modes without a memory operand (immediate, accumulator, implied, branches) have no address.
+    pub fn get_absolute_addr<B: CpuBus>(&self, cpu: &CPU<B>, base: u16) -> Option<(bool, u16)> {
+        Some(match self {
             AddressingMode::ZeroPage => (false, ZERO_PAGE + base),
             AddressingMode::ZeroPage_X => {
                 let pos = (ZERO_PAGE + base) as u8;
@@ -65,68 +72,117 @@ impl AddressingMode {
 
             AddressingMode::Indirect_X => {
                 let ptr: u8 = (base as u8).wrapping_add(cpu.register_x);
-                let lo = cpu.mem_read(ptr as u16);
-                let hi = cpu.mem_read(ptr.wrapping_add(1) as u16);
+                let lo = cpu.bus.peek(ptr as u16);
+                let hi = cpu.bus.peek(ptr.wrapping_add(1) as u16);
                 (false, (hi as u16) << 8 | (lo as u16))
             }
             AddressingMode::Indirect_Y | AddressingMode::Indirect_Y_PageCross => {
-                let lo = cpu.mem_read(base as u16);
-                let hi = cpu.mem_read((base as u8).wrapping_add(1) as u16);
-                // let deref = ((hi as u16) << 8 | (lo as u16)).wrapping_add(cpu.register_y as u16);
+                let lo = cpu.bus.peek(base);
+                let hi = cpu.bus.peek((base as u8).wrapping_add(1) as u16);
 
                 let deref_base = (hi as u16) << 8 | (lo as u16);
                 let deref = deref_base.wrapping_add(cpu.register_y as u16);
                 (page_cross(deref_base, deref), deref)
             }
+            AddressingMode::ZeroPage_Indirect => {
+                let lo = cpu.bus.peek(base);
+                let hi = cpu.bus.peek((base as u8).wrapping_add(1) as u16);
+                (false, (hi as u16) << 8 | (lo as u16))
+            }
             AddressingMode::Accumulator
             | AddressingMode::Immediate
-            | AddressingMode::NoneAddressing => {
-                panic!("mode {:?} is not supported", self);
+            | AddressingMode::NoneAddressing => return None,
+        })
+    }
+
+    /// Fetches operand bytes and resolves the effective address, one bus access per cycle.
+    /// http://nesdev.com/6502_cpu.txt
+    ///
+    /// Indexed modes first read from the address with un-fixed high byte.
+    /// For read instructions (*_PageCross modes) this dummy read happens only when the page is crossed,
+    /// write and read-modify-write instructions always do it.
+    pub fn operand_addr<B: CpuBus>(&self, cpu: &mut CPU<B>) -> u16 {
+        match self {
+            AddressingMode::Immediate => {
+                let addr = cpu.program_counter;
+                cpu.program_counter = cpu.program_counter.wrapping_add(1);
+                addr
+            }
+            AddressingMode::ZeroPage => cpu.fetch_u8() as u16,
+            AddressingMode::ZeroPage_X => {
+                let base = cpu.fetch_u8();
+                cpu.mem_read(ZERO_PAGE + base as u16); // dummy read
+                base.wrapping_add(cpu.register_x) as u16
+            }
+            AddressingMode::ZeroPage_Y => {
+                let base = cpu.fetch_u8();
+                cpu.mem_read(ZERO_PAGE + base as u16); // dummy read
+                base.wrapping_add(cpu.register_y) as u16
+            }
+            AddressingMode::Absolute => cpu.fetch_u16(),
+            AddressingMode::Absolute_X | AddressingMode::Absolute_X_PageCross => {
+                let base = cpu.fetch_u16();
+                self.indexed(cpu, base, cpu.register_x)
+            }
+            AddressingMode::Absolute_Y | AddressingMode::Absolute_Y_PageCross => {
+                let base = cpu.fetch_u16();
+                self.indexed(cpu, base, cpu.register_y)
+            }
+            AddressingMode::Indirect_X => {
+                let base = cpu.fetch_u8();
+                cpu.mem_read(ZERO_PAGE + base as u16); // dummy read
+                let ptr = base.wrapping_add(cpu.register_x);
+                let lo = cpu.mem_read(ZERO_PAGE + ptr as u16);
+                let hi = cpu.mem_read(ZERO_PAGE + ptr.wrapping_add(1) as u16);
+                (hi as u16) << 8 | (lo as u16)
+            }
+            AddressingMode::Indirect_Y | AddressingMode::Indirect_Y_PageCross => {
+                let ptr = cpu.fetch_u8();
+                let lo = cpu.mem_read(ZERO_PAGE + ptr as u16);
+                let hi = cpu.mem_read(ZERO_PAGE + ptr.wrapping_add(1) as u16);
+                let base = (hi as u16) << 8 | (lo as u16);
+                self.indexed(cpu, base, cpu.register_y)
+            }
+            AddressingMode::ZeroPage_Indirect => {
+                let ptr = cpu.fetch_u8();
+                let lo = cpu.mem_read(ZERO_PAGE + ptr as u16);
+                let hi = cpu.mem_read(ZERO_PAGE + ptr.wrapping_add(1) as u16);
+                (hi as u16) << 8 | (lo as u16)
             }
+            // no operand: the byte after the opcode, which the second cycle reads and discards
+            AddressingMode::Accumulator | AddressingMode::NoneAddressing => cpu.program_counter,
         }
     }
 
-    pub fn read_u8<'a>(&self, cpu: &mut CPU) -> u8 {
-        if let AddressingMode::Accumulator = self {
-            return cpu.register_a;
+    fn indexed<B: CpuBus>(&self, cpu: &mut CPU<B>, base: u16, index: u8) -> u16 {
+        let addr = base.wrapping_add(index as u16);
+        if page_cross(base, addr) || !page_cross_mode(self) {
+            cpu.bus.next_read(ReadKind::Dummy);
+            cpu.mem_read((base & 0xFF00) | (addr & 0x00FF)); // dummy read
         }
+        addr
+    }
 
-        if let AddressingMode::Immediate = self {
-            return cpu.mem_read(cpu.program_counter);
+    pub fn read_u8<B: CpuBus>(&self, cpu: &mut CPU<B>) -> u8 {
+        if let AddressingMode::Accumulator = self {
+            cpu.dummy_read();
+            return cpu.register_a;
         }
 
-        let base = match self {
-            AddressingMode::Absolute
-            | AddressingMode::Absolute_X
-            | AddressingMode::Absolute_Y
-            | AddressingMode::Absolute_Y_PageCross
-            | AddressingMode::Absolute_X_PageCross => cpu.mem_read_u16(cpu.program_counter),
-            _ => cpu.mem_read(cpu.program_counter) as u16,
-        };
-
-        let (page_crossed, addr) = self.get_absolute_addr(cpu, base);
-        if page_crossed && page_cross_mode(self) {
-            cpu.bus.tick(1);
+        let addr = self.operand_addr(cpu);
+        match self {
+            AddressingMode::Immediate => cpu.bus.next_read(ReadKind::Code),
+            AddressingMode::Indirect_X
+            | AddressingMode::Indirect_Y
+            | AddressingMode::Indirect_Y_PageCross
+            | AddressingMode::ZeroPage_Indirect => cpu.bus.next_read(ReadKind::IndirectData),
+            _ => {}
         }
         cpu.mem_read(addr)
     }
 
-    pub fn write_u8(&self, cpu: &mut CPU, data: u8) {
-        if let AddressingMode::Accumulator = self {
-            cpu.set_register_a(data);
-            return;
-        }
-
-        let argument = match self {
-            AddressingMode::Absolute
-            | AddressingMode::Absolute_X
-            | AddressingMode::Absolute_Y
-            | AddressingMode::Absolute_Y_PageCross
-            | AddressingMode::Absolute_X_PageCross => cpu.mem_read_u16(cpu.program_counter),
-            _ => cpu.mem_read(cpu.program_counter) as u16,
-        };
-
-        let (_page_cross, addr) = self.get_absolute_addr(cpu, argument);
+    pub fn write_u8<B: CpuBus>(&self, cpu: &mut CPU<B>, data: u8) {
+        let addr = self.operand_addr(cpu);
         cpu.mem_write(addr, data);
     }
 }
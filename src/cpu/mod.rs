@@ -1,133 +1,149 @@
+use crate::bus::CpuBus;
 use crate::cpu::mem::AddressingMode;
+use crate::symbols::Symbols;
 use cpu::CPU;
-use std::collections::HashMap;
+use std::ops::RangeInclusive;
 
 pub mod cpu;
 pub mod mem;
 pub mod opscode;
+#[cfg(test)]
+mod single_step;
 
-lazy_static! {
-    pub static ref NON_READABLE_ADDR: Vec<u16> =
-        vec!(0x2001, 0x2002, 0x2003, 0x2004, 0x2005, 0x2006, 0x2007, 0x4016, 0x4017);
-}
+// APU isn't emulated yet, trace shows its registers as $FF (same as nestest.log)
+const APU_REGISTERS: RangeInclusive<u16> = 0x4000..=0x4015;
 
-pub fn trace(cpu: &mut CPU) -> String {
-    let ref opscodes: HashMap<u8, &'static opscode::OpsCode> = *opscode::OPSCODES_MAP;
-    let ref non_readable_addr = *NON_READABLE_ADDR;
+/// Memory is read through `CpuBus::peek`: tracing doesn't disturb PPU registers or joypads
+pub fn trace<B: CpuBus>(cpu: &CPU<B>) -> String {
+    trace_with_symbols(cpu, &Symbols::new())
+}
 
-    let code = cpu.mem_read(cpu.program_counter);
-    let ops = opscodes.get(&code).unwrap();
+/// Same as `trace`, with operand addresses replaced by their labels: `JSR UpdatePlayer`
+pub fn trace_with_symbols<B: CpuBus>(cpu: &CPU<B>, symbols: &Symbols) -> String {
+    let code = cpu.bus.peek(cpu.program_counter);
+    let ops = cpu.variant().opscodes()[code as usize];
 
     let begin = cpu.program_counter;
     let mut hex_dump = vec![];
     hex_dump.push(code);
 
-    let (mem_addr, stored_value) = match ops.mode {
-        AddressingMode::Immediate
-        | AddressingMode::NoneAddressing
-        | AddressingMode::Accumulator => (0, 0),
-        _ => {
-            let address = if ops.len == 2 {
-                cpu.mem_read(begin + 1) as u16
-            } else {
-                cpu.mem_read_u16(begin + 1)
-            };
-            let (_, addr) = ops.mode.get_absolute_addr(cpu, address);
-            if !non_readable_addr.contains(&addr) {
-                (addr, cpu.mem_read(addr))
-            } else {
-                (addr, 0)
-            }
-        }
+    let address = if ops.len == 2 {
+        cpu.bus.peek(begin.wrapping_add(1)) as u16
+    } else {
+        peek_u16(&cpu.bus, begin.wrapping_add(1))
+    };
+    let (mem_addr, stored_value) = match ops.mode.get_absolute_addr(cpu, address) {
+        None => (0, 0),
+        Some((_, addr)) if APU_REGISTERS.contains(&addr) => (addr, 0xff),
+        Some((_, addr)) => (addr, cpu.bus.peek(addr)),
     };
 
     let tmp = match ops.len {
         1 => match ops.mode {
-            AddressingMode::Accumulator => format!("A "),
+            AddressingMode::Accumulator => "A ".to_string(),
             _ => String::from(""),
         },
         2 => {
-            let address: u8 = cpu.mem_read(begin + 1);
-            // let value = cpu.mem_read(address));
+            let address: u8 = cpu.bus.peek(begin.wrapping_add(1));
+            // let value = cpu.bus.peek(address));
             hex_dump.push(address);
 
             match ops.mode {
-                AddressingMode::Immediate => format!("#${:02x}", address),
-                AddressingMode::ZeroPage => format!("${:02x} = {:02x}", mem_addr, stored_value),
+                AddressingMode::Immediate => format!("#${:02X}", address),
+                AddressingMode::ZeroPage => format!(
+                    "{} = {:02X}",
+                    operand(&cpu.bus, symbols, address as u16, 2),
+                    stored_value
+                ),
                 AddressingMode::ZeroPage_X => format!(
-                    "${:02x},X @ {:02x} = {:02x}",
-                    address, mem_addr, stored_value
+                    "{},X @ {:02X} = {:02X}",
+                    operand(&cpu.bus, symbols, address as u16, 2),
+                    mem_addr,
+                    stored_value
                 ),
                 AddressingMode::ZeroPage_Y => format!(
-                    "${:02x},Y @ {:02x} = {:02x}",
-                    address, mem_addr, stored_value
+                    "{},Y @ {:02X} = {:02X}",
+                    operand(&cpu.bus, symbols, address as u16, 2),
+                    mem_addr,
+                    stored_value
                 ),
                 AddressingMode::Indirect_X => format!(
-                    "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
-                    address,
+                    "({},X) @ {:02X} = {:04X} = {:02X}",
+                    operand(&cpu.bus, symbols, address as u16, 2),
                     (address.wrapping_add(cpu.register_x)),
                     mem_addr,
                     stored_value
                 ),
                 AddressingMode::Indirect_Y | AddressingMode::Indirect_Y_PageCross => format!(
-                    "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
-                    address,
+                    "({}),Y = {:04X} @ {:04X} = {:02X}",
+                    operand(&cpu.bus, symbols, address as u16, 2),
                     (mem_addr.wrapping_sub(cpu.register_y as u16)),
                     mem_addr,
                     stored_value
                 ),
+                AddressingMode::ZeroPage_Indirect => format!(
+                    "({}) = {:04X} = {:02X}",
+                    operand(&cpu.bus, symbols, address as u16, 2),
+                    mem_addr,
+                    stored_value
+                ),
                 AddressingMode::NoneAddressing => {
                     // assuming local jumps: BNE, BVS, etc.... todo: check ?
-                    let address: usize =
-                        (begin as usize + 2).wrapping_add((address as i8) as usize);
-                    format!("${:04x}", address)
+                    let address = begin.wrapping_add(2).wrapping_add((address as i8) as u16);
+                    operand(&cpu.bus, symbols, address, 4)
                 }
 
-                _ => panic!(
-                    "unexpected addressing mode {:?} has ops-len 2. code {:02x}",
-                    ops.mode, ops.code
-                ),
+                // no 2 byte instruction uses the other modes: show the raw operand
+                _ => format!("${:02X}", address),
             }
         }
         3 => {
-            let address_lo = cpu.mem_read(begin + 1);
-            let address_hi = cpu.mem_read(begin + 2);
+            let address_lo = cpu.bus.peek(begin.wrapping_add(1));
+            let address_hi = cpu.bus.peek(begin.wrapping_add(2));
             hex_dump.push(address_lo);
             hex_dump.push(address_hi);
 
-            let address = cpu.mem_read_u16(begin + 1);
-
             match ops.mode {
                 AddressingMode::NoneAddressing => {
                     if ops.code == 0x6c {
                         //jmp indirect
                         let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
+                            let lo = cpu.bus.peek(address);
+                            let hi = cpu.bus.peek(address & 0xFF00);
                             (hi as u16) << 8 | (lo as u16)
                         } else {
-                            cpu.mem_read_u16(address)
+                            peek_u16(&cpu.bus, address)
                         };
 
-                        // let jmp_addr = cpu.mem_read_u16(address);
-                        format!("(${:04x}) = {:04x}", address, jmp_addr)
+                        // let jmp_addr = peek_u16(&cpu.bus, address);
+                        format!(
+                            "({}) = {:04X}",
+                            operand(&cpu.bus, symbols, address, 4),
+                            jmp_addr
+                        )
                     } else {
-                        format!("${:04x}", address)
+                        operand(&cpu.bus, symbols, address, 4)
                     }
                 }
-                AddressingMode::Absolute => format!("${:04x} = {:02x}", mem_addr, stored_value),
+                AddressingMode::Absolute => format!(
+                    "{} = {:02X}",
+                    operand(&cpu.bus, symbols, mem_addr, 4),
+                    stored_value
+                ),
                 AddressingMode::Absolute_X | AddressingMode::Absolute_X_PageCross => format!(
-                    "${:04x},X @ {:04x} = {:02x}",
-                    address, mem_addr, stored_value
+                    "{},X @ {:04X} = {:02X}",
+                    operand(&cpu.bus, symbols, address, 4),
+                    mem_addr,
+                    stored_value
                 ),
                 AddressingMode::Absolute_Y | AddressingMode::Absolute_Y_PageCross => format!(
-                    "${:04x},Y @ {:04x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                _ => panic!(
-                    "unexpected addressing mode {:?} has ops-len 3. code {:02x}",
-                    ops.mode, ops.code
+                    "{},Y @ {:04X} = {:02X}",
+                    operand(&cpu.bus, symbols, address, 4),
+                    mem_addr,
+                    stored_value
                 ),
+                // no 3 byte instruction uses the other modes: show the raw operand
+                _ => format!("${:04X}", address),
             }
         }
         _ => String::from(""),
@@ -135,17 +151,18 @@ pub fn trace(cpu: &mut CPU) -> String {
 
     let hex_str = hex_dump
         .iter()
-        .map(|z| format!("{:02x}", z))
+        .map(|z| format!("{:02X}", z))
         .collect::<Vec<String>>()
         .join(" ");
-    let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ops.mnemonic, tmp)
+    let asm_str = format!("{:04X}  {:8} {: >4} {}", begin, hex_str, ops.mnemonic, tmp)
         .trim()
         .to_string();
 
     let bus_trace = cpu.bus.trace();
+    // labels keep their case, everything else is upper case (same as nestest.log)
     format!(
         // "{:47} A:{:02x} X:{:02x} Y:{:02x} SP:{:02x} FL:{:08b}",
-        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x} PPU:{:3},{:3} CYC:{}",
+        "{:47} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:3},{:3} CYC:{}",
         // "{:30}(a:{:x}, x:{:x}, y:{:x}, sp:{:x}, fl:{:x})",
         asm_str,
         cpu.register_a,
@@ -153,17 +170,29 @@ pub fn trace(cpu: &mut CPU) -> String {
         cpu.register_y,
         cpu.flags,
         cpu.stack_pointer,
-        bus_trace.ppu_cycles,
         bus_trace.ppu_scanline,
+        bus_trace.ppu_cycles,
         bus_trace.cpu_cycles
     )
-    .to_ascii_uppercase()
+}
+
+fn peek_u16<B: CpuBus>(bus: &B, pos: u16) -> u16 {
+    u16::from_le_bytes([bus.peek(pos), bus.peek(pos.wrapping_add(1))])
+}
+
+/// Label of an operand address, or the address itself
+fn operand<B: CpuBus>(bus: &B, symbols: &Symbols, addr: u16, digits: usize) -> String {
+    match symbols.label(addr, bus.prg_rom_offset(addr)) {
+        Some(name) => name.to_string(),
+        None => format!("${:0digits$X}", addr, digits = digits),
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::bus::MockBus;
+    use crate::cpu::cpu::CpuVariant;
 
     #[test]
     fn test_format_trace() {
@@ -172,14 +201,18 @@ mod test {
         mem.space[101] = 0x01;
         mem.space[102] = 0xca;
         mem.space[103] = 0x88;
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.program_counter = 0x64;
         cpu.register_a = 1;
         cpu.register_x = 2;
         cpu.register_y = 3;
         let mut result: Vec<String> = vec![];
-        cpu.interpret_fn(0x64 + 4, |cpu| {
+        cpu.run_until(|cpu| {
+            if cpu.program_counter >= 0x64 + 4 {
+                return true;
+            }
             result.push(trace(cpu));
+            false
         });
         assert_eq!(
             "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD PPU:  0,  0 CYC:0",
@@ -206,16 +239,140 @@ mod test {
         mem.space[0x33] = 00;
         mem.space[0x34] = 04;
         mem.space[0x400] = 0xAA;
-        let mut cpu = CPU::new(Box::from(mem));
+        let mut cpu = CPU::new(mem);
         cpu.program_counter = 0x64;
         cpu.register_y = 0;
         let mut result: Vec<String> = vec![];
-        cpu.interpret_fn(0x64 + 2, |cpu| {
+        cpu.run_until(|cpu| {
+            if cpu.program_counter >= 0x64 + 2 {
+                return true;
+            }
             result.push(trace(cpu));
+            false
         });
         assert_eq!(
             "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
             result[0]
         );
     }
+
+    #[test]
+    fn test_trace_any_byte() {
+        let variants = [
+            CpuVariant::Ricoh2A03,
+            CpuVariant::Nmos6502,
+            CpuVariant::Wdc65C02,
+        ];
+        for variant in variants.iter() {
+            let mut cpu = CPU::with_variant(MockBus::new(), *variant);
+            // operands wrap around the end of the address space
+            cpu.bus.poke(0x0000, 0x34);
+            cpu.bus.poke(0x0001, 0x12);
+            for code in 0..=255u8 {
+                for pc in [0xfffe, 0xffff].iter() {
+                    cpu.bus.poke(*pc, code);
+                    cpu.program_counter = *pc;
+                    trace(&cpu);
+                }
+            }
+            cpu.bus.poke(0xffff, 0xad);
+            assert!(trace(&cpu).starts_with("FFFF  AD 34 12  LDA $1234 = 00 "));
+        }
+    }
+
+    #[test]
+    fn test_format_with_symbols() {
+        let mut mem = MockBus::new();
+        // JSR $0300; LDA $40; LDA $0400,X; BNE $0064
+        mem.space[0x64..0x64 + 10]
+            .copy_from_slice(&[0x20, 0x00, 0x03, 0xa5, 0x40, 0xbd, 0x00, 0x04, 0xd0, 0xf6]);
+        mem.space[0x300] = 0x60;
+        mem.space[0x40] = 0x12;
+        let mut symbols = Symbols::new();
+        symbols
+            .add_nl(
+                "$0300#UpdatePlayer#\n$0040#PlayerX#\n$0400#Table#\n$0064#Main#",
+                None,
+            )
+            .unwrap();
+
+        let mut cpu = CPU::new(mem);
+        cpu.program_counter = 0x64;
+        let mut result: Vec<String> = vec![];
+        cpu.run_until(|cpu| {
+            if result.len() == 5 {
+                return true;
+            }
+            result.push(trace_with_symbols(cpu, &symbols));
+            false
+        });
+        assert_eq!(
+            "0064  20 00 03  JSR UpdatePlayer                A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
+            result[0]
+        );
+        assert_eq!(
+            "0067  A5 40     LDA PlayerX = 12                A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:12",
+            result[2]
+        );
+        assert_eq!(
+            "0069  BD 00 04  LDA Table,X @ 0400 = 00         A:12 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:15",
+            result[3]
+        );
+        assert_eq!(
+            "006C  D0 F6     BNE Main                        A:00 X:00 Y:00 P:26 SP:FD PPU:  0,  0 CYC:19",
+            result[4]
+        );
+    }
+
+    #[test]
+    fn test_format_with_bank_aware_symbols() {
+        use crate::bus::Bus;
+        use crate::input;
+        use crate::ppu::ppu::NesPPU;
+        use crate::rom::Rom;
+
+        let data = std::fs::read("test_rom/nestest.nes").unwrap();
+        let bus = Bus::<NesPPU>::new(
+            Rom::load(&data).unwrap(),
+            |_: &NesPPU, _: &mut input::Joypad| {},
+        );
+        let mut cpu = CPU::new(bus);
+        cpu.power_on();
+        cpu.program_counter = 0xc000;
+        let mut symbols = Symbols::new();
+        // 16K of PRG ROM is mirrored: $C5F5 is at offset $05F5
+        symbols.add_mlb("P:05F5:Main").unwrap();
+
+        assert!(trace_with_symbols(&cpu, &symbols).starts_with("C000  4C F5 C5  JMP Main "));
+    }
+
+    #[test]
+    fn test_trace_has_no_side_effects_on_ppu_registers() {
+        use crate::bus::Bus;
+        use crate::input;
+        use crate::ppu::ppu::NesPPU;
+        use crate::rom::Rom;
+
+        let data = std::fs::read("test_rom/nestest.nes").unwrap();
+        let bus = Bus::<NesPPU>::new(
+            Rom::load(&data).unwrap(),
+            |_: &NesPPU, _: &mut input::Joypad| {},
+        );
+        let mut cpu = CPU::new(bus);
+        cpu.power_on();
+        // JMP $0300 until vblank, then LDA $2002
+        for (i, byte) in [0x4c, 0x00, 0x03, 0xad, 0x02, 0x20].iter().enumerate() {
+            cpu.bus.poke(0x0300 + i as u16, *byte);
+        }
+        cpu.program_counter = 0x0300;
+        cpu.run_until(|cpu| cpu.bus.trace().ppu_scanline == 241);
+        cpu.program_counter = 0x0303;
+
+        let line = trace(&cpu);
+        assert!(line.starts_with("0303  AD 02 20  LDA $2002 = 80 "), "{}", line);
+        assert_eq!(trace(&cpu), line);
+        cpu.step();
+        assert_eq!(cpu.register_a & 0x80, 0x80);
+        assert_eq!(cpu.bus.peek(0x2002) & 0x80, 0);
+    }
 }
@@ -4,6 +4,7 @@
 use crate::cpu::mem::AddressingMode;
 use std::collections::HashMap;
 
+#[derive(Debug)]
 pub struct OpsCode {
     pub code: u8,
     pub mnemonic: &'static str,
@@ -210,18 +211,18 @@ lazy_static! {
         OpsCode::new(0xe3, "*ISB", 2,8, AddressingMode::Indirect_X),
         OpsCode::new(0xf3, "*ISB", 2,8, AddressingMode::Indirect_Y),
 
-        OpsCode::new(0x02, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpsCode::new(0x12, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpsCode::new(0x22, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpsCode::new(0x32, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpsCode::new(0x42, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpsCode::new(0x52, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpsCode::new(0x62, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpsCode::new(0x72, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpsCode::new(0x92, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpsCode::new(0xb2, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpsCode::new(0xd2, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpsCode::new(0xf2, "*NOP", 1,2, AddressingMode::NoneAddressing),
+        OpsCode::new(0x02, "*KIL", 1,2, AddressingMode::NoneAddressing),
+        OpsCode::new(0x12, "*KIL", 1,2, AddressingMode::NoneAddressing),
+        OpsCode::new(0x22, "*KIL", 1,2, AddressingMode::NoneAddressing),
+        OpsCode::new(0x32, "*KIL", 1,2, AddressingMode::NoneAddressing),
+        OpsCode::new(0x42, "*KIL", 1,2, AddressingMode::NoneAddressing),
+        OpsCode::new(0x52, "*KIL", 1,2, AddressingMode::NoneAddressing),
+        OpsCode::new(0x62, "*KIL", 1,2, AddressingMode::NoneAddressing),
+        OpsCode::new(0x72, "*KIL", 1,2, AddressingMode::NoneAddressing),
+        OpsCode::new(0x92, "*KIL", 1,2, AddressingMode::NoneAddressing),
+        OpsCode::new(0xb2, "*KIL", 1,2, AddressingMode::NoneAddressing),
+        OpsCode::new(0xd2, "*KIL", 1,2, AddressingMode::NoneAddressing),
+        OpsCode::new(0xf2, "*KIL", 1,2, AddressingMode::NoneAddressing),
 
         OpsCode::new(0x1a, "*NOP", 1,2, AddressingMode::NoneAddressing),
         OpsCode::new(0x3a, "*NOP", 1,2, AddressingMode::NoneAddressing),
@@ -231,15 +232,15 @@ lazy_static! {
         // OpsCode::new(0xea, "NOP", 1,2, AddressingMode::NoneAddressing),
         OpsCode::new(0xfa, "*NOP", 1,2, AddressingMode::NoneAddressing),
 
-        OpsCode::new(0xab, "*LXA", 2, 3, AddressingMode::Immediate), //todo: highly unstable and not used
+        OpsCode::new(0xab, "*LXA", 2, 2, AddressingMode::Immediate), //todo: highly unstable and not used
         //http://visual6502.org/wiki/index.php?title=6502_Opcode_8B_%28XAA,_ANE%29
-        OpsCode::new(0x8b, "*XAA", 2, 3, AddressingMode::Immediate), //todo: highly unstable and not used
-        OpsCode::new(0xbb, "*LAS", 3, 2, AddressingMode::Absolute_Y), //todo: highly unstable and not used
-        OpsCode::new(0x9b, "*TAS", 3, 2, AddressingMode::Absolute_Y), //todo: highly unstable and not used
-        OpsCode::new(0x93, "*AHX", 2, /* guess */ 8, AddressingMode::Indirect_Y), //todo: highly unstable and not used
-        OpsCode::new(0x9f, "*AHX", 3, /* guess */ 4/* or 5*/, AddressingMode::Absolute_Y_PageCross), //todo: highly unstable and not used
-        OpsCode::new(0x9e, "*SHX", 3, /* guess */ 4/* or 5*/, AddressingMode::Absolute_Y_PageCross), //todo: highly unstable and not used
-        OpsCode::new(0x9c, "*SHY", 3, /* guess */ 4/* or 5*/, AddressingMode::Absolute_X_PageCross), //todo: highly unstable and not used
+        OpsCode::new(0x8b, "*XAA", 2, 2, AddressingMode::Immediate), //todo: highly unstable and not used
+        OpsCode::new(0xbb, "*LAS", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y_PageCross), //todo: highly unstable and not used
+        OpsCode::new(0x9b, "*TAS", 3, 5, AddressingMode::Absolute_Y), //todo: highly unstable and not used
+        OpsCode::new(0x93, "*AHX", 2, 6, AddressingMode::Indirect_Y), //todo: highly unstable and not used
+        OpsCode::new(0x9f, "*AHX", 3, 5, AddressingMode::Absolute_Y), //todo: highly unstable and not used
+        OpsCode::new(0x9e, "*SHX", 3, 5, AddressingMode::Absolute_Y), //todo: highly unstable and not used
+        OpsCode::new(0x9c, "*SHY", 3, 5, AddressingMode::Absolute_X), //todo: highly unstable and not used
 
         OpsCode::new(0xa7, "*LAX", 2, 3, AddressingMode::ZeroPage),
         OpsCode::new(0xb7, "*LAX", 2, 4, AddressingMode::ZeroPage_Y),
@@ -361,6 +362,149 @@ lazy_static! {
        OpsCode::new(0x28, "PLP", 1, 4, AddressingMode::NoneAddressing),
    ];
 
+   /// 65C02 (WDC): official NMOS instructions with a few timing fixes, new instructions and addressing mode.
+   /// Opcodes that are undocumented on NMOS are either new instructions or NOPs, there is no KIL.
+   /// http://www.6502.org/tutorials/65c02opcodes.html
+   pub static ref CPU_65C02_OPS_CODES: Vec<OpsCode> = {
+       let mut codes: Vec<OpsCode> = CPU_OPS_CODES
+           .iter()
+           .filter(|op| !op.mnemonic.starts_with('*'))
+           .map(|op| match op.code {
+               // no page wrap bug anymore, fixing it costs a cycle
+               0x6c => OpsCode::new(op.code, op.mnemonic, 3, 6, AddressingMode::NoneAddressing),
+               // shifts and rotates skip the dummy read unless the page is crossed
+               0x1e | 0x3e | 0x5e | 0x7e => OpsCode::new(op.code, op.mnemonic, 3, 6 /*+1 if page crossed*/, AddressingMode::Absolute_X_PageCross),
+               _ => OpsCode::new(op.code, op.mnemonic, op.len, op.cycles, op.mode),
+           })
+           .collect();
+
+       codes.extend(vec![
+       /* New instructions */
+       OpsCode::new(0x80, "BRA", 2, 3 /*+1 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0xda, "PHX", 1, 3, AddressingMode::NoneAddressing),
+       OpsCode::new(0xfa, "PLX", 1, 4, AddressingMode::NoneAddressing),
+       OpsCode::new(0x5a, "PHY", 1, 3, AddressingMode::NoneAddressing),
+       OpsCode::new(0x7a, "PLY", 1, 4, AddressingMode::NoneAddressing),
+
+       OpsCode::new(0x64, "STZ", 2, 3, AddressingMode::ZeroPage),
+       OpsCode::new(0x74, "STZ", 2, 4, AddressingMode::ZeroPage_X),
+       OpsCode::new(0x9c, "STZ", 3, 4, AddressingMode::Absolute),
+       OpsCode::new(0x9e, "STZ", 3, 5, AddressingMode::Absolute_X),
+
+       OpsCode::new(0x14, "TRB", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0x1c, "TRB", 3, 6, AddressingMode::Absolute),
+       OpsCode::new(0x04, "TSB", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0x0c, "TSB", 3, 6, AddressingMode::Absolute),
+
+       OpsCode::new(0x1a, "INC", 1, 2, AddressingMode::Accumulator),
+       OpsCode::new(0x3a, "DEC", 1, 2, AddressingMode::Accumulator),
+       OpsCode::new(0x89, "BIT", 2, 2, AddressingMode::Immediate),
+       OpsCode::new(0x34, "BIT", 2, 4, AddressingMode::ZeroPage_X),
+       OpsCode::new(0x3c, "BIT", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X_PageCross),
+       OpsCode::new(0x7c, "JMP", 3, 6, AddressingMode::NoneAddressing),
+
+       /* (zp) addressing */
+       OpsCode::new(0x12, "ORA", 2, 5, AddressingMode::ZeroPage_Indirect),
+       OpsCode::new(0x32, "AND", 2, 5, AddressingMode::ZeroPage_Indirect),
+       OpsCode::new(0x52, "EOR", 2, 5, AddressingMode::ZeroPage_Indirect),
+       OpsCode::new(0x72, "ADC", 2, 5, AddressingMode::ZeroPage_Indirect),
+       OpsCode::new(0x92, "STA", 2, 5, AddressingMode::ZeroPage_Indirect),
+       OpsCode::new(0xb2, "LDA", 2, 5, AddressingMode::ZeroPage_Indirect),
+       OpsCode::new(0xd2, "CMP", 2, 5, AddressingMode::ZeroPage_Indirect),
+       OpsCode::new(0xf2, "SBC", 2, 5, AddressingMode::ZeroPage_Indirect),
+
+       /* Bit manipulation (Rockwell and WDC) */
+       OpsCode::new(0x07, "RMB0", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0x17, "RMB1", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0x27, "RMB2", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0x37, "RMB3", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0x47, "RMB4", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0x57, "RMB5", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0x67, "RMB6", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0x77, "RMB7", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0x87, "SMB0", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0x97, "SMB1", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0xa7, "SMB2", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0xb7, "SMB3", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0xc7, "SMB4", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0xd7, "SMB5", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0xe7, "SMB6", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0xf7, "SMB7", 2, 5, AddressingMode::ZeroPage),
+       OpsCode::new(0x0f, "BBR0", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0x1f, "BBR1", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0x2f, "BBR2", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0x3f, "BBR3", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0x4f, "BBR4", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0x5f, "BBR5", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0x6f, "BBR6", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0x7f, "BBR7", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0x8f, "BBS0", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0x9f, "BBS1", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0xaf, "BBS2", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0xbf, "BBS3", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0xcf, "BBS4", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0xdf, "BBS5", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0xef, "BBS6", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+       OpsCode::new(0xff, "BBS7", 3, 5 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
+
+       OpsCode::new(0xcb, "WAI", 1, 3, AddressingMode::NoneAddressing),
+       OpsCode::new(0xdb, "STP", 1, 3, AddressingMode::NoneAddressing),
+
+       /* Undefined opcodes are NOPs of various sizes */
+       OpsCode::new(0x03, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x13, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x23, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x33, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x43, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x53, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x63, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x73, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x83, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x93, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0xa3, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0xb3, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0xc3, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0xd3, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0xe3, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0xf3, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x0b, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x1b, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x2b, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x3b, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x4b, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x5b, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x6b, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x7b, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x8b, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x9b, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0xab, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0xbb, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0xeb, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0xfb, "*NOP", 1, 1, AddressingMode::NoneAddressing),
+       OpsCode::new(0x02, "*NOP", 2, 2, AddressingMode::Immediate),
+       OpsCode::new(0x22, "*NOP", 2, 2, AddressingMode::Immediate),
+       OpsCode::new(0x42, "*NOP", 2, 2, AddressingMode::Immediate),
+       OpsCode::new(0x62, "*NOP", 2, 2, AddressingMode::Immediate),
+       OpsCode::new(0x82, "*NOP", 2, 2, AddressingMode::Immediate),
+       OpsCode::new(0xc2, "*NOP", 2, 2, AddressingMode::Immediate),
+       OpsCode::new(0xe2, "*NOP", 2, 2, AddressingMode::Immediate),
+       OpsCode::new(0x44, "*NOP", 2, 3, AddressingMode::ZeroPage),
+       OpsCode::new(0x54, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
+       OpsCode::new(0xd4, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
+       OpsCode::new(0xf4, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
+       OpsCode::new(0x5c, "*NOP", 3, 8, AddressingMode::NoneAddressing),
+       OpsCode::new(0xdc, "*NOP", 3, 4, AddressingMode::Absolute),
+       OpsCode::new(0xfc, "*NOP", 3, 4, AddressingMode::Absolute),
+       ]);
+       codes
+   };
+
+   /// Opcode tables indexed by the opcode itself, this is what the CPU dispatches through.
+   /// 2A03 is an NMOS 6502 with decimal mode cut out: the instruction set is the same.
+   pub static ref OPSCODES_2A03: [&'static OpsCode; 256] = opscode_table(&CPU_OPS_CODES);
+   pub static ref OPSCODES_6502: [&'static OpsCode; 256] = opscode_table(&CPU_OPS_CODES);
+   pub static ref OPSCODES_65C02: [&'static OpsCode; 256] = opscode_table(&CPU_65C02_OPS_CODES);
+
    pub static ref OPSCODES_MAP: HashMap<u8, &'static OpsCode> = {
        let mut map = HashMap::new();
        for cpuop in &*CPU_OPS_CODES {
@@ -370,3 +514,16 @@ lazy_static! {
    };
 
 }
+
+fn opscode_table(codes: &'static [OpsCode]) -> [&'static OpsCode; 256] {
+    let mut table: [Option<&'static OpsCode>; 256] = [None; 256];
+    for cpuop in codes {
+        assert!(table[cpuop.code as usize].is_none(), "opcode {:02x} is defined twice", cpuop.code);
+        table[cpuop.code as usize] = Some(cpuop);
+    }
+    let mut result = [&codes[0]; 256];
+    for (code, cpuop) in table.iter().enumerate() {
+        result[code] = cpuop.unwrap_or_else(|| panic!("opcode {:02x} is missing", code));
+    }
+    result
+}
@@ -0,0 +1,266 @@
+// Single step CPU tests: https://github.com/TomHarte/ProcessorTests/tree/main/nes6502
+//
+// Every opcode has a `<opcode>.json` file with a list of test cases:
+// the initial state, the state after executing exactly one instruction,
+// and every bus access made while executing it (one per cycle).
+//
+// Two sets run with every `cargo test`, both checked in under `test_rom/single_step`:
+// a handful of hand written cases, and 20 cases for each of the 256 opcodes in `generated/`,
+// written by `generate.py` from its own model of the 2A03 with the conventions of the suite.
+// The full suite (10000 cases for each opcode) is not checked in:
+// `test_rom/single_step/fetch.sh` checks out its `nes6502/v1` directory, then
+//   cargo test single_step -- --ignored --nocapture
+// prints pass/fail for every opcode. SINGLE_STEP_TESTS points to another copy of the directory.
+use crate::bus::BusTrace;
+use crate::bus::CpuBus;
+use crate::bus::MockBus;
+use crate::cpu::cpu::CpuFlags;
+use crate::cpu::cpu::CPU;
+use crate::cpu::mem::Mem;
+use crate::cpu::opscode;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Deserialize)]
+struct TestCase {
+    name: String,
+    initial: CpuState,
+    #[serde(rename = "final")]
+    expected: CpuState,
+    cycles: Vec<(u16, u8, String)>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+struct CpuState {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+/// MockBus that records the access made in every cycle.
+/// Accesses that are not followed by a tick (i.e. debugging reads) are not bus activity.
+struct RecordingBus {
+    mem: MockBus,
+    last_access: Option<(u16, u8, String)>,
+    accesses: Vec<(u16, u8, String)>,
+}
+
+impl Mem for RecordingBus {
+    fn write(&mut self, pos: u16, data: u8) {
+        self.last_access = Some((pos, data, "write".to_string()));
+        self.mem.write(pos, data);
+    }
+
+    fn read(&mut self, pos: u16) -> u8 {
+        let data = self.mem.read(pos);
+        self.last_access = Some((pos, data, "read".to_string()));
+        data
+    }
+}
+
+impl CpuBus for RecordingBus {
+    fn poll_nmi_status(&mut self) -> Option<u8> {
+        self.mem.poll_nmi_status()
+    }
+
+    fn poll_irq_status(&self) -> bool {
+        self.mem.poll_irq_status()
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.accesses.extend(self.last_access.take());
+        self.mem.tick(cycles)
+    }
+
+    fn trace(&self) -> BusTrace {
+        self.mem.trace()
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.mem.peek(addr)
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        self.mem.poke(addr, data)
+    }
+}
+
+fn run(mut bus: RecordingBus, case: &TestCase) -> (RecordingBus, Result<(), String>) {
+    bus.accesses.clear();
+    for (addr, data) in &case.initial.ram {
+        bus.mem.space[*addr as usize] = *data;
+    }
+
+    let mut cpu = CPU::new(bus);
+    cpu.program_counter = case.initial.pc;
+    cpu.stack_pointer = case.initial.s;
+    cpu.register_a = case.initial.a;
+    cpu.register_x = case.initial.x;
+    cpu.register_y = case.initial.y;
+    cpu.flags = CpuFlags::from_bits_truncate(case.initial.p);
+
+    let cycles = cpu.step();
+
+    let actual = CpuState {
+        pc: cpu.program_counter,
+        s: cpu.stack_pointer,
+        a: cpu.register_a,
+        x: cpu.register_x,
+        y: cpu.register_y,
+        p: cpu.flags.bits(),
+        ram: case
+            .expected
+            .ram
+            .iter()
+            .map(|(addr, _)| (*addr, cpu.bus.mem.space[*addr as usize]))
+            .collect(),
+    };
+    let mut bus = cpu.bus;
+
+    let result = if actual != case.expected {
+        Err(format!(
+            "{}: expected {:?}, got {:?}",
+            case.name, case.expected, actual
+        ))
+    } else if bus.accesses != case.cycles || cycles != case.cycles.len() {
+        Err(format!(
+            "{}: expected {} cycles {:?}, got {} cycles {:?}",
+            case.name,
+            case.cycles.len(),
+            case.cycles,
+            cycles,
+            bus.accesses
+        ))
+    } else {
+        Ok(())
+    };
+
+    for (addr, _) in case.expected.ram.iter().chain(case.initial.ram.iter()) {
+        bus.mem.space[*addr as usize] = 0;
+    }
+    (bus, result)
+}
+
+enum Outcome {
+    NoVectors,
+    Passed(usize),
+    Failed(usize, usize, String),
+}
+
+fn run_opcode(dir: &Path, code: u8) -> Outcome {
+    let path = dir.join(format!("{:02x}.json", code));
+    let json = match fs::read_to_string(&path) {
+        Ok(json) => json,
+        Err(_) => return Outcome::NoVectors,
+    };
+    let cases: Vec<TestCase> = serde_json::from_str(&json)
+        .unwrap_or_else(|e| panic!("failed to parse {}: {}", path.display(), e));
+
+    let mut bus = RecordingBus {
+        mem: MockBus::new(),
+        last_access: None,
+        accesses: Vec::new(),
+    };
+
+    let mut first_error = None;
+    let mut failed = 0;
+    for case in cases.iter() {
+        let (used, result) = run(bus, case);
+        bus = used;
+        if let Err(e) = result {
+            failed += 1;
+            first_error.get_or_insert(e);
+        }
+    }
+    match first_error {
+        None => Outcome::Passed(cases.len()),
+        Some(e) => Outcome::Failed(failed, cases.len(), e),
+    }
+}
+
+/// Runs the vectors of `codes` found in `dir`, printing a line per opcode.
+/// Returns the opcodes without vectors and the first failure of every failing opcode.
+fn run_opcodes(dir: &Path, codes: &[u8]) -> (Vec<u8>, Vec<String>) {
+    let mut missing = vec![];
+    let mut failures = vec![];
+    for &code in codes {
+        let mnemonic = opscode::OPSCODES_MAP[&code].mnemonic;
+        match run_opcode(dir, code) {
+            Outcome::NoVectors => {
+                println!("{:02x} {:>4}: no vectors", code, mnemonic);
+                missing.push(code);
+            }
+            Outcome::Passed(total) => println!("{:02x} {:>4}: passed {}", code, mnemonic, total),
+            Outcome::Failed(failed, total, e) => {
+                println!("{:02x} {:>4}: FAILED {}/{}", code, mnemonic, failed, total);
+                failures.push(e);
+            }
+        }
+    }
+    println!(
+        "{} opcodes: {} passed, {} failed, {} without vectors",
+        codes.len(),
+        codes.len() - failures.len() - missing.len(),
+        failures.len(),
+        missing.len()
+    );
+    (missing, failures)
+}
+
+fn vectors_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("test_rom/single_step")
+}
+
+/// The hand written cases: a few opcodes, a few cases each. They are no substitute for the suite.
+#[test]
+fn test_checked_in_vectors() {
+    let codes: Vec<u8> = (0..=255u8)
+        .filter(|code| vectors_dir().join(format!("{:02x}.json", code)).exists())
+        .collect();
+    assert!(!codes.is_empty(), "no vectors in {}", vectors_dir().display());
+
+    let (_, failures) = run_opcodes(&vectors_dir(), &codes);
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+/// Every entry of OPSCODES_MAP against the vectors in `dir`: opcodes without vectors fail the test
+fn test_all_opcodes(dir: &Path) {
+    let mut codes: Vec<u8> = opscode::OPSCODES_MAP.keys().cloned().collect();
+    codes.sort_unstable();
+    assert_eq!(codes.len(), 256);
+
+    let (missing, failures) = run_opcodes(dir, &codes);
+    assert!(
+        missing.is_empty(),
+        "no vectors in {} for {}",
+        dir.display(),
+        missing
+            .iter()
+            .map(|code| format!("{:02x}", code))
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+/// The vectors of test_rom/single_step/generate.py, a few random cases for each of the 256 opcodes
+#[test]
+fn test_generated_vectors() {
+    test_all_opcodes(&vectors_dir().join("generated"));
+}
+
+/// The full upstream suite
+#[test]
+#[ignore = "needs the nes6502 vectors: run test_rom/single_step/fetch.sh first"]
+fn test_single_step_vectors() {
+    let dir = std::env::var("SINGLE_STEP_TESTS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| vectors_dir().join("ProcessorTests/nes6502/v1"));
+    test_all_opcodes(&dir);
+}
@@ -0,0 +1,232 @@
+// Shadow call stack, rebuilt from the instructions the CPU executes.
+// A frame is pushed when JSR moves SP down by the two bytes of its return address, or when
+// the CPU goes through an interrupt sequence (NMI, IRQ, BRK), and dropped as soon as SP climbs back
+// above the return address: RTS, RTI, or code that pops the address by hand.
+use crate::bus::CpuBus;
+use crate::cpu::cpu::{InterruptType, CPU};
+
+const BRK: u8 = 0x00;
+const JSR: u8 = 0x20;
+const RTI: u8 = 0x40;
+const RTS: u8 = 0x60;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Frame {
+    /// Address of the JSR or BRK instruction, or of the instruction an NMI or IRQ came before
+    pub call_site: u16,
+    /// Subroutine entry point or interrupt handler
+    pub target: u16,
+    /// Set if the frame was entered through an interrupt sequence
+    pub interrupt: Option<InterruptType>,
+    /// SP before the return address was pushed
+    return_sp: u8,
+}
+
+#[derive(Debug, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    /// PC, opcode and SP of the previous instruction
+    last: Option<(u16, u8, u8)>,
+    last_cycles: Option<usize>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        CallStack::default()
+    }
+
+    /// Must be called at every instruction boundary. Calling it twice at the same one is harmless.
+    pub fn update<B: CpuBus>(&mut self, cpu: &CPU<B>) {
+        if self.last_cycles == Some(cpu.cycles()) {
+            return;
+        }
+        self.last_cycles = Some(cpu.cycles());
+        if let (Some((kind, handler)), Some((last_pc, last_opcode, last_sp))) =
+            (cpu.serviced_interrupt(), self.last)
+        {
+            self.frames.push(Frame {
+                call_site: last_pc,
+                target: handler,
+                interrupt: Some(kind),
+                return_sp: last_sp,
+            });
+            if last_opcode != BRK {
+                // NMI and IRQ come before the instruction: it was the handler's first one
+                self.last = Some((handler, cpu.bus.peek(handler), last_sp.wrapping_sub(3)));
+            }
+        }
+        let pc = cpu.program_counter;
+        self.record(pc, cpu.registers().sp, cpu.bus.peek(pc));
+    }
+
+    /// Registers and opcode of the instruction about to execute
+    fn record(&mut self, pc: u16, sp: u8, opcode: u8) {
+        if let Some((last_pc, JSR, last_sp)) = self.last {
+            if sp == last_sp.wrapping_sub(2) {
+                self.frames.push(Frame {
+                    call_site: last_pc,
+                    target: pc,
+                    interrupt: None,
+                    return_sp: last_sp,
+                });
+            }
+        }
+        while let Some(frame) = self.frames.last() {
+            if sp < frame.return_sp {
+                break;
+            }
+            self.frames.pop();
+        }
+        self.last = Some((pc, opcode, sp));
+    }
+
+    /// Innermost call last
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Whether the instruction executed last was RTS or RTI
+    pub fn returned(&self) -> bool {
+        match self.last {
+            Some((_, opcode, _)) => opcode == RTS || opcode == RTI,
+            None => false,
+        }
+    }
+
+    /// One line per frame, innermost first: `#0  $c125 in $c120 <UpdatePlayer>`.
+    /// `pc` is the current instruction, `name` formats an address.
+    pub fn backtrace(&self, pc: u16, name: &dyn Fn(u16) -> String) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut pc = pc;
+        for frame in self.frames.iter().rev() {
+            let kind = match frame.interrupt {
+                Some(kind) => format!(" [{:?}]", kind),
+                None => String::new(),
+            };
+            lines.push(format!(
+                "#{:<2} ${:04x} in {}{}",
+                lines.len(),
+                pc,
+                name(frame.target),
+                kind
+            ));
+            pc = frame.call_site;
+        }
+        lines.push(format!("#{:<2} ${:04x}", lines.len(), pc));
+        lines
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.last = None;
+        self.last_cycles = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::MockBus;
+    use crate::cpu::cpu::transform;
+
+    #[test]
+    fn test_jsr_and_rts() {
+        let mut stack = CallStack::new();
+        stack.record(0x8000, 0xfd, JSR);
+        stack.record(0x9000, 0xfb, JSR);
+        stack.record(0xa000, 0xf9, RTS);
+        assert_eq!(
+            stack.frames(),
+            &[
+                Frame {
+                    call_site: 0x8000,
+                    target: 0x9000,
+                    interrupt: None,
+                    return_sp: 0xfd
+                },
+                Frame {
+                    call_site: 0x9000,
+                    target: 0xa000,
+                    interrupt: None,
+                    return_sp: 0xfb
+                },
+            ]
+        );
+        stack.record(0x9003, 0xfb, RTS);
+        assert!(stack.returned());
+        assert_eq!(stack.depth(), 1);
+        stack.record(0x8003, 0xfd, 0xea);
+        assert_eq!(stack.depth(), 0);
+        assert!(!stack.returned());
+    }
+
+    #[test]
+    fn test_return_address_popped_by_hand() {
+        let mut stack = CallStack::new();
+        stack.record(0x8000, 0xfd, JSR);
+        // PLA PLA: the subroutine never returns to its caller
+        stack.record(0x9000, 0xfb, 0x68);
+        stack.record(0x9001, 0xfc, 0x68);
+        assert_eq!(stack.depth(), 1);
+        stack.record(0x9002, 0xfd, 0xea);
+        assert_eq!(stack.depth(), 0);
+    }
+
+    #[test]
+    fn test_interrupt_inside_a_subroutine_keeps_the_frame() {
+        let mut stack = CallStack::new();
+        stack.record(0x8000, 0xfd, JSR);
+        stack.record(0x9000, 0xfb, 0xea);
+        // NMI pushes PC and P
+        stack.record(0xc000, 0xf8, RTI);
+        stack.record(0x9001, 0xfb, RTS);
+        assert_eq!(stack.depth(), 1);
+        stack.record(0x8003, 0xfd, 0xea);
+        assert_eq!(stack.depth(), 0);
+    }
+
+    #[test]
+    fn test_brk_frame_and_backtrace() {
+        let mut cpu = CPU::new(MockBus::new());
+        // JSR $0610; NOP / $0610: BRK; RTS / $0620: NOP; RTI
+        cpu.load(&transform("20 10 06 ea"), 0x0600);
+        cpu.load(&transform("00 ff 60"), 0x0610);
+        cpu.load(&transform("ea 40"), 0x0620);
+        cpu.load(&transform("20 06"), 0xfffe);
+        cpu.program_counter = 0x0600;
+        let mut stack = CallStack::new();
+        let step = |cpu: &mut CPU<MockBus>, stack: &mut CallStack| {
+            stack.update(cpu);
+            cpu.step();
+            stack.update(cpu);
+            // a second look at the same boundary changes nothing
+            stack.update(cpu);
+        };
+
+        step(&mut cpu, &mut stack);
+        step(&mut cpu, &mut stack);
+        assert_eq!(cpu.program_counter, 0x0620);
+        assert_eq!(stack.depth(), 2);
+        assert_eq!(stack.frames()[1].interrupt, Some(InterruptType::BRK));
+        assert_eq!(
+            stack.backtrace(0x0620, &|addr| format!("${:04x}", addr)),
+            vec![
+                "#0  $0620 in $0620 [BRK]",
+                "#1  $0610 in $0610",
+                "#2  $0600"
+            ]
+        );
+
+        step(&mut cpu, &mut stack);
+        step(&mut cpu, &mut stack);
+        assert_eq!(cpu.program_counter, 0x0612);
+        assert_eq!(stack.depth(), 1);
+        assert!(stack.returned());
+        step(&mut cpu, &mut stack);
+        assert_eq!(stack.depth(), 0);
+    }
+}
@@ -0,0 +1,1012 @@
+// Debug Adapter Protocol server, for VS Code and other DAP clients
+// https://microsoft.github.io/debug-adapter-protocol/specification
+//
+// Messages are JSON bodies preceded by a `Content-Length` header, over stdio or a TCP connection.
+// Launch arguments:
+//   program      path to the .nes file
+//   symbols      symbol files (.dbg for source level debugging, .nl, .mlb)
+//   sourceRoot   directory the file names of the debug info are relative to
+//                (defaults to the directory of the first symbol file)
+//   stopOnEntry  stop at the reset vector
+//
+// Registers are exposed as variables, RAM, nametable RAM, palette and OAM as memory references.
+// Breakpoints are set by source line (through ca65 debug info), by instruction address,
+// or by function: an address or a label.
+use super::{flag_names, DebugBus, Debugger, Stop};
+use crate::bus::{Bus, CpuBus};
+use crate::cpu::cpu::{CpuFlags, HaltReason, CPU};
+use crate::input;
+use crate::ppu::ppu::NesPPU;
+use crate::rom::Rom;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+type NesCpu = CPU<DebugBus<Bus<'static, NesPPU>>>;
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+const MEMORY_REFERENCE: i64 = 2;
+/// Instructions executed between two looks at the client's requests while running
+const POLL_INTERVAL: usize = 10_000;
+
+/// Reads a message, `None` at the end of the stream
+pub fn read_message(input: &mut dyn BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| invalid_data("missing Content-Length header"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| invalid_data(&e.to_string()))
+}
+
+pub fn write_message(output: &mut dyn Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Serves a single client until it disconnects.
+/// Requests are read on a separate thread, so that `pause` gets through while the program runs.
+pub fn serve<R: BufRead + Send + 'static, W: Write>(input: R, output: W) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = input;
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    Server::new(output).run(receiver)
+}
+
+struct Server<W: Write> {
+    output: W,
+    seq: i64,
+    debugger: Debugger,
+    cpu: Option<NesCpu>,
+    running: bool,
+    /// Reported in the `stopped` event when the debugger pauses
+    pause_reason: &'static str,
+    stop_on_entry: bool,
+    source_root: PathBuf,
+    /// by source path
+    line_breakpoints: HashMap<String, Vec<u16>>,
+    instruction_breakpoints: Vec<u16>,
+    function_breakpoints: Vec<u16>,
+    done: bool,
+}
+
+impl<W: Write> Server<W> {
+    fn new(output: W) -> Self {
+        Server {
+            output,
+            seq: 0,
+            debugger: Debugger::new(),
+            cpu: None,
+            running: false,
+            pause_reason: "entry",
+            stop_on_entry: false,
+            source_root: PathBuf::from("."),
+            line_breakpoints: HashMap::new(),
+            instruction_breakpoints: vec![],
+            function_breakpoints: vec![],
+            done: false,
+        }
+    }
+
+    fn run(&mut self, receiver: Receiver<Value>) -> io::Result<()> {
+        while !self.done {
+            if self.running {
+                match receiver.try_recv() {
+                    Ok(message) => self.handle(message)?,
+                    Err(TryRecvError::Empty) => self.run_slice()?,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match receiver.recv() {
+                    Ok(message) => self.handle(message)?,
+                    Err(_) => break,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs until the debugger stops or it's time to look at the client's requests
+    fn run_slice(&mut self) -> io::Result<()> {
+        let debugger = &mut self.debugger;
+        let cpu = match self.cpu.as_mut() {
+            Some(cpu) => cpu,
+            None => {
+                self.running = false;
+                return Ok(());
+            }
+        };
+        let mut stop = None;
+        let mut executed = 0;
+        let halt = cpu.run_until(|cpu| {
+            stop = debugger.check(cpu);
+            executed += 1;
+            stop.is_some() || executed > POLL_INTERVAL
+        });
+        let body = match (stop, halt) {
+            (_, HaltReason::Jam { pc, opcode }) => json!({
+                "reason": "exception",
+                "description": format!("CPU jammed by opcode ${:02x} at ${:04x}", opcode, pc),
+            }),
+            (Some(Stop::Pause), _) => json!({ "reason": self.pause_reason }),
+            (Some(Stop::Breakpoint(addr)), _) => {
+                let kind = if self.instruction_breakpoints.contains(&addr) {
+                    "instruction breakpoint"
+                } else if self.function_breakpoints.contains(&addr) {
+                    "function breakpoint"
+                } else {
+                    "breakpoint"
+                };
+                json!({ "reason": kind })
+            }
+            (Some(Stop::Watchpoint { pc, access }), _) => json!({
+                "reason": "data breakpoint",
+                "description": format!(
+                    "{:?} ${:04x} = ${:02x} at ${:04x}",
+                    access.kind, access.addr, access.value, pc
+                ),
+            }),
+            (None, _) => return Ok(()),
+        };
+        self.running = false;
+        let mut body = body;
+        body["threadId"] = json!(THREAD_ID);
+        body["allThreadsStopped"] = json!(true);
+        self.event("stopped", body)
+    }
+
+    fn handle(&mut self, message: Value) -> io::Result<()> {
+        if message["type"] != "request" {
+            return Ok(());
+        }
+        let command = message["command"].as_str().unwrap_or("").to_string();
+        let args = &message["arguments"];
+        let result = match command.as_str() {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+                "supportsDisassembleRequest": true,
+                "supportsSetVariable": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_line_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setFunctionBreakpoints" => self.set_function_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.debugger.pause();
+                    self.pause_reason = "entry";
+                } else {
+                    self.debugger.resume();
+                }
+                self.running = true;
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "6502" }] })),
+            "stackTrace" => self.stack_trace(args),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                { "name": "Memory", "variablesReference": MEMORY_REFERENCE, "expensive": false },
+            ]})),
+            "variables" => self.variables(args),
+            "setVariable" => self.set_variable(args),
+            "readMemory" => self.read_memory(args),
+            "disassemble" => self.disassemble(args),
+            "continue" => self.resume(Debugger::resume, "step"),
+            "next" => self.resume(Debugger::step_over, "step"),
+            "stepIn" => self.resume(Debugger::step, "step"),
+            "stepOut" => self.resume(Debugger::step_out, "step"),
+            "pause" => {
+                // reported at the next instruction boundary
+                self.debugger.pause();
+                self.pause_reason = "pause";
+                Ok(json!({}))
+            }
+            "disconnect" | "terminate" => {
+                self.done = true;
+                Ok(json!({}))
+            }
+            _ => Err(format!("unsupported request {}", command)),
+        };
+        match result {
+            Ok(body) => {
+                let allow_configuration = command == "launch";
+                self.respond(&message, true, body, None)?;
+                if allow_configuration {
+                    // configuration requests need the ROM and its symbols
+                    self.event("initialized", json!({}))?;
+                }
+                if command == "disconnect" || command == "terminate" {
+                    self.event("terminated", json!({}))?;
+                }
+                Ok(())
+            }
+            Err(e) => self.respond(&message, false, json!({}), Some(e)),
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or_else(|| "launch: missing program".to_string())?;
+        let data = std::fs::read(program).map_err(|e| format!("can't read {}: {}", program, e))?;
+        let rom = Rom::load(&data).map_err(|e| format!("can't load {}: {}", program, e))?;
+
+        let mut debugger = Debugger::new();
+        let files: Vec<&str> = args["symbols"]
+            .as_array()
+            .map(|files| files.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        for file in files.iter() {
+            debugger.symbols.load(Path::new(file))?;
+        }
+        self.source_root = match (args["sourceRoot"].as_str(), files.first()) {
+            (Some(root), _) => PathBuf::from(root),
+            (None, Some(file)) => Path::new(file)
+                .parent()
+                .unwrap_or(Path::new("."))
+                .to_path_buf(),
+            (None, None) => PathBuf::from("."),
+        };
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+
+        let bus = Bus::<NesPPU>::new(rom, |_: &NesPPU, _: &mut input::Joypad| {});
+        let mut cpu = CPU::new(DebugBus::new(bus));
+        cpu.power_on();
+        self.cpu = Some(cpu);
+        self.debugger = debugger;
+        Ok(json!({}))
+    }
+
+    fn set_line_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"]
+            .as_str()
+            .ok_or_else(|| "setBreakpoints: missing source path".to_string())?
+            .to_string();
+        let cpu = self.cpu.as_ref().ok_or("setBreakpoints: no program")?;
+        let mut addrs = vec![];
+        let mut breakpoints = vec![];
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            let line_addrs: Vec<u16> = self
+                .debugger
+                .symbols
+                .line_offsets(&path, line)
+                .into_iter()
+                .flat_map(|offset| prg_addresses(cpu, offset))
+                .collect();
+            breakpoints.push(match line_addrs.last() {
+                Some(addr) => json!({
+                    "verified": true,
+                    "line": line,
+                    "instructionReference": reference(*addr),
+                }),
+                None => json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "no code at this line",
+                }),
+            });
+            addrs.extend(line_addrs);
+        }
+        self.line_breakpoints.insert(path, addrs);
+        self.update_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let mut breakpoints = vec![];
+        self.instruction_breakpoints.clear();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let reference = breakpoint["instructionReference"].as_str().unwrap_or("");
+            let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+            match parse_address(reference) {
+                Some(addr) => {
+                    let addr = addr.wrapping_add(offset as u16);
+                    self.instruction_breakpoints.push(addr);
+                    breakpoints.push(json!({
+                        "verified": true,
+                        "instructionReference": self::reference(addr),
+                    }));
+                }
+                None => breakpoints.push(json!({
+                    "verified": false,
+                    "message": format!("invalid address {}", reference),
+                })),
+            }
+        }
+        self.update_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_function_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let cpu = self
+            .cpu
+            .as_ref()
+            .ok_or("setFunctionBreakpoints: no program")?;
+        let mut breakpoints = vec![];
+        self.function_breakpoints.clear();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let name = breakpoint["name"].as_str().unwrap_or("");
+            let addrs: Vec<u16> = match parse_address(name) {
+                Some(addr) => vec![addr],
+                None => (0..=0xffffu16)
+                    .filter(|addr| {
+                        self.debugger
+                            .symbols
+                            .label(*addr, cpu.bus.prg_rom_offset(*addr))
+                            == Some(name)
+                    })
+                    .collect(),
+            };
+            breakpoints.push(match addrs.last() {
+                Some(addr) => json!({
+                    "verified": true,
+                    "instructionReference": reference(*addr),
+                }),
+                None => json!({
+                    "verified": false,
+                    "message": format!("unknown function {}", name),
+                }),
+            });
+            self.function_breakpoints.extend(addrs);
+        }
+        self.update_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn update_breakpoints(&mut self) {
+        let addrs = self
+            .line_breakpoints
+            .values()
+            .flatten()
+            .chain(self.instruction_breakpoints.iter())
+            .chain(self.function_breakpoints.iter())
+            .cloned();
+        self.debugger.set_breakpoints(addrs);
+    }
+
+    /// Innermost frame first: the current instruction, then every JSR on the call stack
+    fn stack_trace(&mut self, args: &Value) -> Result<Value, String> {
+        let cpu = self.cpu.as_ref().ok_or("stackTrace: no program")?;
+        let calls = self.debugger.calls.frames();
+        let mut frames = vec![];
+        let mut pc = cpu.program_counter;
+        for depth in (0..=calls.len()).rev() {
+            // the routine the instruction belongs to was entered by the JSR one level up
+            let routine = match depth {
+                0 => pc,
+                _ => calls[depth - 1].target,
+            };
+            let mut frame = json!({
+                "id": frames.len(),
+                "name": self.label(cpu, routine),
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": reference(pc),
+            });
+            if let Some(source) = cpu
+                .bus
+                .prg_rom_offset(pc)
+                .and_then(|offset| self.debugger.symbols.source_line(offset))
+            {
+                let path = self.source_root.join(&source.file);
+                let name = path.file_name().map(|n| n.to_string_lossy().to_string());
+                frame["source"] = json!({ "name": name, "path": path.to_string_lossy() });
+                frame["line"] = json!(source.line);
+                frame["column"] = json!(1);
+            }
+            frames.push(frame);
+            if depth > 0 {
+                pc = calls[depth - 1].call_site;
+            }
+        }
+        let total = frames.len();
+        let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+        let levels = match args["levels"].as_u64() {
+            Some(levels) if levels > 0 => levels as usize,
+            _ => total,
+        };
+        let frames: Vec<Value> = frames.into_iter().skip(start).take(levels).collect();
+        Ok(json!({ "stackFrames": frames, "totalFrames": total }))
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value, String> {
+        let cpu = self.cpu.as_ref().ok_or("variables: no program")?;
+        let variables = match args["variablesReference"].as_i64() {
+            Some(REGISTERS_REFERENCE) => {
+                let registers = cpu.registers();
+                let byte = |name: &str, value: u8| json!({ "name": name, "value": format!("0x{:02x}", value), "variablesReference": 0 });
+                vec![
+                    byte("register_a", registers.a),
+                    byte("register_x", registers.x),
+                    byte("register_y", registers.y),
+                    json!({
+                        "name": "flags",
+                        "value": format!("0x{:02x} {}", registers.p.bits(), flag_names(registers.p)),
+                        "variablesReference": 0,
+                    }),
+                    byte("stack_pointer", registers.sp),
+                    json!({
+                        "name": "program_counter",
+                        "value": format!("0x{:04x}", registers.pc),
+                        "variablesReference": 0,
+                        "memoryReference": reference(registers.pc),
+                    }),
+                ]
+            }
+            Some(MEMORY_REFERENCE) => [
+                ("RAM", "ram"),
+                ("VRAM", "vram"),
+                ("Palette", "palette"),
+                ("OAM", "oam"),
+            ]
+            .iter()
+            .map(|(name, memory)| {
+                let size = memory_region(cpu, memory).map_or(0, |m| m.len());
+                json!({
+                    "name": name,
+                    "value": format!("{} bytes", size),
+                    "variablesReference": 0,
+                    "memoryReference": memory,
+                })
+            })
+            .collect(),
+            _ => vec![],
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        let cpu = self.cpu.as_mut().ok_or("setVariable: no program")?;
+        if args["variablesReference"].as_i64() != Some(REGISTERS_REFERENCE) {
+            return Err("only registers can be edited".to_string());
+        }
+        let name = args["name"].as_str().unwrap_or("");
+        let text = args["value"].as_str().unwrap_or("");
+        let value = parse_number(text).ok_or_else(|| format!("invalid value {}", text))?;
+        let byte = || u8::try_from(value).map_err(|_| format!("{} is not a byte", text));
+        let mut registers = cpu.registers();
+        let shown = match name {
+            "register_a" => {
+                registers.a = byte()?;
+                format!("0x{:02x}", registers.a)
+            }
+            "register_x" => {
+                registers.x = byte()?;
+                format!("0x{:02x}", registers.x)
+            }
+            "register_y" => {
+                registers.y = byte()?;
+                format!("0x{:02x}", registers.y)
+            }
+            "stack_pointer" => {
+                registers.sp = byte()?;
+                format!("0x{:02x}", registers.sp)
+            }
+            "flags" => {
+                registers.p = CpuFlags::from_bits_truncate(byte()?);
+                format!("0x{:02x} {}", registers.p.bits(), flag_names(registers.p))
+            }
+            "program_counter" => {
+                registers.pc = value;
+                format!("0x{:04x}", registers.pc)
+            }
+            _ => return Err(format!("unknown register {}", name)),
+        };
+        cpu.set_registers(registers);
+        Ok(json!({ "value": shown }))
+    }
+
+    fn read_memory(&mut self, args: &Value) -> Result<Value, String> {
+        let cpu = self.cpu.as_ref().ok_or("readMemory: no program")?;
+        let name = args["memoryReference"].as_str().unwrap_or("");
+        let memory = memory_region(cpu, name).ok_or_else(|| format!("unknown memory {}", name))?;
+        let offset = args["offset"].as_i64().unwrap_or(0).max(0) as usize;
+        let count = args["count"].as_u64().unwrap_or(0).min(memory.len() as u64) as usize;
+        let start = offset.min(memory.len());
+        let end = offset.saturating_add(count).min(memory.len());
+        Ok(json!({
+            "address": format!("0x{:x}", offset),
+            "data": base64(&memory[start..end]),
+            "unreadableBytes": count - (end - start),
+        }))
+    }
+
+    fn disassemble(&mut self, args: &Value) -> Result<Value, String> {
+        let reference = args["memoryReference"].as_str().unwrap_or("");
+        let addr = parse_address(reference)
+            .ok_or_else(|| format!("invalid address {}", reference))?
+            .wrapping_add(args["offset"].as_i64().unwrap_or(0) as u16);
+        // no more instructions than there are addresses
+        let instruction_offset = args["instructionOffset"]
+            .as_i64()
+            .unwrap_or(0)
+            .clamp(-0x10000, 0x10000);
+        let count = args["instructionCount"].as_u64().unwrap_or(0).min(0x10000) as usize;
+        let cpu = self.cpu.as_mut().ok_or("disassemble: no program")?;
+
+        let before = (-instruction_offset).max(0) as usize;
+        let skip = instruction_offset.max(0) as usize;
+        let instructions = self.debugger.instructions(cpu, addr, before, count + skip);
+        let symbols = &self.debugger.symbols;
+        let mut result = vec![];
+        for instruction in instructions.into_iter().skip(skip) {
+            let bytes: Vec<String> = instruction
+                .bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            let mut entry = json!({
+                "address": self::reference(instruction.addr),
+                "instructionBytes": bytes.join(" "),
+                "instruction": instruction.text,
+            });
+            if let Some(source) = cpu
+                .bus
+                .prg_rom_offset(instruction.addr)
+                .and_then(|offset| symbols.source_line(offset))
+            {
+                entry["location"] =
+                    json!({ "path": self.source_root.join(&source.file).to_string_lossy() });
+                entry["line"] = json!(source.line);
+            }
+            result.push(entry);
+        }
+        Ok(json!({ "instructions": result }))
+    }
+
+    fn resume(&mut self, run: fn(&mut Debugger), reason: &'static str) -> Result<Value, String> {
+        if self.cpu.is_none() {
+            return Err("no program".to_string());
+        }
+        run(&mut self.debugger);
+        self.pause_reason = reason;
+        self.running = true;
+        Ok(json!({ "allThreadsContinued": true }))
+    }
+
+    /// `UpdatePlayer` or `$c3a1`
+    fn label(&self, cpu: &NesCpu, addr: u16) -> String {
+        match self
+            .debugger
+            .symbols
+            .label(addr, cpu.bus.prg_rom_offset(addr))
+        {
+            Some(label) => label.to_string(),
+            None => format!("${:04x}", addr),
+        }
+    }
+
+    fn respond(
+        &mut self,
+        request: &Value,
+        success: bool,
+        body: Value,
+        message: Option<String>,
+    ) -> io::Result<()> {
+        self.seq += 1;
+        let mut response = json!({
+            "seq": self.seq,
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": success,
+            "body": body,
+        });
+        if let Some(message) = message {
+            response["message"] = json!(message);
+        }
+        write_message(&mut self.output, &response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.seq += 1;
+        let message = json!({ "seq": self.seq, "type": "event", "event": event, "body": body });
+        write_message(&mut self.output, &message)
+    }
+}
+
+/// CPU addresses a PRG ROM offset is currently mapped at
+fn prg_addresses(cpu: &NesCpu, offset: usize) -> Vec<u16> {
+    (0x8000..=0xffffu16)
+        .filter(|addr| cpu.bus.prg_rom_offset(*addr) == Some(offset))
+        .collect()
+}
+
+fn memory_region<'a>(cpu: &'a NesCpu, name: &str) -> Option<&'a [u8]> {
+    let bus = &cpu.bus.inner;
+    match name {
+        "ram" => Some(&bus.ram[..]),
+        "vram" => Some(&bus.ppu().vram[..]),
+        "palette" => Some(&bus.ppu().palette_table[..]),
+        "oam" => Some(&bus.ppu().oam_data[..]),
+        _ => None,
+    }
+}
+
+/// Instruction and memory references are CPU addresses: `0xc3a1`
+fn reference(addr: u16) -> String {
+    format!("0x{:04x}", addr)
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix('$'))?;
+    u16::from_str_radix(digits, 16).ok()
+}
+
+/// `0x1f`, `$1f` or `31`
+fn parse_number(text: &str) -> Option<u16> {
+    let text = text.trim();
+    parse_address(text).or_else(|| text.parse().ok())
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut result = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, b)| bits | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::assemble;
+    use pretty_assertions::assert_eq;
+    use std::io::BufReader;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    const SOURCE: &str = "\
+            .org $c000
+reset:      LDX #$00
+loop:       JSR sub
+            INX
+            STX $10
+            JMP loop
+sub:        LDA #$42
+            RTS
+nmi:        RTI
+            .org $fffa
+            .word nmi, reset, nmi
+";
+
+    /// NROM image of SOURCE, with ca65 style debug info: offsets and lengths of every line
+    fn write_project(dir: &Path) -> (PathBuf, PathBuf) {
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        let asm = assemble(SOURCE).unwrap();
+        let mut rom = vec![
+            0x4e, 0x45, 0x53, 0x1a, 0x01, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        rom.extend(&asm.bytes);
+        rom.extend(vec![0; 0x2000]);
+        std::fs::write(dir.join("game.nes"), &rom).unwrap();
+        std::fs::write(dir.join("src/main.s"), SOURCE).unwrap();
+
+        let mut dbg = String::from(
+            "version\tmajor=2,minor=0\n\
+             file\tid=0,name=\"src/main.s\",size=0,mtime=0,mod=0\n\
+             seg\tid=0,name=\"CODE\",start=0x00C000,size=0x4000,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16\n",
+        );
+        let lines = [
+            (2, 0, 2),
+            (3, 2, 3),
+            (4, 5, 1),
+            (5, 6, 2),
+            (6, 8, 3),
+            (7, 11, 2),
+            (8, 13, 1),
+            (9, 14, 1),
+        ];
+        for (id, (line, start, size)) in lines.iter().enumerate() {
+            dbg += &format!("span\tid={},seg=0,start={},size={}\n", id, start, size);
+            dbg += &format!("line\tid={},file=0,line={},span={}\n", id, line, id);
+        }
+        for (id, (name, val)) in [("reset", 0xc000), ("loop", 0xc002), ("sub", 0xc00b)]
+            .iter()
+            .enumerate()
+        {
+            dbg += &format!(
+                "sym\tid={},name=\"{}\",addrsize=absolute,scope=0,def=0,val=0x{:X},seg=0,type=lab\n",
+                id, name, val
+            );
+        }
+        std::fs::write(dir.join("game.dbg"), dbg).unwrap();
+        (dir.join("game.nes"), dir.join("game.dbg"))
+    }
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+        seq: i64,
+        events: Vec<Value>,
+    }
+
+    impl Client {
+        fn connect(addr: std::net::SocketAddr) -> Self {
+            let writer = TcpStream::connect(addr).unwrap();
+            writer
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            Client {
+                reader: BufReader::new(writer.try_clone().unwrap()),
+                writer,
+                seq: 0,
+                events: vec![],
+            }
+        }
+
+        /// Sends a request and waits for its response, keeping the events that come first
+        fn request(&mut self, command: &str, arguments: Value) -> Value {
+            self.seq += 1;
+            let request = json!({ "seq": self.seq, "type": "request", "command": command, "arguments": arguments });
+            write_message(&mut self.writer, &request).unwrap();
+            loop {
+                let message = read_message(&mut self.reader).unwrap().unwrap();
+                if message["type"] == "event" {
+                    self.events.push(message);
+                } else {
+                    assert_eq!(message["request_seq"], json!(self.seq));
+                    assert_eq!(message["success"], json!(true), "{}", message);
+                    return message["body"].clone();
+                }
+            }
+        }
+
+        fn event(&mut self, name: &str) -> Value {
+            if let Some(i) = self.events.iter().position(|e| e["event"] == name) {
+                return self.events.remove(i)["body"].clone();
+            }
+            loop {
+                let message = read_message(&mut self.reader).unwrap().unwrap();
+                if message["event"] == name {
+                    return message["body"].clone();
+                }
+                self.events.push(message);
+            }
+        }
+
+        /// (name, line, instruction pointer) of every frame
+        fn stack(&mut self) -> Vec<(String, u64, String)> {
+            let body = self.request("stackTrace", json!({ "threadId": 1 }));
+            body["stackFrames"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|f| {
+                    (
+                        f["name"].as_str().unwrap().to_string(),
+                        f["line"].as_u64().unwrap(),
+                        f["instructionPointerReference"]
+                            .as_str()
+                            .unwrap()
+                            .to_string(),
+                    )
+                })
+                .collect()
+        }
+
+        fn register(&mut self, name: &str) -> String {
+            let body = self.request(
+                "variables",
+                json!({ "variablesReference": REGISTERS_REFERENCE }),
+            );
+            let variable = body["variables"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|v| v["name"] == name)
+                .unwrap()
+                .clone();
+            variable["value"].as_str().unwrap().to_string()
+        }
+    }
+
+    fn frame(name: &str, line: u64, pc: &str) -> (String, u64, String) {
+        (name.to_string(), line, pc.to_string())
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let dir = std::env::temp_dir().join("rustness_dap_test");
+        let (rom, dbg) = write_project(&dir);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(BufReader::new(stream.try_clone().unwrap()), stream).unwrap();
+        });
+        let mut client = Client::connect(addr);
+
+        let capabilities = client.request("initialize", json!({ "adapterID": "rustness" }));
+        assert_eq!(capabilities["supportsInstructionBreakpoints"], json!(true));
+        client.request(
+            "launch",
+            json!({ "program": rom, "symbols": [dbg], "stopOnEntry": true }),
+        );
+        client.event("initialized");
+
+        let source = dir.join("src/main.s");
+        let body = client.request(
+            "setBreakpoints",
+            json!({ "source": { "path": source }, "breakpoints": [{ "line": 5 }, { "line": 1 }] }),
+        );
+        assert_eq!(body["breakpoints"][0]["verified"], json!(true));
+        assert_eq!(
+            body["breakpoints"][0]["instructionReference"],
+            json!("0xc006")
+        );
+        assert_eq!(body["breakpoints"][1]["verified"], json!(false));
+        client.request("configurationDone", json!({}));
+        assert_eq!(client.event("stopped")["reason"], json!("entry"));
+        assert_eq!(client.stack(), vec![frame("reset", 2, "0xc000")]);
+
+        // source breakpoint
+        client.request("continue", json!({ "threadId": 1 }));
+        assert_eq!(client.event("stopped")["reason"], json!("breakpoint"));
+        assert_eq!(client.stack(), vec![frame("$c006", 5, "0xc006")]);
+        assert_eq!(client.register("register_x"), "0x01");
+        assert_eq!(client.register("register_a"), "0x42");
+        assert_eq!(client.register("flags"), "0x24 nv-bdIzc");
+
+        // step over JSR, into it, and out of it
+        client.request(
+            "setBreakpoints",
+            json!({ "source": { "path": source }, "breakpoints": [] }),
+        );
+        client.request("next", json!({ "threadId": 1 }));
+        assert_eq!(client.event("stopped")["reason"], json!("step"));
+        client.request("next", json!({ "threadId": 1 }));
+        client.event("stopped");
+        assert_eq!(client.stack(), vec![frame("loop", 3, "0xc002")]);
+        client.request("next", json!({ "threadId": 1 }));
+        client.event("stopped");
+        assert_eq!(client.stack(), vec![frame("$c005", 4, "0xc005")]);
+        client.request(
+            "setInstructionBreakpoints",
+            json!({ "breakpoints": [{ "instructionReference": "0xc002" }] }),
+        );
+        client.request("continue", json!({ "threadId": 1 }));
+        assert_eq!(
+            client.event("stopped")["reason"],
+            json!("instruction breakpoint")
+        );
+        client.request("setInstructionBreakpoints", json!({ "breakpoints": [] }));
+        client.request("stepIn", json!({ "threadId": 1 }));
+        client.event("stopped");
+        assert_eq!(
+            client.stack(),
+            vec![frame("sub", 7, "0xc00b"), frame("loop", 3, "0xc002")]
+        );
+        client.request("stepOut", json!({ "threadId": 1 }));
+        client.event("stopped");
+        assert_eq!(client.stack(), vec![frame("$c005", 4, "0xc005")]);
+
+        // function breakpoint by label
+        let body = client.request(
+            "setFunctionBreakpoints",
+            json!({ "breakpoints": [{ "name": "sub" }, { "name": "nope" }] }),
+        );
+        assert_eq!(
+            body["breakpoints"][0]["instructionReference"],
+            json!("0xc00b")
+        );
+        assert_eq!(body["breakpoints"][1]["verified"], json!(false));
+        client.request("continue", json!({ "threadId": 1 }));
+        assert_eq!(
+            client.event("stopped")["reason"],
+            json!("function breakpoint")
+        );
+        assert_eq!(client.stack()[0], frame("sub", 7, "0xc00b"));
+        client.request("setFunctionBreakpoints", json!({ "breakpoints": [] }));
+
+        // registers and memory
+        let body = client.request(
+            "setVariable",
+            json!({ "variablesReference": REGISTERS_REFERENCE, "name": "register_y", "value": "0x99" }),
+        );
+        assert_eq!(body["value"], json!("0x99"));
+        assert_eq!(client.register("register_y"), "0x99");
+        let body = client.request(
+            "readMemory",
+            json!({ "memoryReference": "ram", "offset": 0x10, "count": 2 }),
+        );
+        assert_eq!(body["data"], json!("AwA="));
+        let body = client.request(
+            "readMemory",
+            json!({ "memoryReference": "oam", "offset": 0xff, "count": 4 }),
+        );
+        assert_eq!(body["unreadableBytes"], json!(3));
+        let body = client.request(
+            "readMemory",
+            json!({ "memoryReference": "oam", "offset": 0xfe, "count": u64::MAX }),
+        );
+        assert_eq!(body["data"], json!(base64(&[0, 0])));
+        assert_eq!(body["unreadableBytes"], json!(0xfe));
+        let body = client.request(
+            "variables",
+            json!({ "variablesReference": MEMORY_REFERENCE }),
+        );
+        assert_eq!(body["variables"][1]["memoryReference"], json!("vram"));
+
+        let body = client.request(
+            "disassemble",
+            json!({ "memoryReference": "0xc00b", "instructionOffset": -2, "instructionCount": 3 }),
+        );
+        let instructions: Vec<&str> = body["instructions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| i["instruction"].as_str().unwrap())
+            .collect();
+        assert_eq!(instructions, vec!["STX $10", "JMP loop", "LDA #$42"]);
+        assert_eq!(body["instructions"][2]["line"], json!(7));
+
+        // pause while running
+        client.request("continue", json!({ "threadId": 1 }));
+        client.request("pause", json!({ "threadId": 1 }));
+        assert_eq!(client.event("stopped")["reason"], json!("pause"));
+
+        client.request("disconnect", json!({}));
+        client.event("terminated");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xff, 0xfe]), "//4=");
+    }
+}
@@ -0,0 +1,559 @@
+// GDB remote serial protocol stub
+// https://sourceware.org/gdb/current/onlinedocs/gdb/Remote-Protocol.html
+//
+// Registers (`g` packet order, described to the client by target.xml):
+//   a, x, y, p, sp  8 bit each
+//   pc              16 bit, little endian
+// Memory is the 64K CPU address space. Reads go through `CpuBus::peek`, so dumping the PPU
+// registers from gdb doesn't clear vblank or move the VRAM address; writes go through `CpuBus::poke`.
+// Breakpoints (Z0/Z1) are kept on the stub side, ROM is never patched.
+use crate::bus::CpuBus;
+use crate::cpu::cpu::{CpuFlags, HaltReason, Registers, CPU};
+use std::collections::BTreeSet;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+/// Instructions executed between two checks for a Ctrl-C from the client
+const POLL_INTERVAL: usize = 10_000;
+const PACKET_SIZE: usize = 0x1000;
+const INTERRUPT: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rustness.mos6502">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Incoming {
+    Packet(String),
+    Interrupt,
+}
+
+/// Waits for one client on a local port and serves it until it detaches
+pub fn listen<B: CpuBus>(cpu: &mut CPU<B>, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("gdb: waiting on {}", listener.local_addr()?);
+    let (stream, peer) = listener.accept()?;
+    eprintln!("gdb: {} connected", peer);
+    serve(cpu, stream)
+}
+
+pub fn serve<B: CpuBus>(cpu: &mut CPU<B>, stream: TcpStream) -> io::Result<()> {
+    let input = stream.try_clone()?;
+    Stub::new(cpu, stream).run(receive(input))
+}
+
+/// Splits the byte stream into packets and Ctrl-C on a reader thread,
+/// so that a running CPU can be interrupted
+fn receive<R: Read + Send + 'static>(input: R) -> Receiver<Incoming> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut bytes = BufReader::new(input).bytes();
+        while let Some(Ok(byte)) = bytes.next() {
+            let incoming = match byte {
+                INTERRUPT => Incoming::Interrupt,
+                b'$' => {
+                    let mut data = vec![];
+                    loop {
+                        match bytes.next() {
+                            Some(Ok(b'#')) => break,
+                            Some(Ok(byte)) => data.push(byte),
+                            _ => return,
+                        }
+                    }
+                    // checksum: the transport is reliable, a bad packet would still be a bug
+                    for _ in 0..2 {
+                        if bytes.next().is_none() {
+                            return;
+                        }
+                    }
+                    Incoming::Packet(String::from_utf8_lossy(&data).into_owned())
+                }
+                // acks, and noise between packets
+                _ => continue,
+            };
+            if sender.send(incoming).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    hex::decode(hex).map_err(|e| e.to_string())
+}
+
+fn parse_hex(text: &str) -> Result<usize, String> {
+    usize::from_str_radix(text, 16).map_err(|_| format!("invalid number: {}", text))
+}
+
+/// `offset,len` of the qXfer packets
+fn parse_pair(text: &str) -> Result<(usize, usize), String> {
+    let mut parts = text.split(',');
+    let offset = parse_hex(parts.next().unwrap_or(""))?;
+    let len = parse_hex(parts.next().unwrap_or(""))?;
+    Ok((offset, len))
+}
+
+/// `addr,len` of the m/M/Z/z packets
+fn parse_range(text: &str) -> Result<(u16, usize), String> {
+    let (addr, len) = parse_pair(text)?;
+    if addr > 0xFFFF {
+        return Err(format!("address out of range: {:x}", addr));
+    }
+    Ok((addr as u16, len))
+}
+
+struct Stub<'c, B: CpuBus, W: Write> {
+    cpu: &'c mut CPU<B>,
+    output: W,
+    breakpoints: BTreeSet<u16>,
+    no_ack: bool,
+    running: bool,
+    /// Set on continue: the instruction at PC runs even if it has a breakpoint
+    resumed: bool,
+    done: bool,
+}
+
+impl<'c, B: CpuBus, W: Write> Stub<'c, B, W> {
+    fn new(cpu: &'c mut CPU<B>, output: W) -> Self {
+        Stub {
+            cpu,
+            output,
+            breakpoints: BTreeSet::new(),
+            no_ack: false,
+            running: false,
+            resumed: false,
+            done: false,
+        }
+    }
+
+    fn run(&mut self, receiver: Receiver<Incoming>) -> io::Result<()> {
+        while !self.done {
+            let incoming = if self.running {
+                match receiver.try_recv() {
+                    Ok(incoming) => incoming,
+                    Err(TryRecvError::Empty) => {
+                        self.run_slice()?;
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match receiver.recv() {
+                    Ok(incoming) => incoming,
+                    Err(_) => break,
+                }
+            };
+            match incoming {
+                Incoming::Interrupt if self.running => self.stop(SIGINT)?,
+                Incoming::Interrupt => {}
+                Incoming::Packet(packet) => {
+                    if !self.no_ack {
+                        self.output.write_all(b"+")?;
+                    }
+                    let reply = self.handle(&packet);
+                    if let Some(reply) = reply {
+                        self.send(&reply)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        write!(self.output, "${}#{:02x}", data, checksum(data))?;
+        self.output.flush()
+    }
+
+    fn stop(&mut self, signal: u8) -> io::Result<()> {
+        self.running = false;
+        self.resumed = false;
+        self.send(&format!("S{:02x}", signal))
+    }
+
+    fn run_slice(&mut self) -> io::Result<()> {
+        let mut budget = POLL_INTERVAL;
+        let breakpoints = &self.breakpoints;
+        let resumed = &mut self.resumed;
+        let halt = self.cpu.run_until(|cpu| {
+            if std::mem::replace(resumed, false) {
+                return false;
+            }
+            if breakpoints.contains(&cpu.registers().pc) {
+                return true;
+            }
+            budget -= 1;
+            budget == 0
+        });
+        match halt {
+            HaltReason::Breakpoint if budget == 0 => Ok(()),
+            HaltReason::Breakpoint => self.stop(SIGTRAP),
+            _ => self.stop(SIGILL),
+        }
+    }
+
+    /// The reply, or nothing when the CPU was resumed: the stop reply comes later
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => Ok(format!("S{:02x}", SIGTRAP)),
+            Some(b'g') => Ok(hex::encode(self.register_bytes())),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'Z') => self.breakpoint(&packet[1..], true),
+            Some(b'z') => self.breakpoint(&packet[1..], false),
+            Some(b'c') => return self.resume(false),
+            Some(b's') => return self.resume(true),
+            Some(b'H') | Some(b'T') => Ok("OK".to_string()),
+            Some(b'D') => {
+                self.done = true;
+                Ok("OK".to_string())
+            }
+            Some(b'k') => {
+                self.done = true;
+                return None;
+            }
+            Some(b'v') => return self.handle_v(packet),
+            Some(b'q') | Some(b'Q') => self.query(packet),
+            _ => Ok(String::new()),
+        };
+        Some(reply.unwrap_or_else(|_| "E01".to_string()))
+    }
+
+    fn handle_v(&mut self, packet: &str) -> Option<String> {
+        if packet == "vCont?" {
+            return Some("vCont;c;C;s;S".to_string());
+        }
+        if let Some(actions) = packet.strip_prefix("vCont;") {
+            // single thread: the first action is the one that applies
+            return match actions.as_bytes().first() {
+                Some(b'c') | Some(b'C') => self.resume(false),
+                Some(b's') | Some(b'S') => self.resume(true),
+                _ => Some("E01".to_string()),
+            };
+        }
+        Some(String::new())
+    }
+
+    fn query(&mut self, packet: &str) -> Result<String, String> {
+        let name = packet.split(&[':', ','][..]).next().unwrap_or("");
+        match name {
+            "qSupported" => Ok(format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;vContSupported+",
+                PACKET_SIZE
+            )),
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                Ok("OK".to_string())
+            }
+            "qAttached" => Ok("1".to_string()),
+            "qC" => Ok("QC1".to_string()),
+            "qfThreadInfo" => Ok("m1".to_string()),
+            "qsThreadInfo" => Ok("l".to_string()),
+            "qXfer" => {
+                let args = packet
+                    .strip_prefix("qXfer:features:read:target.xml:")
+                    .ok_or("unknown object")?;
+                let (offset, len) = parse_pair(args)?;
+                let offset = offset.min(TARGET_XML.len());
+                let end = offset.saturating_add(len).min(TARGET_XML.len());
+                let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+                Ok(format!("{}{}", more, &TARGET_XML[offset..end]))
+            }
+            _ => Ok(String::new()),
+        }
+    }
+
+    fn resume(&mut self, step: bool) -> Option<String> {
+        if step {
+            self.cpu.step();
+            // checks for a jam without executing anything
+            return Some(match self.cpu.run_until(|_| true) {
+                HaltReason::Jam { .. } => format!("S{:02x}", SIGILL),
+                _ => format!("S{:02x}", SIGTRAP),
+            });
+        }
+        self.running = true;
+        self.resumed = true;
+        None
+    }
+
+    fn register_bytes(&self) -> Vec<u8> {
+        let r = self.cpu.registers();
+        let pc = r.pc.to_le_bytes();
+        vec![r.a, r.x, r.y, r.p.bits(), r.sp, pc[0], pc[1]]
+    }
+
+    fn set_register_bytes(&mut self, bytes: &[u8]) {
+        self.cpu.set_registers(Registers {
+            a: bytes[0],
+            x: bytes[1],
+            y: bytes[2],
+            p: CpuFlags::from_bits_truncate(bytes[3]),
+            sp: bytes[4],
+            pc: u16::from_le_bytes([bytes[5], bytes[6]]),
+        });
+    }
+
+    fn write_registers(&mut self, hex: &str) -> Result<String, String> {
+        let bytes = decode_hex(hex)?;
+        if bytes.len() != 7 {
+            return Err("expected 7 bytes".to_string());
+        }
+        self.set_register_bytes(&bytes);
+        Ok("OK".to_string())
+    }
+
+    /// Offset and size in the `g` packet of register number `n`
+    fn register_range(n: &str) -> Result<(usize, usize), String> {
+        match parse_hex(n)? {
+            n @ 0..=4 => Ok((n, 1)),
+            5 => Ok((5, 2)),
+            _ => Err(format!("no register {}", n)),
+        }
+    }
+
+    fn read_register(&mut self, n: &str) -> Result<String, String> {
+        let (offset, size) = Self::register_range(n)?;
+        Ok(hex::encode(&self.register_bytes()[offset..offset + size]))
+    }
+
+    fn write_register(&mut self, args: &str) -> Result<String, String> {
+        let mut parts = args.splitn(2, '=');
+        let (offset, size) = Self::register_range(parts.next().unwrap_or(""))?;
+        let value = decode_hex(parts.next().unwrap_or(""))?;
+        if value.len() != size {
+            return Err("bad register size".to_string());
+        }
+        let mut bytes = self.register_bytes();
+        bytes[offset..offset + size].copy_from_slice(&value);
+        self.set_register_bytes(&bytes);
+        Ok("OK".to_string())
+    }
+
+    fn read_memory(&mut self, args: &str) -> Result<String, String> {
+        let (addr, len) = parse_range(args)?;
+        let len = len.min(PACKET_SIZE / 2);
+        let bytes: Vec<u8> = (0..len)
+            .map(|i| self.cpu.bus.peek(addr.wrapping_add(i as u16)))
+            .collect();
+        Ok(hex::encode(bytes))
+    }
+
+    fn write_memory(&mut self, args: &str) -> Result<String, String> {
+        let mut parts = args.splitn(2, ':');
+        let (addr, len) = parse_range(parts.next().unwrap_or(""))?;
+        let bytes = decode_hex(parts.next().unwrap_or(""))?;
+        if bytes.len() != len {
+            return Err("length mismatch".to_string());
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            self.cpu.bus.poke(addr.wrapping_add(i as u16), *byte);
+        }
+        Ok("OK".to_string())
+    }
+
+    /// Z0/Z1 (software and hardware breakpoints) are the same thing here,
+    /// watchpoints are not supported
+    fn breakpoint(&mut self, args: &str, insert: bool) -> Result<String, String> {
+        let mut parts = args.splitn(2, ',');
+        match parts.next() {
+            Some("0") | Some("1") => {}
+            _ => return Ok(String::new()),
+        }
+        let (addr, _kind) = parse_range(parts.next().unwrap_or(""))?;
+        if insert {
+            self.breakpoints.insert(addr);
+        } else {
+            self.breakpoints.remove(&addr);
+        }
+        Ok("OK".to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::bus::Bus;
+    use crate::input;
+    use crate::ppu::ppu::NesPPU;
+    use crate::rom::Rom;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    // NMI handler at $c00d, its RTI at $c00f
+    const SOURCE: &str = "
+            .org $c000
+    reset:  LDX #$00
+    loop:   LDA #$80
+            STA $2000
+            INX
+            STX $10
+            JMP loop
+    nmi:    INC $11
+            RTI
+            .org $fffa
+            .word nmi, reset, nmi
+    ";
+
+    fn nrom(source: &str) -> Rom {
+        let asm = assemble(source).unwrap();
+        let mut data = vec![
+            0x4e, 0x45, 0x53, 0x1a, 0x01, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        data.extend(&asm.bytes);
+        data.extend(vec![0; 0x2000]);
+        Rom::load(&data).unwrap()
+    }
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.reader.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn reply(&mut self) -> String {
+            let mut byte = self.byte();
+            while byte == b'+' {
+                byte = self.byte();
+            }
+            assert_eq!(byte as char, '$');
+            let mut data = vec![];
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let data = String::from_utf8(data).unwrap();
+            let sum = [self.byte(), self.byte()];
+            assert_eq!(
+                u8::from_str_radix(std::str::from_utf8(&sum).unwrap(), 16).unwrap(),
+                checksum(&data)
+            );
+            data
+        }
+
+        fn send(&mut self, packet: &str) {
+            write!(self.writer, "${}#{:02x}", packet, checksum(packet)).unwrap();
+        }
+
+        fn request(&mut self, packet: &str) -> String {
+            self.send(packet);
+            self.reply()
+        }
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let bus = Bus::<NesPPU>::new(nrom(SOURCE), |_: &NesPPU, _: &mut input::Joypad| {});
+            let mut cpu = CPU::new(bus);
+            cpu.power_on();
+            let (stream, _) = listener.accept().unwrap();
+            serve(&mut cpu, stream).unwrap();
+        });
+        let writer = TcpStream::connect(addr).unwrap();
+        writer
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut client = Client {
+            reader: BufReader::new(writer.try_clone().unwrap()),
+            writer,
+        };
+
+        assert!(client
+            .request("qSupported:swbreak+;xmlRegisters=i386")
+            .contains("qXfer:features:read+"));
+        assert_eq!(client.request("QStartNoAckMode"), "OK");
+        assert_eq!(client.request("?"), "S05");
+        assert_eq!(client.request("g"), "00000024fd00c0");
+        let xml = client.request("qXfer:features:read:target.xml:0,a");
+        assert_eq!(xml, "m<?xml vers");
+        let xml = client.request("qXfer:features:read:target.xml:a,1000");
+        assert!(xml.starts_with('l') && xml.ends_with("</target>\n"));
+        let xml = client.request("qXfer:features:read:target.xml:0,ffffffffffffffff");
+        assert!(xml.starts_with("l<?xml") && xml.ends_with("</target>\n"));
+
+        // inside the NMI handler: vblank is set, and stays set however many times gdb reads it
+        assert_eq!(client.request("Z0,c00f,1"), "OK");
+        client.send("c");
+        assert_eq!(client.reply(), "S05");
+        assert_eq!(client.request("p5"), "0fc0");
+        assert_eq!(client.request("m2002,1"), "80");
+        assert_eq!(client.request("m3ffa,1"), "80");
+        assert_eq!(client.request("m2002,1"), "80");
+        assert_eq!(client.request("mc000,4"), "a200a980");
+
+        // step out of the handler, breakpoints are not hit twice in a row
+        assert_eq!(client.request("s"), "S05");
+        let pc = client.request("p5");
+        assert_ne!(pc, "0fc0");
+        assert_eq!(client.request("z0,c00f,1"), "OK");
+        assert_eq!(client.request("Z0,c007,1"), "OK");
+        assert_eq!(client.request("vCont;c"), "S05");
+        assert_eq!(client.request("p5"), "07c0");
+        assert_eq!(client.request("vCont;s:1"), "S05");
+        assert_eq!(client.request("p5"), "08c0");
+        assert_eq!(client.request("z0,c007,1"), "OK");
+
+        // registers and memory
+        assert_eq!(client.request("P0=42"), "OK");
+        assert_eq!(client.request("p0"), "42");
+        assert_eq!(client.request("P5=0c0"), "E01");
+        assert_eq!(client.request("G11223324f902c0"), "OK");
+        assert_eq!(client.request("g"), "11223324f902c0");
+        assert_eq!(client.request("M0300,2:abcd"), "OK");
+        assert_eq!(client.request("m0b00,3"), "abcd00");
+        assert_eq!(client.request("Mc000,1:ea"), "OK");
+        assert_eq!(client.request("mc000,2"), "ea00");
+        assert_eq!(client.request("Z2,0300,1"), "");
+        assert_eq!(client.request("m10000,1"), "E01");
+        assert_eq!(client.request("M1c000,1:ea"), "E01");
+        assert_eq!(client.request("Z0,1c007,1"), "E01");
+        assert_eq!(client.request("mc000,1"), "ea");
+        assert_eq!(client.request("qThreadExtraInfo,1"), "");
+
+        // Ctrl-C
+        client.send("c");
+        thread::sleep(Duration::from_millis(50));
+        client.writer.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(client.reply(), "S02");
+
+        assert_eq!(client.request("D"), "OK");
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+// What led to the current instruction: the shadow call stack and the last instructions executed.
+// It stays on for the whole run, so recording has to be cheap: an instruction is kept as its bytes
+// and the registers before it, and only formatted when the history is printed.
+//
+// `run` wraps `CPU::run_until` and prints the history to stderr when emulation aborts:
+// the CPU jams, or the emulator panics (e.g. a write to an unmapped address).
+use crate::bus::CpuBus;
+use crate::cpu::cpu::{HaltReason, Registers, CPU};
+use crate::cpu::opscode::OPSCODES_MAP;
+use crate::debugger::calls::CallStack;
+use crate::disasm;
+use crate::symbols::Symbols;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Trace lines kept by default
+pub const DEFAULT_LENGTH: usize = 32;
+
+struct Entry {
+    registers: Registers,
+    bytes: [u8; 3],
+    cycles: usize,
+    scanline: usize,
+}
+
+pub struct History {
+    pub calls: CallStack,
+    entries: VecDeque<Entry>,
+    length: usize,
+}
+
+impl History {
+    /// Keeps the last `length` instructions
+    pub fn new(length: usize) -> Self {
+        History {
+            calls: CallStack::new(),
+            entries: VecDeque::with_capacity(length),
+            length,
+        }
+    }
+
+    /// Must be called before every instruction
+    pub fn update<B: CpuBus>(&mut self, cpu: &CPU<B>) {
+        self.calls.update(cpu);
+        if self.entries.len() == self.length {
+            self.entries.pop_front();
+        }
+        let pc = cpu.program_counter;
+        let trace = cpu.bus.trace();
+        self.entries.push_back(Entry {
+            registers: cpu.registers(),
+            bytes: [
+                cpu.bus.peek(pc),
+                cpu.bus.peek(pc.wrapping_add(1)),
+                cpu.bus.peek(pc.wrapping_add(2)),
+            ],
+            cycles: trace.cpu_cycles,
+            scanline: trace.ppu_scanline,
+        });
+    }
+
+    /// Oldest first, the last line is the instruction that was executing:
+    /// `C000  4C F5 C5  JMP $c5f5         A:00 X:00 Y:00 P:24 SP:FD CYC:7 SL:0`
+    pub fn trace<B: CpuBus>(&self, cpu: &CPU<B>, symbols: &Symbols) -> Vec<String> {
+        self.entries
+            .iter()
+            .map(|entry| {
+                let Registers { a, x, y, sp, pc, p } = entry.registers;
+                let text = disasm::instruction_with_symbols(&entry.bytes, pc, symbols, &cpu.bus);
+                let len = OPSCODES_MAP[&entry.bytes[0]].len as usize;
+                let hex: Vec<String> = entry.bytes[..len]
+                    .iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect();
+                format!(
+                    "{:04X}  {:<8}  {:<24} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{} SL:{}",
+                    pc,
+                    hex.join(" "),
+                    text,
+                    a,
+                    x,
+                    y,
+                    p.bits(),
+                    sp,
+                    entry.cycles,
+                    entry.scanline
+                )
+            })
+            .collect()
+    }
+
+    /// Backtrace of the instruction recorded last, with labels
+    pub fn backtrace<B: CpuBus>(&self, cpu: &CPU<B>, symbols: &Symbols) -> Vec<String> {
+        let pc = match self.entries.back() {
+            Some(entry) => entry.registers.pc,
+            None => cpu.program_counter,
+        };
+        self.calls.backtrace(
+            pc,
+            &|addr| match symbols.label(addr, cpu.bus.prg_rom_offset(addr)) {
+                Some(label) => format!("${:04x} <{}>", addr, label),
+                None => format!("${:04x}", addr),
+            },
+        )
+    }
+
+    pub fn report<B: CpuBus>(&self, cpu: &CPU<B>, symbols: &Symbols) -> String {
+        let mut lines = vec!["backtrace:".to_string()];
+        lines.extend(self.backtrace(cpu, symbols));
+        lines.push(format!("last {} instructions:", self.entries.len()));
+        lines.extend(self.trace(cpu, symbols));
+        lines.join("\n")
+    }
+}
+
+/// `CPU::run_until`, recording the history before every instruction. If the CPU jams or the
+/// emulator panics, the history is printed to stderr; the panic then goes on unwinding.
+pub fn run<B, F>(
+    cpu: &mut CPU<B>,
+    history: &mut History,
+    symbols: &Symbols,
+    mut predicate: F,
+) -> HaltReason
+where
+    B: CpuBus,
+    F: FnMut(&mut CPU<B>) -> bool,
+{
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        cpu.run_until(|cpu| {
+            history.update(cpu);
+            predicate(cpu)
+        })
+    }));
+    match result {
+        Ok(halt @ HaltReason::Jam { .. }) => {
+            eprintln!("{}", history.report(cpu, symbols));
+            halt
+        }
+        Ok(halt) => halt,
+        Err(panic) => {
+            eprintln!("{}", history.report(cpu, symbols));
+            panic::resume_unwind(panic)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::{BusTrace, MockBus};
+    use crate::cpu::cpu::transform;
+    use crate::cpu::mem::Mem;
+
+    #[test]
+    fn test_keeps_the_last_instructions() {
+        let mut cpu = CPU::new(MockBus::new());
+        // JSR $0610 / $0610: LDA #$01; LDX #$02; INY; INY
+        cpu.load(&transform("20 10 06"), 0x0600);
+        cpu.load(&transform("a9 01 a2 02 c8 c8"), 0x0610);
+        cpu.program_counter = 0x0600;
+        let mut symbols = Symbols::new();
+        symbols.add_nl("$0610#Setup#", None).unwrap();
+
+        let mut history = History::new(3);
+        cpu.run_until(|cpu| {
+            history.update(cpu);
+            cpu.program_counter == 0x0615
+        });
+        // the predicate saw $0615 too, before the second INY
+        let trace = history.trace(&cpu, &symbols);
+        assert_eq!(trace.len(), 3);
+        assert!(
+            trace[0].starts_with("0612  A2 02     LDX #$02 "),
+            "{:?}",
+            trace
+        );
+        assert!(trace[0].contains(" A:01 X:00 "), "{:?}", trace);
+        assert!(trace[1].starts_with("0614  C8        INY "), "{:?}", trace);
+        assert!(trace[2].contains(" Y:01 P:24 SP:FB "), "{:?}", trace);
+        assert_eq!(
+            history.backtrace(&cpu, &symbols),
+            vec!["#0  $0615 in $0610 <Setup>", "#1  $0600"]
+        );
+    }
+
+    #[test]
+    fn test_report_on_panic() {
+        struct Faulty(MockBus);
+        impl Mem for Faulty {
+            fn read(&mut self, addr: u16) -> u8 {
+                self.0.read(addr)
+            }
+            fn write(&mut self, addr: u16, data: u8) {
+                if addr == 0x5000 {
+                    unimplemented!("attempting to write to {:x}", addr);
+                }
+                self.0.write(addr, data)
+            }
+        }
+        impl CpuBus for Faulty {
+            fn poll_nmi_status(&mut self) -> Option<u8> {
+                self.0.poll_nmi_status()
+            }
+            fn poll_irq_status(&self) -> bool {
+                self.0.poll_irq_status()
+            }
+            fn tick(&mut self, cycles: u8) {
+                self.0.tick(cycles)
+            }
+            fn trace(&self) -> BusTrace {
+                self.0.trace()
+            }
+            fn peek(&self, addr: u16) -> u8 {
+                self.0.peek(addr)
+            }
+            fn poke(&mut self, addr: u16, data: u8) {
+                self.0.poke(addr, data)
+            }
+        }
+
+        let mut cpu = CPU::new(Faulty(MockBus::new()));
+        cpu.load(&transform("20 10 06"), 0x0600);
+        cpu.load(&transform("8d 00 50"), 0x0610);
+        cpu.program_counter = 0x0600;
+        let mut history = History::new(DEFAULT_LENGTH);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run(&mut cpu, &mut history, &Symbols::new(), |_| false)
+        }));
+        assert!(result.is_err());
+        let report = history.report(&cpu, &Symbols::new());
+        assert!(
+            report.contains("#0  $0610 in $0610\n#1  $0600"),
+            "{}",
+            report
+        );
+        let last = report.lines().last().unwrap();
+        assert!(last.starts_with("0610  8D 00 50  STA $5000 "), "{}", report);
+    }
+}
@@ -0,0 +1,973 @@
+// Debugger console: breakpoints, watchpoints, stepping, register/memory editing and disassembly.
+// It hooks into `CPU::run_until`, so the same console drives the headless binary and the SDL frontend:
+//
+// cpu.run_until(|cpu| match debugger.check(cpu) {
+//     Some(stop) => !debugger.prompt(cpu, stop, &mut input, &mut output),
+//     None => false,
+// });
+use crate::bus::{BusTrace, CpuBus};
+use crate::cdl::ReadKind;
+use crate::cpu::cpu::{CpuFlags, Registers, CPU};
+use crate::cpu::mem::Mem;
+use crate::cpu::opscode::OPSCODES_MAP;
+use crate::disasm::Disasm;
+use crate::symbols::Symbols;
+use calls::CallStack;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::io::{BufRead, Write};
+
+pub mod calls;
+pub mod dap;
+pub mod gdb;
+pub mod history;
+pub mod profiler;
+
+const HELP: &str = "\
+break <addr>              stop before executing the instruction at <addr> (b)
+delete <addr>             remove a breakpoint or a watchpoint
+watch <addr>[-<end>] [r|w|rw]
+                          stop after an instruction reads or writes memory (default: w)
+info                      list breakpoints and watchpoints
+step [n]                  execute n instructions (s)
+next                      step over subroutine calls (n)
+finish                    run until the current subroutine returns
+backtrace                 show the subroutines and interrupt handlers being executed (bt)
+scanline                  run until the PPU moves to the next scanline
+frame                     run until the next frame starts
+continue                  run until a breakpoint or a watchpoint is hit (c)
+regs                      show registers (r)
+reg <a|x|y|sp|pc|p> <value>
+                          edit a register
+mem <addr> [len]          dump memory (m)
+write <addr> <byte>...    edit memory
+disasm [addr] [count]     disassemble, around PC by default (d)
+quit                      stop the emulation (q)
+Numbers are hex, with an optional $ or 0x prefix. An empty line repeats the last command.";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// Memory access that triggered a watchpoint
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Access {
+    pub kind: AccessKind,
+    pub addr: u16,
+    pub value: u8,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    fn matches(&self, kind: AccessKind, addr: u16) -> bool {
+        let kind_matches = match kind {
+            AccessKind::Read => self.read,
+            AccessKind::Write => self.write,
+        };
+        kind_matches && (self.start..=self.end).contains(&addr)
+    }
+}
+
+/// Bus wrapper that records CPU accesses hitting a watchpoint.
+/// The debugger reads and edits memory through `inner`, which doesn't trigger watchpoints.
+pub struct DebugBus<B: CpuBus> {
+    pub inner: B,
+    pub watchpoints: Vec<Watchpoint>,
+    hit: Option<Access>,
+}
+
+impl<B: CpuBus> DebugBus<B> {
+    pub fn new(inner: B) -> Self {
+        DebugBus {
+            inner,
+            watchpoints: vec![],
+            hit: None,
+        }
+    }
+
+    /// First watched access since the last call
+    pub fn take_hit(&mut self) -> Option<Access> {
+        self.hit.take()
+    }
+
+    fn watch(&mut self, kind: AccessKind, addr: u16, value: u8) {
+        if self.hit.is_none() && self.watchpoints.iter().any(|w| w.matches(kind, addr)) {
+            self.hit = Some(Access { kind, addr, value });
+        }
+    }
+}
+
+impl<B: CpuBus> Mem for DebugBus<B> {
+    fn write(&mut self, pos: u16, data: u8) {
+        self.inner.write(pos, data);
+        self.watch(AccessKind::Write, pos, data);
+    }
+
+    fn read(&mut self, pos: u16) -> u8 {
+        let data = self.inner.read(pos);
+        self.watch(AccessKind::Read, pos, data);
+        data
+    }
+}
+
+impl<B: CpuBus> CpuBus for DebugBus<B> {
+    fn poll_nmi_status(&mut self) -> Option<u8> {
+        self.inner.poll_nmi_status()
+    }
+
+    fn poll_irq_status(&self) -> bool {
+        self.inner.poll_irq_status()
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.inner.tick(cycles)
+    }
+
+    fn trace(&self) -> BusTrace {
+        self.inner.trace()
+    }
+
+    fn power_on(&mut self) {
+        self.inner.power_on()
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset()
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.inner.peek(addr)
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        self.inner.poke(addr, data)
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.inner.prg_rom_offset(addr)
+    }
+
+    fn next_read(&mut self, kind: ReadKind) {
+        self.inner.next_read(kind)
+    }
+    fn take_stall_cycles(&mut self) -> usize {
+        self.inner.take_stall_cycles()
+    }
+}
+
+/// Why the debugger took control
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Stop {
+    /// Pause requested, or a step/scanline/frame command completed
+    Pause,
+    Breakpoint(u16),
+    /// `pc` is the address of the instruction that made the access
+    Watchpoint {
+        pc: u16,
+        access: Access,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Run {
+    Pause,
+    Continue,
+    /// Instructions left to execute
+    Steps(usize),
+    /// Until the PPU leaves this scanline
+    Scanline(usize),
+    Frame,
+    /// Until the call stack is back to this depth: steps over JSR
+    Over(usize),
+    /// Until the call stack is shallower than this depth, or until RTS/RTI at depth 0
+    Out(usize),
+}
+
+/// Decoded instruction, for disassembly views
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    /// `LDA PlayerX,Y`
+    pub text: String,
+}
+
+/// Result of a console command
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reply {
+    Output(String),
+    Resume,
+    Quit,
+}
+
+pub struct Debugger {
+    pub symbols: Symbols,
+    pub calls: CallStack,
+    breakpoints: BTreeSet<u16>,
+    run: Run,
+    /// Cycle count at the last check: the instruction boundary execution resumes from
+    /// must not stop again, whether the caller checks it twice or not
+    last_cycles: Option<usize>,
+    last_pc: u16,
+    last_scanline: usize,
+    last_command: String,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    /// Starts paused: the first `check` hands control to the user
+    pub fn new() -> Self {
+        Debugger {
+            symbols: Symbols::new(),
+            calls: CallStack::new(),
+            breakpoints: BTreeSet::new(),
+            run: Run::Pause,
+            last_cycles: None,
+            last_pc: 0,
+            last_scanline: 0,
+            last_command: String::new(),
+        }
+    }
+
+    /// Stops before the next instruction
+    pub fn pause(&mut self) {
+        self.run = Run::Pause;
+    }
+
+    /// Runs until a breakpoint or a watchpoint is hit
+    pub fn resume(&mut self) {
+        self.run = Run::Continue;
+    }
+
+    pub fn step(&mut self) {
+        self.run = Run::Steps(1);
+    }
+
+    /// Steps over subroutine calls
+    pub fn step_over(&mut self) {
+        self.run = Run::Over(self.calls.depth());
+    }
+
+    /// Runs until the current subroutine returns
+    pub fn step_out(&mut self) {
+        self.run = Run::Out(self.calls.depth());
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Replaces all breakpoints
+    pub fn set_breakpoints<I: IntoIterator<Item = u16>>(&mut self, addrs: I) {
+        self.breakpoints = addrs.into_iter().collect();
+    }
+
+    /// Must be called before every instruction. Returns the reason to stop, if any.
+    pub fn check<B: CpuBus>(&mut self, cpu: &mut CPU<DebugBus<B>>) -> Option<Stop> {
+        let pc = cpu.program_counter;
+        let scanline = cpu.bus.trace().ppu_scanline;
+        if self.run != Run::Pause && self.last_cycles == Some(cpu.cycles()) {
+            return None;
+        }
+        self.calls.update(cpu);
+
+        let stop = if let Some(access) = cpu.bus.take_hit() {
+            Some(Stop::Watchpoint {
+                pc: self.last_pc,
+                access,
+            })
+        } else if self.breakpoints.contains(&pc) {
+            Some(Stop::Breakpoint(pc))
+        } else {
+            let done = match self.run {
+                Run::Pause => true,
+                Run::Continue => false,
+                Run::Steps(n) => {
+                    self.run = Run::Steps(n - 1);
+                    n <= 1
+                }
+                Run::Scanline(line) => scanline != line,
+                Run::Frame => scanline < self.last_scanline,
+                Run::Over(depth) => self.calls.depth() <= depth,
+                Run::Out(0) => self.calls.returned(),
+                Run::Out(depth) => self.calls.depth() < depth,
+            };
+            if done {
+                Some(Stop::Pause)
+            } else {
+                None
+            }
+        };
+        if stop.is_some() {
+            self.run = Run::Pause;
+        }
+        self.last_cycles = Some(cpu.cycles());
+        self.last_pc = pc;
+        self.last_scanline = scanline;
+        stop
+    }
+
+    /// Reads and executes commands until one of them resumes the emulation.
+    /// Returns false if the user wants to quit.
+    pub fn prompt<B: CpuBus>(
+        &mut self,
+        cpu: &mut CPU<DebugBus<B>>,
+        stop: Stop,
+        input: &mut dyn BufRead,
+        output: &mut dyn Write,
+    ) -> bool {
+        match stop {
+            Stop::Pause => {}
+            Stop::Breakpoint(addr) => {
+                let _ = writeln!(output, "breakpoint at {}", self.address(cpu, addr));
+            }
+            Stop::Watchpoint { pc, access } => {
+                let kind = match access.kind {
+                    AccessKind::Read => "read",
+                    AccessKind::Write => "write",
+                };
+                let _ = writeln!(
+                    output,
+                    "watchpoint: {} {} = ${:02x} by the instruction at {}",
+                    kind,
+                    self.address(cpu, access.addr),
+                    access.value,
+                    self.address(cpu, pc)
+                );
+            }
+        }
+        let _ = writeln!(output, "{}", self.current_line(cpu));
+
+        let resume = loop {
+            let _ = write!(output, "> ");
+            let _ = output.flush();
+            let mut line = String::new();
+            match input.read_line(&mut line) {
+                Ok(0) | Err(_) => break false,
+                Ok(_) => {}
+            }
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => line.to_string(),
+            };
+            self.last_command = line.clone();
+            match self.execute(cpu, &line) {
+                Ok(Reply::Output(text)) => {
+                    let _ = writeln!(output, "{}", text);
+                }
+                Ok(Reply::Resume) => break true,
+                Ok(Reply::Quit) => break false,
+                Err(e) => {
+                    let _ = writeln!(output, "error: {}", e);
+                }
+            }
+        };
+        resume
+    }
+
+    /// Executes a single console command
+    pub fn execute<B: CpuBus>(
+        &mut self,
+        cpu: &mut CPU<DebugBus<B>>,
+        line: &str,
+    ) -> Result<Reply, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(Reply::Output(String::new())),
+        };
+        match (command, args) {
+            ("help", _) | ("h", _) | ("?", _) => Ok(Reply::Output(HELP.to_string())),
+            ("break", [addr]) | ("b", [addr]) => {
+                let addr = parse_u16(addr)?;
+                self.breakpoints.insert(addr);
+                Ok(Reply::Output(format!(
+                    "breakpoint at {}",
+                    self.address(cpu, addr)
+                )))
+            }
+            ("delete", [addr]) => {
+                let addr = parse_u16(addr)?;
+                let watchpoints = cpu.bus.watchpoints.len();
+                cpu.bus.watchpoints.retain(|w| w.start != addr);
+                if !self.breakpoints.remove(&addr) && watchpoints == cpu.bus.watchpoints.len() {
+                    return Err(format!("nothing to delete at ${:04x}", addr));
+                }
+                Ok(Reply::Output(String::new()))
+            }
+            ("watch", [range]) | ("watch", [range, _]) => {
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (parse_u16(start)?, parse_u16(end)?),
+                    None => (parse_u16(range)?, parse_u16(range)?),
+                };
+                if end < start {
+                    return Err(format!("empty range {}", range));
+                }
+                let (read, write) = match args.get(1) {
+                    None | Some(&"w") => (false, true),
+                    Some(&"r") => (true, false),
+                    Some(&"rw") => (true, true),
+                    Some(kind) => return Err(format!("unknown access kind {}", kind)),
+                };
+                cpu.bus.watchpoints.push(Watchpoint {
+                    start,
+                    end,
+                    read,
+                    write,
+                });
+                Ok(Reply::Output(String::new()))
+            }
+            ("info", []) => Ok(Reply::Output(self.info(cpu))),
+            ("step", []) | ("s", []) => self.start(Run::Steps(1)),
+            ("step", [n]) | ("s", [n]) => match parse_u16(n)? {
+                0 => Err("nothing to step".to_string()),
+                n => self.start(Run::Steps(n as usize)),
+            },
+            ("next", []) | ("n", []) => self.start(Run::Over(self.calls.depth())),
+            ("finish", []) => self.start(Run::Out(self.calls.depth())),
+            ("backtrace", []) | ("bt", []) => Ok(Reply::Output(
+                self.calls
+                    .backtrace(cpu.program_counter, &|addr| self.address(cpu, addr))
+                    .join("\n"),
+            )),
+            ("scanline", []) => self.start(Run::Scanline(cpu.bus.trace().ppu_scanline)),
+            ("frame", []) => self.start(Run::Frame),
+            ("continue", []) | ("c", []) => self.start(Run::Continue),
+            ("regs", []) | ("r", []) => Ok(Reply::Output(registers(cpu))),
+            ("reg", [name, value]) => {
+                let mut edited = cpu.registers();
+                let value = parse_u16(value)?;
+                let byte =
+                    || u8::try_from(value).map_err(|_| format!("${:x} is not a byte", value));
+                match *name {
+                    "a" => edited.a = byte()?,
+                    "x" => edited.x = byte()?,
+                    "y" => edited.y = byte()?,
+                    "sp" => edited.sp = byte()?,
+                    "p" => edited.p = CpuFlags::from_bits_truncate(byte()?),
+                    "pc" => edited.pc = value,
+                    _ => return Err(format!("unknown register {}", name)),
+                }
+                cpu.set_registers(edited);
+                Ok(Reply::Output(registers(cpu)))
+            }
+            ("mem", [addr]) | ("m", [addr]) => Ok(Reply::Output(dump(cpu, parse_u16(addr)?, 0x40))),
+            ("mem", [addr, len]) | ("m", [addr, len]) => Ok(Reply::Output(dump(
+                cpu,
+                parse_u16(addr)?,
+                parse_u16(len)? as usize,
+            ))),
+            ("write", [addr, bytes @ ..]) if !bytes.is_empty() => {
+                let addr = parse_u16(addr)?;
+                for (i, byte) in bytes.iter().enumerate() {
+                    let byte = u8::try_from(parse_u16(byte)?)
+                        .map_err(|_| format!("{} is not a byte", byte))?;
+                    cpu.bus.poke(addr.wrapping_add(i as u16), byte);
+                }
+                Ok(Reply::Output(dump(cpu, addr, bytes.len())))
+            }
+            ("disasm", []) | ("d", []) => Ok(Reply::Output(
+                self.disassembly(cpu, cpu.program_counter, 12).join("\n"),
+            )),
+            ("disasm", [addr]) | ("d", [addr]) => Ok(Reply::Output(
+                self.disassembly(cpu, parse_u16(addr)?, 12).join("\n"),
+            )),
+            ("disasm", [addr, count]) | ("d", [addr, count]) => {
+                let count = parse_u16(count)? as usize;
+                Ok(Reply::Output(
+                    self.disassembly(cpu, parse_u16(addr)?, count).join("\n"),
+                ))
+            }
+            ("quit", []) | ("q", []) => Ok(Reply::Quit),
+            _ => Err(format!("can't parse '{}', try 'help'", line)),
+        }
+    }
+
+    /// Disassembles `count` instructions around `addr`: a few before it, the rest after.
+    /// The current instruction is marked with `=>`.
+    pub fn disassembly<B: CpuBus>(
+        &self,
+        cpu: &mut CPU<DebugBus<B>>,
+        addr: u16,
+        count: usize,
+    ) -> Vec<String> {
+        self.instructions(cpu, addr, count / 3, count)
+            .iter()
+            .map(|i| {
+                let marker = if i.addr == addr { "=>" } else { "  " };
+                format!("{} {:04x}: {}", marker, i.addr, i.text)
+            })
+            .collect()
+    }
+
+    /// Decodes up to `before` instructions preceding `addr`, then the ones from `addr` on,
+    /// `count` in total
+    pub fn instructions<B: CpuBus>(
+        &self,
+        cpu: &mut CPU<DebugBus<B>>,
+        addr: u16,
+        before: usize,
+        count: usize,
+    ) -> Vec<Instruction> {
+        // 6502 code can't be decoded backwards: pick the farthest start that decodes into `addr`
+        let start = (1..=before * 3)
+            .rev()
+            .map(|back| addr.saturating_sub(back as u16))
+            .find(|start| decodes_to(cpu, *start, addr))
+            .unwrap_or(addr);
+        let end = (addr as usize + count * 3).min(0x10000);
+
+        // Disasm takes addresses as offsets in the program
+        let mut image = vec![0; end];
+        for (pos, byte) in image.iter_mut().enumerate().skip(start as usize) {
+            *byte = cpu.bus.peek(pos as u16);
+        }
+        let disasm = Disasm::with_symbols(&image, start as usize, &self.symbols, &cpu.bus);
+        let index = disasm.ops_index_map[&addr];
+        let first = index.saturating_sub(before);
+        disasm.program[first..]
+            .iter()
+            .zip(disasm.hex_dump[first..].iter())
+            .take(count)
+            .map(|(line, bytes)| {
+                // "c000: LDA #$10"
+                let (addr, text) = line.split_at(4);
+                Instruction {
+                    addr: u16::from_str_radix(addr, 16).unwrap(),
+                    bytes: bytes.clone(),
+                    text: text[2..].to_string(),
+                }
+            })
+            .collect()
+    }
+
+    fn start(&mut self, run: Run) -> Result<Reply, String> {
+        self.run = run;
+        Ok(Reply::Resume)
+    }
+
+    fn current_line<B: CpuBus>(&self, cpu: &mut CPU<DebugBus<B>>) -> String {
+        crate::cpu::trace_with_symbols(cpu, &self.symbols)
+    }
+
+    fn info<B: CpuBus>(&self, cpu: &CPU<DebugBus<B>>) -> String {
+        let mut lines = vec![];
+        for addr in self.breakpoints.iter() {
+            lines.push(format!("breakpoint {}", self.address(cpu, *addr)));
+        }
+        for w in cpu.bus.watchpoints.iter() {
+            let kind = match (w.read, w.write) {
+                (true, true) => "rw",
+                (true, false) => "r",
+                _ => "w",
+            };
+            if w.start == w.end {
+                lines.push(format!("watchpoint ${:04x} {}", w.start, kind));
+            } else {
+                lines.push(format!(
+                    "watchpoint ${:04x}-${:04x} {}",
+                    w.start, w.end, kind
+                ));
+            }
+        }
+        if lines.is_empty() {
+            "no breakpoints or watchpoints".to_string()
+        } else {
+            lines.join("\n")
+        }
+    }
+
+    /// `$c3a1`, followed by its label if there is one
+    fn address<B: CpuBus>(&self, cpu: &CPU<DebugBus<B>>, addr: u16) -> String {
+        match self.symbols.label(addr, cpu.bus.prg_rom_offset(addr)) {
+            Some(label) => format!("${:04x} <{}>", addr, label),
+            None => format!("${:04x}", addr),
+        }
+    }
+}
+
+fn registers<B: CpuBus>(cpu: &CPU<DebugBus<B>>) -> String {
+    let Registers { a, x, y, sp, pc, p } = cpu.registers();
+    let trace = cpu.bus.trace();
+    format!(
+        "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} P:{:02X} {} CYC:{} SL:{}",
+        pc,
+        a,
+        x,
+        y,
+        sp,
+        p.bits(),
+        flag_names(p),
+        trace.cpu_cycles,
+        trace.ppu_scanline
+    )
+}
+
+/// `Nv-bdIzC`: set flags in upper case
+fn flag_names(p: CpuFlags) -> String {
+    "NV-BDIZC"
+        .chars()
+        .enumerate()
+        .map(|(i, name)| {
+            if p.bits() & (0x80 >> i) != 0 {
+                name
+            } else {
+                name.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+/// Whether decoding instructions from `start` lands exactly on `end`
+fn decodes_to<B: CpuBus>(cpu: &mut CPU<DebugBus<B>>, start: u16, end: u16) -> bool {
+    let mut pos = start;
+    while pos < end {
+        let ops = OPSCODES_MAP[&cpu.bus.peek(pos)];
+        pos = match pos.checked_add(ops.len as u16) {
+            Some(next) => next,
+            None => return false,
+        };
+    }
+    pos == end
+}
+
+/// Hex dump, 16 bytes per line
+fn dump<B: CpuBus>(cpu: &mut CPU<DebugBus<B>>, addr: u16, len: usize) -> String {
+    let mut lines = vec![];
+    let mut pos = addr as usize;
+    let end = (addr as usize + len).min(0x10000);
+    while pos < end {
+        let line_end = (pos + 16).min(end);
+        let bytes: Vec<String> = (pos..line_end)
+            .map(|p| format!("{:02x}", cpu.bus.peek(p as u16)))
+            .collect();
+        lines.push(format!("{:04x}: {}", pos, bytes.join(" ")));
+        pos = line_end;
+    }
+    lines.join("\n")
+}
+
+fn parse_u16(text: &str) -> Result<u16, String> {
+    let digits = text
+        .strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("{} is not a hex number", text))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::bus::{Bus, MockBus};
+    use crate::input;
+    use crate::ppu::ppu::NesPPU;
+    use crate::rom::Rom;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    fn mock_cpu(source: &str) -> CPU<DebugBus<MockBus>> {
+        let asm = assemble(source).unwrap();
+        let mut cpu = CPU::new(DebugBus::new(MockBus::new()));
+        cpu.load(&asm.bytes, asm.origin);
+        cpu.program_counter = asm.origin;
+        cpu
+    }
+
+    fn nestest_cpu() -> CPU<DebugBus<Bus<'static, NesPPU>>> {
+        let data = std::fs::read("test_rom/nestest.nes").unwrap();
+        let bus = Bus::<NesPPU>::new(
+            Rom::load(&data).unwrap(),
+            |_: &NesPPU, _: &mut input::Joypad| {},
+        );
+        let mut cpu = CPU::new(DebugBus::new(bus));
+        cpu.power_on();
+        cpu
+    }
+
+    /// Runs until the debugger stops, returns the stop and the PC it stopped at
+    fn run<B: CpuBus>(debugger: &mut Debugger, cpu: &mut CPU<DebugBus<B>>) -> (Stop, u16) {
+        let mut stop = None;
+        cpu.run_until(|cpu| {
+            stop = debugger.check(cpu);
+            stop.is_some()
+        });
+        (stop.unwrap(), cpu.program_counter)
+    }
+
+    fn execute<B: CpuBus>(
+        debugger: &mut Debugger,
+        cpu: &mut CPU<DebugBus<B>>,
+        line: &str,
+    ) -> String {
+        match debugger.execute(cpu, line) {
+            Ok(Reply::Output(text)) => text,
+            other => panic!("{}: {:?}", line, other),
+        }
+    }
+
+    const LOOP: &str = "
+        .org $0600
+        start:  LDX #$00
+        loop:   INX
+                STX $10
+                LDA $20
+                CPX #$05
+                BNE loop
+                BRK
+        ";
+
+    #[test]
+    fn test_starts_paused_and_steps() {
+        let mut debugger = Debugger::new();
+        let mut cpu = mock_cpu(LOOP);
+        assert_eq!(run(&mut debugger, &mut cpu), (Stop::Pause, 0x0600));
+        assert_eq!(debugger.execute(&mut cpu, "step"), Ok(Reply::Resume));
+        assert_eq!(run(&mut debugger, &mut cpu), (Stop::Pause, 0x0602));
+        assert_eq!(debugger.execute(&mut cpu, "s 3"), Ok(Reply::Resume));
+        assert_eq!(run(&mut debugger, &mut cpu), (Stop::Pause, 0x0607));
+    }
+
+    #[test]
+    fn test_step_over_and_out_of_subroutines() {
+        let source = "
+            .org $0600
+            start:  JSR sub
+                    LDX #$01
+                    BRK
+            sub:    JSR inner
+                    RTS
+            inner:  LDA #$02
+                    RTS
+            ";
+        let mut debugger = Debugger::new();
+        let mut cpu = mock_cpu(source);
+        run(&mut debugger, &mut cpu);
+        debugger.execute(&mut cpu, "next").unwrap();
+        assert_eq!(run(&mut debugger, &mut cpu), (Stop::Pause, 0x0603));
+        assert_eq!(cpu.registers().a, 2);
+
+        let mut debugger = Debugger::new();
+        let mut cpu = mock_cpu(source);
+        run(&mut debugger, &mut cpu);
+        debugger.execute(&mut cpu, "s").unwrap();
+        assert_eq!(run(&mut debugger, &mut cpu), (Stop::Pause, 0x0606));
+        debugger.execute(&mut cpu, "s").unwrap();
+        assert_eq!(run(&mut debugger, &mut cpu), (Stop::Pause, 0x060a));
+        assert_eq!(debugger.calls.depth(), 2);
+        debugger.symbols.add_nl("$0606#sub#", None).unwrap();
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "bt"),
+            "#0  $060a in $060a\n#1  $0606 in $0606 <sub>\n#2  $0600"
+        );
+        debugger.execute(&mut cpu, "finish").unwrap();
+        assert_eq!(run(&mut debugger, &mut cpu), (Stop::Pause, 0x0609));
+        debugger.execute(&mut cpu, "finish").unwrap();
+        assert_eq!(run(&mut debugger, &mut cpu), (Stop::Pause, 0x0603));
+
+        // without a known caller, finish stops after the next RTS
+        let mut debugger = Debugger::new();
+        let mut cpu = mock_cpu(source);
+        cpu.program_counter = 0x060a;
+        run(&mut debugger, &mut cpu);
+        debugger.execute(&mut cpu, "finish").unwrap();
+        assert_eq!(run(&mut debugger, &mut cpu).0, Stop::Pause);
+        assert_eq!(debugger.calls.depth(), 0);
+        assert!(debugger.calls.returned());
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = Debugger::new();
+        let mut cpu = mock_cpu(LOOP);
+        run(&mut debugger, &mut cpu);
+        execute(&mut debugger, &mut cpu, "b $0605");
+        debugger.execute(&mut cpu, "c").unwrap();
+        assert_eq!(
+            run(&mut debugger, &mut cpu),
+            (Stop::Breakpoint(0x0605), 0x0605)
+        );
+        assert_eq!(cpu.registers().x, 1);
+
+        // resuming from a breakpoint doesn't hit it again right away
+        debugger.execute(&mut cpu, "c").unwrap();
+        assert_eq!(
+            run(&mut debugger, &mut cpu),
+            (Stop::Breakpoint(0x0605), 0x0605)
+        );
+        assert_eq!(cpu.registers().x, 2);
+
+        execute(&mut debugger, &mut cpu, "delete 605");
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "info"),
+            "no breakpoints or watchpoints"
+        );
+        assert!(debugger.execute(&mut cpu, "delete 605").is_err());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = Debugger::new();
+        let mut cpu = mock_cpu(LOOP);
+        run(&mut debugger, &mut cpu);
+        execute(&mut debugger, &mut cpu, "watch $10");
+        execute(&mut debugger, &mut cpu, "watch 18-20 r");
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "info"),
+            "watchpoint $0010 w\nwatchpoint $0018-$0020 r"
+        );
+
+        debugger.execute(&mut cpu, "c").unwrap();
+        let access = Access {
+            kind: AccessKind::Write,
+            addr: 0x10,
+            value: 1,
+        };
+        assert_eq!(
+            run(&mut debugger, &mut cpu),
+            (Stop::Watchpoint { pc: 0x0603, access }, 0x0605)
+        );
+
+        debugger.execute(&mut cpu, "c").unwrap();
+        let access = Access {
+            kind: AccessKind::Read,
+            addr: 0x20,
+            value: 0,
+        };
+        assert_eq!(
+            run(&mut debugger, &mut cpu),
+            (Stop::Watchpoint { pc: 0x0605, access }, 0x0607)
+        );
+
+        // the console's own reads don't trigger watchpoints
+        execute(&mut debugger, &mut cpu, "mem 10 20");
+        execute(&mut debugger, &mut cpu, "delete 10");
+        execute(&mut debugger, &mut cpu, "delete 18");
+        debugger.execute(&mut cpu, "s").unwrap();
+        assert_eq!(run(&mut debugger, &mut cpu), (Stop::Pause, 0x0609));
+    }
+
+    #[test]
+    fn test_edit_registers_and_memory() {
+        let mut debugger = Debugger::new();
+        let mut cpu = mock_cpu(LOOP);
+        run(&mut debugger, &mut cpu);
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "reg x 4"),
+            "PC:0600 A:00 X:04 Y:00 SP:FD P:24 nv-bdIzc CYC:0 SL:0"
+        );
+        execute(&mut debugger, &mut cpu, "reg pc 602");
+        execute(&mut debugger, &mut cpu, "reg p $a5");
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "r"),
+            "PC:0602 A:00 X:04 Y:00 SP:FD P:A5 Nv-bdIzC CYC:0 SL:0"
+        );
+        assert!(debugger.execute(&mut cpu, "reg a 100").is_err());
+        assert!(debugger.execute(&mut cpu, "reg q 1").is_err());
+
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "write 20 de ad be ef"),
+            "0020: de ad be ef"
+        );
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "m 1c 24"),
+            "001c: 00 00 00 00 de ad be ef 00 00 00 00 00 00 00 00\n002c: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n003c: 00 00 00 00"
+        );
+
+        // X = 4 already: the loop runs once and reads $beef through LDA
+        debugger.execute(&mut cpu, "s 3").unwrap();
+        run(&mut debugger, &mut cpu);
+        assert_eq!(cpu.registers().a, 0xde);
+    }
+
+    #[test]
+    fn test_disassembly_around_pc() {
+        let mut debugger = Debugger::new();
+        debugger
+            .symbols
+            .add_nl("$0602#loop#\n$0010#counter#", None)
+            .unwrap();
+        let mut cpu = mock_cpu(LOOP);
+        cpu.program_counter = 0x0609;
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "disasm $0609 6"),
+            [
+                "   0605: LDA $20",
+                "   0607: CPX #$05",
+                "=> 0609: BNE loop",
+                "   060b: BRK",
+                "   060c: BRK",
+                "   060d: BRK",
+            ]
+            .join("\n")
+        );
+        let view = execute(&mut debugger, &mut cpu, "disasm");
+        assert_eq!(view.lines().count(), 12);
+        assert!(view.starts_with("   0602: INX\n   0603: STX counter\n"));
+    }
+
+    #[test]
+    fn test_scanline_and_frame_steps() {
+        let mut debugger = Debugger::new();
+        let mut cpu = nestest_cpu();
+        run(&mut debugger, &mut cpu);
+
+        debugger.execute(&mut cpu, "scanline").unwrap();
+        run(&mut debugger, &mut cpu);
+        assert_eq!(cpu.bus.trace().ppu_scanline, 1);
+
+        debugger.execute(&mut cpu, "frame").unwrap();
+        run(&mut debugger, &mut cpu);
+        assert_eq!(cpu.bus.trace().ppu_scanline, 0);
+        let frame_start = cpu.bus.trace().cpu_cycles;
+
+        debugger.execute(&mut cpu, "frame").unwrap();
+        run(&mut debugger, &mut cpu);
+        assert_eq!(cpu.bus.trace().ppu_scanline, 0);
+        // 262 scanlines of 341 PPU dots, 3 dots per CPU cycle
+        let cycles = cpu.bus.trace().cpu_cycles - frame_start;
+        assert!((29775..29788).contains(&cycles), "{}", cycles);
+    }
+
+    #[test]
+    fn test_prompt_session() {
+        let mut debugger = Debugger::new();
+        let mut cpu = mock_cpu(LOOP);
+        let mut input = Cursor::new("b 609\nfoo\nc\ns\n\nregs\nq\n");
+        let mut output = vec![];
+        let halt = cpu.run_until(|cpu| match debugger.check(cpu) {
+            Some(stop) => !debugger.prompt(cpu, stop, &mut input, &mut output),
+            None => false,
+        });
+        assert_eq!(halt, crate::cpu::cpu::HaltReason::Breakpoint);
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            vec![
+                "0600  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
+                "> breakpoint at $0609",
+                "> error: can't parse 'foo', try 'help'",
+                "> breakpoint at $0609",
+                "0609  D0 F7     BNE $0602                       A:00 X:01 Y:00 P:A4 SP:FD PPU:  0,  0 CYC:12",
+                "> 0602  E8        INX                             A:00 X:01 Y:00 P:A4 SP:FD PPU:  0,  0 CYC:15",
+                "> 0603  86 10     STX $10 = 01                    A:00 X:02 Y:00 P:24 SP:FD PPU:  0,  0 CYC:17",
+                "> PC:0603 A:00 X:02 Y:00 SP:FD P:24 nv-bdIzc CYC:17 SL:0",
+                "> ",
+            ]
+        );
+    }
+}
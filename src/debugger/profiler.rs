@@ -0,0 +1,484 @@
+// Execution profiler: attributes CPU cycles to every instruction and to the subroutine it runs in.
+// Like the debugger, it looks at the CPU before every instruction through `CPU::run_until`:
+//
+// cpu.run_until(|cpu| {
+//     profiler.update(cpu);
+//     profiler.frames() == 600
+// });
+//
+// Subroutines are kept on a call stack of their own: JSR enters one, NMI, IRQ and BRK enter their handler,
+// and it is left as soon as SP climbs back above the return address: RTS, RTI, or code popping it by hand.
+// Cycles spent outside of any subroutine are counted against the reset handler.
+// Every distinct stack is a node of a call tree, which is what the folded stacks are written from.
+use crate::bus::CpuBus;
+use crate::cpu::cpu::{InterruptType, CPU};
+use crate::symbols::Symbols;
+use std::collections::HashMap;
+use std::io;
+
+const BRK: u8 = 0x00;
+const JSR: u8 = 0x20;
+
+/// CPU cycles per NTSC frame: 262 scanlines of 341 PPU dots, 3 dots per CPU cycle
+pub const FRAME_CYCLES: f64 = 262.0 * 341.0 / 3.0;
+/// CPU cycles from the NMI at the start of vblank until the PPU renders again
+pub const VBLANK_CYCLES: f64 = 20.0 * 341.0 / 3.0;
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct RoutineStats {
+    pub calls: usize,
+    /// Cycles from entry to exit, subroutines included
+    pub inclusive: usize,
+    /// Cycles of the routine's own instructions
+    pub exclusive: usize,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct NmiStats {
+    pub calls: usize,
+    pub cycles: usize,
+    /// Longest handler run, entry sequence included
+    pub max: usize,
+}
+
+/// Call tree node: a routine, called through the stack of its parents
+struct Node {
+    parent: Option<usize>,
+    routine: u16,
+    cycles: usize,
+}
+
+struct Call {
+    node: usize,
+    routine: u16,
+    nmi: bool,
+    /// SP before the return address was pushed. The reset handler has nothing to return to.
+    return_sp: u16,
+    start: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Boundary {
+    pc: u16,
+    opcode: u8,
+    sp: u8,
+    cycles: usize,
+    scanline: usize,
+}
+
+pub struct Profiler {
+    pc_cycles: Vec<usize>,
+    nodes: Vec<Node>,
+    children: HashMap<(usize, u16), usize>,
+    calls: HashMap<u16, usize>,
+    inclusive: HashMap<u16, usize>,
+    stack: Vec<Call>,
+    nmi: NmiStats,
+    frames: usize,
+    start: usize,
+    last: Option<Boundary>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            pc_cycles: vec![0; 0x10000],
+            nodes: Vec::new(),
+            children: HashMap::new(),
+            calls: HashMap::new(),
+            inclusive: HashMap::new(),
+            stack: Vec::new(),
+            nmi: NmiStats::default(),
+            frames: 0,
+            start: 0,
+            last: None,
+        }
+    }
+
+    /// Must be called before every instruction: the cycles since the previous call go to
+    /// the instruction that was about to execute then.
+    pub fn update<B: CpuBus>(&mut self, cpu: &CPU<B>) {
+        let trace = cpu.bus.trace();
+        let now = trace.cpu_cycles;
+        let sp = cpu.registers().sp;
+        let pc = cpu.program_counter;
+
+        match self.last {
+            None => {
+                self.start = now;
+                self.enter(pc, false, 0x100, now);
+            }
+            Some(last) => {
+                let mut executed = last.pc;
+                let mut opcode = last.opcode;
+                let mut sp_before = last.sp;
+                let serviced = cpu.serviced_interrupt();
+                if let Some((kind, handler)) = serviced {
+                    if last.opcode != BRK {
+                        // the interrupt sequence ran first, then the handler's first instruction
+                        self.enter(
+                            handler,
+                            kind == InterruptType::NMI,
+                            last.sp as u16,
+                            last.cycles,
+                        );
+                        executed = handler;
+                        opcode = cpu.bus.peek(handler);
+                        sp_before = last.sp.wrapping_sub(3);
+                    }
+                }
+
+                let cycles = now - last.cycles;
+                self.pc_cycles[executed as usize] += cycles;
+                let node = self.stack.last().expect("reset handler never returns").node;
+                self.nodes[node].cycles += cycles;
+
+                if opcode == JSR && sp == sp_before.wrapping_sub(2) {
+                    self.enter(pc, false, sp_before as u16, now);
+                } else if let (BRK, Some((kind, handler))) = (opcode, serviced) {
+                    self.enter(handler, kind == InterruptType::NMI, sp_before as u16, now);
+                }
+                while self
+                    .stack
+                    .last()
+                    .is_some_and(|call| sp as u16 >= call.return_sp)
+                {
+                    self.leave(now);
+                }
+                if trace.ppu_scanline < last.scanline {
+                    self.frames += 1;
+                }
+            }
+        }
+        self.last = Some(Boundary {
+            pc,
+            opcode: cpu.bus.peek(pc),
+            sp,
+            cycles: now,
+            scanline: trace.ppu_scanline,
+        });
+    }
+
+    fn enter(&mut self, routine: u16, nmi: bool, return_sp: u16, start: usize) {
+        let parent = self.stack.last().map(|call| call.node);
+        let nodes = &mut self.nodes;
+        let node = *self
+            .children
+            .entry((parent.unwrap_or(usize::MAX), routine))
+            .or_insert_with(|| {
+                nodes.push(Node {
+                    parent,
+                    routine,
+                    cycles: 0,
+                });
+                nodes.len() - 1
+            });
+        *self.calls.entry(routine).or_insert(0) += 1;
+        self.stack.push(Call {
+            node,
+            routine,
+            nmi,
+            return_sp,
+            start,
+        });
+    }
+
+    fn leave(&mut self, now: usize) {
+        let call = self.stack.pop().unwrap();
+        let cycles = now - call.start;
+        // a recursive call is already counted by the outer one
+        if self.stack.iter().all(|outer| outer.routine != call.routine) {
+            *self.inclusive.entry(call.routine).or_insert(0) += cycles;
+        }
+        if call.nmi {
+            self.nmi.calls += 1;
+            self.nmi.cycles += cycles;
+            self.nmi.max = self.nmi.max.max(cycles);
+        }
+    }
+
+    /// Number of frames rendered since profiling started
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Cycles profiled so far
+    pub fn cycles(&self) -> usize {
+        self.last.map_or(0, |last| last.cycles - self.start)
+    }
+
+    /// Cycles spent on the instruction at `addr`
+    pub fn pc_cycles(&self, addr: u16) -> usize {
+        self.pc_cycles[addr as usize]
+    }
+
+    /// NMI handler runs that have returned
+    pub fn nmi(&self) -> NmiStats {
+        self.nmi
+    }
+
+    /// All routines entered so far, by their entry address. Routines that are still running
+    /// count the cycles up to now.
+    pub fn routines(&self) -> HashMap<u16, RoutineStats> {
+        let mut routines: HashMap<u16, RoutineStats> = self
+            .calls
+            .iter()
+            .map(|(&routine, &calls)| {
+                let stats = RoutineStats {
+                    calls,
+                    inclusive: self.inclusive.get(&routine).copied().unwrap_or(0),
+                    exclusive: 0,
+                };
+                (routine, stats)
+            })
+            .collect();
+        for node in &self.nodes {
+            routines.get_mut(&node.routine).unwrap().exclusive += node.cycles;
+        }
+        let now = self.last.map_or(0, |last| last.cycles);
+        for (i, call) in self.stack.iter().enumerate() {
+            if self.stack[..i]
+                .iter()
+                .all(|outer| outer.routine != call.routine)
+            {
+                routines.get_mut(&call.routine).unwrap().inclusive += now - call.start;
+            }
+        }
+        routines
+    }
+
+    /// Summary of the run, the `top` hottest routines by their own cycles, and the hottest instructions
+    pub fn report<B: CpuBus>(&self, cpu: &CPU<B>, symbols: &Symbols, top: usize) -> String {
+        let name = |addr: u16| match symbols.label(addr, cpu.bus.prg_rom_offset(addr)) {
+            Some(label) => format!("${:04x} <{}>", addr, label),
+            None => format!("${:04x}", addr),
+        };
+        let total = self.cycles();
+        let frames = self.frames.max(1) as f64;
+        let mut lines = vec![format!(
+            "{} cycles, {} frames: {:.0} cycles per frame ({:.1}% of {:.0})",
+            total,
+            self.frames,
+            total as f64 / frames,
+            100.0 * total as f64 / frames / FRAME_CYCLES,
+            FRAME_CYCLES
+        )];
+        if self.nmi.calls > 0 {
+            let average = self.nmi.cycles as f64 / self.nmi.calls as f64;
+            lines.push(format!(
+                "NMI handler: {} runs, {:.0} cycles on average ({:.1}% of the {:.0} cycle vblank), {} at most ({:.1}%)",
+                self.nmi.calls,
+                average,
+                100.0 * average / VBLANK_CYCLES,
+                VBLANK_CYCLES,
+                self.nmi.max,
+                100.0 * self.nmi.max as f64 / VBLANK_CYCLES
+            ));
+        }
+
+        let mut routines: Vec<(u16, RoutineStats)> = self.routines().into_iter().collect();
+        routines.sort_by_key(|(addr, stats)| (std::cmp::Reverse(stats.exclusive), *addr));
+        lines.push(String::new());
+        lines.push(format!(
+            "{:<32} {:>7} {:>10} {:>9} {:>10} {:>9}",
+            "routine", "calls", "inclusive", "/frame", "exclusive", "/frame"
+        ));
+        for (addr, stats) in routines.iter().take(top) {
+            lines.push(format!(
+                "{:<32} {:>7} {:>10} {:>9.1} {:>10} {:>9.1}",
+                name(*addr),
+                stats.calls,
+                stats.inclusive,
+                stats.inclusive as f64 / frames,
+                stats.exclusive,
+                stats.exclusive as f64 / frames
+            ));
+        }
+
+        let mut instructions: Vec<(usize, usize)> = self
+            .pc_cycles
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, cycles)| *cycles > 0)
+            .collect();
+        instructions.sort_by_key(|(addr, cycles)| (std::cmp::Reverse(*cycles), *addr));
+        lines.push(String::new());
+        lines.push(format!(
+            "{:<32} {:>10} {:>9}",
+            "instruction", "cycles", "/frame"
+        ));
+        for (addr, cycles) in instructions.iter().take(top) {
+            lines.push(format!(
+                "{:<32} {:>10} {:>9.1}",
+                name(*addr as u16),
+                cycles,
+                *cycles as f64 / frames
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Folded stacks, one `reset;NmiHandler;UpdateSprites 1234` line per call tree node,
+    /// as read by flamegraph.pl and inferno
+    pub fn write_folded<B: CpuBus>(
+        &self,
+        cpu: &CPU<B>,
+        symbols: &Symbols,
+        output: &mut dyn io::Write,
+    ) -> io::Result<()> {
+        let name = |addr: u16| match symbols.label(addr, cpu.bus.prg_rom_offset(addr)) {
+            Some(label) => label.to_string(),
+            None => format!("${:04x}", addr),
+        };
+        let mut lines = Vec::new();
+        for node in self.nodes.iter().filter(|node| node.cycles > 0) {
+            let mut names = vec![name(node.routine)];
+            let mut parent = node.parent;
+            while let Some(i) = parent {
+                names.push(name(self.nodes[i].routine));
+                parent = self.nodes[i].parent;
+            }
+            names.reverse();
+            lines.push(format!("{} {}", names.join(";"), node.cycles));
+        }
+        lines.sort();
+        for line in lines {
+            writeln!(output, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::bus::Bus;
+    use crate::input;
+    use crate::ppu::ppu::NesPPU;
+    use crate::rom::Rom;
+
+    fn profile(
+        frames: usize,
+    ) -> (
+        Profiler,
+        CPU<Bus<'static, NesPPU>>,
+        Symbols,
+        HashMap<String, u16>,
+    ) {
+        let asm = assemble(
+            "
+            .org $c000
+    reset:  LDA #$80
+            STA $2000
+            JSR work
+            JMP reset
+    work:   LDX #$10
+    delay:  DEX
+            BNE delay
+            RTS
+    nmi:    PHA
+            JSR upload
+            PLA
+            RTI
+    upload: LDY #$20
+    copy:   DEY
+            BNE copy
+            RTS
+            .org $fffa
+            .word nmi, reset, reset
+            ",
+        )
+        .unwrap();
+        let mut data = vec![
+            0x4e, 0x45, 0x53, 0x1a, 0x01, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        data.extend(&asm.bytes);
+        data.extend(vec![0; 0x2000]);
+        let rom = Rom::load(&data).unwrap();
+        let bus = Bus::<NesPPU>::new(rom, |_: &NesPPU, _: &mut input::Joypad| {});
+        let mut cpu = CPU::new(bus);
+        cpu.power_on();
+
+        let mut symbols = Symbols::new();
+        let mut labels: Vec<_> = asm.symbols.iter().collect();
+        labels.sort();
+        for (label, addr) in labels {
+            symbols
+                .add_nl(&format!("${:04X}#{}#", addr, label), Some(0))
+                .unwrap();
+        }
+
+        let mut profiler = Profiler::new();
+        cpu.run_until(|cpu| {
+            profiler.update(cpu);
+            profiler.frames() == frames
+        });
+        (profiler, cpu, symbols, asm.symbols)
+    }
+
+    #[test]
+    fn test_cycles_per_routine() {
+        let (profiler, cpu, symbols, labels) = profile(3);
+        let routines = profiler.routines();
+        let reset = routines[&labels["reset"]];
+        let work = routines[&labels["work"]];
+        let nmi = routines[&labels["nmi"]];
+        let upload = routines[&labels["upload"]];
+
+        // every cycle is somebody's own, the reset handler spans all of them
+        let exclusive: usize = routines.values().map(|stats| stats.exclusive).sum();
+        assert_eq!(exclusive, profiler.cycles());
+        assert_eq!(reset.inclusive, profiler.cycles());
+        assert_eq!(reset.calls, 1);
+
+        // one NMI per frame: PHA, JSR, PLA and RTI, plus the 7 cycle entry sequence
+        assert_eq!(nmi.calls, 3);
+        assert_eq!(upload.calls, 3);
+        let upload_cycles = 2 + 0x20 * 5 - 1 + 6;
+        assert_eq!(upload.inclusive, 3 * upload_cycles);
+        assert_eq!(upload.exclusive, upload.inclusive);
+        assert_eq!(nmi.exclusive, 3 * (7 + 3 + 6 + 4 + 6));
+        assert_eq!(profiler.nmi().calls, 3);
+        assert_eq!(profiler.nmi().max, 7 + 3 + 6 + 4 + 6 + upload_cycles);
+
+        // NMIs interrupting work are part of its inclusive cycles only
+        assert!(work.calls > 100);
+        assert!(work.inclusive > work.exclusive);
+        assert!(work.inclusive - work.exclusive <= profiler.nmi().cycles);
+
+        let report = profiler.report(&cpu, &symbols, 4);
+        assert!(report.contains("3 frames"), "{}", report);
+        assert!(report.contains("NMI handler: 3 runs"), "{}", report);
+        assert!(report.contains("<work>"), "{}", report);
+        assert!(report.contains("<delay>"), "{}", report);
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        let (profiler, cpu, symbols, _) = profile(2);
+        let mut output = Vec::new();
+        profiler.write_folded(&cpu, &symbols, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let stacks: Vec<(&str, &str)> = output
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap())
+            .collect();
+        let has = |stack: &str| stacks.iter().any(|(s, _)| *s == stack);
+        assert!(has("reset"), "{}", output);
+        assert!(has("reset;work"), "{}", output);
+        assert!(has("reset;work;nmi;upload") || has("reset;nmi;upload"));
+        let total: usize = stacks
+            .iter()
+            .map(|(_, cycles)| cycles.parse::<usize>().unwrap())
+            .sum();
+        assert_eq!(total, profiler.cycles());
+    }
+}
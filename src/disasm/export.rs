@@ -0,0 +1,341 @@
+// Reassemblable source for a whole .nes file, in ca65 or asm6 syntax.
+// Assembling it (and linking with the generated config for ca65) reproduces the file byte for byte:
+// the iNES header is kept as data, every 16K PRG bank gets its own segment and
+// CHR ROM is included from a separate file.
+use super::byte_directive;
+use super::flow::{self, Entry, EntryKind, Listing};
+use crate::asm::Operand;
+use crate::rom::Rom;
+
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_BANK_SIZE: usize = 0x4000;
+const HEADER_BYTES_PER_LINE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Ca65,
+    Asm6,
+}
+
+#[derive(Debug)]
+pub struct Export {
+    pub source: String,
+    /// ld65 memory layout (ca65 only)
+    pub linker_config: Option<String>,
+    /// CHR ROM and anything else that follows PRG ROM in the file, to be saved as `chr_file`
+    pub chr: Vec<u8>,
+}
+
+pub fn export(data: &[u8], dialect: Dialect, chr_file: &str) -> Result<Export, String> {
+    let rom = Rom::load(data).map_err(|e| e.to_string())?;
+    let header_len = HEADER_SIZE + rom.trainer.as_ref().map_or(0, |_| TRAINER_SIZE);
+    let prg_end = header_len + rom.prg_rom.len();
+
+    let mut out = Writer {
+        dialect,
+        source: String::new(),
+        segments: vec![],
+    };
+    out.line("; iNES header");
+    if dialect == Dialect::Ca65 {
+        out.line(".setcpu \"6502\"");
+    }
+    out.segment("HEADER", 0, header_len);
+    for chunk in data[..header_len].chunks(HEADER_BYTES_PER_LINE) {
+        out.instruction(&byte_directive(chunk));
+    }
+
+    for listing in bank_listings(&rom.prg_rom) {
+        out.listing(&listing);
+    }
+
+    let chr = data[prg_end..].to_vec();
+    if !chr.is_empty() {
+        out.line("");
+        out.segment("CHR", 0, chr.len());
+        out.instruction(&format!(".incbin \"{}\"", chr_file));
+    }
+
+    let linker_config = match dialect {
+        Dialect::Ca65 => Some(out.linker_config()),
+        Dialect::Asm6 => None,
+    };
+    Ok(Export {
+        source: out.source,
+        linker_config,
+        chr,
+    })
+}
+
+/// The same layout as `flow::disassemble_rom`: banks other than the last one of a banked ROM
+/// can't be followed without knowing the mapper, so they are disassembled as data at $8000.
+fn bank_listings(prg: &[u8]) -> Vec<Listing> {
+    if prg.len() <= 2 * PRG_BANK_SIZE {
+        return vec![flow::disassemble(prg, (0x10000 - prg.len()) as u16)];
+    }
+    let fixed = prg.len() - PRG_BANK_SIZE;
+    let mut listings: Vec<Listing> = prg[..fixed]
+        .chunks(PRG_BANK_SIZE)
+        .map(|bank| flow::disassemble_from(bank, 0x8000, &[]))
+        .collect();
+    listings.push(flow::disassemble(&prg[fixed..], 0xc000));
+    listings
+}
+
+/// Undocumented opcodes have assembler specific mnemonics, and assemblers shorten absolute
+/// addressing of zero page addresses, so both are kept as data.
+fn reassemblable(ops_mnemonic: &str, operand: Operand, bytes: &[u8]) -> bool {
+    if ops_mnemonic.starts_with('*') {
+        return false;
+    }
+    match operand {
+        Operand::Absolute | Operand::AbsoluteX | Operand::AbsoluteY => bytes[2] != 0,
+        _ => true,
+    }
+}
+
+struct Writer {
+    dialect: Dialect,
+    source: String,
+    /// name, start address and size of every segment
+    segments: Vec<(String, u16, usize)>,
+}
+
+impl Writer {
+    fn line(&mut self, line: &str) {
+        self.source.push_str(line);
+        self.source.push('\n');
+    }
+
+    fn instruction(&mut self, text: &str) {
+        self.line(&format!("    {}", text));
+    }
+
+    fn segment(&mut self, name: &str, start: u16, size: usize) {
+        match self.dialect {
+            Dialect::Ca65 => self.line(&format!(".segment \"{}\"", name)),
+            Dialect::Asm6 => self.line(&format!(".base ${:04x}", start)),
+        }
+        self.segments.push((name.to_string(), start, size));
+    }
+
+    fn listing(&mut self, listing: &Listing) {
+        let image_len: usize = listing.entries.iter().map(|e| e.bytes.len()).sum();
+        let mut offset = 0;
+        for entry in listing.entries.iter() {
+            if offset % PRG_BANK_SIZE == 0 {
+                self.prg_segment(listing.origin, offset, image_len);
+            }
+            if let Some(label) = listing.labels.get(&entry.addr) {
+                self.line(&format!("{}:", label));
+            }
+
+            let bank_end = (offset / PRG_BANK_SIZE + 1) * PRG_BANK_SIZE;
+            if offset + entry.bytes.len() > bank_end {
+                // an instruction crossing into the next bank has to be split between the segments
+                let (head, tail) = entry.bytes.split_at(bank_end - offset);
+                self.instruction(&format!(
+                    "{} ; {}",
+                    byte_directive(head),
+                    listing.text(entry)
+                ));
+                self.prg_segment(listing.origin, bank_end, image_len);
+                self.instruction(&byte_directive(tail));
+            } else {
+                self.instruction(&entry_text(listing, entry));
+            }
+            offset += entry.bytes.len();
+        }
+    }
+
+    fn prg_segment(&mut self, origin: u16, offset: usize, image_len: usize) {
+        let index = self
+            .segments
+            .iter()
+            .filter(|s| s.0.starts_with("PRG"))
+            .count();
+        let size = PRG_BANK_SIZE.min(image_len - offset);
+        self.line("");
+        self.segment(
+            &format!("PRG{}", index),
+            origin.wrapping_add(offset as u16),
+            size,
+        );
+    }
+
+    fn linker_config(&self) -> String {
+        let mut config = String::from("MEMORY {\n");
+        for (name, start, size) in self.segments.iter() {
+            config.push_str(&format!(
+                "    {}: start = ${:04x}, size = ${:04x}, file = %O, fill = yes;\n",
+                name, start, size
+            ));
+        }
+        config.push_str("}\nSEGMENTS {\n");
+        for (name, _, _) in self.segments.iter() {
+            config.push_str(&format!("    {}: load = {}, type = ro;\n", name, name));
+        }
+        config.push_str("}\n");
+        config
+    }
+}
+
+fn entry_text(listing: &Listing, entry: &Entry) -> String {
+    match entry.kind {
+        EntryKind::Code(ops) if !reassemblable(ops.mnemonic, Operand::of(ops), &entry.bytes) => {
+            format!("{} ; {}", byte_directive(&entry.bytes), listing.text(entry))
+        }
+        _ => listing.text(entry),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::assemble_with;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashMap;
+
+    fn reassemble(export: &Export) -> Vec<u8> {
+        let chr = export.chr.clone();
+        let include = move |path: &str| match path {
+            "game.chr" => Ok(chr.clone()),
+            _ => Err(format!("unexpected include {}", path)),
+        };
+        let source = match &export.linker_config {
+            None => export.source.clone(),
+            Some(config) => link(&export.source, config),
+        };
+        assemble_with(&source, &include)
+            .unwrap_or_else(|e| panic!("{}\n{}", e, source))
+            .bytes
+    }
+
+    /// What ld65 does with the generated config: every segment goes to its own memory area,
+    /// at the configured start address, in the order of the config
+    fn link(source: &str, config: &str) -> String {
+        let starts: HashMap<String, String> = config
+            .lines()
+            .filter(|line| line.contains("start = "))
+            .map(|line| {
+                let name = line.trim().split(':').next().unwrap().to_string();
+                let start = line.split("start = ").nth(1).unwrap();
+                (name, start.split(',').next().unwrap().to_string())
+            })
+            .collect();
+        source
+            .lines()
+            .filter(|line| !line.starts_with(".setcpu"))
+            .map(|line| match line.strip_prefix(".segment ") {
+                Some(name) => format!(".base {}", starts[name.trim_matches('"')]),
+                None => line.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn random_rom(rng: &mut StdRng, prg_banks: u8, chr_banks: u8, trainer: bool) -> Vec<u8> {
+        let flags = if trainer { 0b100 } else { 0 };
+        let mut data = vec![b'N', b'E', b'S', 0x1a, prg_banks, chr_banks, flags, 0];
+        data.extend_from_slice(&[0; 8]);
+        let len = if trainer { TRAINER_SIZE } else { 0 }
+            + prg_banks as usize * PRG_BANK_SIZE
+            + chr_banks as usize * 0x2000;
+        data.extend((0..len).map(|_| rng.gen::<u8>()));
+        data
+    }
+
+    #[test]
+    fn test_nestest_round_trip() {
+        let data = std::fs::read("test_rom/nestest.nes").unwrap();
+        for dialect in [Dialect::Asm6, Dialect::Ca65].iter() {
+            let export = export(&data, *dialect, "game.chr").unwrap();
+            assert_eq!(export.chr.len(), 0x2000);
+            assert!(export.source.contains("reset:\n    SEI\n    CLD\n"));
+            assert_eq!(reassemble(&export), data);
+        }
+    }
+
+    #[test]
+    fn test_ca65_layout() {
+        let data = std::fs::read("test_rom/nestest.nes").unwrap();
+        let export = export(&data, Dialect::Ca65, "game.chr").unwrap();
+        assert_eq!(
+            export.linker_config.unwrap(),
+            "MEMORY {
+    HEADER: start = $0000, size = $0010, file = %O, fill = yes;
+    PRG0: start = $c000, size = $4000, file = %O, fill = yes;
+    CHR: start = $0000, size = $2000, file = %O, fill = yes;
+}
+SEGMENTS {
+    HEADER: load = HEADER, type = ro;
+    PRG0: load = PRG0, type = ro;
+    CHR: load = CHR, type = ro;
+}
+"
+        );
+        assert!(export.source.contains(".segment \"PRG0\"\n"));
+        assert!(export
+            .source
+            .ends_with(".segment \"CHR\"\n    .incbin \"game.chr\"\n"));
+    }
+
+    #[test]
+    fn test_random_roms_round_trip() {
+        let mut rng = StdRng::seed_from_u64(12);
+        for (prg_banks, chr_banks, trainer) in [(1, 1, false), (2, 0, true), (4, 2, false)].iter() {
+            let mut data = random_rom(&mut rng, *prg_banks, *chr_banks, *trainer);
+            // some bytes after CHR ROM
+            data.extend_from_slice(b"trailing");
+            for dialect in [Dialect::Asm6, Dialect::Ca65].iter() {
+                let export = export(&data, *dialect, "game.chr").unwrap();
+                assert_eq!(reassemble(&export), data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_instruction_across_banks() {
+        let mut data = vec![b'N', b'E', b'S', 0x1a, 2, 0, 0, 0];
+        data.extend_from_slice(&[0; 8]);
+        let mut prg = vec![0xea; 2 * PRG_BANK_SIZE];
+        // $bffe: JMP $bffe, reset vector pointing at it
+        prg[0x3ffe..0x4001].copy_from_slice(&[0x4c, 0xfe, 0xbf]);
+        prg[0x7ffc..0x7ffe].copy_from_slice(&[0xfe, 0xbf]);
+        data.extend(prg);
+
+        let export = export(&data, Dialect::Asm6, "game.chr").unwrap();
+        assert!(export
+            .source
+            .contains("reset:\n    .byte $4c, $fe ; JMP reset\n\n.base $c000\n    .byte $bf\n"));
+        assert_eq!(reassemble(&export), data);
+    }
+
+    /// Runs the real ca65/ld65: `cargo test -- --ignored` with cc65 installed
+    #[test]
+    #[ignore = "needs ca65 and ld65 from cc65 on the PATH"]
+    fn test_ca65_toolchain() {
+        use std::process::Command;
+        let data = std::fs::read("test_rom/nestest.nes").unwrap();
+        let export = export(&data, Dialect::Ca65, "game.chr").unwrap();
+        let dir = std::env::temp_dir().join("rustness_ca65_export");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("game.s"), &export.source).unwrap();
+        std::fs::write(dir.join("game.cfg"), export.linker_config.unwrap()).unwrap();
+        std::fs::write(dir.join("game.chr"), &export.chr).unwrap();
+
+        let run = |cmd: &str, args: &[&str]| {
+            let status = Command::new(cmd)
+                .args(args)
+                .current_dir(&dir)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{} failed", cmd);
+        };
+        run("ca65", &["game.s", "-o", "game.o"]);
+        run("ld65", &["-C", "game.cfg", "game.o", "-o", "game.nes"]);
+        assert_eq!(std::fs::read(dir.join("game.nes")).unwrap(), data);
+    }
+}
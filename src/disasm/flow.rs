@@ -0,0 +1,389 @@
+// Recursive descent disassembler: starts at the interrupt vectors and follows branches,
+// JSR and JMP to tell code from data. Bytes that are never reached are kept as data.
+use super::{byte_directive, format_operand_with, hex_address};
+use crate::asm::Operand;
+use crate::cpu::opscode::{OpsCode, OPSCODES_2A03};
+use crate::rom::Rom;
+use std::collections::BTreeMap;
+
+const NMI_VECTOR: u16 = 0xfffa;
+const RESET_VECTOR: u16 = 0xfffc;
+const IRQ_VECTOR: u16 = 0xfffe;
+const DATA_BYTES_PER_LINE: usize = 8;
+
+#[derive(Debug)]
+pub enum EntryKind {
+    Code(&'static OpsCode),
+    Data,
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub kind: EntryKind,
+}
+
+#[derive(Debug)]
+pub struct Listing {
+    /// CPU address of the first byte of the image
+    pub origin: u16,
+    /// Instructions and data, covering the whole image in address order
+    pub entries: Vec<Entry>,
+    pub labels: BTreeMap<u16, String>,
+}
+
+impl Listing {
+    /// Assembly text of an entry, with jump targets replaced by labels
+    pub fn text(&self, entry: &Entry) -> String {
+        match entry.kind {
+            EntryKind::Data => byte_directive(&entry.bytes),
+            EntryKind::Code(ops) => {
+                // jump targets only, zero page operands are never code
+                let address = |addr: u16, digits: usize| match self.labels.get(&addr) {
+                    Some(label) if digits == 4 => label.clone(),
+                    _ => hex_address(addr, digits),
+                };
+                let operand =
+                    format_operand_with(Operand::of(ops), &entry.bytes, entry.addr, &address);
+                format!("{} {}", ops.mnemonic, operand).trim().to_string()
+            }
+        }
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for entry in self.entries.iter() {
+            if let Some(label) = self.labels.get(&entry.addr) {
+                lines.push(format!("{}:", label));
+            }
+            lines.push(format!("{:04x}: {}", entry.addr, self.text(entry)));
+        }
+        lines
+    }
+}
+
+/// Disassembles the PRG ROM as the CPU sees it at reset.
+/// NROM maps up to 32K at the top of the address space. Bigger (banked) ROMs only have
+/// their last 16K bank followed, as most mappers keep it fixed at $C000.
+pub fn disassemble_rom(rom: &Rom) -> Listing {
+    let prg = &rom.prg_rom;
+    let image = if prg.len() > 0x8000 {
+        &prg[prg.len() - 0x4000..]
+    } else {
+        &prg[..]
+    };
+    disassemble(image, 0x10000usize.saturating_sub(image.len()) as u16)
+}
+
+/// Disassembles `image` mapped at `origin`, starting from the NMI, RESET and IRQ vectors.
+pub fn disassemble(image: &[u8], origin: u16) -> Listing {
+    let mut entry_points = vec![];
+    for (vector, name) in [
+        (RESET_VECTOR, "reset"),
+        (NMI_VECTOR, "nmi"),
+        (IRQ_VECTOR, "irq"),
+    ]
+    .iter()
+    {
+        if let Some(addr) = read_u16(image, origin, *vector) {
+            entry_points.push((addr, *name));
+        }
+    }
+    disassemble_from(image, origin, &entry_points)
+}
+
+/// Disassembles `image` mapped at `origin`, starting from the given labeled addresses.
+pub fn disassemble_from(image: &[u8], origin: u16, entry_points: &[(u16, &str)]) -> Listing {
+    let mut tracer = Tracer {
+        image,
+        origin,
+        starts: BTreeMap::new(),
+        claimed: vec![false; image.len()],
+        labels: BTreeMap::new(),
+        pending: vec![],
+    };
+    for (addr, name) in entry_points.iter().rev() {
+        if tracer.offset(*addr).is_some() {
+            tracer.labels.insert(*addr, name.to_string());
+            tracer.pending.push(*addr);
+        }
+    }
+    tracer.run();
+    tracer.listing()
+}
+
+fn offset(image: &[u8], origin: u16, addr: u16) -> Option<usize> {
+    let offset = addr.checked_sub(origin)? as usize;
+    if offset < image.len() {
+        Some(offset)
+    } else {
+        None
+    }
+}
+
+fn read_u16(image: &[u8], origin: u16, addr: u16) -> Option<u16> {
+    // the 6502 doesn't carry into the high byte of the pointer: JMP ($10ff) reads $10ff and $1000
+    let hi_addr = (addr & 0xff00) | (addr.wrapping_add(1) & 0x00ff);
+    let lo = image[offset(image, origin, addr)?];
+    let hi = image[offset(image, origin, hi_addr)?];
+    Some(u16::from_le_bytes([lo, hi]))
+}
+
+struct Tracer<'a> {
+    image: &'a [u8],
+    origin: u16,
+    /// Offsets of the decoded instructions
+    starts: BTreeMap<usize, &'static OpsCode>,
+    claimed: Vec<bool>,
+    labels: BTreeMap<u16, String>,
+    pending: Vec<u16>,
+}
+
+impl<'a> Tracer<'a> {
+    fn offset(&self, addr: u16) -> Option<usize> {
+        offset(self.image, self.origin, addr)
+    }
+
+    fn jump(&mut self, target: u16, prefix: &str) {
+        if self.offset(target).is_some() {
+            self.labels
+                .entry(target)
+                .or_insert_with(|| format!("{}_{:04x}", prefix, target));
+            self.pending.push(target);
+        }
+    }
+
+    fn run(&mut self) {
+        while let Some(mut pc) = self.pending.pop() {
+            while let Some(offset) = self.offset(pc) {
+                if self.claimed[offset] {
+                    break;
+                }
+                let ops = OPSCODES_2A03[self.image[offset] as usize];
+                let len = ops.len as usize;
+                let end = offset + len;
+                // jams and instructions overlapping other code are most likely data
+                if ops.mnemonic == "*KIL"
+                    || end > self.image.len()
+                    || self.claimed[offset..end].iter().any(|c| *c)
+                {
+                    break;
+                }
+                for c in self.claimed[offset..end].iter_mut() {
+                    *c = true;
+                }
+                self.starts.insert(offset, ops);
+
+                let bytes = &self.image[offset..end];
+                let next = pc.wrapping_add(len as u16);
+                match (ops.mnemonic, Operand::of(ops)) {
+                    (_, Operand::Relative) => {
+                        self.jump(next.wrapping_add(bytes[1] as i8 as u16), "loc");
+                    }
+                    ("JSR", _) => self.jump(u16::from_le_bytes([bytes[1], bytes[2]]), "sub"),
+                    ("JMP", Operand::Absolute) => {
+                        self.jump(u16::from_le_bytes([bytes[1], bytes[2]]), "loc");
+                        break;
+                    }
+                    ("JMP", _) => {
+                        let pointer = u16::from_le_bytes([bytes[1], bytes[2]]);
+                        if let Some(target) = read_u16(self.image, self.origin, pointer) {
+                            self.jump(target, "loc");
+                        }
+                        break;
+                    }
+                    ("RTS", _) | ("RTI", _) | ("BRK", _) => break,
+                    _ => {}
+                }
+                pc = next;
+            }
+        }
+    }
+
+    fn listing(self) -> Listing {
+        let Tracer {
+            image,
+            origin,
+            starts,
+            claimed,
+            mut labels,
+            ..
+        } = self;
+        // targets in the middle of an instruction can't be labeled
+        labels.retain(|addr, _| {
+            let offset = addr.wrapping_sub(origin) as usize;
+            !claimed[offset] || starts.contains_key(&offset)
+        });
+
+        let mut entries = vec![];
+        let mut offset = 0;
+        while offset < image.len() {
+            let addr = origin.wrapping_add(offset as u16);
+            let entry = match starts.get(&offset) {
+                Some(ops) => Entry {
+                    addr,
+                    bytes: image[offset..offset + ops.len as usize].to_vec(),
+                    kind: EntryKind::Code(ops),
+                },
+                None => {
+                    let mut end = offset + 1;
+                    while end < image.len()
+                        && end - offset < DATA_BYTES_PER_LINE
+                        && !starts.contains_key(&end)
+                        && !labels.contains_key(&origin.wrapping_add(end as u16))
+                    {
+                        end += 1;
+                    }
+                    Entry {
+                        addr,
+                        bytes: image[offset..end].to_vec(),
+                        kind: EntryKind::Data,
+                    }
+                }
+            };
+            offset += entry.bytes.len();
+            entries.push(entry);
+        }
+
+        Listing {
+            origin,
+            entries,
+            labels,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::assemble;
+    use pretty_assertions::assert_eq;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn image(source: &str) -> Vec<u8> {
+        let asm = assemble(source).unwrap();
+        assert_eq!(asm.origin as usize + asm.bytes.len(), 0x10000);
+        asm.bytes
+    }
+
+    #[test]
+    fn test_follows_control_flow_from_vectors() {
+        let image = image(
+            "
+            .org $ffe8
+            reset:  LDX #$02
+            wait:   DEX
+                    BNE wait
+                    JSR sub
+                    JMP reset
+            sub:    RTS
+            nmi:    RTI
+            .org $fffa
+            .word nmi, reset, reset
+            ",
+        );
+        let listing = disassemble(&image, 0xffe8);
+        assert_eq!(
+            listing.lines(),
+            vec![
+                "reset:",
+                "ffe8: LDX #$02",
+                "loc_ffea:",
+                "ffea: DEX",
+                "ffeb: BNE loc_ffea",
+                "ffed: JSR sub_fff3",
+                "fff0: JMP reset",
+                "sub_fff3:",
+                "fff3: RTS",
+                "nmi:",
+                "fff4: RTI",
+                "fff5: .byte $00, $00, $00, $00, $00, $f4, $ff, $e8",
+                "fffd: .byte $ff, $e8, $ff",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unreached_bytes_are_data() {
+        let image = image(
+            "
+            .org $ffe0
+            reset:  LDA table
+                    JMP (pointer)
+            table:  .byte 1, 2, 3, 4, 5, 6, 7, 8, 9
+            pointer: .word end
+            end:    BEQ end
+                    .byte $02
+            .org $fffa
+            .word reset, reset, reset
+            ",
+        );
+        let listing = disassemble(&image, 0xffe0);
+        assert_eq!(
+            listing.lines(),
+            vec![
+                "reset:",
+                "ffe0: LDA $ffe6",
+                "ffe3: JMP ($ffef)",
+                "ffe6: .byte $01, $02, $03, $04, $05, $06, $07, $08",
+                "ffee: .byte $09, $f1, $ff",
+                "loc_fff1:",
+                "fff1: BEQ loc_fff1",
+                "fff3: .byte $02, $00, $00, $00, $00, $00, $00, $e0",
+                "fffb: .byte $ff, $e0, $ff, $e0, $ff",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jump_into_the_middle_of_an_instruction() {
+        // BIT $00a9 hides LDA #$00 when jumped to at $fff3
+        let image = image(
+            "
+            .org $fff0
+            reset:  BNE *+3
+                    .byte $2c, $a9, $00
+                    RTS
+            .org $fffa
+            .word reset, reset, reset
+            ",
+        );
+        let listing = disassemble(&image, 0xfff0);
+        assert_eq!(listing.labels.len(), 1);
+        assert_eq!(
+            &listing.lines()[..4],
+            &["reset:", "fff0: BNE $fff3", "fff2: BIT $00a9", "fff5: RTS",]
+        );
+    }
+
+    #[test]
+    fn test_random_images_do_not_panic() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..64 {
+            let len = rng.gen_range(0, 0x4000);
+            let image: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let origin = 0x10000 - len as u32;
+            let listing = disassemble(&image, origin as u16);
+            let total: usize = listing.entries.iter().map(|e| e.bytes.len()).sum();
+            assert_eq!(total, image.len());
+            listing.lines();
+
+            let origin: u16 = rng.gen();
+            disassemble(&image, origin).lines();
+        }
+    }
+
+    #[test]
+    fn test_nestest_rom() {
+        let data = std::fs::read("test_rom/nestest.nes").unwrap();
+        let rom = Rom::load(&data).unwrap();
+        let listing = disassemble_rom(&rom);
+        assert_eq!(listing.origin, 0xc000);
+        assert_eq!(listing.labels[&0xc004], "reset");
+        assert_eq!(listing.labels[&0xc5af], "nmi");
+        let lines = listing.lines();
+        assert_eq!(&lines[1..4], &["reset:", "c004: SEI", "c005: CLD"]);
+    }
+}
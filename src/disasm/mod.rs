@@ -0,0 +1,298 @@
+use crate::asm;
+use crate::bus::CpuBus;
+use crate::cpu::opscode;
+use crate::symbols::Symbols;
+use byteorder::{ByteOrder, LittleEndian};
+use std::cmp::min;
+use std::collections::HashMap;
+
+pub mod export;
+pub mod flow;
+
+pub struct Disasm {
+    pub program: Vec<String>,
+    pub hex_dump: Vec<Vec<u8>>,
+    pub ops_index_map: HashMap<u16, usize>,
+}
+
+impl Disasm {
+    pub fn new(program: &[u8], start: usize) -> Self {
+        Disasm::disassemble(program, start, &|addr, digits| hex_address(addr, digits))
+    }
+
+    /// Disassembles with operand addresses replaced by their labels.
+    /// `bus` tells which PRG ROM bank is mapped at an address.
+    pub fn with_symbols<B: CpuBus>(
+        program: &[u8],
+        start: usize,
+        symbols: &Symbols,
+        bus: &B,
+    ) -> Self {
+        Disasm::disassemble(program, start, &|addr, digits| match symbols
+            .label(addr, bus.prg_rom_offset(addr))
+        {
+            Some(name) => name.to_string(),
+            None => hex_address(addr, digits),
+        })
+    }
+
+    fn disassemble(program: &[u8], start: usize, address: &dyn Fn(u16, usize) -> String) -> Self {
+        let ref opscodes: HashMap<u8, &'static opscode::OpsCode> = *opscode::OPSCODES_MAP;
+
+        let mut begin = start;
+        let mut asm = Vec::new();
+        let mut mapping: HashMap<u16, usize> = HashMap::new();
+        let mut hex_dump: Vec<Vec<u8>> = Vec::new();
+        while begin < program.len() {
+            let code = &program[begin];
+            let ops = opscodes[code];
+            let len = ops.len as usize;
+
+            let asm_str = if begin + len > program.len() {
+                // truncated instruction at the end of the program
+                let bytes = &program[begin..];
+                format!("{:04x}: {}", begin, byte_directive(bytes))
+            } else {
+                let bytes = &program[begin..begin + len];
+                format!("{:04x}: {}", begin, instruction_text(bytes, begin as u16, address))
+            };
+            hex_dump.push(program[begin..min(begin + len, program.len())].to_vec());
+
+            asm.push(asm_str);
+            mapping.insert(begin as u16, asm.len() - 1);
+            begin += len;
+        }
+        Disasm {
+            program: asm,
+            ops_index_map: mapping,
+            hex_dump,
+        }
+    }
+
+    pub fn slice(&self, pos: u16) -> (&[String], usize) {
+        let index = *self.ops_index_map.get(&pos).unwrap();
+        let slice_size = min(10 as usize, self.program.len());
+
+        if index > slice_size / 2 {
+            let end = min(self.program.len(), index + slice_size / 2);
+            let begin = end - slice_size;
+            (&self.program[begin..end], index - begin)
+        } else {
+            (&self.program[0..slice_size], index)
+        }
+    }
+}
+
+/// `LDA PlayerX,Y`: the instruction at `pc`, `bytes` start with its opcode and hold at least all its operand bytes
+pub fn instruction_with_symbols<B: CpuBus>(
+    bytes: &[u8],
+    pc: u16,
+    symbols: &Symbols,
+    bus: &B,
+) -> String {
+    let len = opscode::OPSCODES_MAP[&bytes[0]].len as usize;
+    instruction_text(&bytes[..len], pc, &|addr, digits| {
+        match symbols.label(addr, bus.prg_rom_offset(addr)) {
+            Some(name) => name.to_string(),
+            None => hex_address(addr, digits),
+        }
+    })
+}
+
+fn instruction_text(bytes: &[u8], pc: u16, address: &dyn Fn(u16, usize) -> String) -> String {
+    let ops = opscode::OPSCODES_MAP[&bytes[0]];
+    let operand = asm::Operand::of(ops);
+    let operand_text = format_operand_with(operand, bytes, pc, address);
+    let text = format!("{} {}", ops.mnemonic, operand_text);
+    if asm::opcode(ops.mnemonic, operand) == Some(ops.code) {
+        text.trim().to_string()
+    } else {
+        // duplicate encoding (e.g. undocumented NOPs): the assembler would pick another opcode
+        format!("{} ; {}", byte_directive(bytes), text.trim())
+    }
+}
+
+fn byte_directive(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("${:02x}", b)).collect();
+    format!(".byte {}", bytes.join(", "))
+}
+
+fn hex_address(addr: u16, digits: usize) -> String {
+    format!("${:0digits$x}", addr, digits = digits)
+}
+
+/// Formats the operand of a complete instruction located at `pc`.
+/// `address` renders addresses with the given number of hex digits, e.g. as labels.
+fn format_operand_with(
+    operand: asm::Operand,
+    bytes: &[u8],
+    pc: u16,
+    address: &dyn Fn(u16, usize) -> String,
+) -> String {
+    use asm::Operand;
+    match bytes.len() {
+        2 => {
+            let arg = bytes[1];
+            let zero_page = address(arg as u16, 2);
+            match operand {
+                Operand::Immediate => format!("#${:02x}", arg),
+                Operand::ZeroPage => zero_page,
+                Operand::ZeroPageX => format!("{},X", zero_page),
+                Operand::ZeroPageY => format!("{},Y", zero_page),
+                Operand::IndirectX => format!("({},X)", zero_page),
+                Operand::IndirectY => format!("({}),Y", zero_page),
+                Operand::ZeroPageIndirect => format!("({})", zero_page),
+                _ => {
+                    // BNE, BVS, etc.
+                    let target = pc.wrapping_add(2).wrapping_add(arg as i8 as u16);
+                    address(target, 4)
+                }
+            }
+        }
+        3 => {
+            let arg = address(LittleEndian::read_u16(&bytes[1..]), 4);
+            match operand {
+                Operand::AbsoluteX => format!("{},X", arg),
+                Operand::AbsoluteY => format!("{},Y", arg),
+                Operand::Indirect => format!("({})", arg),
+                _ => arg,
+            }
+        }
+        _ => String::new(),
+    }
+}
+
+pub fn disasm(program: &[u8], start: usize) -> Vec<String> {
+    let ref opscodes: HashMap<u8, &'static opscode::OpsCode> = *opscode::OPSCODES_MAP;
+
+    let mut begin = start;
+    let mut result = Vec::new();
+    while begin < program.len() {
+        let code = &program[begin];
+        let ops = opscodes.get(code).unwrap();
+
+        let tmp = match ops.len {
+            2 => format!("#${:02x}", program[begin + 1]),
+            3 => format!(
+                "#{:x}",
+                LittleEndian::read_u16(&program[begin + 1 as usize..])
+            ),
+            _ => String::from(""),
+        };
+
+        result.push(format!("{:04x}: {} {}", begin, ops.mnemonic, tmp));
+        begin += ops.len as usize;
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::MockBus;
+    use crate::cpu::cpu::transform;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test() {
+        let asm = Disasm::new(&transform("a2 08 ca"), 0);
+        let result = vec!["0000: LDX #$08", "0002: DEX"];
+        assert_eq!(asm.program, result);
+        assert_eq!(asm.hex_dump, vec!(vec!(0xa2, 0x08), vec!(0xca)));
+        assert_eq!(asm.ops_index_map.get(&0), Some(&0));
+        assert_eq!(asm.ops_index_map.get(&2), Some(&1));
+    }
+
+    #[test]
+    fn test_operands_and_raw_bytes() {
+        let asm = Disasm::new(&transform("bd 34 12 6c 00 02 b1 10 f0 fb 82 10 ad 34"), 0);
+        let result = vec![
+            "0000: LDA $1234,X",
+            "0003: JMP ($0200)",
+            "0006: LDA ($10),Y",
+            "0008: BEQ $0005",
+            "000a: .byte $82, $10 ; *NOP #$10",
+            "000c: .byte $ad, $34",
+        ];
+        assert_eq!(asm.program, result);
+        assert_eq!(asm.hex_dump[5], vec!(0xad, 0x34));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut symbols = Symbols::new();
+        symbols
+            .add_nl("$0010#counter#\n$0600#start#\n$0200#screen#", None)
+            .unwrap();
+        let asm = Disasm::with_symbols(
+            &transform("e6 10 9d 00 02 4c 00 06 d0 f6 a9 10"),
+            0,
+            &symbols,
+            &MockBus::new(),
+        );
+        assert_eq!(
+            asm.program,
+            vec![
+                "0000: INC counter",
+                "0002: STA screen,X",
+                "0005: JMP start",
+                "0008: BNE $0000",
+                "000a: LDA #$10",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_slice() {
+        let asm = Disasm::new(
+            &transform("a2 08 ca c8 e0 03 d0 fa 00 a2 08 ca c8 e0 03 d0 fa 00"),
+            0,
+        );
+        let result = vec![
+            "0000: LDX #$08",
+            "0002: DEX",
+            "0003: INY",
+            "0004: CPX #$03",
+            "0006: BNE $0002",
+            "0008: BRK",
+            "0009: LDX #$08",
+            "000b: DEX",
+            "000c: INY",
+            "000d: CPX #$03",
+            "000f: BNE $000b",
+            "0011: BRK",
+        ];
+        assert_eq!(asm.program, result);
+        let (slice, idx) = asm.slice(0004);
+        assert_eq!(3, idx);
+        assert_eq!(10, slice.len());
+        assert_eq!(slice, &result[0..10]);
+    }
+
+    #[test]
+    fn test_slice_end_of_program() {
+        let asm = Disasm::new(
+            &transform("a2 08 ca c8 e0 03 d0 fa 00 a2 08 ca c8 e0 03 d0 fa 00"),
+            0,
+        );
+        let result = vec![
+            "0000: LDX #$08",
+            "0002: DEX",
+            "0003: INY",
+            "0004: CPX #$03",
+            "0006: BNE $0002",
+            "0008: BRK",
+            "0009: LDX #$08",
+            "000b: DEX",
+            "000c: INY",
+            "000d: CPX #$03",
+            "000f: BNE $000b",
+            "0011: BRK",
+        ];
+        assert_eq!(asm.program, result);
+        let (slice, idx) = asm.slice(0x000f);
+        assert_eq!(8, idx);
+        assert_eq!(10, slice.len());
+        assert_eq!(slice, &result[2..12]);
+    }
+}
@@ -45,6 +45,20 @@ impl Joypad {
         response
     }
 
+    /// What read would return, without shifting to the next button
+    pub fn peek(&self) -> u8 {
+        if self.button_index > 7 {
+            return 1;
+        }
+        (self.button_status.bits & (1 << self.button_index)) >> self.button_index
+    }
+
+    /// Reset clears the shift register, pressed buttons are left as is
+    pub fn reset(&mut self) {
+        self.strobe = false;
+        self.button_index = 0;
+    }
+
     pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
         self.button_status.set(button, pressed);
     }
@@ -1,10 +1,15 @@
+pub mod asm;
 pub mod bus;
+pub mod cdl;
 pub mod cpu;
+pub mod debugger;
 pub mod disasm;
 pub mod input;
+pub mod mapper;
 pub mod ppu;
 pub mod rom;
 pub mod screen;
+pub mod symbols;
 
 #[macro_use]
 extern crate bitflags;
@@ -1,18 +1,22 @@
 use rustness::bus::Bus;
 use rustness::cpu::cpu::CPU;
-use rustness::cpu::mem::Mem;
 use rustness::input;
 use rustness::ppu::ppu::NesPPU;
 use rustness::rom::Rom;
+use rustness::symbols::Symbols;
 use std::io::Read;
 
-use rustness::bus::DynamicBusWrapper;
-use std::cell::RefCell;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
-use std::rc::Rc;
+use std::path::Path;
 fn main() {
+    // symbol files (.nl, .mlb, .dbg) to label the trace with
+    let mut symbols = Symbols::new();
+    for path in std::env::args().skip(1) {
+        symbols.load(Path::new(&path)).unwrap();
+    }
+
     // let mut file = File::open("test_rom/ice_climber.nes").unwrap();
     let mut file = File::open("test_rom/nestest.nes").unwrap();
     let mut data = Vec::new();
@@ -24,14 +28,10 @@ fn main() {
         // do nothing
     };
 
-    let mut bus = Bus::<NesPPU>::new(rom, func);
-
-    let start_pc = Mem::read_u16(&mut bus, 0xfffc);
+    let bus = Bus::<NesPPU>::new(rom, func);
 
-    let memory = Rc::from(RefCell::from(bus));
-    let mem_wraper = DynamicBusWrapper::new(memory.clone());
-    let mut cpu = CPU::new(Box::from(mem_wraper));
-    cpu.program_counter = start_pc; //0x8000 as u16 + pc as u16;
+    let mut cpu = CPU::new(bus);
+    cpu.power_on();
 
     let mut file = OpenOptions::new()
         .write(true)
@@ -40,10 +40,12 @@ fn main() {
         .open("nestest.log")
         .unwrap();
 
-    cpu.interpret_fn(0xffff, |cpu| {
-        file.write_all(&(rustness::cpu::trace(cpu) + "\n").as_bytes())
-            .unwrap();
+    let halt = cpu.run_until(|cpu| {
+        let line = rustness::cpu::trace_with_symbols(cpu, &symbols);
+        file.write_all((line.clone() + "\n").as_bytes()).unwrap();
         file.flush().unwrap();
-        println!("{}", rustness::cpu::trace(cpu));
+        println!("{}", line);
+        false
     });
+    println!("CPU halted: {:?}", halt);
 }
@@ -0,0 +1,130 @@
+// AxROM, mapper 7: ANROM, AMROM and AOROM. https://wiki.nesdev.com/w/index.php/AxROM
+//
+// A write to $8000-$FFFF selects the 32K PRG ROM bank (bits 0-2) and which 1K of VRAM all
+// nametables show (bit 4). 8K of CHR RAM. AMROM has bus conflicts, the others don't.
+use crate::mapper::{mirror, Mapper};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_ROM: u16 = 0x8000;
+const PRG_BANK_SIZE: usize = 0x8000;
+
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    register: u8,
+    bus_conflicts: bool,
+}
+
+impl Axrom {
+    pub fn new(rom: Rom) -> Self {
+        Axrom {
+            prg_rom: rom.prg_rom,
+            chr: rom.chr_rom,
+            chr_ram: rom.chr_ram,
+            register: 0,
+            bus_conflicts: false,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn peek(&self, addr: u16) -> u8 {
+        match self.prg_rom_offset(addr) {
+            Some(offset) => self.prg_rom[offset],
+            None => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if addr >= PRG_ROM {
+            self.register = if self.bus_conflicts {
+                data & self.peek(addr)
+            } else {
+                data
+            };
+        }
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        if let Some(offset) = self.prg_rom_offset(addr) {
+            self.prg_rom[offset] = data;
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < PRG_ROM {
+            return None;
+        }
+        let bank = (self.register & 0x07) as usize * PRG_BANK_SIZE;
+        Some(mirror(bank + (addr - PRG_ROM) as usize, self.prg_rom.len()))
+    }
+
+    fn peek_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            self.poke_chr(addr, data);
+        }
+    }
+
+    fn poke_chr(&mut self, addr: u16, data: u8) {
+        let offset = self.chr_offset(addr);
+        self.chr[offset] = data;
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        mirror(addr as usize, self.chr.len())
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.register & 0x10 == 0 {
+            Mirroring::SINGLE_SCREEN_A
+        } else {
+            Mirroring::SINGLE_SCREEN_B
+        }
+    }
+
+    fn set_bus_conflicts(&mut self, enabled: bool) {
+        self.bus_conflicts = enabled;
+    }
+
+    fn power_on(&mut self) {
+        self.register = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::input;
+    use crate::mapper::test::banked_rom;
+    use crate::ppu::ppu::{NesPPU, PPU};
+
+    #[test]
+    fn test_switches_32k_banks_and_the_nametable() {
+        let mut bus = Bus::<NesPPU>::new(
+            banked_rom(7, 8, 0, false),
+            |_: &NesPPU, _: &mut input::Joypad| {},
+        );
+        assert_eq!(bus.ppu().mirroring(), Mirroring::SINGLE_SCREEN_A);
+
+        bus.write(0x8000, 0x12);
+        // 32K bank 2 is made of the 16K banks 4 and 5
+        assert_eq!(bus.read(0x8000), 4);
+        assert_eq!(bus.read(0xc000), 5);
+        assert_eq!(bus.prg_rom_offset(0xc010), Some(5 * 0x4000 + 0x10));
+        assert_eq!(bus.ppu().mirroring(), Mirroring::SINGLE_SCREEN_B);
+        assert_eq!(bus.ppu().mirror_vram_addr(0x2005), 0x405);
+        assert_eq!(bus.ppu().mirror_vram_addr(0x2c05), 0x405);
+
+        // CHR RAM
+        bus.write(0x2006, 0x00);
+        bus.write(0x2006, 0x10);
+        bus.write(0x2007, 0x66);
+        assert_eq!(bus.ppu().peek(0x0010), 0x66);
+    }
+}
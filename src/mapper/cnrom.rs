@@ -0,0 +1,127 @@
+// CNROM, mapper 3. https://wiki.nesdev.com/w/index.php/CNROM
+//
+// NROM with switchable CHR: a write to $8000-$FFFF selects the 8K CHR ROM bank.
+// 16K or 32K of PRG ROM, 16K is mirrored at $C000. The register is a plain latch: writes
+// have bus conflicts.
+use crate::mapper::{mirror, Mapper};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_ROM: u16 = 0x8000;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+pub struct Cnrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+    bank: u8,
+    bus_conflicts: bool,
+}
+
+impl Cnrom {
+    pub fn new(rom: Rom) -> Self {
+        Cnrom {
+            mirroring: rom.rom_flags.mirroring(),
+            prg_rom: rom.prg_rom,
+            chr: rom.chr_rom,
+            chr_ram: rom.chr_ram,
+            bank: 0,
+            bus_conflicts: false,
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn peek(&self, addr: u16) -> u8 {
+        match self.prg_rom_offset(addr) {
+            Some(offset) => self.prg_rom[offset],
+            None => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if addr >= PRG_ROM {
+            self.bank = if self.bus_conflicts {
+                data & self.peek(addr)
+            } else {
+                data
+            };
+        }
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        if let Some(offset) = self.prg_rom_offset(addr) {
+            self.prg_rom[offset] = data;
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr >= PRG_ROM {
+            Some(mirror((addr - PRG_ROM) as usize, self.prg_rom.len()))
+        } else {
+            None
+        }
+    }
+
+    fn peek_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            self.poke_chr(addr, data);
+        }
+    }
+
+    fn poke_chr(&mut self, addr: u16, data: u8) {
+        let offset = self.chr_offset(addr);
+        self.chr[offset] = data;
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        mirror(
+            self.bank as usize * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1)),
+            self.chr.len(),
+        )
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn set_bus_conflicts(&mut self, enabled: bool) {
+        self.bus_conflicts = enabled;
+    }
+
+    fn power_on(&mut self) {
+        self.bank = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::Bus;
+    use crate::input;
+    use crate::mapper::test::banked_rom;
+    use crate::ppu::ppu::{NesPPU, PPU};
+
+    #[test]
+    fn test_switches_8k_chr_banks() {
+        let mut bus = Bus::<NesPPU>::new(
+            banked_rom(3, 1, 4, false),
+            |_: &NesPPU, _: &mut input::Joypad| {},
+        );
+        assert_eq!(bus.ppu().peek(0x0000), 0);
+        assert_eq!(bus.read(0xc000), bus.read(0x8000));
+
+        bus.write(0x8000, 2);
+        // 1K banks are numbered: the 8K bank 2 starts with the 16th
+        assert_eq!(bus.ppu().peek(0x0000), 16);
+        assert_eq!(bus.ppu().peek(0x1fff), 23);
+
+        bus.set_bus_conflicts(true);
+        // PRG ROM bank 0 is filled with zeros
+        bus.write(0x8000, 3);
+        assert_eq!(bus.ppu().peek(0x0000), 0);
+    }
+}
@@ -0,0 +1,347 @@
+// MMC1, mapper 1: the SxROM boards. https://wiki.nesdev.com/w/index.php/MMC1
+//
+// Registers are loaded one bit at a time. Writes to $8000-$FFFF shift their bit 0 into a 5 bit
+// shift register, lowest bit first, and the fifth write copies it to the register its address selects:
+//   $8000-$9FFF control: mirroring (bits 0-1), PRG ROM bank mode (2-3), CHR bank mode (4)
+//   $A000-$BFFF CHR bank at $0000, 4K or 8K (the low bit is ignored then)
+//   $C000-$DFFF CHR bank at $1000, ignored in 8K mode
+//   $E000-$FFFF PRG ROM bank (bits 0-3), PRG RAM disabled (bit 4)
+// A write with bit 7 set empties the shift register and fixes the last PRG bank at $C000.
+//
+// The MMC1 ignores a write on the cycle right after another one: read-modify-write
+// instructions write twice, only the first write counts.
+//
+// SUROM has 512K of PRG ROM: bit 4 of the CHR bank register selects the 256K half.
+use crate::mapper::{mirror, Mapper};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_RAM: u16 = 0x6000;
+const PRG_ROM: u16 = 0x8000;
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    shift: u8,
+    /// Bits in the shift register
+    writes: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+    cycles: usize,
+    /// Cycle of the last write to the shift register
+    last_write: Option<usize>,
+}
+
+impl Mmc1 {
+    pub fn new(rom: Rom) -> Self {
+        let mut mmc1 = Mmc1 {
+            prg_rom: rom.prg_rom,
+            prg_ram: vec![0; 0x2000],
+            chr: rom.chr_rom,
+            chr_ram: rom.chr_ram,
+            shift: 0,
+            writes: 0,
+            control: 0,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycles: 0,
+            last_write: None,
+        };
+        mmc1.power_on();
+        mmc1
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        let consecutive = self.last_write == Some(self.cycles.wrapping_sub(1));
+        self.last_write = Some(self.cycles);
+        if consecutive {
+            return;
+        }
+
+        if data & 0x80 != 0 {
+            self.shift = 0;
+            self.writes = 0;
+            self.control |= 0x0c;
+            return;
+        }
+
+        self.shift |= (data & 1) << self.writes;
+        self.writes += 1;
+        if self.writes < 5 {
+            return;
+        }
+        match addr {
+            0x8000..=0x9fff => self.control = self.shift,
+            0xa000..=0xbfff => self.chr_bank_0 = self.shift,
+            0xc000..=0xdfff => self.chr_bank_1 = self.shift,
+            _ => self.prg_bank = self.shift,
+        }
+        self.shift = 0;
+        self.writes = 0;
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            PRG_RAM..=0x7fff if self.prg_ram_enabled() => self.prg_ram[(addr - PRG_RAM) as usize],
+            PRG_ROM..=0xffff => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=0x7fff if self.prg_ram_enabled() => {
+                self.prg_ram[(addr - PRG_RAM) as usize] = data
+            }
+            PRG_ROM..=0xffff => self.write_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=0x7fff => self.prg_ram[(addr - PRG_RAM) as usize] = data,
+            PRG_ROM..=0xffff => {
+                let offset = self.prg_rom_offset(addr).unwrap();
+                self.prg_rom[offset] = data;
+            }
+            _ => {}
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < PRG_ROM {
+            return None;
+        }
+        let bank = (self.prg_bank & 0x0f) as usize;
+        let bank = match (self.control >> 2) & 3 {
+            // 32K at $8000
+            0 | 1 => (bank & !1) | (addr >= 0xc000) as usize,
+            // first bank fixed at $8000
+            2 if addr < 0xc000 => 0,
+            2 => bank,
+            // last bank fixed at $C000
+            _ if addr < 0xc000 => bank,
+            _ => 0x0f,
+        };
+        let outer = if self.prg_rom.len() > 0x40000 {
+            (self.chr_bank_0 & 0x10) as usize
+        } else {
+            0
+        };
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        Some(mirror(outer | bank, banks) * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1)))
+    }
+
+    fn peek_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            self.poke_chr(addr, data);
+        }
+    }
+
+    fn poke_chr(&mut self, addr: u16, data: u8) {
+        let offset = self.chr_offset(addr);
+        self.chr[offset] = data;
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = if self.control & 0x10 == 0 {
+            (self.chr_bank_0 & !1) as usize | (addr >= 0x1000) as usize
+        } else if addr < 0x1000 {
+            self.chr_bank_0 as usize
+        } else {
+            self.chr_bank_1 as usize
+        };
+        mirror(
+            bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1)),
+            self.chr.len(),
+        )
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 3 {
+            0 => Mirroring::SINGLE_SCREEN_A,
+            1 => Mirroring::SINGLE_SCREEN_B,
+            2 => Mirroring::VERTICAL,
+            _ => Mirroring::HORIZONTAL,
+        }
+    }
+
+    fn tick(&mut self, cycles: u16) {
+        self.cycles += cycles as usize;
+    }
+
+    fn power_on(&mut self) {
+        self.shift = 0;
+        self.writes = 0;
+        self.control = 0x0c;
+        self.chr_bank_0 = 0;
+        self.chr_bank_1 = 0;
+        self.prg_bank = 0;
+        self.cycles = 0;
+        self.last_write = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::input;
+    use crate::mapper::test::banked_rom;
+    use crate::ppu::ppu::{NesPPU, PPU};
+
+    fn mmc1_bus(prg_banks: u8, chr_banks: u8) -> Bus<'static, NesPPU> {
+        Bus::<NesPPU>::new(
+            banked_rom(1, prg_banks, chr_banks, false),
+            |_: &NesPPU, _: &mut input::Joypad| {},
+        )
+    }
+
+    /// Five STA instructions, lowest bit first
+    fn load_register(bus: &mut Bus<NesPPU>, addr: u16, value: u8) {
+        for i in 0..5 {
+            bus.write(addr, value >> i & 1);
+            bus.tick(4);
+        }
+    }
+
+    #[test]
+    fn test_prg_banks_switch_at_8000_by_default() {
+        let mut bus = mmc1_bus(8, 2);
+        assert_eq!(bus.read(0x8000), 0);
+        assert_eq!(bus.read(0xffff), 7);
+
+        load_register(&mut bus, 0xe000, 3);
+        assert_eq!(bus.read(0x8000), 3);
+        assert_eq!(bus.read(0xbfff), 3);
+        assert_eq!(bus.read(0xc000), 7);
+        assert_eq!(bus.prg_rom_offset(0x8010), Some(3 * 0x4000 + 0x10));
+    }
+
+    #[test]
+    fn test_prg_bank_modes() {
+        let mut bus = mmc1_bus(8, 2);
+        load_register(&mut bus, 0xe000, 5);
+
+        // 32K: the low bit of the bank is ignored
+        load_register(&mut bus, 0x8000, 0b00000);
+        assert_eq!(bus.read(0x8000), 4);
+        assert_eq!(bus.read(0xc000), 5);
+
+        // first bank fixed at $8000
+        load_register(&mut bus, 0x8000, 0b01000);
+        assert_eq!(bus.read(0x8000), 0);
+        assert_eq!(bus.read(0xc000), 5);
+    }
+
+    #[test]
+    fn test_bit_7_resets_the_shift_register() {
+        let mut bus = mmc1_bus(8, 2);
+        load_register(&mut bus, 0x8000, 0b01000);
+        load_register(&mut bus, 0xe000, 2);
+        assert_eq!(bus.read(0xc000), 2);
+
+        bus.write(0xe000, 1);
+        bus.tick(4);
+        bus.write(0xe000, 1);
+        bus.tick(4);
+        bus.write(0x8000, 0x80);
+        bus.tick(4);
+        // back to the last bank fixed at $C000, and the two bits are gone
+        assert_eq!(bus.read(0xc000), 7);
+        load_register(&mut bus, 0xe000, 4);
+        assert_eq!(bus.read(0x8000), 4);
+    }
+
+    #[test]
+    fn test_writes_on_consecutive_cycles_are_ignored() {
+        let mut bus = mmc1_bus(8, 2);
+        // INC $E000 of a 1: it writes the 1 back, then the 2 on the next cycle
+        bus.write(0xe000, 1);
+        bus.tick(1);
+        bus.write(0xe000, 2);
+        bus.tick(4);
+        for _ in 0..4 {
+            bus.write(0xe000, 0);
+            bus.tick(4);
+        }
+        assert_eq!(bus.read(0x8000), 1);
+    }
+
+    #[test]
+    fn test_chr_banks() {
+        let mut bus = mmc1_bus(2, 4);
+        // 8K mode: $A000 selects both halves, its low bit is ignored
+        load_register(&mut bus, 0xa000, 3);
+        assert_eq!(bus.ppu().peek(0x0000), 2 * 4);
+        assert_eq!(bus.ppu().peek(0x1fff), 3 * 4 + 3);
+
+        // 4K mode
+        load_register(&mut bus, 0x8000, 0b11100);
+        load_register(&mut bus, 0xa000, 5);
+        load_register(&mut bus, 0xc000, 2);
+        assert_eq!(bus.ppu().peek(0x0000), 5 * 4);
+        assert_eq!(bus.ppu().peek(0x1000), 2 * 4);
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut bus = mmc1_bus(2, 2);
+        let expected = [
+            Mirroring::SINGLE_SCREEN_A,
+            Mirroring::SINGLE_SCREEN_B,
+            Mirroring::VERTICAL,
+            Mirroring::HORIZONTAL,
+        ];
+        for (value, mirroring) in expected.iter().enumerate() {
+            load_register(&mut bus, 0x8000, 0b01100 | value as u8);
+            assert_eq!(bus.ppu().mirroring(), *mirroring);
+        }
+
+        load_register(&mut bus, 0x8000, 0b01101);
+        assert_eq!(bus.ppu().mirror_vram_addr(0x2005), 0x405);
+        assert_eq!(bus.ppu().mirror_vram_addr(0x2c05), 0x405);
+        load_register(&mut bus, 0x8000, 0b01100);
+        assert_eq!(bus.ppu().mirror_vram_addr(0x2805), 0x005);
+    }
+
+    #[test]
+    fn test_prg_ram_enable() {
+        let mut bus = mmc1_bus(2, 2);
+        bus.write(0x6005, 0x66);
+        assert_eq!(bus.read(0x6005), 0x66);
+
+        load_register(&mut bus, 0xe000, 0x10);
+        assert_eq!(bus.read(0x6005), 0);
+        bus.write(0x6005, 0x77);
+
+        load_register(&mut bus, 0xe000, 0);
+        assert_eq!(bus.read(0x6005), 0x66);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut bus = mmc1_bus(2, 0);
+        bus.write(0x2006, 0x10);
+        bus.write(0x2006, 0x05);
+        bus.write(0x2007, 0x66);
+        assert_eq!(bus.ppu().peek(0x1005), 0x66);
+    }
+}
@@ -0,0 +1,322 @@
+// MMC2, mapper 9 (PxROM: Punch-Out!!) and MMC4, mapper 10 (FxROM: Fire Emblem).
+// https://wiki.nesdev.com/w/index.php/MMC2 https://wiki.nesdev.com/w/index.php/MMC4
+//
+// Each pattern table has two 4K CHR banks and a latch choosing between them. The PPU flips
+// a latch by fetching tile $FD or $FE from its pattern table: the bank switches right after
+// the fetch, so the game can change the bank halfway through a scanline without an IRQ.
+// MMC2 flips its first latch on $0FD8 and $0FE8 only, the other latches take $xFD8-$xFDF
+// and $xFE8-$xFEF: any row of the high bits of the tile.
+//   $A000-$AFFF PRG ROM bank at $8000: 8K on the MMC2, the last three are fixed at $A000;
+//               16K on the MMC4, the last one is fixed at $C000
+//   $B000-$BFFF CHR bank at $0000 when latch 0 is $FD, $C000-$CFFF when it's $FE
+//   $D000-$DFFF CHR bank at $1000 when latch 1 is $FD, $E000-$EFFF when it's $FE
+//   $F000-$FFFF mirroring: vertical (0), horizontal (1)
+// FxROM has 8K of PRG RAM at $6000.
+use crate::mapper::{mirror, Mapper};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_RAM: u16 = 0x6000;
+const PRG_ROM: u16 = 0x8000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+pub struct Mmc2 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    mmc4: bool,
+    prg_bank: u8,
+    /// $FD and $FE banks of each pattern table
+    chr_banks: [[u8; 2]; 2],
+    /// The latch of each pattern table is $FE
+    latches: [bool; 2],
+    horizontal: bool,
+}
+
+impl Mmc2 {
+    pub fn new(rom: Rom) -> Self {
+        let mut mmc2 = Mmc2 {
+            prg_rom: rom.prg_rom,
+            prg_ram: vec![0; 0x2000],
+            chr: rom.chr_rom,
+            chr_ram: rom.chr_ram,
+            mmc4: false,
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [true; 2],
+            horizontal: false,
+        };
+        mmc2.power_on();
+        mmc2
+    }
+
+    pub fn mmc4(rom: Rom) -> Self {
+        Mmc2 {
+            mmc4: true,
+            ..Mmc2::new(rom)
+        }
+    }
+
+    fn prg_bank_size(&self) -> usize {
+        if self.mmc4 {
+            0x4000
+        } else {
+            0x2000
+        }
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            PRG_RAM..=0x7fff => self.prg_ram[(addr - PRG_RAM) as usize],
+            PRG_ROM..=0xffff => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=0x7fff => self.prg_ram[(addr - PRG_RAM) as usize] = data,
+            0xa000..=0xafff => self.prg_bank = data & 0x0f,
+            0xb000..=0xefff => {
+                let register = (addr - 0xb000) as usize / 0x1000;
+                self.chr_banks[register / 2][register % 2] = data & 0x1f;
+            }
+            0xf000..=0xffff => self.horizontal = data & 1 == 1,
+            _ => {}
+        }
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=0x7fff => self.prg_ram[(addr - PRG_RAM) as usize] = data,
+            PRG_ROM..=0xffff => {
+                let offset = self.prg_rom_offset(addr).unwrap();
+                self.prg_rom[offset] = data;
+            }
+            _ => {}
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < PRG_ROM {
+            return None;
+        }
+        let size = self.prg_bank_size();
+        let banks = self.prg_rom.len() / size;
+        let slot = (addr - PRG_ROM) as usize / size;
+        let slots = 0x8000 / size;
+        let bank = if slot == 0 {
+            mirror(self.prg_bank as usize, banks)
+        } else {
+            // the last slots, mirrored when there are fewer banks than slots
+            mirror(banks * slots + slot - slots, banks)
+        };
+        Some(bank * size + (addr as usize & (size - 1)))
+    }
+
+    fn peek_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        let data = self.peek_chr(addr);
+        let table = (addr >> 12) as usize & 1;
+        let exact = self.mmc4 || table == 1 || addr & 0x07 == 0;
+        match addr & 0x0ff8 {
+            0x0fd8 if exact => self.latches[table] = false,
+            0x0fe8 if exact => self.latches[table] = true,
+            _ => {}
+        }
+        data
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            self.poke_chr(addr, data);
+        }
+    }
+
+    fn poke_chr(&mut self, addr: u16, data: u8) {
+        let offset = self.chr_offset(addr);
+        self.chr[offset] = data;
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let table = (addr >> 12) as usize & 1;
+        let bank = self.chr_banks[table][self.latches[table] as usize] as usize;
+        mirror(
+            bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1)),
+            self.chr.len(),
+        )
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.horizontal {
+            Mirroring::HORIZONTAL
+        } else {
+            Mirroring::VERTICAL
+        }
+    }
+
+    fn power_on(&mut self) {
+        self.prg_bank = 0;
+        self.chr_banks = [[0; 2]; 2];
+        self.latches = [true; 2];
+        self.horizontal = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::Bus;
+    use crate::cdl::{CodeDataLog, CHR_RENDERED};
+    use crate::input;
+    use crate::mapper::test::banked_rom;
+    use crate::ppu::ppu::{NesPPU, PPU};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn mmc2_bus(mapper: u8) -> Bus<'static, NesPPU> {
+        let mut bus = Bus::<NesPPU>::new(
+            banked_rom(mapper, 8, 8, false),
+            |_: &NesPPU, _: &mut input::Joypad| {},
+        );
+        bus.write(0xb000, 1);
+        bus.write(0xc000, 2);
+        bus.write(0xd000, 3);
+        bus.write(0xe000, 4);
+        bus
+    }
+
+    /// 4K CHR bank at a PPU address, banked_rom fills every 1K with its number
+    fn chr_bank(bus: &Bus<NesPPU>, addr: u16) -> u8 {
+        bus.ppu().peek(addr) / 4
+    }
+
+    fn read_ppu(bus: &mut Bus<NesPPU>, addr: u16) {
+        bus.write(0x2006, (addr >> 8) as u8);
+        bus.write(0x2006, addr as u8);
+        bus.read(0x2007);
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut bus = mmc2_bus(9);
+        bus.write(0xa000, 5);
+        // 8K banks in 16K ones
+        assert_eq!(bus.prg_rom_offset(0x8000), Some(5 * 0x2000));
+        assert_eq!(bus.prg_rom_offset(0xa000), Some(13 * 0x2000));
+        assert_eq!(bus.prg_rom_offset(0xffff), Some(0x20000 - 1));
+
+        // a single 16K bank is both the switchable and the fixed ones
+        let mut bus = Bus::<NesPPU>::new(
+            banked_rom(9, 1, 1, false),
+            |_: &NesPPU, _: &mut input::Joypad| {},
+        );
+        bus.write(0xa000, 3);
+        assert_eq!(bus.prg_rom_offset(0x8000), Some(0x2000));
+        assert_eq!(bus.prg_rom_offset(0xa000), Some(0x2000));
+        assert_eq!(bus.prg_rom_offset(0xc000), Some(0));
+        assert_eq!(bus.prg_rom_offset(0xffff), Some(0x4000 - 1));
+        assert_eq!(bus.read(0xa000), 0);
+
+        let mut bus = mmc2_bus(10);
+        bus.write(0xa000, 5);
+        assert_eq!(bus.read(0x8000), 5);
+        assert_eq!(bus.read(0xc000), 7);
+        bus.write(0x6005, 0x66);
+        assert_eq!(bus.read(0x6005), 0x66);
+    }
+
+    #[test]
+    fn test_latches_flip_after_fetching_fd_and_fe() {
+        let mut bus = mmc2_bus(9);
+        assert_eq!(chr_bank(&bus, 0x0000), 2);
+        assert_eq!(chr_bank(&bus, 0x1000), 4);
+
+        read_ppu(&mut bus, 0x1fdd);
+        assert_eq!(chr_bank(&bus, 0x0000), 2);
+        assert_eq!(chr_bank(&bus, 0x1000), 3);
+        // MMC2 flips latch 0 on $0FD8 only
+        read_ppu(&mut bus, 0x0fd9);
+        assert_eq!(chr_bank(&bus, 0x0000), 2);
+        read_ppu(&mut bus, 0x0fd8);
+        assert_eq!(chr_bank(&bus, 0x0000), 1);
+        read_ppu(&mut bus, 0x1fe8);
+        assert_eq!(chr_bank(&bus, 0x1000), 4);
+
+        let mut bus = mmc2_bus(10);
+        read_ppu(&mut bus, 0x0fd9);
+        assert_eq!(chr_bank(&bus, 0x0000), 1);
+    }
+
+    #[test]
+    fn test_rendering_flips_the_latch_midway_through_a_scanline() {
+        let mut bus = mmc2_bus(9);
+        let cdl = Rc::new(RefCell::new(CodeDataLog::new(&banked_rom(9, 8, 8, false))));
+        bus.log_code_data(cdl.clone());
+        // a $FD tile halfway through the first row of the nametable, backgrounds at $1000
+        bus.write(0x2006, 0x20);
+        bus.write(0x2006, 0x10);
+        bus.write(0x2007, 0xfd);
+        bus.write(0x2000, 0x10);
+        bus.write(0x2001, 0x08);
+
+        bus.tick(114);
+        assert_eq!(bus.ppu().line, 1);
+        assert_eq!(chr_bank(&bus, 0x1000), 3);
+        // scanline 0 fetched the first row of tile 0 from both banks, the end of the scanline
+        // prefetched the second one for scanline 1
+        let cdl = cdl.borrow();
+        assert_eq!(cdl.chr[4 * 0x1000], CHR_RENDERED);
+        assert_eq!(cdl.chr[3 * 0x1000], CHR_RENDERED);
+        assert_eq!(cdl.chr[3 * 0x1000 + 1], CHR_RENDERED);
+        assert_eq!(cdl.chr[4 * 0x1000 + 1], 0);
+        assert_eq!(cdl.chr[3 * 0x1000 + 2], 0);
+    }
+
+    #[test]
+    fn test_fetches_put_off_read_the_banks_from_before_a_switch() {
+        let mut bus = mmc2_bus(9);
+        let cdl = Rc::new(RefCell::new(CodeDataLog::new(&banked_rom(9, 8, 8, false))));
+        bus.log_code_data(cdl.clone());
+        // tile 0 everywhere, backgrounds at $1000
+        bus.write(0x2000, 0x10);
+        bus.write(0x2001, 0x08);
+
+        // halfway through scanline 0, the $FE bank of $1000 switches from 4 to 6
+        bus.tick(57);
+        bus.write(0xe000, 6);
+        bus.tick(57);
+        let cdl = cdl.borrow();
+        assert_eq!(cdl.chr[4 * 0x1000], CHR_RENDERED);
+        assert_eq!(cdl.chr[6 * 0x1000], CHR_RENDERED);
+        // the end of the scanline prefetched scanline 1 from the new bank
+        assert_eq!(cdl.chr[4 * 0x1000 + 1], 0);
+        assert_eq!(cdl.chr[6 * 0x1000 + 1], CHR_RENDERED);
+    }
+
+    #[test]
+    fn test_tiles_out_of_the_screen_are_not_fetched() {
+        let mut bus = mmc2_bus(9);
+        read_ppu(&mut bus, 0x1fd8);
+        // a $FE tile in the first row of the second nametable, backgrounds at $1000
+        bus.write(0x2006, 0x24);
+        bus.write(0x2006, 0x0a);
+        bus.write(0x2007, 0xfe);
+        bus.write(0x2000, 0x10);
+        bus.write(0x2001, 0x08);
+
+        // without scrolling, scanlines fetch the first two tiles of the second nametable only
+        bus.tick(114 * 4);
+        assert_eq!(chr_bank(&bus, 0x1000), 3);
+
+        // scrolled by 10 tiles, it's on the screen from the next scanline on
+        bus.write(0x2005, 80);
+        bus.write(0x2005, 0);
+        bus.tick(114 * 2);
+        assert_eq!(chr_bank(&bus, 0x1000), 4);
+    }
+}
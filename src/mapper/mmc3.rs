@@ -0,0 +1,420 @@
+// MMC3, mapper 4: the TxROM boards. https://wiki.nesdev.com/w/index.php/MMC3
+//
+// Registers are pairs selected by the address range and whether the address is even or odd:
+//   $8000 bank select: register to update (bits 0-2), PRG ROM bank mode (6), CHR A12 inversion (7)
+//   $8001 bank data:
+//     R0, R1 2K CHR banks at $0000 and $0800 (the low bit is ignored)
+//     R2-R5  1K CHR banks at $1000, $1400, $1800 and $1C00
+//     R6, R7 8K PRG ROM banks at $8000 (or $C000) and $A000
+//   the second last PRG bank is fixed at $C000 (or $8000), the last one at $E000.
+//   CHR A12 inversion swaps the pattern tables: 2K banks at $1000, 1K banks at $0000.
+//   $A000 mirroring: vertical (0), horizontal (1)
+//   $A001 PRG RAM: enabled (bit 7), writes denied (bit 6)
+//   $C000 IRQ latch, $C001 IRQ reload
+//   $E000 IRQ disable and acknowledge, $E001 IRQ enable
+//
+// The scanline counter is clocked by the rising edges of PPU A12: with backgrounds at $0000 and
+// sprites at $1000, A12 rises once a line when sprite patterns are fetched. When clocked, the
+// counter is reloaded from the latch if it's 0 (or a reload was asked), decremented otherwise.
+// Reaching 0 holds /IRQ low, if enabled.
+// A12 also drops between the sprite slots, for their nametable fetches: the chip ignores rises
+// after A12 was low for less than 3 M2 cycles. Only pattern fetches reach the mapper here, A12 is
+// taken as low from the last fetch with it high: while rendering, a nametable fetch follows.
+use crate::mapper::{mirror, Mapper};
+use crate::rom::{Mirroring, Rom, RomFlags};
+
+const PRG_RAM: u16 = 0x6000;
+const PRG_ROM: u16 = 0x8000;
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+/// M2 cycles A12 has to stay low for its next rise to clock the counter
+const A12_LOW_CYCLES: usize = 3;
+
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    four_screen: bool,
+    bank_select: u8,
+    banks: [u8; 8],
+    horizontal: bool,
+    prg_ram_protect: u8,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq: bool,
+    cycles: usize,
+    /// Cycle of the last pattern fetch with A12 high
+    a12_high_at: usize,
+}
+
+impl Mmc3 {
+    pub fn new(rom: Rom) -> Self {
+        let mut mmc3 = Mmc3 {
+            four_screen: rom.rom_flags.contains(RomFlags::FOUR_SCREEN),
+            horizontal: !rom.rom_flags.contains(RomFlags::VERTICAL_MIRRORING),
+            prg_rom: rom.prg_rom,
+            prg_ram: vec![0; 0x2000],
+            chr: rom.chr_rom,
+            chr_ram: rom.chr_ram,
+            bank_select: 0,
+            banks: [0; 8],
+            prg_ram_protect: 0,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq: false,
+            cycles: 0,
+            a12_high_at: 0,
+        };
+        mmc3.power_on();
+        mmc3
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_ram_protect & 0x80 != 0
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect & 0xc0 == 0x80
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq = true;
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match (addr & 0xe000, addr & 1) {
+            (0x8000, 0) => self.bank_select = data,
+            (0x8000, _) => self.banks[(self.bank_select & 0x07) as usize] = data,
+            (0xa000, 0) => self.horizontal = data & 1 == 1,
+            (0xa000, _) => self.prg_ram_protect = data,
+            (0xc000, 0) => self.irq_latch = data,
+            (0xc000, _) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, 0) => {
+                self.irq_enabled = false;
+                self.irq = false;
+            }
+            (_, _) => self.irq_enabled = true,
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            PRG_RAM..=0x7fff if self.prg_ram_enabled() => self.prg_ram[(addr - PRG_RAM) as usize],
+            PRG_ROM..=0xffff => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=0x7fff if self.prg_ram_writable() => {
+                self.prg_ram[(addr - PRG_RAM) as usize] = data
+            }
+            PRG_ROM..=0xffff => self.write_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=0x7fff => self.prg_ram[(addr - PRG_RAM) as usize] = data,
+            PRG_ROM..=0xffff => {
+                let offset = self.prg_rom_offset(addr).unwrap();
+                self.prg_rom[offset] = data;
+            }
+            _ => {}
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < PRG_ROM {
+            return None;
+        }
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let swapped = self.bank_select & 0x40 != 0;
+        let bank = match (addr & 0xe000, swapped) {
+            (0x8000, false) | (0xc000, true) => self.banks[6] as usize,
+            (0x8000, true) | (0xc000, false) => banks - 2,
+            (0xa000, _) => self.banks[7] as usize,
+            _ => banks - 1,
+        };
+        Some(mirror(bank, banks) * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1)))
+    }
+
+    fn peek_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        if addr & 0x1000 != 0 {
+            if self.cycles - self.a12_high_at >= A12_LOW_CYCLES {
+                self.clock_irq_counter();
+            }
+            self.a12_high_at = self.cycles;
+        }
+        self.peek_chr(addr)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            self.poke_chr(addr, data);
+        }
+    }
+
+    fn poke_chr(&mut self, addr: u16, data: u8) {
+        let offset = self.chr_offset(addr);
+        self.chr[offset] = data;
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let inverted = if self.bank_select & 0x80 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+        let bank = match inverted >> 10 {
+            0 => self.banks[0] & 0xfe,
+            1 => self.banks[0] | 0x01,
+            2 => self.banks[1] & 0xfe,
+            3 => self.banks[1] | 0x01,
+            slot => self.banks[slot as usize - 2],
+        } as usize;
+        mirror(
+            bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1)),
+            self.chr.len(),
+        )
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // four screen boards have VRAM of their own for the other two nametables: not supported
+        if self.horizontal && !self.four_screen {
+            Mirroring::HORIZONTAL
+        } else {
+            Mirroring::VERTICAL
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq
+    }
+
+    /// The counter is clocked by A12 rising on pattern table reads
+    fn counts_chr_reads(&self) -> bool {
+        true
+    }
+
+    fn tick(&mut self, cycles: u16) {
+        self.cycles += cycles as usize;
+    }
+
+    fn power_on(&mut self) {
+        self.bank_select = 0;
+        self.banks = [0, 2, 4, 5, 6, 7, 0, 1];
+        self.prg_ram_protect = 0x80;
+        self.irq_latch = 0;
+        self.irq_counter = 0;
+        self.irq_reload = false;
+        self.irq_enabled = false;
+        self.irq = false;
+        self.cycles = 0;
+        self.a12_high_at = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::input;
+    use crate::mapper::test::banked_rom;
+    use crate::ppu::ppu::{NesPPU, PPU};
+
+    fn mmc3_bus(prg_banks: u8, chr_banks: u8) -> Bus<'static, NesPPU> {
+        Bus::<NesPPU>::new(
+            banked_rom(4, prg_banks, chr_banks, true),
+            |_: &NesPPU, _: &mut input::Joypad| {},
+        )
+    }
+
+    /// 1K CHR bank at a PPU address, banked_rom fills every 1K with its number
+    fn chr_bank(bus: &Bus<NesPPU>, addr: u16) -> u8 {
+        bus.ppu().peek(addr)
+    }
+
+    /// 8K PRG bank at a CPU address, banked_rom fills 16K banks
+    fn prg_bank(bus: &Bus<NesPPU>, addr: u16) -> usize {
+        bus.prg_rom_offset(addr).unwrap() / PRG_BANK_SIZE
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut bus = mmc3_bus(8, 8);
+        bus.write(0x8000, 6);
+        bus.write(0x8001, 3);
+        bus.write(0x8000, 7);
+        bus.write(0x8001, 4);
+        assert_eq!(prg_bank(&bus, 0x8000), 3);
+        assert_eq!(prg_bank(&bus, 0xa000), 4);
+        assert_eq!(prg_bank(&bus, 0xc000), 14);
+        assert_eq!(prg_bank(&bus, 0xe000), 15);
+        assert_eq!(bus.read(0xa000), 2);
+
+        // the second last bank moves to $8000
+        bus.write(0x8000, 0x46);
+        assert_eq!(prg_bank(&bus, 0x8000), 14);
+        assert_eq!(prg_bank(&bus, 0xc000), 3);
+        assert_eq!(prg_bank(&bus, 0xe000), 15);
+    }
+
+    #[test]
+    fn test_chr_banks_and_inversion() {
+        let mut bus = mmc3_bus(2, 8);
+        for (register, bank) in [9, 20, 30, 31, 40, 41].iter().enumerate() {
+            bus.write(0x8000, register as u8);
+            bus.write(0x8001, *bank);
+        }
+        assert_eq!(chr_bank(&bus, 0x0000), 8);
+        assert_eq!(chr_bank(&bus, 0x0400), 9);
+        assert_eq!(chr_bank(&bus, 0x0800), 20);
+        assert_eq!(chr_bank(&bus, 0x0c00), 21);
+        assert_eq!(chr_bank(&bus, 0x1000), 30);
+        assert_eq!(chr_bank(&bus, 0x1c00), 41);
+
+        bus.write(0x8000, 0x80);
+        assert_eq!(chr_bank(&bus, 0x0000), 30);
+        assert_eq!(chr_bank(&bus, 0x0c00), 41);
+        assert_eq!(chr_bank(&bus, 0x1000), 8);
+        assert_eq!(chr_bank(&bus, 0x1c00), 21);
+    }
+
+    #[test]
+    fn test_mirroring_and_prg_ram_protect() {
+        let mut bus = mmc3_bus(2, 8);
+        assert_eq!(bus.ppu().mirroring(), Mirroring::VERTICAL);
+        bus.write(0xa000, 1);
+        assert_eq!(bus.ppu().mirroring(), Mirroring::HORIZONTAL);
+
+        bus.write(0x6005, 0x66);
+        assert_eq!(bus.read(0x6005), 0x66);
+        // write protected
+        bus.write(0xa001, 0xc0);
+        bus.write(0x6005, 0x77);
+        assert_eq!(bus.read(0x6005), 0x66);
+        // disabled
+        bus.write(0xa001, 0x00);
+        assert_eq!(bus.read(0x6005), 0);
+    }
+
+    /// Runs the CPU one cycle at a time for up to a frame until the IRQ, returns the scanline
+    /// and dot it ended on
+    fn irq_at(bus: &mut Bus<NesPPU>) -> Option<(usize, usize)> {
+        for _ in 0..262 * 341 / 3 {
+            bus.tick(1);
+            if bus.poll_irq_status() {
+                return Some((bus.ppu().line, bus.ppu().cycles));
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_scanline_irq_is_clocked_by_sprite_fetches() {
+        let mut bus = mmc3_bus(2, 8);
+        // backgrounds at $0000, sprites at $1000, rendering on
+        bus.write(0x2000, 0x08);
+        bus.write(0x2001, 0x18);
+        bus.write(0xc000, 10);
+        bus.write(0xc001, 0);
+        bus.write(0xe001, 0);
+
+        // the first clock, on scanline 0, loads the latch, 10 more get the counter to 0.
+        // A12 rises with the first sprite pattern fetch, on dot 261: the CPU cycle ends on 262.
+        assert_eq!(irq_at(&mut bus), Some((10, 262)));
+        assert!(bus.poll_irq_status());
+
+        bus.write(0xe000, 0);
+        assert!(!bus.poll_irq_status());
+        bus.write(0xe001, 0);
+        // the counter is reloaded on scanline 11 and reaches 0 again 10 scanlines later
+        assert_eq!(irq_at(&mut bus), Some((21, 264)));
+    }
+
+    #[test]
+    fn test_scanline_irq_needs_a12_to_rise() {
+        let mut bus = mmc3_bus(2, 8);
+        bus.write(0xc000, 10);
+        bus.write(0xc001, 0);
+        bus.write(0xe001, 0);
+
+        // rendering off: no fetches
+        bus.write(0x2000, 0x08);
+        assert_eq!(irq_at(&mut bus), None);
+        // backgrounds and sprites at $0000: A12 stays low
+        bus.write(0x2000, 0x00);
+        bus.write(0x2001, 0x18);
+        assert_eq!(irq_at(&mut bus), None);
+    }
+
+    /// OAM with every sprite hidden below the screen but `sprites`, (y, tile) pairs.
+    /// Written through $2004: OAM DMA would move the PPU on by 513 cycles.
+    fn load_sprites(bus: &mut Bus<NesPPU>, sprites: &[(u8, u8)]) {
+        let mut oam = [0xff; 256];
+        for (i, (y, tile)) in sprites.iter().enumerate() {
+            oam[i * 4] = *y;
+            oam[i * 4 + 1] = *tile;
+        }
+        bus.write(0x2003, 0);
+        for data in oam.iter() {
+            bus.write(0x2004, *data);
+        }
+    }
+
+    #[test]
+    fn test_8x16_sprites_from_both_pattern_tables_clock_twice_a_scanline() {
+        let mut bus = mmc3_bus(2, 8);
+        bus.write(0xc000, 30);
+        bus.write(0xc001, 0);
+        bus.write(0xe001, 0);
+        // 8x16 sprites, backgrounds at $0000
+        bus.write(0x2000, 0x20);
+        bus.write(0x2001, 0x18);
+
+        // on scanlines 20-35: slot 0 fetches from $1000, slot 1 from $0000, the empty slots
+        // tile $FF from $1000. A12 rises twice: with slot 0, and again after the 12 dots it's
+        // low for slot 1. Between the other slots it's low for 4 dots only: filtered out.
+        load_sprites(&mut bus, &[(20, 0x01), (20, 0x02)]);
+        // clocks: 1 on scanline 0 loads the latch, 19 until scanline 19, then 2 a scanline
+        assert_eq!(irq_at(&mut bus), Some((25, 262)));
+    }
+
+    #[test]
+    fn test_8x16_sprites_from_one_pattern_table_clock_once_a_scanline() {
+        let mut bus = mmc3_bus(2, 8);
+        bus.write(0xc000, 30);
+        bus.write(0xc001, 0);
+        bus.write(0xe001, 0);
+        bus.write(0x2000, 0x20);
+        bus.write(0x2001, 0x18);
+
+        load_sprites(&mut bus, &[(20, 0x01), (20, 0x03)]);
+        assert_eq!(irq_at(&mut bus), Some((30, 264)));
+    }
+}
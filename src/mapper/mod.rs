@@ -0,0 +1,285 @@
+// Cartridge boards: what the CPU sees at $4020-$FFFF and the PPU at $0000-$1FFF.
+// https://wiki.nesdev.com/w/index.php/Mapper
+//
+// The bus sends CPU accesses above the APU and I/O registers to the mapper, the PPU fetches
+// pattern tables through it and asks it which nametables are mirrored. The PPU owns the
+// cartridge and the bus reaches it through the PPU: bank switching registers written by the CPU
+// change what the PPU renders.
+//
+// Boards are selected by the iNES mapper number, see `MAPPERS`. `Cartridge` holds one of them:
+// the CPU and the PPU go through it on every access, a match is cheaper than a virtual call.
+use crate::rom::{Mirroring, Rom};
+
+pub mod axrom;
+pub mod cnrom;
+pub mod mmc1;
+pub mod mmc2;
+pub mod mmc3;
+pub mod nrom;
+pub mod uxrom;
+
+pub trait Mapper {
+    /// What a CPU read of $4020-$FFFF would return, without side effects
+    fn peek(&self, addr: u16) -> u8;
+
+    /// CPU read of $4020-$FFFF
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    /// CPU write to $4020-$FFFF: PRG RAM or the board's registers
+    fn write(&mut self, addr: u16, data: u8);
+
+    /// Changes PRG ROM or RAM mapped at a CPU address, registers are left alone
+    fn poke(&mut self, addr: u16, data: u8);
+
+    /// Offset in PRG ROM currently mapped at a CPU address
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize>;
+
+    /// What a PPU read of the pattern tables would return, without side effects
+    fn peek_chr(&self, addr: u16) -> u8;
+
+    /// PPU read of the pattern tables, either fetching tiles to render or through $2007
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        self.peek_chr(addr)
+    }
+
+    /// PPU write to the pattern tables, only CHR RAM takes it
+    fn write_chr(&mut self, addr: u16, data: u8);
+
+    /// Changes CHR ROM or RAM mapped at a PPU address
+    fn poke_chr(&mut self, addr: u16, data: u8);
+
+    /// Offset in CHR memory currently mapped at a PPU address
+    fn chr_offset(&self, addr: u16) -> usize;
+
+    fn mirroring(&self) -> Mirroring;
+
+    /// The board holds /IRQ low
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// The IRQ depends on when the PPU reads the pattern tables: the PPU can't put its
+    /// fetches off until something else needs them
+    fn counts_chr_reads(&self) -> bool {
+        false
+    }
+
+    /// CPU cycles went by, the board sees them on M2
+    fn tick(&mut self, _cycles: u16) {}
+
+    /// Boards without a chip to decode the address write their registers while PRG ROM
+    /// drives the data bus too: the register gets the AND of both. Emulating it is optional,
+    /// some homebrew and hacks were only ever tested on emulators without it.
+    fn set_bus_conflicts(&mut self, _enabled: bool) {}
+
+    /// Boards without registers have nothing to do here. There's no reset line on the
+    /// cartridge connector: the reset button doesn't reach the mapper.
+    fn power_on(&mut self) {}
+}
+
+/// The board plugged in, see `MAPPERS`
+pub enum Cartridge {
+    Nrom(nrom::Nrom),
+    Mmc1(mmc1::Mmc1),
+    Uxrom(uxrom::Uxrom),
+    Cnrom(cnrom::Cnrom),
+    Mmc3(mmc3::Mmc3),
+    Axrom(axrom::Axrom),
+    Mmc2(mmc2::Mmc2),
+}
+
+macro_rules! dispatch {
+    ($cartridge:expr, $board:ident => $call:expr) => {
+        match $cartridge {
+            Cartridge::Nrom($board) => $call,
+            Cartridge::Mmc1($board) => $call,
+            Cartridge::Uxrom($board) => $call,
+            Cartridge::Cnrom($board) => $call,
+            Cartridge::Mmc3($board) => $call,
+            Cartridge::Axrom($board) => $call,
+            Cartridge::Mmc2($board) => $call,
+        }
+    };
+}
+
+impl Mapper for Cartridge {
+    #[inline]
+    fn peek(&self, addr: u16) -> u8 {
+        dispatch!(self, board => board.peek(addr))
+    }
+
+    #[inline]
+    fn read(&mut self, addr: u16) -> u8 {
+        dispatch!(self, board => board.read(addr))
+    }
+
+    #[inline]
+    fn write(&mut self, addr: u16, data: u8) {
+        dispatch!(self, board => board.write(addr, data))
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        dispatch!(self, board => board.poke(addr, data))
+    }
+
+    #[inline]
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        dispatch!(self, board => board.prg_rom_offset(addr))
+    }
+
+    #[inline]
+    fn peek_chr(&self, addr: u16) -> u8 {
+        dispatch!(self, board => board.peek_chr(addr))
+    }
+
+    #[inline]
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        dispatch!(self, board => board.read_chr(addr))
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        dispatch!(self, board => board.write_chr(addr, data))
+    }
+
+    fn poke_chr(&mut self, addr: u16, data: u8) {
+        dispatch!(self, board => board.poke_chr(addr, data))
+    }
+
+    #[inline]
+    fn chr_offset(&self, addr: u16) -> usize {
+        dispatch!(self, board => board.chr_offset(addr))
+    }
+
+    #[inline]
+    fn mirroring(&self) -> Mirroring {
+        dispatch!(self, board => board.mirroring())
+    }
+
+    #[inline]
+    fn irq(&self) -> bool {
+        dispatch!(self, board => board.irq())
+    }
+
+    fn counts_chr_reads(&self) -> bool {
+        dispatch!(self, board => board.counts_chr_reads())
+    }
+
+    #[inline]
+    fn tick(&mut self, cycles: u16) {
+        dispatch!(self, board => board.tick(cycles))
+    }
+
+    fn set_bus_conflicts(&mut self, enabled: bool) {
+        dispatch!(self, board => board.set_bus_conflicts(enabled))
+    }
+
+    fn power_on(&mut self) {
+        dispatch!(self, board => board.power_on())
+    }
+}
+
+struct Board {
+    number: u8,
+    name: &'static str,
+    new: fn(Rom) -> Cartridge,
+}
+
+static MAPPERS: &[Board] = &[
+    Board {
+        number: 0,
+        name: "NROM",
+        new: |rom| Cartridge::Nrom(nrom::Nrom::new(rom)),
+    },
+    Board {
+        number: 1,
+        name: "MMC1",
+        new: |rom| Cartridge::Mmc1(mmc1::Mmc1::new(rom)),
+    },
+    Board {
+        number: 2,
+        name: "UxROM",
+        new: |rom| Cartridge::Uxrom(uxrom::Uxrom::new(rom)),
+    },
+    Board {
+        number: 3,
+        name: "CNROM",
+        new: |rom| Cartridge::Cnrom(cnrom::Cnrom::new(rom)),
+    },
+    Board {
+        number: 4,
+        name: "MMC3",
+        new: |rom| Cartridge::Mmc3(mmc3::Mmc3::new(rom)),
+    },
+    Board {
+        number: 7,
+        name: "AxROM",
+        new: |rom| Cartridge::Axrom(axrom::Axrom::new(rom)),
+    },
+    Board {
+        number: 9,
+        name: "MMC2",
+        new: |rom| Cartridge::Mmc2(mmc2::Mmc2::new(rom)),
+    },
+    Board {
+        number: 10,
+        name: "MMC4",
+        new: |rom| Cartridge::Mmc2(mmc2::Mmc2::mmc4(rom)),
+    },
+];
+
+/// Name of the board behind an iNES mapper number, `None` if it's not supported
+pub fn name(number: u8) -> Option<&'static str> {
+    MAPPERS
+        .iter()
+        .find(|board| board.number == number)
+        .map(|board| board.name)
+}
+
+/// The mapper of `rom`, it takes over PRG and CHR memory
+pub fn for_rom(rom: Rom) -> Result<Cartridge, String> {
+    match MAPPERS.iter().find(|board| board.number == rom.mapper) {
+        Some(board) => Ok((board.new)(rom)),
+        None => Err(unsupported(rom.mapper)),
+    }
+}
+
+pub fn unsupported(number: u8) -> String {
+    format!("mapper {} is not supported", number)
+}
+
+/// `offset` wrapped around `len`: smaller ROMs show up several times in their window, registers
+/// select banks past the end. Boards have power of two sizes, the division is for odd dumps.
+pub fn mirror(offset: usize, len: usize) -> usize {
+    if len.is_power_of_two() {
+        offset & (len - 1)
+    } else {
+        offset % len
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::rom::Rom;
+
+    /// iNES ROM for `mapper`: every 16K PRG ROM bank is filled with its number, and every 1K of
+    /// CHR ROM with its own. No CHR banks means CHR RAM.
+    pub fn banked_rom(mapper: u8, prg_banks: u8, chr_banks: u8, vertical: bool) -> Rom {
+        let mut data = b"NES\x1a".to_vec();
+        data.extend(&[
+            prg_banks,
+            chr_banks,
+            mapper << 4 | vertical as u8,
+            mapper & 0xf0,
+        ]);
+        data.extend(&[0; 8]);
+        for bank in 0..prg_banks {
+            data.extend(vec![bank; 0x4000]);
+        }
+        for bank in 0..chr_banks as usize * 8 {
+            data.extend(vec![bank as u8; 0x400]);
+        }
+        Rom::load(&data).unwrap()
+    }
+}
@@ -0,0 +1,127 @@
+// NROM, mapper 0: no bank switching. https://wiki.nesdev.com/w/index.php/NROM
+//
+// 16K or 32K of PRG ROM at $8000, 16K is mirrored at $C000. 8K of CHR ROM, or CHR RAM
+// when the ROM has none. Mirroring is soldered on the board.
+// Family BASIC has 8K of PRG RAM at $6000, it's there for every game: the others never touch it.
+use crate::mapper::{mirror, Mapper};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_RAM: u16 = 0x6000;
+const PRG_ROM: u16 = 0x8000;
+
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: Rom) -> Self {
+        Nrom {
+            mirroring: rom.rom_flags.mirroring(),
+            prg_rom: rom.prg_rom,
+            prg_ram: vec![0; 0x2000],
+            chr: rom.chr_rom,
+            chr_ram: rom.chr_ram,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            PRG_RAM..=0x7fff => self.prg_ram[(addr - PRG_RAM) as usize],
+            PRG_ROM..=0xffff => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if let PRG_RAM..=0x7fff = addr {
+            self.prg_ram[(addr - PRG_RAM) as usize] = data;
+        }
+        // no registers: writes to ROM go nowhere
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=0x7fff => self.prg_ram[(addr - PRG_RAM) as usize] = data,
+            PRG_ROM..=0xffff => {
+                let offset = self.prg_rom_offset(addr).unwrap();
+                self.prg_rom[offset] = data;
+            }
+            _ => {}
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr >= PRG_ROM {
+            Some(mirror((addr - PRG_ROM) as usize, self.prg_rom.len()))
+        } else {
+            None
+        }
+    }
+
+    fn peek_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            self.poke_chr(addr, data);
+        }
+    }
+
+    fn poke_chr(&mut self, addr: u16, data: u8) {
+        let offset = self.chr_offset(addr);
+        self.chr[offset] = data;
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        mirror(addr as usize, self.chr.len())
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::test_ines_rom;
+
+    #[test]
+    fn test_16k_prg_rom_is_mirrored() {
+        let mut rom = test_ines_rom::test_rom();
+        rom.prg_rom = (0..0x4000).map(|i| (i >> 8) as u8).collect();
+        let mut nrom = Nrom::new(rom);
+
+        assert_eq!(nrom.read(0x8123), 0x01);
+        assert_eq!(nrom.read(0xc123), 0x01);
+        assert_eq!(nrom.prg_rom_offset(0xfffc), Some(0x3ffc));
+        assert_eq!(nrom.prg_rom_offset(0x6000), None);
+
+        nrom.write(0xc123, 0xff);
+        assert_eq!(nrom.read(0x8123), 0x01);
+        nrom.write(0x6005, 0x66);
+        assert_eq!(nrom.read(0x6005), 0x66);
+    }
+
+    #[test]
+    fn test_only_chr_ram_is_writable() {
+        let mut nrom = Nrom::new(test_ines_rom::test_rom());
+        nrom.write_chr(0x0010, 0x66);
+        assert_eq!(nrom.read_chr(0x0010), 2);
+        nrom.poke_chr(0x0010, 0x66);
+        assert_eq!(nrom.read_chr(0x0010), 0x66);
+
+        let mut rom = test_ines_rom::test_rom();
+        rom.chr_ram = true;
+        let mut nrom = Nrom::new(rom);
+        nrom.write_chr(0x1ff0, 0x77);
+        assert_eq!(nrom.read_chr(0x1ff0), 0x77);
+    }
+}
@@ -0,0 +1,145 @@
+// UxROM, mapper 2: UNROM and UOROM. https://wiki.nesdev.com/w/index.php/UxROM
+//
+// A write to $8000-$FFFF selects the 16K PRG ROM bank at $8000, the last bank is fixed at $C000.
+// 8K of CHR RAM, mirroring is soldered on the board. The register is a plain latch: writes
+// have bus conflicts.
+use crate::mapper::{mirror, Mapper};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_ROM: u16 = 0x8000;
+const PRG_BANK_SIZE: usize = 0x4000;
+
+pub struct Uxrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+    bank: u8,
+    bus_conflicts: bool,
+}
+
+impl Uxrom {
+    pub fn new(rom: Rom) -> Self {
+        Uxrom {
+            mirroring: rom.rom_flags.mirroring(),
+            prg_rom: rom.prg_rom,
+            chr: rom.chr_rom,
+            chr_ram: rom.chr_ram,
+            bank: 0,
+            bus_conflicts: false,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn peek(&self, addr: u16) -> u8 {
+        match self.prg_rom_offset(addr) {
+            Some(offset) => self.prg_rom[offset],
+            None => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if addr >= PRG_ROM {
+            self.bank = if self.bus_conflicts {
+                data & self.peek(addr)
+            } else {
+                data
+            };
+        }
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        if let Some(offset) = self.prg_rom_offset(addr) {
+            self.prg_rom[offset] = data;
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < PRG_ROM {
+            return None;
+        }
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let bank = if addr < 0xc000 {
+            mirror(self.bank as usize, banks)
+        } else {
+            banks - 1
+        };
+        Some(bank * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1)))
+    }
+
+    fn peek_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            self.poke_chr(addr, data);
+        }
+    }
+
+    fn poke_chr(&mut self, addr: u16, data: u8) {
+        let offset = self.chr_offset(addr);
+        self.chr[offset] = data;
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        mirror(addr as usize, self.chr.len())
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn set_bus_conflicts(&mut self, enabled: bool) {
+        self.bus_conflicts = enabled;
+    }
+
+    fn power_on(&mut self) {
+        self.bank = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::input;
+    use crate::mapper::test::banked_rom;
+    use crate::ppu::ppu::NesPPU;
+
+    #[test]
+    fn test_switches_8000_and_keeps_the_last_bank_at_c000() {
+        let mut bus = Bus::<NesPPU>::new(
+            banked_rom(2, 8, 0, true),
+            |_: &NesPPU, _: &mut input::Joypad| {},
+        );
+        assert_eq!(bus.read(0x8000), 0);
+        assert_eq!(bus.read(0xc000), 7);
+
+        bus.write(0x8000, 5);
+        assert_eq!(bus.read(0xbfff), 5);
+        assert_eq!(bus.read(0xffff), 7);
+        assert_eq!(bus.prg_rom_offset(0x8010), Some(5 * 0x4000 + 0x10));
+        assert_eq!(bus.ppu().mirroring(), Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_bus_conflicts() {
+        let mut bus = Bus::<NesPPU>::new(
+            banked_rom(2, 8, 0, true),
+            |_: &NesPPU, _: &mut input::Joypad| {},
+        );
+        bus.set_bus_conflicts(true);
+        // the last bank is filled with 7: 0b111 & 0b1110
+        bus.write(0xc000, 0x0e);
+        assert_eq!(bus.read(0x8000), 6);
+        // the current bank is 6: 0b110 & 0b011
+        bus.write(0x8000, 0x03);
+        assert_eq!(bus.read(0x8000), 2);
+
+        bus.set_bus_conflicts(false);
+        bus.write(0x8000, 0x03);
+        assert_eq!(bus.read(0x8000), 3);
+    }
+}
@@ -1,5 +1,7 @@
 // http://www.dustmop.io/blog/2015/04/28/nes-graphics-part-1/
 
+use crate::cdl::{CodeDataLog, CHR_READ, CHR_RENDERED};
+use crate::mapper::{Cartridge, Mapper};
 use crate::ppu::registers::control::ControlRegister;
 use crate::ppu::registers::mask::MaskRegister;
 use crate::ppu::registers::status::StatusRegister;
@@ -7,10 +9,32 @@ use crate::rom::Mirroring;
 use crate::screen::frame::Frame;
 use crate::screen::render;
 use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The first dot at or after each dot of a scanline where `render_dots` has something to do: the
+/// fetch steps 0, 4 and 6 of every 8 dots up to dot 336, and dots 256 and 257. 341 when none.
+const NEXT_DOT: [u16; 342] = next_dots();
+
+const fn next_dots() -> [u16; 342] {
+    let mut next = [341; 342];
+    let mut dot = 340;
+    while dot > 0 {
+        let step = (dot - 1) % 8;
+        let fetch = dot <= 336 && (step == 0 || step == 4 || step == 6);
+        next[dot] = if fetch || dot == 256 || dot == 257 {
+            dot as u16
+        } else {
+            next[dot + 1]
+        };
+        dot -= 1;
+    }
+    next[0] = next[1];
+    next
+}
 
 pub struct NesPPU {
-    pub chr_rom: Vec<u8>,
-    pub mirroring: Mirroring,
+    /// The cartridge: pattern tables and nametable mirroring. The bus reaches it through the PPU.
+    pub mapper: Cartridge,
     pub ctrl: ControlRegister,
     pub mask: MaskRegister,
     pub status: StatusRegister,
@@ -21,13 +45,41 @@ pub struct NesPPU {
     pub oam_data: [u8; 256],
     pub line: usize,
     pub cycles: usize,
+    /// Dots of the scanline done with their fetches. The others wait for the end of the
+    /// scanline or for `catch_up`, unless the mapper counts the reads.
+    fetched: usize,
+    defer_fetches: bool,
+    /// Background tiles of the current scanline: two fetched at the end of the previous one,
+    /// then 32 on dots 1-256. Fine X scroll shows 33 of them.
+    pub bg_tiles: [BgTile; 34],
+    /// Where the background tiles start, see `scanline_origin`
+    pub bg_origin: (usize, usize),
+    /// Pattern addresses fetched for the sprite slots of the current scanline
+    sprite_patterns: [u16; 8],
+    /// Pattern tables were read since the last poll_chr_reads
+    chr_reads: bool,
     nmi_interrupt: Option<u8>,
     pub palette_table: [u8; 32],
     read_data_buf: u8,
 
     pub frame: RefCell<Frame>,
 
-    pub sprite_zero_pixels: Vec<(u8, u8)>
+    pub sprite_zero_pixels: Vec<(u8, u8)>,
+    warming_up: bool,
+
+    /// Code/data logger, shared with the bus
+    pub cdl: Option<Rc<RefCell<CodeDataLog>>>,
+}
+
+/// A background tile fetched for a scanline
+#[derive(Clone, Copy, Default)]
+pub struct BgTile {
+    /// The tile row in the pattern table
+    pub addr: u16,
+    /// Attribute bits of the tile: its palette
+    pub palette: u8,
+    pub low: u8,
+    pub high: u8,
 }
 
 pub struct Addr {
@@ -114,20 +166,35 @@ pub trait PPU {
     fn write_to_ppu_addr(&mut self, value: u8);
     fn write_to_data(&mut self, value: u8);
     fn read_data(&mut self) -> u8;
+    /// What read_status would return, without clearing vblank or the write latch
+    fn peek_status(&self) -> u8;
+    /// What read_data would return, without advancing the address or refilling the read buffer
+    fn peek_data(&self) -> u8;
+    /// Reads the PPU address space ($0000-$3FFF: pattern tables, nametables, palette)
+    /// without going through the PPUADDR/PPUDATA registers
+    fn peek(&self, addr: u16) -> u8;
+    /// Writes the PPU address space, CHR ROM included, without touching the registers
+    fn poke(&mut self, addr: u16, value: u8);
     fn write_oam_dma(&mut self, value: &[u8; 256]);
     fn tick(&mut self, cycles: u16) -> bool;
     fn poll_nmi_interrupt(&mut self) -> Option<u8>;
+    fn power_on(&mut self);
+    fn reset(&mut self);
+    /// The cartridge, the bus sends CPU accesses from $4020 up to it
+    fn mapper(&self) -> &Cartridge;
+    fn mapper_mut(&mut self) -> &mut Cartridge;
+    /// The pattern tables were read since the last poll: mappers watching these reads may have
+    /// changed their IRQ
+    fn poll_chr_reads(&mut self) -> bool;
+    /// Runs the fetches `tick` put off, before the CPU changes what they read
+    fn catch_up(&mut self);
 }
 
 impl NesPPU {
-    pub fn new_empty_rom() -> Self {
-        NesPPU::new(vec![0; 2048], Mirroring::HORIZONTAL)
-    }
-
-    pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+    pub fn new(mapper: Cartridge) -> Self {
         NesPPU {
-            chr_rom: chr_rom,
-            mirroring: mirroring,
+            defer_fetches: !mapper.counts_chr_reads(),
+            mapper,
             ctrl: ControlRegister::new(),
             mask: MaskRegister::new(),
             status: StatusRegister::new(),
@@ -138,11 +205,18 @@ impl NesPPU {
             oam_data: [0; 64 * 4],
             line: 0,
             cycles: 0,
+            fetched: 0,
+            bg_tiles: [BgTile::default(); 34],
+            bg_origin: (0, 0),
+            sprite_patterns: [0; 8],
+            chr_reads: false,
             nmi_interrupt: None,
             palette_table: [0; 32],
             read_data_buf: 0,
             frame: RefCell::from(Frame::new()),
             sprite_zero_pixels: vec!(),
+            warming_up: false,
+            cdl: None,
         }
     }
 
@@ -153,19 +227,30 @@ impl NesPPU {
     // Vertical:
     //   [ A ] [ B ]
     //   [ a ] [ b ]
+
+    // Single screen A (B is the same with the second 1K of VRAM):
+    //   [ A ] [ a ]
+    //   [ a ] [ a ]
     pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
         let mirrored_vram = addr & 0b10111111111111; // mirror down 0x3000-0x3eff to 0x2000 - 0x2eff
         let vram_index = mirrored_vram - 0x2000; // to vram vector
         let name_table = vram_index / 0x400;
-        match (&self.mirroring, name_table) {
+        match (self.mirroring(), name_table) {
             (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
             (Mirroring::HORIZONTAL, 2) => vram_index - 0x400,
             (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
             (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
+            (Mirroring::SINGLE_SCREEN_A, _) => vram_index % 0x400,
+            (Mirroring::SINGLE_SCREEN_B, _) => vram_index % 0x400 + 0x400,
             _ => vram_index,
         }
     }
 
+    /// Nametable mirroring, some mappers switch it at runtime
+    pub fn mirroring(&self) -> Mirroring {
+        self.mapper.mirroring()
+    }
+
     fn increment_vram_addr(&mut self) {
         self.addr.increment(self.ctrl.vram_addr_increment());
 
@@ -175,6 +260,175 @@ impl NesPPU {
         }
     }
 
+    /// Marks CHR bytes mapped at `addr` in the code/data log
+    pub fn log_chr(&self, addr: u16, len: usize, flags: u8) {
+        if let Some(cdl) = &self.cdl {
+            let offset = self.mapper.chr_offset(addr);
+            cdl.borrow_mut().log_chr(offset, len, flags);
+        }
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask.show_background() || self.mask.show_sprites()
+    }
+
+    /// Dots `from..to` of the current scanline. The visible scanlines and the pre-render one
+    /// fetch patterns while rendering is enabled. Mappers watch these reads on the PPU address
+    /// bus: MMC3 counts scanlines by the A12 rising edges, MMC2 switches banks after tile $FD/$FE.
+    fn render_dots(&mut self, from: usize, to: usize) {
+        if !(self.line < 240 || self.line == 261) {
+            return;
+        }
+        let fetching = self.rendering_enabled();
+        let mut dot = NEXT_DOT[from] as usize;
+        while dot < to {
+            match dot {
+                1..=256 if fetching => self.fetch_bg_tile((dot - 1) / 8 + 2, (dot - 1) % 8),
+                257..=320 if fetching => self.fetch_sprite_pattern(dot),
+                321..=336 if fetching => self.fetch_bg_tile((dot - 321) / 8, (dot - 321) % 8),
+                _ => {}
+            }
+            if dot == 256 && self.line < 240 {
+                self.draw_bg_line();
+            }
+            if dot == 257 {
+                // the horizontal scroll is copied for the next scanline
+                self.bg_origin = self.scanline_origin((self.line + 1) % 262);
+            }
+            dot = NEXT_DOT[dot + 1] as usize;
+        }
+    }
+
+    /// `tick` up to a fetch the mapper is waiting for or past the end of the scanline
+    #[inline(never)]
+    fn run_dots(&mut self, mut cycles: usize) -> bool {
+        let mut frame_done = false;
+        while cycles > 0 {
+            let dots = cycles.min(341 - self.cycles);
+            self.cycles += dots;
+            cycles -= dots;
+            if self.cycles == 341 {
+                self.catch_up();
+                frame_done |= self.next_line();
+            }
+        }
+        if !self.defer_fetches {
+            self.catch_up();
+        }
+        frame_done
+    }
+
+    /// Where `line` starts in the 512x480 pixels of the four nametables
+    fn scanline_origin(&self, line: usize) -> (usize, usize) {
+        let nametable = (self.ctrl.nametable_addr() - 0x2000) as usize / 0x400;
+        let x = nametable % 2 * 256 + self.scroll.scroll_x as usize;
+        let y = (nametable / 2 * 240 + self.scroll.scroll_y as usize + line) % 480;
+        (x, y)
+    }
+
+    /// Nametable and attribute bytes of a background tile of the scanline, its pattern bytes
+    /// are left to fetch
+    fn bg_tile(&self, slot: usize) -> BgTile {
+        let (x, y) = self.bg_origin;
+        let column = (x / 8 + slot) % 64;
+        let row = y / 8;
+        let nametable = 0x2000 + (column / 32 + row / 30 * 2) as u16 * 0x400;
+        let (column, row) = (column % 32, row % 30);
+        let tile_addr = nametable + (row * 32 + column) as u16;
+        let tile = self.vram[self.mirror_vram_addr(tile_addr) as usize];
+        let attribute_addr = nametable + 0x3c0 + (row / 4 * 8 + column / 4) as u16;
+        let attribute = self.vram[self.mirror_vram_addr(attribute_addr) as usize];
+        let shift = (row % 4 / 2 * 2 + column % 4 / 2) * 2;
+        BgTile {
+            addr: self.ctrl.bknd_pattern_addr() + tile as u16 * 16 + (y % 8) as u16,
+            palette: attribute >> shift & 0b11,
+            low: 0,
+            high: 0,
+        }
+    }
+
+    /// Background fetches, 8 dots a tile: nametable and attribute bytes come from VRAM (the
+    /// mapper doesn't see them), then the low and high bytes of the tile row
+    fn fetch_bg_tile(&mut self, slot: usize, step: usize) {
+        let addr = self.bg_tiles[slot].addr;
+        match step {
+            0 => self.bg_tiles[slot] = self.bg_tile(slot),
+            4 => self.bg_tiles[slot].low = self.fetch_pattern(addr),
+            6 => self.bg_tiles[slot].high = self.fetch_pattern(addr + 8),
+            _ => {}
+        }
+    }
+
+    fn fetch_pattern(&mut self, addr: u16) -> u8 {
+        self.log_chr(addr, 1, CHR_RENDERED);
+        self.read_chr(addr)
+    }
+
+    /// Pattern table read the mapper sees, its IRQ may change
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        self.chr_reads = true;
+        self.mapper.read_chr(addr)
+    }
+
+    /// Draws the background of the scanline from its fetched tiles. With rendering disabled the
+    /// PPU fetched nothing: the tiles are looked up without the mapper seeing it.
+    fn draw_bg_line(&mut self) {
+        if !self.rendering_enabled() {
+            for slot in 0..33 {
+                let mut tile = self.bg_tile(slot);
+                tile.low = self.mapper.peek_chr(tile.addr);
+                tile.high = self.mapper.peek_chr(tile.addr + 8);
+                self.bg_tiles[slot] = tile;
+            }
+        }
+        render::render_bg_scanline(self, self.line, &mut self.frame.borrow_mut());
+    }
+
+    /// Sprite pattern fetches (dots 257-320): 8 slots of 8 dots, two garbage nametable fetches
+    /// then the low and high pattern bytes of a sprite on the line, tile $FF for the empty slots.
+    /// Sprites are drawn at the end of the frame, these reads are only there for the mapper.
+    fn fetch_sprite_pattern(&mut self, dot: usize) {
+        let slot = (dot - 257) / 8;
+        let addr = self.sprite_patterns[slot];
+        match (dot - 257) % 8 {
+            0 if slot == 0 => self.evaluate_sprites(),
+            4 => {
+                self.read_chr(addr);
+            }
+            6 => {
+                self.read_chr(addr + 8);
+            }
+            _ => {}
+        }
+    }
+
+    /// Pattern addresses of the first 8 sprites on the line. Vertical flip changes only the row,
+    /// it's ignored.
+    fn evaluate_sprites(&mut self) {
+        let height = self.ctrl.sprite_size() as usize;
+        let line = self.line;
+        let mut patterns = [self.sprite_pattern_addr(0xff, 0); 8];
+        let mut fetched = 0;
+        for sprite in self.oam_data.chunks(4) {
+            let y = sprite[0] as usize;
+            if fetched < 8 && line < 240 && line >= y && line < y + height {
+                patterns[fetched] = self.sprite_pattern_addr(sprite[1], (line - y) as u16);
+                fetched += 1;
+            }
+        }
+        self.sprite_patterns = patterns;
+    }
+
+    fn sprite_pattern_addr(&self, tile: u8, row: u16) -> u16 {
+        if self.ctrl.sprite_size() == 8 {
+            return self.ctrl.sprt_pattern_addr() + tile as u16 * 16 + row;
+        }
+        // 8x16: bit 0 of the tile selects the pattern table, the bottom half is the next tile
+        let bank = (tile as u16 & 1) * 0x1000;
+        let tile = (tile & 0xfe) as u16 + row / 8;
+        bank + tile * 16 + row % 8
+    }
+
     fn has_sprite_hit(&self, cycle: usize) -> bool {
         let y = self.oam_data[0] as usize;
         let x = self.oam_data[3] as usize;
@@ -182,11 +436,62 @@ impl NesPPU {
         (y+5 == self.line) && x <= cycle && self.mask.show_sprites()
     }
 
+    /// End of a scanline, true when the frame is done
+    fn next_line(&mut self) -> bool {
+        if self.has_sprite_hit(self.cycles) {
+            self.status.set_sprite_zero_hit(true);
+        }
+        // } else {
+        //     self.status.set_sprite_zero_hit(false);
+        // }
+
+        self.cycles -= 341;
+        self.fetched = 0;
+        self.line += 1;
+
+        if self.line == 261 {
+            // pre-render scanline: ctrl, mask, scroll and addr accept writes from now on
+            self.warming_up = false;
+        }
+
+        if self.line == 241 {
+            render::render_sprites(self, &mut self.frame.borrow_mut());
+            self.status.set_vblank_status(true);
+            self.status.set_sprite_zero_hit(false);
+            if self.ctrl.generate_vblank_nmi() {
+                self.nmi_interrupt = Some(1);
+            }
+        }
+
+        if self.line >= 262 {
+            // self.frame.borrow_mut().clear();
+            self.line = 0;
+            self.nmi_interrupt = None;
+            self.status.set_sprite_zero_hit(false);
+            self.status.reset_vblank_status();
+            return true;
+        }
+        false
+    }
+
+
+}
 
+// $3F20-$3FFF are mirrors of $3F00-$3F1F
+// Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
+fn palette_index(addr: u16) -> usize {
+    let index = (addr & 0x1f) as usize;
+    match index {
+        0x10 | 0x14 | 0x18 | 0x1c => index - 0x10,
+        _ => index,
+    }
 }
 
 impl PPU for NesPPU {
     fn write_to_ctrl(&mut self, value: u8) {
+        if self.warming_up {
+            return;
+        }
         let before_nmi_status = self.ctrl.generate_vblank_nmi();
         self.ctrl.update(value);
         if !before_nmi_status && self.ctrl.generate_vblank_nmi() && self.status.is_in_vblank() {
@@ -195,6 +500,9 @@ impl PPU for NesPPU {
     }
 
     fn write_to_mask(&mut self, value: u8) {
+        if self.warming_up {
+            return;
+        }
         self.mask.update(value);
     }
 
@@ -220,10 +528,16 @@ impl PPU for NesPPU {
     }
 
     fn write_to_scroll(&mut self, value: u8) {
+        if self.warming_up {
+            return;
+        }
         self.scroll.write(value);
     }
 
     fn write_to_ppu_addr(&mut self, value: u8) {
+        if self.warming_up {
+            return;
+        }
         self.addr.udpate(value);
         if self.addr.read() > 0x3fff {
             self.addr.set(self.addr.read() & 0b11111111111111); //mirror down addr above 0x3fff
@@ -233,22 +547,15 @@ impl PPU for NesPPU {
     fn write_to_data(&mut self, value: u8) {
         let addr = self.addr.read();
         match addr {
-            0..=0x1fff => println!("attempt to write to chr rom space {}", addr), //panic!("attempt to write to chr rom space {}", addr),
-            0x2000..=0x2fff => {
+            0..=0x1fff => self.mapper.write_chr(addr, value),
+            0x2000..=0x3eff => {
                 self.vram[self.mirror_vram_addr(addr) as usize] = value;
             }
-            0x3000..=0x3eff => unimplemented!("addr {} shouldn't be used in reallity", addr),
-
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = value;
-            }
-            0x3f00..=0x3fff =>
-            {
-                self.palette_table[(addr - 0x3f00) as usize] = value;
+            0x3f00..=0x3fff => {
+                // palette entries are 6 bit wide
+                self.palette_table[palette_index(addr)] = value & 0b111111;
             }
-            _ => panic!("unexpected access to mirrored space {}", addr),
+            _ => unreachable!("ppu addr is mirrored down to 0x3fff"),
         }
         self.increment_vram_addr();
     }
@@ -261,27 +568,48 @@ impl PPU for NesPPU {
         match addr {
             0..=0x1fff => {
                 let result = self.read_data_buf;
-                self.read_data_buf = self.chr_rom[addr as usize];
+                self.read_data_buf = self.read_chr(addr);
+                self.log_chr(addr, 1, CHR_READ);
                 result
             }
-            0x2000..=0x2fff => {
+            0x2000..=0x3eff => {
                 let result = self.read_data_buf;
                 self.read_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
                 result
             }
-            0x3000..=0x3eff => unimplemented!("addr {} shouldn't be used in reallity", addr),
+            0x3f00..=0x3fff => self.palette_table[palette_index(addr)],
+            _ => unreachable!("ppu addr is mirrored down to 0x3fff"),
+        }
+    }
 
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize]
-            }
+    fn peek_status(&self) -> u8 {
+        self.status.snapshot()
+    }
 
-            0x3f00..=0x3fff =>
-            {
-                self.palette_table[(addr - 0x3f00) as usize]
-            }
-            _ => panic!("unexpected access to mirrored space {}", addr),
+    fn peek_data(&self) -> u8 {
+        let addr = self.addr.read();
+        match addr {
+            0..=0x3eff => self.read_data_buf,
+            _ => self.peek(addr),
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        let addr = addr & 0x3fff;
+        match addr {
+            0..=0x1fff => self.mapper.peek_chr(addr),
+            0x2000..=0x3eff => self.vram[self.mirror_vram_addr(addr) as usize],
+            _ => self.palette_table[palette_index(addr)],
+        }
+    }
+
+    fn poke(&mut self, addr: u16, value: u8) {
+        self.catch_up();
+        let addr = addr & 0x3fff;
+        match addr {
+            0..=0x1fff => self.mapper.poke_chr(addr, value),
+            0x2000..=0x3eff => self.vram[self.mirror_vram_addr(addr) as usize] = value,
+            _ => self.palette_table[palette_index(addr)] = value & 0b111111,
         }
     }
 
@@ -292,54 +620,83 @@ impl PPU for NesPPU {
         }
     }
 
+    #[inline]
     fn tick(&mut self, cycles: u16) -> bool {
-        self.cycles += cycles as usize;
-        if self.cycles >= 341 {
-            if self.has_sprite_hit(self.cycles) {
-                self.status.set_sprite_zero_hit(true);
-            }
-            // } else {
-            //     self.status.set_sprite_zero_hit(false);
-            // }
+        let to = self.cycles + cycles as usize;
+        if to < 341 && (self.defer_fetches || NEXT_DOT[self.fetched] as usize >= to) {
+            self.cycles = to;
+            return false;
+        }
+        self.run_dots(cycles as usize)
+    }
 
-            self.cycles = self.cycles - 341;
-            self.line += 1;
+    fn poll_nmi_interrupt(&mut self) -> Option<u8> {
+        self.nmi_interrupt.take()
+    }
 
-            if(self.line < 241) {
-                render::render_bg_scanline(&self, self.line, &mut self.frame.borrow_mut());
-            }
+    // https://wiki.nesdev.com/w/index.php/PPU_power_up_state
+    fn power_on(&mut self) {
+        self.ctrl = ControlRegister::new();
+        self.mask = MaskRegister::new();
+        self.status = StatusRegister::new();
+        self.oam_addr = 0;
+        self.scroll = Scroll::new();
+        self.addr = Addr::new();
+        self.vram = [0; 2048];
+        self.oam_data = [0; 64 * 4];
+        self.line = 0;
+        self.cycles = 0;
+        self.fetched = 0;
+        self.bg_tiles = [BgTile::default(); 34];
+        self.bg_origin = (0, 0);
+        self.sprite_patterns = [0; 8];
+        self.chr_reads = false;
+        self.nmi_interrupt = None;
+        self.palette_table = [0; 32];
+        self.read_data_buf = 0;
+        self.frame = RefCell::from(Frame::new());
+        self.sprite_zero_pixels = vec![];
+        self.warming_up = true;
+    }
 
-            if self.line == 241 {
-                render::render_sprites(self, &mut self.frame.borrow_mut());
-                self.status.set_vblank_status(true);
-                self.status.set_sprite_zero_hit(false);
-                if self.ctrl.generate_vblank_nmi() {
-                    self.nmi_interrupt = Some(1);
-                }
-            }
+    fn reset(&mut self) {
+        self.catch_up();
+        self.ctrl.update(0);
+        self.mask.update(0);
+        self.scroll = Scroll::new();
+        self.addr.reset_latch();
+        self.read_data_buf = 0;
+        self.nmi_interrupt = None;
+        self.warming_up = true;
+    }
 
-            if self.line >= 262 {
-                // self.frame.borrow_mut().clear();
-                self.line = 0;
-                self.nmi_interrupt = None;
-                self.status.set_sprite_zero_hit(false);
-                self.status.reset_vblank_status();
-                return true;
-            }
-        }
-        return false;
+    fn mapper(&self) -> &Cartridge {
+        &self.mapper
     }
 
+    fn mapper_mut(&mut self) -> &mut Cartridge {
+        &mut self.mapper
+    }
 
+    fn poll_chr_reads(&mut self) -> bool {
+        std::mem::replace(&mut self.chr_reads, false)
+    }
 
-    fn poll_nmi_interrupt(&mut self) -> Option<u8> {
-        self.nmi_interrupt.take()
+    fn catch_up(&mut self) {
+        if self.fetched < self.cycles {
+            self.render_dots(self.fetched, self.cycles);
+            self.fetched = self.cycles;
+        }
     }
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::mapper;
+    use crate::mapper::test::banked_rom;
+    use crate::rom::test_ines_rom;
+
     pub struct MockPPU {
         pub ctrl: u8,
         pub mask: u8,
@@ -352,6 +709,7 @@ pub mod test {
         pub vram: [u8; 2048],
         pub oam: [u8; 64 * 4],
         pub ticks: usize,
+        pub mapper: Cartridge,
     }
 
     impl PPU for MockPPU {
@@ -369,6 +727,8 @@ pub mod test {
         }
         fn write_to_oam_data(&mut self, value: u8) {
             self.oamdata = value;
+            self.oam[self.oamaddr as usize] = value;
+            self.oamaddr = self.oamaddr.wrapping_add(1);
         }
         fn read_oam_data(&self) -> u8 {
             self.oamdata
@@ -385,8 +745,20 @@ pub mod test {
         fn read_data(&mut self) -> u8 {
             self.data
         }
+        fn peek_status(&self) -> u8 {
+            self.status
+        }
+        fn peek_data(&self) -> u8 {
+            self.data
+        }
+        fn peek(&self, addr: u16) -> u8 {
+            self.vram[addr as usize % self.vram.len()]
+        }
+        fn poke(&mut self, addr: u16, value: u8) {
+            self.vram[addr as usize % self.vram.len()] = value;
+        }
         fn write_oam_dma(&mut self, value: &[u8; 256]) {
-            self.oam = value.clone();
+            self.oam = *value;
         }
         fn tick(&mut self, cycles: u16) -> bool {
             self.ticks += cycles as usize;
@@ -395,6 +767,25 @@ pub mod test {
         fn poll_nmi_interrupt(&mut self) -> Option<u8> {
             None
         }
+        fn power_on(&mut self) {
+            let mut ppu = stub_ppu();
+            std::mem::swap(&mut ppu.mapper, &mut self.mapper);
+            *self = ppu;
+        }
+        fn reset(&mut self) {
+            self.ctrl = 0;
+            self.mask = 0;
+        }
+        fn mapper(&self) -> &Cartridge {
+            &self.mapper
+        }
+        fn mapper_mut(&mut self) -> &mut Cartridge {
+            &mut self.mapper
+        }
+        fn poll_chr_reads(&mut self) -> bool {
+            false
+        }
+        fn catch_up(&mut self) {}
     }
 
     pub fn stub_ppu() -> MockPPU {
@@ -410,12 +801,23 @@ pub mod test {
             vram: [0; 2048],
             oam: [0; 64 * 4],
             ticks: 0,
+            mapper: mapper::for_rom(test_ines_rom::test_rom()).unwrap(),
         }
     }
 
+    /// PPU on an NROM board with CHR RAM
+    fn new_ppu(mirroring: Mirroring) -> NesPPU {
+        let rom = banked_rom(0, 1, 0, mirroring == Mirroring::VERTICAL);
+        NesPPU::new(mapper::for_rom(rom).unwrap())
+    }
+
+    fn new_empty_rom() -> NesPPU {
+        new_ppu(Mirroring::HORIZONTAL)
+    }
+
     #[test]
     fn test_ppu_vram_writes() {
-        let mut ppu = NesPPU::new_empty_rom();
+        let mut ppu = new_empty_rom();
         ppu.write_to_ppu_addr(0x23);
         ppu.write_to_ppu_addr(0x05);
         ppu.write_to_data(0x66);
@@ -427,15 +829,42 @@ pub mod test {
     // #[test]
     // #[should_panic]
     // fn test_ppu_writing_to_chr_rom_is_prohibited() {
-    //     let mut ppu = NesPPU::new_empty_rom();
+    //     let mut ppu = new_empty_rom();
     //     ppu.write_to_ppu_addr(0x03);
     //     ppu.write_to_ppu_addr(0x05);
     //     ppu.write_to_data(0x66);
     // }
 
+    #[test]
+    fn test_ppu_ignores_writes_while_warming_up() {
+        let mut ppu = new_empty_rom();
+        ppu.vram[0x0305] = 0x66;
+        ppu.power_on();
+        assert_eq!(ppu.vram[0x0305], 0);
+
+        ppu.write_to_ctrl(0x80);
+        ppu.write_to_ppu_addr(0x23);
+        assert_eq!(ppu.ctrl.bits(), 0);
+        assert_eq!(ppu.addr.read(), 0);
+
+        // up to the pre-render scanline
+        for _ in 0..261 {
+            ppu.tick(341);
+        }
+        ppu.write_to_ctrl(0x80);
+        assert_eq!(ppu.ctrl.bits(), 0x80);
+
+        ppu.write_to_mask(0x18);
+        ppu.reset();
+        assert_eq!(ppu.ctrl.bits(), 0);
+        assert_eq!(ppu.mask.bits(), 0);
+        ppu.write_to_ctrl(0x80);
+        assert_eq!(ppu.ctrl.bits(), 0);
+    }
+
     #[test]
     fn test_ppu_vram_reads() {
-        let mut ppu = NesPPU::new_empty_rom();
+        let mut ppu = new_empty_rom();
         ppu.write_to_ctrl(0);
         ppu.vram[0x0305] = 0x66;
 
@@ -449,7 +878,7 @@ pub mod test {
 
     #[test]
     fn test_ppu_vram_reads_cross_page() {
-        let mut ppu = NesPPU::new_empty_rom();
+        let mut ppu = new_empty_rom();
         ppu.write_to_ctrl(0);
         ppu.vram[0x01ff] = 0x66;
         ppu.vram[0x0200] = 0x77;
@@ -464,7 +893,7 @@ pub mod test {
 
     #[test]
     fn test_ppu_vram_reads_step_32() {
-        let mut ppu = NesPPU::new_empty_rom();
+        let mut ppu = new_empty_rom();
         ppu.write_to_ctrl(0b100);
         ppu.vram[0x01ff] = 0x66;
         ppu.vram[0x01ff + 32] = 0x77;
@@ -484,7 +913,7 @@ pub mod test {
     //   [0x2800 B ] [0x2C00 b ]
     #[test]
     fn test_vram_horizontal_mirror() {
-        let mut ppu = NesPPU::new_empty_rom();
+        let mut ppu = new_empty_rom();
         ppu.write_to_ppu_addr(0x24);
         ppu.write_to_ppu_addr(0x05);
 
@@ -513,7 +942,7 @@ pub mod test {
     //   [0x2800 a ] [0x2C00 b ]
     #[test]
     fn test_vram_vertical_mirror() {
-        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::VERTICAL);
+        let mut ppu = new_ppu(Mirroring::VERTICAL);
 
         ppu.write_to_ppu_addr(0x20);
         ppu.write_to_ppu_addr(0x05);
@@ -540,7 +969,7 @@ pub mod test {
 
     #[test]
     fn test_read_status_resets_latch() {
-        let mut ppu = NesPPU::new_empty_rom();
+        let mut ppu = new_empty_rom();
         ppu.vram[0x0305] = 0x66;
 
         ppu.write_to_ppu_addr(0x21);
@@ -561,7 +990,7 @@ pub mod test {
 
     #[test]
     fn test_ppu_vram_mirroring() {
-        let mut ppu = NesPPU::new_empty_rom();
+        let mut ppu = new_empty_rom();
         ppu.write_to_ctrl(0);
         ppu.vram[0x0305] = 0x66;
 
@@ -575,7 +1004,7 @@ pub mod test {
 
     #[test]
     fn test_read_status_resets_vblank() {
-        let mut ppu = NesPPU::new_empty_rom();
+        let mut ppu = new_empty_rom();
         ppu.status.set_vblank_status(true);
 
         let status = ppu.read_status();
@@ -584,9 +1013,45 @@ pub mod test {
         assert_eq!(ppu.status.snapshot() >> 7, 0);
     }
 
+    #[test]
+    fn test_peek_has_no_side_effects() {
+        let mut ppu = new_empty_rom();
+        ppu.status.set_vblank_status(true);
+        ppu.vram[0x0305] = 0x66;
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+
+        assert_eq!(ppu.peek_status() >> 7, 1);
+        assert_eq!(ppu.peek_status() >> 7, 1);
+        assert_eq!(ppu.peek_data(), 0);
+        assert_eq!(ppu.addr.read(), 0x2305);
+
+        ppu.read_data(); //load_into_buffer
+        assert_eq!(ppu.peek_data(), 0x66);
+        assert_eq!(ppu.read_data(), 0x66);
+        assert_eq!(ppu.read_status() >> 7, 1);
+    }
+
+    #[test]
+    fn test_peek_and_poke_ppu_address_space() {
+        let mut ppu = new_ppu(Mirroring::VERTICAL);
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x00);
+
+        ppu.poke(0x0010, 0x11);
+        ppu.poke(0x2c05, 0x22);
+        ppu.poke(0x3f10, 0xff);
+        assert_eq!(ppu.mapper.peek_chr(0x10), 0x11);
+        assert_eq!(ppu.peek(0x2405), 0x22);
+        assert_eq!(ppu.peek(0x3f00), 0x3f);
+        assert_eq!(ppu.peek(0x7f20), 0x3f);
+        assert_eq!(ppu.addr.read(), 0x3f00);
+        assert_eq!(ppu.peek_data(), 0x3f);
+    }
+
     #[test]
     fn test_oam_read_write() {
-        let mut ppu = NesPPU::new_empty_rom();
+        let mut ppu = new_empty_rom();
         ppu.write_to_oam_addr(0x10);
         ppu.write_to_oam_data(0x66);
         ppu.write_to_oam_data(0x77);
@@ -600,7 +1065,7 @@ pub mod test {
 
     #[test]
     fn test_oam_dma() {
-        let mut ppu = NesPPU::new_empty_rom();
+        let mut ppu = new_empty_rom();
 
         let mut data = [0x66; 256];
         data[0] = 0x77;
@@ -617,4 +1082,52 @@ pub mod test {
         ppu.write_to_oam_addr(0x11);
         ppu.write_to_oam_addr(0x66);
     }
+
+    /// Frame drawn one scanline at a time with the background scrolled by `x`, `y`
+    fn scrolled_frame(mirroring: Mirroring, x: u8, y: u8) -> (NesPPU, Vec<u8>) {
+        let mut ppu = new_ppu(mirroring);
+        let mut seed = 1u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        };
+        for addr in 0..0x2000 {
+            ppu.poke(addr, next());
+        }
+        for addr in 0x2000..0x2800 {
+            ppu.poke(addr, next());
+        }
+        for addr in 0x3f00..0x3f20 {
+            ppu.poke(addr, next());
+        }
+        // no sprites
+        ppu.write_oam_dma(&[0xff; 256]);
+        ppu.write_to_scroll(x);
+        ppu.write_to_scroll(y);
+        ppu.write_to_mask(0x08);
+
+        // the first scanline of the first frame starts before the scroll is copied
+        for _ in 0..262 * 2 {
+            ppu.tick(341);
+        }
+        let frame = ppu.frame.borrow().data.clone();
+        (ppu, frame)
+    }
+
+    #[test]
+    fn test_scanlines_match_the_full_frame_render() {
+        for &(mirroring, x, y) in [
+            (Mirroring::VERTICAL, 0, 0),
+            (Mirroring::VERTICAL, 13, 0),
+            (Mirroring::VERTICAL, 200, 0),
+            (Mirroring::HORIZONTAL, 0, 21),
+        ]
+        .iter()
+        {
+            let (ppu, frame) = scrolled_frame(mirroring, x, y);
+            let mut expected = Frame::new();
+            render::render(&ppu, &mut expected);
+            assert!(frame == expected.data, "scrolled by {}, {}", x, y);
+        }
+    }
 }
@@ -3,6 +3,7 @@
 //
 extern crate nom;
 
+use crate::mapper;
 use nom::{
     bytes::complete::tag, cond, error::make_error, error::ErrorKind, number::complete::be_u8, take,
     Err, IResult,
@@ -13,10 +14,15 @@ const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
 const PRG_RAM_PAGE_SIZE: usize = 8192;
 
-#[derive(Debug)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
+    /// All four nametables are the first 1K of VRAM, mappers switch to it at runtime
+    SINGLE_SCREEN_A,
+    /// All four nametables are the second 1K of VRAM
+    SINGLE_SCREEN_B,
 }
 
 #[derive(Debug)]
@@ -24,6 +30,8 @@ pub struct Rom {
     pub trainer: Option<Vec<u8>>,
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
+    /// The cartridge has CHR RAM instead of CHR ROM, `chr_rom` is its blank 8kB
+    pub chr_ram: bool,
     pub mapper: u8,
     pub tv_format: TVFormat,
     pub ram_size: usize,
@@ -109,12 +117,19 @@ impl Rom {
 
         let (input, prg_rom) = take!(input, PRG_ROM_PAGE_SIZE * len_prg_rom as usize)?;
         let (input, chr_rom) = take!(input, CHR_ROM_PAGE_SIZE * len_chr_rom as usize)?;
+        // no VROM banks means the cartridge has 8kB of CHR RAM instead
+        let chr_rom = if len_chr_rom == 0 {
+            vec![0; CHR_ROM_PAGE_SIZE]
+        } else {
+            chr_rom.to_vec()
+        };
         Ok((
             input,
             Rom {
                 trainer: trainer.map(|t| t.to_vec()),
                 prg_rom: prg_rom.to_vec(),
-                chr_rom: chr_rom.to_vec(),
+                chr_rom,
+                chr_ram: len_chr_rom == 0,
                 mapper: mapper,
                 tv_format: (if pal == 1 {
                     TVFormat::PAL
@@ -127,15 +142,27 @@ impl Rom {
         ))
     }
 
-    pub fn load(input: &[u8]) -> Result<Rom, &str> {
+    pub fn load(input: &[u8]) -> Result<Rom, String> {
         match Rom::_load(input) {
+            IResult::Ok((_, rom)) if rom.prg_rom.is_empty() => {
+                Result::Err("ROM has no PRG ROM banks".to_string())
+            }
+            IResult::Ok((_, rom)) if mapper::name(rom.mapper).is_none() => {
+                Result::Err(mapper::unsupported(rom.mapper))
+            }
             IResult::Ok((_, rom)) => Result::Ok(rom),
-            IResult::Err(nom::Err::Error((_, _kind))) => Result::Err("failed to read file"),
+            IResult::Err(nom::Err::Error((_, _kind))) => {
+                Result::Err("failed to read file".to_string())
+            }
             IResult::Err(nom::Err::Failure((_, kind))) if kind == ErrorKind::OneOf => {
-                Result::Err("NES2.0 format is not supported")
+                Result::Err("NES2.0 format is not supported".to_string())
+            }
+            IResult::Err(nom::Err::Failure((_, _kind))) => {
+                Result::Err("failed to read file".to_string())
+            }
+            IResult::Err(nom::Err::Incomplete(_)) => {
+                Result::Err("Unexpected end of file".to_string())
             }
-            IResult::Err(nom::Err::Failure((_, _kind))) => Result::Err("failed to read file"),
-            IResult::Err(nom::Err::Incomplete(_)) => Result::Err("Unexpected end of file"),
         }
     }
 }
@@ -173,7 +200,7 @@ pub mod test_ines_rom {
     pub fn test_rom() -> Rom {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
             ],
             trainer: None,
             pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
@@ -200,6 +227,7 @@ pub mod test_ines_rom {
         assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
         assert_eq!(rom.mapper, 3);
+        assert!(!rom.chr_ram);
         assert_eq!(rom.ram_size, 0);
         assert_eq!(rom.rom_flags.bits, 0b0001);
     }
@@ -238,4 +266,55 @@ pub mod test_ines_rom {
             Result::Err(str) => assert_eq!(str, "NES2.0 format is not supported"),
         }
     }
+
+    #[test]
+    fn test_chr_ram() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        let rom: Rom = Rom::load(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec!(0; CHR_ROM_PAGE_SIZE));
+        assert!(rom.chr_ram);
+    }
+
+    #[test]
+    fn test_unsupported_mapper() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x51, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        let rom = Rom::load(&test_rom);
+        match rom {
+            Result::Ok(_) => panic!("should not load rom"),
+            Result::Err(str) => assert_eq!(str, "mapper 5 is not supported"),
+        }
+    }
+
+    #[test]
+    fn test_no_prg_rom() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x00, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        let rom = Rom::load(&test_rom);
+        match rom {
+            Result::Ok(_) => panic!("should not load rom"),
+            Result::Err(str) => assert_eq!(str, "ROM has no PRG ROM banks"),
+        }
+    }
 }
@@ -13,15 +13,20 @@ impl Frame {
     }
 
     pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
-        let base = y * 3 * Frame::WIDTH + x * 3;
-        // println!("{}", base);
-        if base + 2 < self.data.len() {
+        if x < Frame::WIDTH && y < Frame::HIGHT {
+            let base = y * 3 * Frame::WIDTH + x * 3;
             self.data[base] = rgb.0;
             self.data[base + 1] = rgb.1;
             self.data[base + 2] = rgb.2;
         }
     }
 
+    /// RGB bytes of a line of pixels
+    pub fn line_mut(&mut self, y: usize) -> &mut [u8] {
+        let start = y * 3 * Frame::WIDTH;
+        &mut self.data[start..start + 3 * Frame::WIDTH]
+    }
+
     pub fn clear(&mut self) {
         self.data = vec![0; (Frame::WIDTH) * (Frame::HIGHT) * 3];
     }
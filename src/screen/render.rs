@@ -1,5 +1,7 @@
 use super::frame::Frame;
 use crate::screen::palette;
+use crate::cdl::CHR_RENDERED;
+use crate::mapper::Mapper;
 use crate::ppu::ppu::NesPPU;
 use crate::rom::Mirroring;
 
@@ -35,6 +37,19 @@ fn sprite_palette(ppu: &NesPPU, pallete_idx: u8) -> [u8; 4] {
     ]
 }
 
+// 16 bytes of a tile: 8 rows of low bits, then 8 rows of high bits.
+// The PPU fetched the patterns on their scanlines: drawing must not look like fetches to the mapper
+fn pattern_tile(ppu: &NesPPU, bank: u16, tile_idx: u16) -> [u8; 16] {
+    let start = bank + tile_idx * 16;
+    ppu.log_chr(start, 16, CHR_RENDERED);
+    let mapper = &ppu.mapper;
+    let mut tile = [0; 16];
+    for (i, byte) in tile.iter_mut().enumerate() {
+        *byte = mapper.peek_chr(start + i as u16);
+    }
+    tile
+}
+
 struct Rect {
     x1: usize,
     y1: usize,
@@ -63,7 +78,7 @@ fn render_name_table(ppu: &NesPPU, frame: &mut Frame, name_table: &[u8],
         let tile_column = i % 32;
         let tile_row = i / 32;
         let tile_idx = name_table[i] as u16;
-        let tile = &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile = pattern_tile(ppu, bank, tile_idx);
         let palette = bg_pallette(ppu, attribute_table, tile_column, tile_row);
 
         for y in 0..=7 {
@@ -96,15 +111,17 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
     let scroll_x = (ppu.scroll.scroll_x) as usize;
     let scroll_y = (ppu.scroll.scroll_y) as usize;
 
-    let (main_nametable, second_nametable) = match (&ppu.mirroring, ppu.ctrl.nametable_addr()) {
+    let (main_nametable, second_nametable) = match (ppu.mirroring(), ppu.ctrl.nametable_addr()) {
         (Mirroring::VERTICAL, 0x2000) | (Mirroring::VERTICAL, 0x2800) | (Mirroring::HORIZONTAL, 0x2000) | (Mirroring::HORIZONTAL, 0x2400) => {
             (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800])
         }
         (Mirroring::VERTICAL, 0x2400) | (Mirroring::VERTICAL, 0x2C00) | (Mirroring::HORIZONTAL, 0x2800) | (Mirroring::HORIZONTAL, 0x2C00) => {
             ( &ppu.vram[0x400..0x800], &ppu.vram[0..0x400])
         }
+        (Mirroring::SINGLE_SCREEN_A, _) => (&ppu.vram[0..0x400], &ppu.vram[0..0x400]),
+        (Mirroring::SINGLE_SCREEN_B, _) => (&ppu.vram[0x400..0x800], &ppu.vram[0x400..0x800]),
         (_,_) => {
-            panic!("Not supported mirroring type {:?}", ppu.mirroring);
+            panic!("Not supported mirroring type {:?}", ppu.mirroring());
         }
     };
 
@@ -130,82 +147,29 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
     render_sprites(ppu, frame);
 }
 
+// Background of a scanline from the tiles the PPU fetched (NesPPU::bg_tiles): fine X scroll
+// starts it in the middle of the first one
 pub fn render_bg_scanline(ppu: &NesPPU, scanline: usize, frame: &mut Frame) {
-    let scroll_x = (ppu.scroll.scroll_x) as usize;
-    let scroll_y = (ppu.scroll.scroll_y) as usize;
-
-    let (main_nametable, second_nametable) = match (&ppu.mirroring, ppu.ctrl.nametable_addr()) {
-        (Mirroring::VERTICAL, 0x2000) | (Mirroring::VERTICAL, 0x2800) | (Mirroring::HORIZONTAL, 0x2000) | (Mirroring::HORIZONTAL, 0x2400) => {
-            (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800])
-        }
-        (Mirroring::VERTICAL, 0x2400) | (Mirroring::VERTICAL, 0x2C00) | (Mirroring::HORIZONTAL, 0x2800) | (Mirroring::HORIZONTAL, 0x2C00) => {
-            ( &ppu.vram[0x400..0x800], &ppu.vram[0..0x400])
-        }
-        (_,_) => {
-            panic!("Not supported mirroring type {:?}", ppu.mirroring);
-        }
-    };
-
-
-    if(scroll_y == 0) {
-        render_name_table_scanline(ppu, frame, scanline, main_nametable, 
-            Rect::new(scroll_x,scroll_y,256, 240), 
-            -(scroll_x as isize), -(scroll_y as isize));
-
-        render_name_table_scanline(ppu, frame, scanline, second_nametable, 
-            Rect::new(0,0,scroll_x, 240), 
-            (256 - scroll_x as isize), 0);
-    } else {
-        if(scanline + scroll_y > 240) {
-            render_name_table_scanline(ppu, frame, scanline + scroll_y - 240, second_nametable, 
-                Rect::new(0,0,256, 240), 
-                0, (239 - scroll_y) as isize)
-        } else {
-            render_name_table_scanline(ppu, frame, scroll_y + scanline, main_nametable, 
-                Rect::new(0,0,256, 240), 
-                0, -(scroll_y as isize))
-
-        }
-    }
-}
-
-fn render_name_table_scanline(ppu: &NesPPU, frame: &mut Frame, scanline: usize, name_table: &[u8], view_port: Rect, shift_x: isize, shift_y: isize) {
-    let bank = ppu.ctrl.bknd_pattern_addr();
-
-    let attribute_table = &name_table[0x3c0.. 0x400];
-
-    let tile_row = scanline / 8;
-
-    for tile_column in 0..32usize {
-
-        let tile_idx = name_table[tile_row * 32 + tile_column] as u16;
-        let tile = &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
-        let palette = bg_pallette(ppu, attribute_table, tile_column, tile_row);
-
-        let y = scanline % 8;
-        let mut upper = tile[y];
-        let mut lower = tile[y + 8];
-
-        for x in (0..=7).rev() {
-            let value = (1 & lower) << 1 | (1 & upper);
-            upper = upper >> 1;
-            lower = lower >> 1;
-            let rgb = match value {
-                0 => palette::SYSTEM_PALETTE[ppu.palette_table[0] as usize],
-                1 => palette::SYSTEM_PALETTE[palette[1] as usize],
-                2 => palette::SYSTEM_PALETTE[palette[2] as usize],
-                3 => palette::SYSTEM_PALETTE[palette[3] as usize],
-                _ => panic!("can't be"),
-            };
-            let pixel_x = tile_column * 8 + x;
-            let pixel_y = tile_row * 8 + y;
-
-            if pixel_x >= view_port.x1 && pixel_x < view_port.x2 && pixel_y >= view_port.y1 && pixel_y < view_port.y2 {
-                frame.set_pixel((shift_x + pixel_x as isize) as usize, (shift_y + pixel_y as isize) as usize, rgb);
+    let fine_x = ppu.bg_origin.0 % 8;
+    let line = frame.line_mut(scanline);
+    for (i, tile) in ppu.bg_tiles[..33].iter().enumerate() {
+        let start = tile.palette as usize * 4;
+        let colors = [
+            palette::SYSTEM_PALETTE[ppu.palette_table[0] as usize],
+            palette::SYSTEM_PALETTE[ppu.palette_table[start + 1] as usize],
+            palette::SYSTEM_PALETTE[ppu.palette_table[start + 2] as usize],
+            palette::SYSTEM_PALETTE[ppu.palette_table[start + 3] as usize],
+        ];
+        for bit in 0..8 {
+            let x = (i * 8 + bit).wrapping_sub(fine_x);
+            if x < 256 {
+                let shift = 7 - bit;
+                let value = (tile.high >> shift & 1) << 1 | (tile.low >> shift & 1);
+                let rgb = colors[value as usize];
+                line[x * 3..x * 3 + 3].copy_from_slice(&[rgb.0, rgb.1, rgb.2]);
             }
         }
     }
-
 }
 
 
@@ -214,6 +178,10 @@ pub fn render_sprites(ppu:&NesPPU, frame: &mut Frame) {
         let tile_idx = ppu.oam_data[i + 1] as u16;
         let tile_x = ppu.oam_data[i + 3] as usize;
         let tile_y = ppu.oam_data[i] as usize;
+        if tile_y >= 240 {
+            // hidden below the screen: its tile is never fetched
+            continue;
+        }
 
         let flip_vertical = if ppu.oam_data[i + 2] >> 7 & 1 == 1 {
             true
@@ -229,8 +197,7 @@ pub fn render_sprites(ppu:&NesPPU, frame: &mut Frame) {
         let sprite_palette = sprite_palette(ppu, pallette_idx);
         let bank: u16 = ppu.ctrl.sprt_pattern_addr();
 
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile = pattern_tile(ppu, bank, tile_idx);
 
         for y in 0..=7 {
             let mut upper = tile[y];
@@ -0,0 +1,420 @@
+// Debug symbols for traces and disassembly.
+//
+// Supported formats:
+//  * FCEUX name lists: `<rom>.ram.nl` for RAM and registers, `<rom>.<bank>.nl` for every 16K PRG bank
+//    http://fceux.com/web/help/NLFilesFormat.html
+//  * ca65 debug info (`ld65 --dbgfile`): symbols in segments that are written to the ROM
+//    are mapped to PRG ROM, the rest (zero page, BSS) are RAM addresses.
+//    Source lines of the code in PRG ROM are kept too, for source level debugging.
+//  * Mesen label files (.mlb), both `P:`/`R:`/`S:`/`W:`/`G:` and the `NesPrgRom:`... prefixes
+//
+// Labels in PRG ROM are kept by PRG ROM offset, so the same CPU address can have
+// different names depending on the bank mapped there.
+use std::collections::HashMap;
+use std::path::Path;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const INES_HEADER_SIZE: usize = 16;
+const SRAM: u16 = 0x6000;
+
+/// Line of assembly source, as named in the debug info
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    /// RAM, registers and everything else that is not PRG ROM, by CPU address
+    cpu: HashMap<u16, String>,
+    /// by offset in PRG ROM
+    prg: HashMap<usize, String>,
+    /// Source line of the first byte of an instruction, by offset in PRG ROM
+    lines: HashMap<usize, SourceLine>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols::default()
+    }
+
+    /// Loads a symbol file, the format is picked by extension.
+    /// FCEUX bank files are expected to be named `<rom>.<bank in hex>.nl`.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let result = match extension.to_ascii_lowercase().as_str() {
+            "nl" => {
+                let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+                let bank = stem
+                    .rsplit_once('.')
+                    .and_then(|(_, bank)| usize::from_str_radix(bank, 16).ok());
+                self.add_nl(&text, bank)
+            }
+            "dbg" => self.add_dbg(&text),
+            "mlb" => self.add_mlb(&text),
+            _ => Err(format!("unknown symbol file format: {}", path.display())),
+        };
+        result.map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Label of a CPU address. `prg_offset` is where the address is mapped to in PRG ROM, if it is.
+    pub fn label(&self, addr: u16, prg_offset: Option<usize>) -> Option<&str> {
+        match prg_offset {
+            Some(offset) => self.prg.get(&offset),
+            None => self.cpu.get(&addr),
+        }
+        .map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cpu.is_empty() && self.prg.is_empty()
+    }
+
+    pub fn source_line(&self, prg_offset: usize) -> Option<&SourceLine> {
+        self.lines.get(&prg_offset)
+    }
+
+    /// PRG ROM offsets of the code assembled from a source line.
+    /// `path` may be absolute: it matches the file names of the debug info by suffix.
+    pub fn line_offsets(&self, path: &str, line: usize) -> Vec<usize> {
+        let path = path.replace('\\', "/");
+        let mut offsets: Vec<usize> = self
+            .lines
+            .iter()
+            .filter(|(_, source)| {
+                let file = source.file.replace('\\', "/");
+                source.line == line
+                    && (path == file
+                        || path.ends_with(&format!("/{}", file.trim_start_matches("./"))))
+            })
+            .map(|(offset, _)| *offset)
+            .collect();
+        offsets.sort_unstable();
+        offsets
+    }
+
+    fn add_cpu(&mut self, addr: u16, name: &str) {
+        self.cpu.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    fn add_prg(&mut self, offset: usize, name: &str) {
+        self.prg.entry(offset).or_insert_with(|| name.to_string());
+    }
+
+    /// FCEUX: `$C3A1#UpdatePlayer#comment`, `$0300/10#Buffer#` for arrays.
+    /// Addresses of a bank file are CPU addresses the bank is seen at.
+    pub fn add_nl(&mut self, text: &str, bank: Option<usize>) -> Result<(), String> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if !line.starts_with('$') {
+                // comment continuation lines start with a backslash
+                continue;
+            }
+            let mut fields = line[1..].splitn(3, '#');
+            let addr = fields.next().unwrap_or("");
+            let addr = addr.split('/').next().unwrap_or("");
+            let addr = u16::from_str_radix(addr, 16)
+                .map_err(|_| format!("line {}: invalid address {}", i + 1, addr))?;
+            let name = fields.next().unwrap_or("").trim();
+            if name.is_empty() {
+                continue;
+            }
+            match bank {
+                Some(bank) if addr >= 0x8000 => {
+                    self.add_prg(bank * PRG_BANK_SIZE + (addr as usize % PRG_BANK_SIZE), name)
+                }
+                _ => self.add_cpu(addr, name),
+            }
+        }
+        Ok(())
+    }
+
+    /// Mesen: `<type>:<address>[-<end>]:<label>[:comment]`
+    pub fn add_mlb(&mut self, text: &str) -> Result<(), String> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.splitn(4, ':');
+            let kind = fields.next().unwrap_or("");
+            let addr = fields.next().unwrap_or("");
+            let name = fields.next().unwrap_or("").trim();
+            let addr = addr.split('-').next().unwrap_or("");
+            let addr = usize::from_str_radix(addr, 16)
+                .map_err(|_| format!("line {}: invalid address {}", i + 1, addr))?;
+            if name.is_empty() {
+                // comment only
+                continue;
+            }
+            match kind {
+                "P" | "NesPrgRom" => self.add_prg(addr, name),
+                "R" | "G" | "NesInternalRam" | "NesMemory" if addr <= 0xffff => {
+                    self.add_cpu(addr as u16, name)
+                }
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" if addr < 0x2000 => {
+                    self.add_cpu(SRAM + addr as u16, name)
+                }
+                // CHR, palette, etc. are not visible to the CPU
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// ca65 debug info, as written by `ld65 --dbgfile`.
+    /// Assumes the ROM was linked without a trainer, i.e. PRG ROM starts right after the header.
+    pub fn add_dbg(&mut self, text: &str) -> Result<(), String> {
+        // segment id -> (start address, offset in the output file)
+        let mut segments: HashMap<String, (usize, Option<usize>)> = HashMap::new();
+        let mut symbols = vec![];
+        let mut files: HashMap<String, String> = HashMap::new();
+        // span id -> (segment id, offset in the segment)
+        let mut spans: HashMap<String, (String, usize)> = HashMap::new();
+        let mut lines = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let mut parts = line.splitn(2, '\t');
+            let kind = parts.next().unwrap_or("");
+            let attrs = dbg_attributes(parts.next().unwrap_or(""));
+            let number = |key: &str| -> Result<Option<usize>, String> {
+                match attrs.get(key) {
+                    None => Ok(None),
+                    Some(value) => parse_dbg_number(value)
+                        .map(Some)
+                        .ok_or_else(|| format!("line {}: invalid {} {}", i + 1, key, value)),
+                }
+            };
+            match kind {
+                "seg" => {
+                    let start = number("start")?.unwrap_or(0);
+                    let ooffs = number("ooffs")?;
+                    segments.insert(attrs.get("id").cloned().unwrap_or_default(), (start, ooffs));
+                }
+                "sym" if attrs.get("type").map(String::as_str) != Some("imp") => {
+                    if let (Some(name), Some(val)) = (attrs.get("name"), number("val")?) {
+                        symbols.push((name.clone(), val, attrs.get("seg").cloned()));
+                    }
+                }
+                "file" => {
+                    if let (Some(id), Some(name)) = (attrs.get("id"), attrs.get("name")) {
+                        files.insert(id.clone(), name.clone());
+                    }
+                }
+                "span" => {
+                    if let (Some(id), Some(seg)) = (attrs.get("id"), attrs.get("seg")) {
+                        spans.insert(id.clone(), (seg.clone(), number("start")?.unwrap_or(0)));
+                    }
+                }
+                // type 2 lines are macro bodies: the line invoking the macro is more useful
+                "line" if attrs.get("type").map(String::as_str) != Some("2") => {
+                    if let (Some(file), Some(line), Some(span)) =
+                        (attrs.get("file"), number("line")?, attrs.get("span"))
+                    {
+                        lines.push((file.clone(), line, span.clone()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (name, val, seg) in symbols {
+            let rom_offset = seg
+                .and_then(|seg| segments.get(&seg))
+                .and_then(|(start, ooffs)| Some(ooffs.as_ref()? + val.checked_sub(*start)?))
+                .and_then(|offset| offset.checked_sub(INES_HEADER_SIZE));
+            match rom_offset {
+                Some(offset) => self.add_prg(offset, &name),
+                None if val <= 0xffff => self.add_cpu(val as u16, &name),
+                None => {}
+            }
+        }
+
+        for (file, line, span_list) in lines {
+            let file = match files.get(&file) {
+                Some(file) => file,
+                None => continue,
+            };
+            // "3+4": the line produced several spans
+            for span in span_list.split('+') {
+                let rom_offset = spans
+                    .get(span)
+                    .and_then(|(seg, start)| Some(segments.get(seg)?.1? + start))
+                    .and_then(|offset| offset.checked_sub(INES_HEADER_SIZE));
+                if let Some(offset) = rom_offset {
+                    self.lines.entry(offset).or_insert_with(|| SourceLine {
+                        file: file.clone(),
+                        line,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `id=0,name="CODE",start=0x008000` -> {id: 0, name: CODE, start: 0x008000}
+fn dbg_attributes(attrs: &str) -> HashMap<String, String> {
+    let mut result = HashMap::new();
+    let mut quoted = false;
+    let mut start = 0;
+    let mut fields = vec![];
+    for (i, c) in attrs.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                fields.push(&attrs[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(&attrs[start..]);
+    for field in fields {
+        let mut kv = field.splitn(2, '=');
+        if let (Some(key), Some(value)) = (kv.next(), kv.next()) {
+            result.insert(key.trim().to_string(), value.trim_matches('"').to_string());
+        }
+    }
+    result
+}
+
+fn parse_dbg_number(value: &str) -> Option<usize> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fceux_name_lists() {
+        let mut symbols = Symbols::new();
+        symbols
+            .add_nl(
+                "$0040#PlayerX#horizontal position\n$0300/10#Buffer#\n\\more comment\n$2002##\n",
+                None,
+            )
+            .unwrap();
+        symbols
+            .add_nl("$C3A1#UpdatePlayer#\n$0041#PlayerY#", Some(2))
+            .unwrap();
+
+        assert_eq!(symbols.label(0x0040, None), Some("PlayerX"));
+        assert_eq!(symbols.label(0x0300, None), Some("Buffer"));
+        assert_eq!(symbols.label(0x0041, None), Some("PlayerY"));
+        assert_eq!(symbols.label(0x2002, None), None);
+        assert_eq!(symbols.label(0xc3a1, Some(0x83a1)), Some("UpdatePlayer"));
+        assert_eq!(symbols.label(0xc3a1, Some(0x03a1)), None);
+        assert_eq!(symbols.label(0xc3a1, None), None);
+
+        assert_eq!(
+            symbols.add_nl("$XYZ#Broken#", None),
+            Err("line 1: invalid address XYZ".to_string())
+        );
+    }
+
+    #[test]
+    fn test_mesen_labels() {
+        let mut symbols = Symbols::new();
+        symbols
+            .add_mlb(
+                "P:03A1:UpdatePlayer:moves the player\nR:0040-0041:PlayerX\nG:2002:PPUSTATUS\n\
+                 S:0010:SaveSlot\nP:0000::comment only\nNesPrgRom:83A1:Bank2Routine\nC:0000:Tiles",
+            )
+            .unwrap();
+
+        assert_eq!(symbols.label(0xc3a1, Some(0x03a1)), Some("UpdatePlayer"));
+        assert_eq!(symbols.label(0xc3a1, Some(0x83a1)), Some("Bank2Routine"));
+        assert_eq!(symbols.label(0x0040, None), Some("PlayerX"));
+        assert_eq!(symbols.label(0x2002, None), Some("PPUSTATUS"));
+        assert_eq!(symbols.label(0x6010, None), Some("SaveSlot"));
+        assert_eq!(symbols.label(0x0000, Some(0)), None);
+        assert_eq!(symbols.label(0x0000, None), None);
+    }
+
+    #[test]
+    fn test_ca65_debug_info() {
+        let dbg = "version\tmajor=2,minor=0
+seg\tid=0,name=\"ZEROPAGE\",start=0x000000,size=0x0010,addrsize=zeropage,type=rw
+seg\tid=1,name=\"HEADER\",start=0x000000,size=0x0010,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=0
+seg\tid=2,name=\"CODE\",start=0x00C000,size=0x0200,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16
+sym\tid=0,name=\"PlayerX\",addrsize=zeropage,scope=0,def=1,ref=5,val=0x40,seg=0,type=lab
+sym\tid=1,name=\"UpdatePlayer\",addrsize=absolute,scope=0,def=2,ref=6,val=0xC1A1,seg=2,type=lab
+sym\tid=2,name=\"PPUCTRL\",addrsize=absolute,scope=0,def=3,val=0x2000,type=equ
+sym\tid=3,name=\"Imported\",addrsize=absolute,scope=0,def=4,type=imp,exp=1
+";
+        let mut symbols = Symbols::new();
+        symbols.add_dbg(dbg).unwrap();
+
+        assert_eq!(symbols.label(0x0040, None), Some("PlayerX"));
+        assert_eq!(symbols.label(0xc1a1, Some(0x01a1)), Some("UpdatePlayer"));
+        assert_eq!(symbols.label(0x2000, None), Some("PPUCTRL"));
+        assert_eq!(symbols.cpu.len() + symbols.prg.len(), 3);
+    }
+
+    #[test]
+    fn test_ca65_source_lines() {
+        let dbg = "version\tmajor=2,minor=0
+file\tid=0,name=\"src/main.s\",size=120,mtime=0x5F000000,mod=0
+file\tid=1,name=\"macros.inc\",size=40,mtime=0x5F000000,mod=0
+line\tid=0,file=0,line=10,span=0
+line\tid=1,file=0,line=11,span=1+2
+line\tid=2,file=1,line=3,type=2,span=1
+line\tid=3,file=0,line=20,span=3
+seg\tid=0,name=\"CODE\",start=0x00C000,size=0x0200,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16
+seg\tid=1,name=\"BSS\",start=0x000300,size=0x0010,addrsize=absolute,type=rw
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=3
+span\tid=2,seg=0,start=5,size=1
+span\tid=3,seg=1,start=0,size=1
+";
+        let mut symbols = Symbols::new();
+        symbols.add_dbg(dbg).unwrap();
+
+        let main_line = |line| SourceLine {
+            file: "src/main.s".to_string(),
+            line,
+        };
+        assert_eq!(symbols.source_line(0), Some(&main_line(10)));
+        assert_eq!(symbols.source_line(2), Some(&main_line(11)));
+        assert_eq!(symbols.source_line(5), Some(&main_line(11)));
+        assert_eq!(symbols.source_line(1), None);
+
+        assert_eq!(
+            symbols.line_offsets("/home/me/game/src/main.s", 11),
+            vec![2, 5]
+        );
+        assert_eq!(symbols.line_offsets("src\\main.s", 10), vec![0]);
+        assert!(symbols
+            .line_offsets("/home/me/game/other/main.s", 10)
+            .is_empty());
+        assert!(symbols
+            .line_offsets("/home/me/game/macros.inc", 3)
+            .is_empty());
+        // code in RAM segments has no PRG ROM location
+        assert!(symbols.line_offsets("src/main.s", 20).is_empty());
+    }
+
+    #[test]
+    fn test_load_picks_format_and_bank_from_file_name() {
+        let dir = std::env::temp_dir().join("rustness_symbols_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let bank = dir.join("game.nes.a.nl");
+        std::fs::write(&bank, "$8000#BankA#").unwrap();
+        let ram = dir.join("game.nes.ram.nl");
+        std::fs::write(&ram, "$0010#Counter#").unwrap();
+
+        let mut symbols = Symbols::new();
+        symbols.load(&bank).unwrap();
+        symbols.load(&ram).unwrap();
+        assert_eq!(symbols.label(0x8000, Some(0xa * 0x4000)), Some("BankA"));
+        assert_eq!(symbols.label(0x0010, None), Some("Counter"));
+        assert!(symbols.load(&dir.join("game.sym")).is_err());
+    }
+}
@@ -0,0 +1,18 @@
+#!/bin/sh
+# Downloads the nes6502 single step tests next to this script, for
+#   cargo test single_step -- --ignored --nocapture
+# Only the nes6502/v1 directory is checked out (256 files, about 1GB of JSON).
+#
+# fetch.sh [<commit>]: the upstream commit to test against, the latest one by default
+set -e
+
+repo=https://github.com/TomHarte/ProcessorTests.git
+dir=$(dirname "$0")/ProcessorTests
+
+if [ ! -d "$dir" ]; then
+    git clone --filter=blob:none --no-checkout "$repo" "$dir"
+    git -C "$dir" sparse-checkout set nes6502/v1
+fi
+git -C "$dir" fetch origin
+git -C "$dir" checkout --detach "${1:-origin/HEAD}"
+echo "nes6502 vectors at $(git -C "$dir" rev-parse HEAD)"
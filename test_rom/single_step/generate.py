@@ -0,0 +1,376 @@
+#!/usr/bin/env python3
+# Writes single step vectors for all 256 opcodes of the 2A03 to generated/, in the nes6502 format:
+# a list of cases per `<opcode>.json`, each with the initial state, the state after the instruction
+# and the bus access of every cycle.
+#
+# They come from the small cycle-level model below, written from
+#   http://nesdev.com/6502_cpu.txt and http://nesdev.com/undocumented_opcodes.txt
+# and not from the emulator: `cargo test` checks the emulator against it. The conventions are
+# the ones of the nes6502 suite: no decimal mode, B and bit 5 are only set on the pushed copy of P,
+# 0xEE is the magic constant of ANE and LXA, and SHA/SHX/SHY/TAS store `register & (H + 1)`,
+# at an address with that value as its high byte when the index crosses a page.
+# JAM reads the byte after the opcode and stops with PC on the opcode, the emulator's model
+# of a CPU that never fetches another instruction.
+#
+# generate.py [<cases per opcode>]: deterministic, the same count writes the same files
+import json
+import os
+import random
+import sys
+
+C, Z, I, D, B, U, V, N = 0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80
+
+# mnemonic and addressing mode of every opcode, a row per high nibble
+TABLE = """
+BRK imp|ORA izx|JAM imp|SLO izx|NOP zp |ORA zp |ASL zp |SLO zp |PHP imp|ORA imm|ASL acc|ANC imm|NOP abs|ORA abs|ASL abs|SLO abs
+BPL rel|ORA izy|JAM imp|SLO izy|NOP zpx|ORA zpx|ASL zpx|SLO zpx|CLC imp|ORA aby|NOP imp|SLO aby|NOP abx|ORA abx|ASL abx|SLO abx
+JSR abs|AND izx|JAM imp|RLA izx|BIT zp |AND zp |ROL zp |RLA zp |PLP imp|AND imm|ROL acc|ANC imm|BIT abs|AND abs|ROL abs|RLA abs
+BMI rel|AND izy|JAM imp|RLA izy|NOP zpx|AND zpx|ROL zpx|RLA zpx|SEC imp|AND aby|NOP imp|RLA aby|NOP abx|AND abx|ROL abx|RLA abx
+RTI imp|EOR izx|JAM imp|SRE izx|NOP zp |EOR zp |LSR zp |SRE zp |PHA imp|EOR imm|LSR acc|ALR imm|JMP abs|EOR abs|LSR abs|SRE abs
+BVC rel|EOR izy|JAM imp|SRE izy|NOP zpx|EOR zpx|LSR zpx|SRE zpx|CLI imp|EOR aby|NOP imp|SRE aby|NOP abx|EOR abx|LSR abx|SRE abx
+RTS imp|ADC izx|JAM imp|RRA izx|NOP zp |ADC zp |ROR zp |RRA zp |PLA imp|ADC imm|ROR acc|ARR imm|JMP ind|ADC abs|ROR abs|RRA abs
+BVS rel|ADC izy|JAM imp|RRA izy|NOP zpx|ADC zpx|ROR zpx|RRA zpx|SEI imp|ADC aby|NOP imp|RRA aby|NOP abx|ADC abx|ROR abx|RRA abx
+NOP imm|STA izx|NOP imm|SAX izx|STY zp |STA zp |STX zp |SAX zp |DEY imp|NOP imm|TXA imp|ANE imm|STY abs|STA abs|STX abs|SAX abs
+BCC rel|STA izy|JAM imp|SHA izy|STY zpx|STA zpx|STX zpy|SAX zpy|TYA imp|STA aby|TXS imp|TAS aby|SHY abx|STA abx|SHX aby|SHA aby
+LDY imm|LDA izx|LDX imm|LAX izx|LDY zp |LDA zp |LDX zp |LAX zp |TAY imp|LDA imm|TAX imp|LXA imm|LDY abs|LDA abs|LDX abs|LAX abs
+BCS rel|LDA izy|JAM imp|LAX izy|LDY zpx|LDA zpx|LDX zpy|LAX zpy|CLV imp|LDA aby|TSX imp|LAS aby|LDY abx|LDA abx|LDX aby|LAX aby
+CPY imm|CMP izx|NOP imm|DCP izx|CPY zp |CMP zp |DEC zp |DCP zp |INY imp|CMP imm|DEX imp|AXS imm|CPY abs|CMP abs|DEC abs|DCP abs
+BNE rel|CMP izy|JAM imp|DCP izy|NOP zpx|CMP zpx|DEC zpx|DCP zpx|CLD imp|CMP aby|NOP imp|DCP aby|NOP abx|CMP abx|DEC abx|DCP abx
+CPX imm|SBC izx|NOP imm|ISC izx|CPX zp |SBC zp |INC zp |ISC zp |INX imp|SBC imm|NOP imp|SBC imm|CPX abs|SBC abs|INC abs|ISC abs
+BEQ rel|SBC izy|JAM imp|ISC izy|NOP zpx|SBC zpx|INC zpx|ISC zpx|SED imp|SBC aby|NOP imp|ISC aby|NOP abx|SBC abx|INC abx|ISC abx
+"""
+OPCODES = [tuple(entry.split()) for line in TABLE.strip().splitlines() for entry in line.split("|")]
+assert len(OPCODES) == 256
+
+READS = "LDA LDX LDY EOR AND ORA ADC SBC CMP CPX CPY BIT LAX NOP LAS ANC ALR ARR AXS ANE LXA".split()
+WRITES = "STA STX STY SAX SHA SHX SHY TAS".split()
+RMWS = "ASL LSR ROL ROR INC DEC SLO RLA SRE RRA DCP ISC".split()
+BRANCHES = {
+    "BPL": (N, 0), "BMI": (N, N), "BVC": (V, 0), "BVS": (V, V),
+    "BCC": (C, 0), "BCS": (C, C), "BNE": (Z, 0), "BEQ": (Z, Z),
+}
+FLAG_OPS = {"CLC": (C, 0), "SEC": (C, C), "CLI": (I, 0), "SEI": (I, I),
+            "CLV": (V, 0), "CLD": (D, 0), "SED": (D, D)}
+
+
+class Cpu:
+    def __init__(self, rng):
+        self.rng = rng
+        self.pc = rng.randrange(0x10000)
+        self.s = rng.randrange(0x100)
+        self.a = rng.randrange(0x100)
+        self.x = rng.randrange(0x100)
+        self.y = rng.randrange(0x100)
+        self.p = rng.randrange(0x100) | U
+        self.mem = {}
+        self.initial = {}
+        self.cycles = []
+
+    def state(self, ram):
+        return {"pc": self.pc, "s": self.s, "a": self.a, "x": self.x, "y": self.y, "p": self.p,
+                "ram": [[addr, ram[addr]] for addr in sorted(ram)]}
+
+    def value(self, addr):
+        # memory nobody set is random
+        if addr not in self.mem:
+            self.mem[addr] = self.initial[addr] = self.rng.randrange(0x100)
+        return self.mem[addr]
+
+    def read(self, addr):
+        addr &= 0xFFFF
+        data = self.value(addr)
+        self.cycles.append([addr, data, "read"])
+        return data
+
+    def write(self, addr, data):
+        addr &= 0xFFFF
+        self.value(addr)
+        self.mem[addr] = data
+        self.cycles.append([addr, data, "write"])
+
+    def fetch(self):
+        data = self.read(self.pc)
+        self.pc = (self.pc + 1) & 0xFFFF
+        return data
+
+    def push(self, data):
+        self.write(0x100 + self.s, data)
+        self.s = (self.s - 1) & 0xFF
+
+    def pull(self):
+        self.s = (self.s + 1) & 0xFF
+        return self.read(0x100 + self.s)
+
+    def flag(self, mask, on):
+        self.p = self.p | mask if on else self.p & ~mask
+
+    def nz(self, value):
+        self.flag(Z, value == 0)
+        self.flag(N, value & 0x80)
+        return value
+
+    def address(self, mode, always_fix):
+        """Effective address, the base it was indexed from, and whether the index crossed a page"""
+        if mode == "zp":
+            return self.fetch(), None, False
+        if mode in ("zpx", "zpy"):
+            base = self.fetch()
+            self.read(base)
+            return (base + (self.x if mode == "zpx" else self.y)) & 0xFF, None, False
+        if mode == "abs":
+            lo = self.fetch()
+            return self.fetch() << 8 | lo, None, False
+        if mode in ("abx", "aby"):
+            lo = self.fetch()
+            base = self.fetch() << 8 | lo
+            return self.indexed(base, self.x if mode == "abx" else self.y, always_fix)
+        if mode == "izx":
+            ptr = self.fetch()
+            self.read(ptr)
+            ptr = (ptr + self.x) & 0xFF
+            lo = self.read(ptr)
+            return self.read((ptr + 1) & 0xFF) << 8 | lo, None, False
+        if mode == "izy":
+            ptr = self.fetch()
+            lo = self.read(ptr)
+            base = self.read((ptr + 1) & 0xFF) << 8 | lo
+            return self.indexed(base, self.y, always_fix)
+        raise ValueError(mode)
+
+    def indexed(self, base, index, always_fix):
+        addr = (base + index) & 0xFFFF
+        crossed = addr & 0xFF00 != base & 0xFF00
+        if crossed or always_fix:
+            self.read(base & 0xFF00 | addr & 0xFF)
+        return addr, base, crossed
+
+    def adc(self, data):
+        total = self.a + data + (self.p & C)
+        self.flag(V, ~(self.a ^ data) & (self.a ^ total) & 0x80)
+        self.flag(C, total > 0xFF)
+        self.a = self.nz(total & 0xFF)
+
+    def compare(self, register, data):
+        self.flag(C, register >= data)
+        self.nz((register - data) & 0xFF)
+
+    def read_op(self, op, data):
+        if op == "LDA":
+            self.a = self.nz(data)
+        elif op == "LDX":
+            self.x = self.nz(data)
+        elif op == "LDY":
+            self.y = self.nz(data)
+        elif op == "EOR":
+            self.a = self.nz(self.a ^ data)
+        elif op == "AND":
+            self.a = self.nz(self.a & data)
+        elif op == "ORA":
+            self.a = self.nz(self.a | data)
+        elif op == "ADC":
+            self.adc(data)
+        elif op == "SBC":
+            self.adc(data ^ 0xFF)
+        elif op == "CMP":
+            self.compare(self.a, data)
+        elif op == "CPX":
+            self.compare(self.x, data)
+        elif op == "CPY":
+            self.compare(self.y, data)
+        elif op == "BIT":
+            self.flag(Z, self.a & data == 0)
+            self.flag(N, data & N)
+            self.flag(V, data & V)
+        elif op == "LAX":
+            self.a = self.x = self.nz(data)
+        elif op == "LAS":
+            self.a = self.x = self.s = self.nz(data & self.s)
+        elif op == "ANC":
+            self.a = self.nz(self.a & data)
+            self.flag(C, self.a & 0x80)
+        elif op == "ALR":
+            value = self.a & data
+            self.flag(C, value & 1)
+            self.a = self.nz(value >> 1)
+        elif op == "ARR":
+            self.a = self.nz((self.a & data) >> 1 | (self.p & C) << 7)
+            self.flag(C, self.a & 0x40)
+            self.flag(V, (self.a >> 6 ^ self.a >> 5) & 1)
+        elif op == "AXS":
+            value = self.a & self.x
+            self.flag(C, value >= data)
+            self.x = self.nz((value - data) & 0xFF)
+        elif op == "ANE":
+            self.a = self.nz((self.a | 0xEE) & self.x & data)
+        elif op == "LXA":
+            self.a = self.x = self.nz((self.a | 0xEE) & data)
+
+    def shift(self, op, data):
+        if op in ("ASL", "SLO"):
+            self.flag(C, data & 0x80)
+            return self.nz(data << 1 & 0xFF)
+        if op in ("LSR", "SRE"):
+            self.flag(C, data & 1)
+            return self.nz(data >> 1)
+        if op in ("ROL", "RLA"):
+            result = (data << 1 | self.p & C) & 0xFF
+            self.flag(C, data & 0x80)
+            return self.nz(result)
+        if op in ("ROR", "RRA"):
+            result = data >> 1 | (self.p & C) << 7
+            self.flag(C, data & 1)
+            return self.nz(result)
+        if op in ("INC", "ISC"):
+            return self.nz((data + 1) & 0xFF)
+        if op in ("DEC", "DCP"):
+            return self.nz((data - 1) & 0xFF)
+        raise ValueError(op)
+
+    def rmw_op(self, op, data):
+        result = self.shift(op, data)
+        combined = {"SLO": "ORA", "RLA": "AND", "SRE": "EOR", "RRA": "ADC", "DCP": "CMP", "ISC": "SBC"}
+        if op in combined:
+            self.read_op(combined[op], result)
+        return result
+
+    def store_value(self, op):
+        return {"STA": self.a, "STX": self.x, "STY": self.y, "SAX": self.a & self.x,
+                "SHA": self.a & self.x, "SHX": self.x, "SHY": self.y, "TAS": self.a & self.x}[op]
+
+    def step(self, opcode):
+        op, mode = OPCODES[opcode]
+        start = self.pc
+        self.mem[self.pc] = self.initial[self.pc] = opcode
+        self.fetch()
+
+        if mode == "imm":
+            self.read_op(op, self.fetch())
+        elif op in READS and mode != "imp":
+            addr, _, _ = self.address(mode, False)
+            self.read_op(op, self.read(addr))
+        elif op in WRITES:
+            addr, base, crossed = self.address(mode, True)
+            value = self.store_value(op)
+            if op == "TAS":
+                self.s = value
+            if op in ("SHA", "SHX", "SHY", "TAS"):
+                value &= (base >> 8) + 1 & 0xFF
+                if crossed:
+                    addr = value << 8 | addr & 0xFF
+            self.write(addr, value)
+        elif op in RMWS and mode == "acc":
+            self.read(self.pc)
+            self.a = self.shift(op, self.a)
+        elif op in RMWS:
+            addr, _, _ = self.address(mode, True)
+            data = self.read(addr)
+            self.write(addr, data)
+            self.write(addr, self.rmw_op(op, data))
+        elif op in BRANCHES:
+            offset = self.fetch()
+            mask, value = BRANCHES[op]
+            if self.p & mask == value:
+                self.read(self.pc)
+                target = (self.pc + offset - (0x100 if offset & 0x80 else 0)) & 0xFFFF
+                if target & 0xFF00 != self.pc & 0xFF00:
+                    self.read(self.pc & 0xFF00 | target & 0xFF)
+                self.pc = target
+        elif op == "JMP":
+            lo = self.fetch()
+            addr = self.fetch() << 8 | lo
+            if mode == "ind":
+                lo = self.read(addr)
+                addr = self.read(addr & 0xFF00 | (addr + 1) & 0xFF) << 8 | lo
+            self.pc = addr
+        elif op == "JSR":
+            lo = self.fetch()
+            self.read(0x100 + self.s)
+            self.push(self.pc >> 8)
+            self.push(self.pc & 0xFF)
+            self.pc = self.read(self.pc) << 8 | lo
+        elif op == "RTS":
+            self.read(self.pc)
+            self.read(0x100 + self.s)
+            lo = self.pull()
+            self.pc = self.pull() << 8 | lo
+            self.read(self.pc)
+            self.pc = (self.pc + 1) & 0xFFFF
+        elif op == "RTI":
+            self.read(self.pc)
+            self.read(0x100 + self.s)
+            self.p = self.pull() & ~B | U
+            lo = self.pull()
+            self.pc = self.pull() << 8 | lo
+        elif op == "BRK":
+            self.fetch()
+            self.push(self.pc >> 8)
+            self.push(self.pc & 0xFF)
+            self.push(self.p | B | U)
+            self.p |= I
+            lo = self.read(0xFFFE)
+            self.pc = self.read(0xFFFF) << 8 | lo
+        elif op in ("PHA", "PHP"):
+            self.read(self.pc)
+            self.push(self.a if op == "PHA" else self.p | B | U)
+        elif op in ("PLA", "PLP"):
+            self.read(self.pc)
+            self.read(0x100 + self.s)
+            data = self.pull()
+            if op == "PLA":
+                self.a = self.nz(data)
+            else:
+                self.p = data & ~B | U
+        elif op == "JAM":
+            self.read(self.pc)
+            self.pc = start
+        else:
+            # implied
+            self.read(self.pc)
+            if op in FLAG_OPS:
+                mask, value = FLAG_OPS[op]
+                self.flag(mask, value)
+            elif op in ("TAX", "TAY", "TSX"):
+                value = self.nz({"TAX": self.a, "TAY": self.a, "TSX": self.s}[op])
+                if op == "TAY":
+                    self.y = value
+                else:
+                    self.x = value
+            elif op in ("TXA", "TYA"):
+                self.a = self.nz(self.x if op == "TXA" else self.y)
+            elif op == "TXS":
+                self.s = self.x
+            elif op in ("INX", "DEX"):
+                self.x = self.nz((self.x + (1 if op == "INX" else -1)) & 0xFF)
+            elif op in ("INY", "DEY"):
+                self.y = self.nz((self.y + (1 if op == "INY" else -1)) & 0xFF)
+            elif op != "NOP":
+                raise ValueError(op)
+
+
+def case(rng, opcode):
+    cpu = Cpu(rng)
+    # the opcode byte is part of the initial RAM, set by step
+    before = (cpu.pc, cpu.s, cpu.a, cpu.x, cpu.y, cpu.p)
+    cpu.step(opcode)
+    after = cpu.state(cpu.mem)
+    cpu.pc, cpu.s, cpu.a, cpu.x, cpu.y, cpu.p = before
+    initial = cpu.state(cpu.initial)
+    name = " ".join("{:02x}".format(cpu.initial.get((cpu.pc + i) & 0xFFFF, 0)) for i in range(3))
+    return {"name": name, "initial": initial, "final": after, "cycles": cpu.cycles}
+
+
+def main():
+    count = int(sys.argv[1]) if len(sys.argv) > 1 else 20
+    out = os.path.join(os.path.dirname(os.path.abspath(__file__)), "generated")
+    os.makedirs(out, exist_ok=True)
+    for opcode in range(256):
+        rng = random.Random(opcode)
+        cases = [case(rng, opcode) for _ in range(count)]
+        with open(os.path.join(out, "{:02x}.json".format(opcode)), "w") as f:
+            f.write("[\n")
+            f.write(",\n".join(json.dumps(c, separators=(",", ":")) for c in cases))
+            f.write("\n]\n")
+
+
+if __name__ == "__main__":
+    main()
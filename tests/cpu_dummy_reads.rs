@@ -0,0 +1,51 @@
+// http://wiki.nesdev.com/w/index.php/Emulator_tests
+// test_rom/cpu_dummy_reads.nes is a blargg's test: it prints the result into the name table
+use rustness::bus::Bus;
+use rustness::cpu::cpu::CPU;
+use rustness::input;
+use rustness::ppu::ppu::NesPPU;
+use rustness::rom::Rom;
+
+const MAX_INSTRUCTIONS: usize = 5_000_000;
+
+fn screen_text(ppu: &NesPPU) -> String {
+    ppu.vram
+        .iter()
+        .map(|&b| {
+            if (0x20..0x7f).contains(&b) {
+                b as char
+            } else {
+                ' '
+            }
+        })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+#[test]
+fn test_cpu_dummy_reads() {
+    let data = std::fs::read("test_rom/cpu_dummy_reads.nes").unwrap();
+    let rom = Rom::load(&data).unwrap();
+    let bus = Bus::<NesPPU>::new(rom, |_: &NesPPU, _: &mut input::Joypad| {});
+    let mut cpu = CPU::new(bus);
+    cpu.power_on();
+
+    for i in 0..MAX_INSTRUCTIONS {
+        cpu.step();
+        if i % 10_000 == 0 {
+            let text = screen_text(cpu.bus.ppu());
+            if text.contains("Passed") {
+                return;
+            }
+            if text.contains("Failed") || text.contains("Error") {
+                panic!("cpu_dummy_reads failed: {}", text);
+            }
+        }
+    }
+    panic!(
+        "cpu_dummy_reads didn't finish: {}",
+        screen_text(cpu.bus.ppu())
+    );
+}
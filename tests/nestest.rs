@@ -0,0 +1,48 @@
+// http://wiki.nesdev.com/w/index.php/Emulator_tests
+// test_rom/nestest.nes in automation mode (starting at $C000) runs through all official and
+// unofficial opcodes without a PPU. test_rom/nestest.log is the reference trace from Nintendulator.
+use rustness::bus::Bus;
+use rustness::cpu::cpu::CPU;
+use rustness::input;
+use rustness::ppu::ppu::NesPPU;
+use rustness::rom::Rom;
+
+const CONTEXT_LINES: usize = 5;
+
+#[test]
+fn test_nestest_golden_log() {
+    let data = std::fs::read("test_rom/nestest.nes").unwrap();
+    let golden = std::fs::read_to_string("test_rom/nestest.log").unwrap();
+    let golden: Vec<&str> = golden.lines().collect();
+
+    let rom = Rom::load(&data).unwrap();
+    let bus = Bus::<NesPPU>::new(rom, |_: &NesPPU, _: &mut input::Joypad| {});
+    let mut cpu = CPU::new(bus);
+    cpu.power_on();
+    cpu.program_counter = 0xc000;
+
+    for (i, expected) in golden.iter().enumerate() {
+        let actual = rustness::cpu::trace(&cpu);
+        if actual != *expected {
+            let context = golden[i.saturating_sub(CONTEXT_LINES)..i].join("\n");
+            let expected_after =
+                golden[i + 1..(i + 1 + CONTEXT_LINES).min(golden.len())].join("\n");
+            let mut actual_after = vec![];
+            for _ in 0..CONTEXT_LINES {
+                cpu.step();
+                actual_after.push(rustness::cpu::trace(&cpu));
+            }
+            panic!(
+                "nestest.log diverges at line {}:\n{}\nexpected: {}\nactual:   {}\n\
+                 expected next:\n{}\nactual next:\n{}",
+                i + 1,
+                context,
+                expected,
+                actual,
+                expected_after,
+                actual_after.join("\n")
+            );
+        }
+        cpu.step();
+    }
+}
@@ -0,0 +1,71 @@
+// Arbitrary ROM content must never bring the emulator down: random PRG/CHR data
+// either runs forever or jams the CPU
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rustness::bus::Bus;
+use rustness::cpu::cpu::{HaltReason, CPU};
+use rustness::input;
+use rustness::ppu::ppu::NesPPU;
+use rustness::rom::Rom;
+
+const SEEDS: u64 = 32;
+const CYCLES_PER_ROM: usize = 300_000;
+/// Every supported mapper, with 16K PRG ROM banks below and above its fixed bank count
+const MAPPERS: [u8; 8] = [0, 1, 2, 3, 4, 7, 9, 10];
+const PRG_BANKS: [u8; 3] = [1, 2, 8];
+const SEEDS_PER_MAPPER: u64 = 2;
+
+fn random_rom(rng: &mut StdRng, mapper: u8, prg_banks: u8) -> Vec<u8> {
+    let chr_banks = rng.gen_range(0, 3);
+    let mut data = vec![b'N', b'E', b'S', 0x1a, prg_banks, chr_banks];
+    data.extend_from_slice(&[mapper << 4 | rng.gen::<u8>() & 0x01, mapper & 0xf0]);
+    data.extend_from_slice(&[0; 8]);
+    let mut banks = vec![0u8; prg_banks as usize * 0x4000 + chr_banks as usize * 0x2000];
+    rng.fill(&mut banks[..]);
+    // KILs would jam the CPU within the first few instructions, while the point is to run for a while
+    for b in banks
+        .iter_mut()
+        .filter(|b| **b & 0x0f == 0x02 && **b != 0x82 && **b != 0xc2 && **b != 0xe2)
+    {
+        *b = 0xea;
+    }
+    data.extend(banks);
+    data
+}
+
+fn run(data: &[u8]) -> HaltReason {
+    let rom = Rom::load(data).unwrap();
+    let bus = Bus::<NesPPU>::new(rom, |_: &NesPPU, _: &mut input::Joypad| {});
+    let mut cpu = CPU::new(bus);
+    cpu.power_on();
+    cpu.run_cycles(CYCLES_PER_ROM)
+}
+
+#[test]
+fn test_random_rom_content_does_not_panic() {
+    for seed in 0..SEEDS {
+        let mut rng = StdRng::seed_from_u64(seed);
+        match run(&random_rom(&mut rng, 0, 2)) {
+            HaltReason::Jam { pc, opcode } => {
+                println!("seed {}: jammed at {:04x} ({:02x})", seed, pc, opcode)
+            }
+            halt => println!("seed {}: {:?}", seed, halt),
+        }
+    }
+}
+
+#[test]
+fn test_random_rom_content_does_not_panic_on_any_mapper() {
+    for mapper in MAPPERS.iter() {
+        for prg_banks in PRG_BANKS.iter() {
+            for seed in 0..SEEDS_PER_MAPPER {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let halt = run(&random_rom(&mut rng, *mapper, *prg_banks));
+                println!(
+                    "mapper {}, {}x16K, seed {}: {:?}",
+                    mapper, prg_banks, seed, halt
+                );
+            }
+        }
+    }
+}